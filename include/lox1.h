@@ -0,0 +1,91 @@
+/*
+ * The C interface to the lox1 interpreter, built into liblox1 with the `ffi` feature:
+ *
+ *     cargo build --release --features ffi
+ *
+ * Every handle, value and string these functions hand out belongs to the caller, and has to
+ * be passed back to the matching `_free` function once it's no longer needed. Null is
+ * accepted anywhere a pointer is, and never dereferenced; see src/ffi.rs for what each
+ * function does with one.
+ */
+
+#ifndef LOX1_H
+#define LOX1_H
+
+#include <stdbool.h>
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* An interpreter along with its globals and the last error it ran into. */
+typedef struct LoxHandle LoxHandle;
+
+/* A Lox value. */
+typedef struct LoxValue LoxValue;
+
+/* What lox_value_type returns. */
+#define LOX_NIL 0
+#define LOX_BOOL 1
+#define LOX_NUMBER 2
+#define LOX_STRING 3
+/* A function, class, instance, list or map, which can only be passed back to Lox. */
+#define LOX_OBJECT 4
+
+/* What lox_run returns when given a null handle or source, as sysexits.h's EX_USAGE. */
+#define LOX_USAGE_ERROR 64
+
+/*
+ * A native function. It's given the arguments, which are only valid until it returns, and
+ * the user_data it was registered with. It returns a new value from one of the lox_value_
+ * functions for the interpreter to take ownership of, or NULL to fail with a runtime error.
+ * It mustn't call back into the interpreter running it.
+ */
+typedef LoxValue *(*LoxNativeFn)(const LoxValue *const *args, size_t arg_count, void *user_data);
+
+/* Creates an interpreter with the standard library. Errors are kept, not printed. */
+LoxHandle *lox_new(void);
+void lox_free(LoxHandle *handle);
+
+/*
+ * Runs source, keeping the globals it defines. Returns 0 if it ran to the end, the code it
+ * passed to exit(), 65 if it didn't compile, 70 if it failed as it ran, or LOX_USAGE_ERROR.
+ */
+int lox_run(LoxHandle *handle, const char *source);
+
+/* What went wrong in the last call that failed, or NULL. Valid until the next call. */
+const char *lox_last_error(const LoxHandle *handle);
+
+/* The global called name, or NULL if it has no value. */
+LoxValue *lox_get_global(LoxHandle *handle, const char *name);
+
+/* Defines the global called name to hold a copy of value. Returns whether it did. */
+bool lox_set_global(LoxHandle *handle, const char *name, const LoxValue *value);
+
+/* Defines a global function called name. Returns whether it did. */
+bool lox_register_native(LoxHandle *handle, const char *name, size_t arity, LoxNativeFn function, void *user_data);
+
+LoxValue *lox_value_nil(void);
+LoxValue *lox_value_bool(bool value);
+LoxValue *lox_value_number(double number);
+/* A copy of string, or NULL if it isn't valid UTF-8. */
+LoxValue *lox_value_string(const char *string);
+void lox_value_free(LoxValue *value);
+
+/* One of LOX_NIL, LOX_BOOL, LOX_NUMBER, LOX_STRING or LOX_OBJECT. */
+int lox_value_type(const LoxValue *value);
+/* Whether value is truthy, as an if would decide. */
+bool lox_value_as_bool(const LoxValue *value);
+/* The number value holds, or NaN. */
+double lox_value_as_number(const LoxValue *value);
+
+/* value as print would show it, to be freed with lox_string_free. */
+char *lox_value_to_string(const LoxHandle *handle, const LoxValue *value);
+void lox_string_free(char *string);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
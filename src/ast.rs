@@ -1,68 +1,132 @@
 use scanner;
-use std::rc::Rc;
+use scanner::Span;
+use std::sync::Arc;
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Binary {
     pub left: Box<Expr>,
     pub operator: scanner::Token,
-    pub right: Box<Expr>
+    pub right: Box<Expr>,
+    pub span: Span
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Call {
     pub callee: Box<Expr>,
     pub paren: scanner::Token,
-    pub arguments: Vec<Expr>
+    pub arguments: Vec<Expr>,
+    pub span: Span
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Grouping {
-    pub expression: Box<Expr>
+    pub expression: Box<Expr>,
+    pub span: Span
 }
 
-#[derive(Debug, Clone)]
+/// A literal as written in the source. The interpreter turns it into a `Value` when it's
+/// evaluated.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Literal {
-    Callable(Rc<Callable>),
-    String(String),
+    String(Arc<str>),
     Number(f64),
     Bool(bool),
     Nil
 }
 
-impl PartialEq for Literal {
-    fn eq(&self, other: &Literal) -> bool {
-        match (self, other) {
-            (&Literal::String(ref string1), &Literal::String(ref string2)) => string1 == string2,
-            (&Literal::Number(ref number1), &Literal::Number(ref number2)) => number1 == number2,
-            (&Literal::Bool(ref bool1), &Literal::Bool(ref bool2)) => bool1 == bool2,
-            (&Literal::Nil, &Literal::Nil) => true,
-            _ => false
-        }
-    }
-}
-
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Logical {
     pub left: Box<Expr>,
     pub operator: scanner::Token,
-    pub right: Box<Expr>
+    pub right: Box<Expr>,
+    pub span: Span
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Unary {
     pub operator: scanner::Token,
-    pub right: Box<Expr>
+    pub right: Box<Expr>,
+    pub span: Span
+}
+
+/// A local's position: how many scopes out from the innermost it's declared, and where it sits
+/// among that scope's declarations.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Slot {
+    pub depth: usize,
+    pub index: usize
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Variable {
-    pub name: scanner::Token
+    pub name: scanner::Token,
+    pub span: Span,
+    /// Where the variable lives, once the resolver has run. None for globals, which are looked
+    /// up by name.
+    pub slot: Option<Slot>
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Assign {
     pub name: scanner::Token,
-    pub value: Box<Expr>
+    pub value: Box<Expr>,
+    pub span: Span,
+    /// As for `Variable::slot`.
+    pub slot: Option<Slot>
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Get {
+    pub object: Box<Expr>,
+    pub name: scanner::Token,
+    pub span: Span
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Set {
+    pub object: Box<Expr>,
+    pub name: scanner::Token,
+    pub value: Box<Expr>,
+    pub span: Span
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct This {
+    pub keyword: scanner::Token,
+    pub span: Span,
+    /// As for `Variable::slot`.
+    pub slot: Option<Slot>
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Super {
+    pub keyword: scanner::Token,
+    pub method: scanner::Token,
+    pub span: Span,
+    /// As for `Variable::slot`.
+    pub slot: Option<Slot>
+}
+
+/// Stands in for an expression or statement that couldn't be parsed, so that the rest of the
+/// tree survives a syntax error. `token` is where the error was found.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ErrorNode {
+    pub token: scanner::Token,
+    pub span: Span
 }
 
 pub trait ExprVisitor<T> {
@@ -81,18 +145,34 @@ pub trait ExprVisitor<T> {
     fn visit_assign<'a>(&mut self, _: &'a Assign) -> T;
 
     fn visit_logical<'a>(&mut self, _: &'a Logical) -> T;
+
+    fn visit_get<'a>(&mut self, _: &'a Get) -> T;
+
+    fn visit_set<'a>(&mut self, _: &'a Set) -> T;
+
+    fn visit_this<'a>(&mut self, _: &'a This) -> T;
+
+    fn visit_super<'a>(&mut self, _: &'a Super) -> T;
+
+    fn visit_error<'a>(&mut self, _: &'a ErrorNode) -> T;
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Expr {
     Binary(Binary),
     Call(Call),
     Grouping(Grouping),
-    Literal(Literal),
+    Literal(Literal, Span),
     Logical(Logical),
     Unary(Unary),
     Variable(Variable),
-    Assign(Assign)
+    Assign(Assign),
+    Get(Get),
+    Set(Set),
+    This(This),
+    Super(Super),
+    Error(ErrorNode)
 }
 
 impl Expr {
@@ -101,11 +181,35 @@ impl Expr {
             Expr::Binary(ref v) => visitor.visit_binary(v),
             Expr::Call(ref v) => visitor.visit_call(v),
             Expr::Grouping(ref v) => visitor.visit_grouping(v),
-            Expr::Literal(ref v) => visitor.visit_literal(v),
+            Expr::Literal(ref v, _) => visitor.visit_literal(v),
             Expr::Unary(ref v) => visitor.visit_unary(v),
             Expr::Variable(ref v) => visitor.visit_variable(v),
             Expr::Assign(ref v) => visitor.visit_assign(v),
-            Expr::Logical(ref v) => visitor.visit_logical(v)
+            Expr::Logical(ref v) => visitor.visit_logical(v),
+            Expr::Get(ref v) => visitor.visit_get(v),
+            Expr::Set(ref v) => visitor.visit_set(v),
+            Expr::This(ref v) => visitor.visit_this(v),
+            Expr::Super(ref v) => visitor.visit_super(v),
+            Expr::Error(ref v) => visitor.visit_error(v)
+        }
+    }
+
+    /// The region of source this expression was parsed from.
+    pub fn span(&self) -> Span {
+        match *self {
+            Expr::Binary(ref v) => v.span,
+            Expr::Call(ref v) => v.span,
+            Expr::Grouping(ref v) => v.span,
+            Expr::Literal(_, span) => span,
+            Expr::Unary(ref v) => v.span,
+            Expr::Variable(ref v) => v.span,
+            Expr::Assign(ref v) => v.span,
+            Expr::Logical(ref v) => v.span,
+            Expr::Get(ref v) => v.span,
+            Expr::Set(ref v) => v.span,
+            Expr::This(ref v) => v.span,
+            Expr::Super(ref v) => v.span,
+            Expr::Error(ref v) => v.span
         }
     }
 }
@@ -135,59 +239,132 @@ impl Expr {
 //}
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Stmt {
-    Expression(Expr),
+    Defer(Arc<Expr>, Span),
+    Expression(Expr, Span),
+    Function(Arc<Function>),
     If(If),
-    Print(Expr),
+    Print(Expr, Span),
+    Return(Return),
     Var(Var),
     While(While),
-    Block(Block)
+    Block(Block),
+    Class(Class),
+    Error(ErrorNode)
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Function {
+    pub name: scanner::Token,
+    pub params: Vec<scanner::Token>,
+    // Optional type annotations: one per parameter, and the one after `->`.
+    pub param_types: Vec<Option<scanner::Token>>,
+    pub return_type: Option<scanner::Token>,
+    pub body: Vec<Stmt>,
+    pub span: Span
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Class {
+    pub name: scanner::Token,
+    pub superclass: Option<Variable>,
+    pub mixins: Vec<Variable>,
+    pub methods: Vec<Arc<Function>>,
+    pub span: Span
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Return {
+    pub keyword: scanner::Token,
+    pub value: Option<Expr>,
+    pub span: Span
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct If {
     pub condition: Expr,
     pub then_branch: Box<Stmt>,
-    pub else_branch: Option<Box<Stmt>>
+    pub else_branch: Option<Box<Stmt>>,
+    pub span: Span
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Var {
     pub name: scanner::Token,
-    pub initializer: Option<Expr>
+    pub type_annotation: Option<scanner::Token>,
+    pub initializer: Option<Expr>,
+    pub span: Span
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct While {
     pub condition: Expr,
-    pub body: Box<Stmt>
+    pub body: Box<Stmt>,
+    pub span: Span
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Block {
-    pub statements: Vec<Stmt>
+    pub statements: Vec<Stmt>,
+    pub span: Span
 }
 
 impl Stmt {
     pub fn accept<'a, T: StmtVisitor<U> + 'a, U>(&self, visitor: &'a mut T) -> U {
         match *self {
-            Stmt::Expression(ref v) => visitor.visit_expr(v),
-            Stmt::Print(ref v) => visitor.visit_print(v),
+            Stmt::Defer(ref v, _) => visitor.visit_defer(v),
+            Stmt::Expression(ref v, _) => visitor.visit_expr(v),
+            Stmt::Function(ref v) => visitor.visit_function(v),
+            Stmt::Print(ref v, _) => visitor.visit_print(v),
+            Stmt::Return(ref v) => visitor.visit_return(v),
             Stmt::Var(ref v) => visitor.visit_var(v),
             Stmt::Block(ref v) => visitor.visit_block(v),
             Stmt::If(ref v) => visitor.visit_if(v),
-            Stmt::While(ref v) => visitor.visit_while(v)
+            Stmt::While(ref v) => visitor.visit_while(v),
+            Stmt::Class(ref v) => visitor.visit_class(v),
+            Stmt::Error(ref v) => visitor.visit_error(v)
+        }
+    }
+
+    /// The region of source this statement was parsed from. Statements desugared from a `for`
+    /// loop share the loop's span.
+    pub fn span(&self) -> Span {
+        match *self {
+            Stmt::Defer(_, span) => span,
+            Stmt::Expression(_, span) => span,
+            Stmt::Print(_, span) => span,
+            Stmt::Function(ref v) => v.span,
+            Stmt::Return(ref v) => v.span,
+            Stmt::Var(ref v) => v.span,
+            Stmt::Block(ref v) => v.span,
+            Stmt::If(ref v) => v.span,
+            Stmt::While(ref v) => v.span,
+            Stmt::Class(ref v) => v.span,
+            Stmt::Error(ref v) => v.span
         }
     }
 }
 
 pub trait StmtVisitor<T> {
+    fn visit_defer<'a>(&mut self, _: &'a Arc<Expr>) -> T;
     fn visit_expr<'a>(&mut self, _: &'a Expr) -> T;
+    fn visit_function<'a>(&mut self, _: &'a Arc<Function>) -> T;
     fn visit_print<'a>(&mut self, _: &'a Expr) -> T;
+    fn visit_return<'a>(&mut self, _: &'a Return) -> T;
     fn visit_var<'a>(&mut self, _: &'a Var) -> T;
     fn visit_block<'a>(&mut self, _: &'a Block) -> T;
     fn visit_if<'a>(&mut self, _: &'a If) -> T;
     fn visit_while<'a>(&mut self, _: &'a While) -> T;
+    fn visit_class<'a>(&mut self, _: &'a Class) -> T;
+    fn visit_error<'a>(&mut self, _: &'a ErrorNode) -> T;
 }
 
 pub struct AstPrinter;
@@ -218,8 +395,10 @@ impl ExprVisitor<String> for AstPrinter {
         self.parenthesize(&format!("{}", expr.operator.lexeme), vec![&*expr.left, &*expr.right])
     }
 
-    fn visit_call<'a>(&mut self, _: &'a Call) -> String {
-        unimplemented!()
+    fn visit_call<'a>(&mut self, expr: &'a Call) -> String {
+        let mut exprs = vec![&*expr.callee];
+        exprs.extend(expr.arguments.iter());
+        self.parenthesize("call", exprs)
     }
 
     fn visit_grouping<'a>(&mut self, expr: &'a Grouping) -> String {
@@ -245,9 +424,24 @@ impl ExprVisitor<String> for AstPrinter {
     fn visit_logical<'a>(&mut self, expr: &'a Logical) -> String {
         self.parenthesize(&format!("{}", expr.operator.lexeme), vec![&*expr.left, &*expr.right])
     }
-}
 
-pub trait Callable: ::std::fmt::Debug {
-    fn call(&self, interpreter: &mut ::interpreter::Interpreter, arguments: Vec<Literal>) -> Result<Literal, ::interpreter::RuntimeError>;
-    fn arity(&self) -> usize;
-}
\ No newline at end of file
+    fn visit_get<'a>(&mut self, expr: &'a Get) -> String {
+        self.parenthesize(&format!(". {}", expr.name.lexeme), vec![&*expr.object])
+    }
+
+    fn visit_set<'a>(&mut self, expr: &'a Set) -> String {
+        self.parenthesize(&format!("= {}", expr.name.lexeme), vec![&*expr.object, &*expr.value])
+    }
+
+    fn visit_this<'a>(&mut self, _: &'a This) -> String {
+        "this".to_string()
+    }
+
+    fn visit_super<'a>(&mut self, expr: &'a Super) -> String {
+        format!("(super {})", expr.method.lexeme)
+    }
+
+    fn visit_error<'a>(&mut self, _: &'a ErrorNode) -> String {
+        "(error)".to_string()
+    }
+}
@@ -1,16 +1,107 @@
 use std::env;
+use std::io::stderr;
+use std::process::exit;
+use std::thread;
 extern crate lox1;
 
 use lox1::*;
+use lox1::coverage::Report;
+use lox1::interpreter::InterpreterConfig;
+use lox1::lint::Severity;
 
+// Scripts run on a thread of their own so that they have room to recurse as deeply as the
+// interpreter's call depth limit allows, which the main thread's stack isn't always enough for.
+const STACK_SIZE: usize = 256 * 1024 * 1024;
+
+// Options come before the script: `-W <lint>`, `-A <lint>` and `-D <lint>` warn about, allow
+// or deny a lint, as in `lox1exec -W shadowing script.lox`, `--trace` logs everything the
+// script runs to stderr, `--profile` reports where it spent its time once it's done,
+// `--debug` starts it paused in a debugger and `--watch <name>` reports every change to a
+// variable in the trace or the debugger. `--lcov <path>` and `--annotate <path>` write a report
+// of which lines ran, as an LCOV tracefile or as the script with counts down the side.
+// `--state <path>` loads the globals saved in a file before running and saves them back after.
+// `--sandbox` stops the script reaching outside the interpreter: no files, input, environment,
+// clock or random numbers.
 fn main() {
+    let runner = thread::Builder::new().stack_size(STACK_SIZE).spawn(run).unwrap();
+    if runner.join().is_err() {
+        exit(70);
+    }
+}
+
+fn run() {
     let mut lox = Lox::new();
-    let args: Vec<_> = env::args().collect();
-    if args.len() > 2 {
-        println!("Usage: rlox1 [script]");
-    } else if args.len() == 2 {
-        lox.run_file(&args[1]).unwrap();
+    lox.set_reporter(Box::new(PrintReporter::new(Box::new(stderr()))));
+    let mut args: Vec<_> = env::args().skip(1).collect();
+
+    while args.len() >= 1 {
+        let severity = match &args[0][..] {
+            "--trace" => {
+                lox.trace = true;
+                args.remove(0);
+                continue;
+            },
+            "--profile" => {
+                lox.profile = true;
+                args.remove(0);
+                continue;
+            },
+            "--sandbox" => {
+                lox.config = InterpreterConfig::sandboxed();
+                args.remove(0);
+                continue;
+            },
+            "--debug" => {
+                lox.debug = true;
+                args.remove(0);
+                continue;
+            },
+            _ if args.len() < 2 => break,
+            "--watch" => {
+                lox.watches.push(args[1].clone());
+                args.drain(..2);
+                continue;
+            },
+            "--lcov" => {
+                lox.coverage = Some(Report::Lcov(args[1].clone()));
+                args.drain(..2);
+                continue;
+            },
+            "--annotate" => {
+                lox.coverage = Some(Report::Annotated(args[1].clone()));
+                args.drain(..2);
+                continue;
+            },
+            "--state" => {
+                lox.state = Some(args[1].clone());
+                args.drain(..2);
+                continue;
+            },
+            "-W" => Severity::Warn,
+            "-A" => Severity::Allow,
+            "-D" => Severity::Deny,
+            _ => break
+        };
+
+        if !lox.linter.set_severity(&args[1], severity) {
+            eprintln!("Unknown lint '{}'.", args[1]);
+            exit(64);
+        }
+        args.drain(..2);
+    }
+
+    let result = if args.len() >= 1 {
+        lox.args = args[1..].to_vec();
+        lox.run_file(&args[0])
     } else {
-        lox.run_prompt();
+        lox.run_prompt()
+    };
+
+    // Compile and runtime errors have been reported already.
+    if let Err(error) = result {
+        if let LoxError::Io(ref error) = error {
+            eprintln!("{}", error);
+        }
+        exit(error.exit_code());
     }
 }
@@ -1,27 +1,30 @@
+use std::env;
 extern crate lox1;
 use lox1::ast::*;
-use lox1::scanner::*;
+use lox1::parser::Parser;
+use lox1::scanner::Scanner;
 
 fn main() {
-    let ast = Binary {
-        left: Box::new(Unary {
-            operator: Token {
-                lexeme: "-".to_string(),
-                line: 0,
-                token_type: TokenType::Minus
-            },
-            right: Box::new(Literal::Number(123.0).into())
-        }.into()),
-        operator: Token {
-            lexeme: "*".to_string(),
-            line: 0,
-            token_type: TokenType::Star
-        },
-        right: Box::new(Grouping {
-            expression: Box::new(Literal::Number(45.67).into())
-        }.into())
-    }.into();
+    let args: Vec<_> = env::args().collect();
+    let source = if args.len() >= 2 { args[1..].join(" ") } else { "-123 * (45.67)".to_string() };
+
+    let (tokens, errors) = Scanner::new(&source).scan_tokens();
+    if let Some(error) = errors.into_iter().next() {
+        eprintln!("{}", error);
+        return;
+    }
 
-    let printer = AstPrinter;
-    println!("{}", printer.print(&ast));
-}
\ No newline at end of file
+    let mut parser = Parser::new(&tokens);
+    let mut errors: Vec<String> = Vec::new();
+    match parser.parse_expression(&mut errors) {
+        Ok(ast) => {
+            let mut printer = AstPrinter;
+            println!("{}", printer.print(&ast));
+        },
+        Err(_) => {
+            for error in errors {
+                eprintln!("{}", error);
+            }
+        }
+    }
+}
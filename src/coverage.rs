@@ -0,0 +1,132 @@
+use std::collections::BTreeMap;
+use ast::Stmt;
+
+/// Which lines of a script have statements on them, and how many times those statements ran.
+#[derive(Debug, Clone, Default)]
+pub struct Coverage {
+    lines: BTreeMap<i32, u64>
+}
+
+/// Where `lox1exec` writes a coverage report once the script has run, and in what format.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Report {
+    Lcov(String),
+    Annotated(String)
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Coverage::default()
+    }
+
+    /// Counts every line with a statement on it, including those in function bodies, as one
+    /// that should run.
+    pub fn add_statements(&mut self, statements: &[Stmt]) {
+        for statement in statements {
+            self.add_statement(statement);
+        }
+    }
+
+    fn add_statement(&mut self, statement: &Stmt) {
+        self.lines.entry(statement.span().line).or_insert(0);
+        match *statement {
+            Stmt::Function(ref function) => self.add_statements(&function.body),
+            Stmt::Class(ref class) => {
+                for method in &class.methods {
+                    self.add_statements(&method.body);
+                }
+            },
+            Stmt::If(ref if_statement) => {
+                self.add_statement(&if_statement.then_branch);
+                if let Some(ref else_branch) = if_statement.else_branch {
+                    self.add_statement(else_branch);
+                }
+            },
+            Stmt::While(ref while_statement) => self.add_statement(&while_statement.body),
+            Stmt::Block(ref block) => self.add_statements(&block.statements),
+            _ => ()
+        }
+    }
+
+    pub fn hit(&mut self, line: i32) {
+        *self.lines.entry(line).or_insert(0) += 1;
+    }
+
+    /// How many times the statements on each line ran, by line. Lines with statements that
+    /// never ran are there with a count of 0.
+    pub fn lines(&self) -> &BTreeMap<i32, u64> {
+        &self.lines
+    }
+
+    /// The report in LCOV's tracefile format, for the script at `path`.
+    pub fn lcov(&self, path: &str) -> String {
+        let mut report = format!("TN:\nSF:{}\n", path);
+        for (line, hits) in &self.lines {
+            report.push_str(&format!("DA:{},{}\n", line, hits));
+        }
+        let hit = self.lines.values().filter(|&&hits| hits > 0).count();
+        report.push_str(&format!("LF:{}\nLH:{}\nend_of_record\n", self.lines.len(), hit));
+        report
+    }
+
+    /// `source` with how many times each line ran in front of it: `#####` for a line that
+    /// never did, and `-` for one with no statements.
+    pub fn annotate(&self, source: &str) -> String {
+        let mut report = String::new();
+        for (index, text) in source.lines().enumerate() {
+            let count = match self.lines.get(&(index as i32 + 1)) {
+                Some(&0) => "#####".to_string(),
+                Some(hits) => hits.to_string(),
+                None => "-".to_string()
+            };
+            report.push_str(&format!("{:>9}: {}\n", count, text));
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use interpreter::Interpreter;
+    use {CollectingReporter, Lox};
+
+    const SOURCE: &str = "fun f(x) {\n  if (x) {\n    return 1;\n  }\n  return 2;\n}\n\nf(true);\nf(true);";
+
+    fn coverage() -> Coverage {
+        let mut interpreter = Interpreter::with_stdlib();
+        interpreter.set_coverage(true);
+        let mut lox = Lox::new();
+        lox.set_reporter(Box::new(CollectingReporter::new()));
+        lox.run(&SOURCE.to_string(), &mut interpreter).unwrap();
+        interpreter.coverage().unwrap().clone()
+    }
+
+    #[test]
+    fn counts_lines_that_ran_and_those_that_never_did() {
+        let lines: Vec<(i32, u64)> = coverage().lines().iter().map(|(&line, &hits)| (line, hits)).collect();
+        assert_eq!(lines, vec![(1, 1), (2, 4), (3, 2), (5, 0), (8, 1), (9, 1)]);
+    }
+
+    #[test]
+    fn writes_an_lcov_tracefile() {
+        assert_eq!(coverage().lcov("test.lox"), "TN:\nSF:test.lox\nDA:1,1\nDA:2,4\nDA:3,2\nDA:5,0\nDA:8,1\nDA:9,1\nLF:6\nLH:5\nend_of_record\n");
+    }
+
+    #[test]
+    fn annotates_the_source() {
+        let expected = [
+            "        1: fun f(x) {",
+            "        4:   if (x) {",
+            "        2:     return 1;",
+            "        -:   }",
+            "    #####:   return 2;",
+            "        -: }",
+            "        -: ",
+            "        1: f(true);",
+            "        1: f(true);",
+            ""
+        ];
+        assert_eq!(coverage().annotate(SOURCE), expected.join("\n"));
+    }
+}
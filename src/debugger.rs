@@ -0,0 +1,448 @@
+use std::io::{stdin, stdout, BufReader};
+use std::io::prelude::*;
+use std::sync::Arc;
+use diagnostics;
+use interpreter::{Frame, Interpreter, Value};
+use scanner::Span;
+
+/// Gets control whenever the interpreter pauses: before a statement on a line with a
+/// breakpoint, before the next statement once it's been asked to step, or when a watched
+/// variable changes.
+pub trait Debugger {
+    /// Called with the script stopped just before the statement at `pause.span()`, returning
+    /// how to carry on.
+    fn paused(&mut self, pause: &mut Pause) -> Resume;
+
+    /// Called just after a watched variable has been declared or assigned, at `pause.span()`.
+    /// Carries straight on unless overridden.
+    fn changed(&mut self, _pause: &mut Pause, _change: &Change) -> Resume {
+        Resume::Continue
+    }
+}
+
+/// A watched variable being given a value.
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub name: Arc<str>,
+    /// What it held before, or None if it was only just declared or never assigned.
+    pub old: Option<Value>,
+    /// What it holds now, or None if it was declared without a value.
+    pub new: Option<Value>,
+    pub line: i32
+}
+
+impl Change {
+    /// Says what changed, as in `x changed from 1 to 2`, showing values with `stringify`.
+    pub fn describe<F: Fn(Value) -> String>(&self, stringify: F) -> String {
+        match (self.old.clone(), self.new.clone()) {
+            (Some(old), Some(new)) => format!("{} changed from {} to {}", self.name, stringify(old), stringify(new)),
+            (None, Some(new)) => format!("{} set to {}", self.name, stringify(new)),
+            (_, None) => format!("{} declared", self.name)
+        }
+    }
+}
+
+/// How to carry on from a pause.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resume {
+    /// Run until the next breakpoint.
+    Continue,
+    /// Pause again before the very next statement, even one inside a function being called.
+    Step,
+    /// Pause again before the next statement that isn't inside a function called from here.
+    StepOver,
+    /// Pause again once the current function has returned.
+    StepOut,
+    /// Stop the script, as cancelling it would.
+    Stop
+}
+
+/// The state of a paused script, for a debugger to look at.
+pub struct Pause<'i> {
+    interpreter: &'i mut Interpreter,
+    span: Span
+}
+
+impl<'i> Pause<'i> {
+    pub fn new(interpreter: &'i mut Interpreter, span: Span) -> Self {
+        Pause {
+            interpreter: interpreter,
+            span: span
+        }
+    }
+
+    /// Where the script is paused: the statement about to run, or the name of the variable
+    /// that just changed.
+    pub fn span(&self) -> Span {
+        self.span
+    }
+
+    /// The calls in progress, outermost first.
+    pub fn call_stack(&self) -> &[Frame] {
+        self.interpreter.call_stack()
+    }
+
+    /// Every local variable that's been assigned a value and isn't shadowed, innermost first.
+    /// Globals aren't included, there being every native function among them.
+    pub fn locals(&self) -> Vec<(Arc<str>, Value)> {
+        let mut locals: Vec<(Arc<str>, Value)> = Vec::new();
+        let mut environment = self.interpreter.environment().clone();
+        loop {
+            let enclosing = match environment.borrow().enclosing() {
+                Some(enclosing) => enclosing,
+                None => break
+            };
+            for name in environment.borrow().declared_names().into_iter().rev() {
+                if locals.iter().any(|&(ref local, _)| *local == name) {
+                    continue;
+                }
+                if let Some(value) = environment.borrow().value(&name) {
+                    locals.push((name, value));
+                }
+            }
+            environment = enclosing;
+        }
+        locals
+    }
+
+    /// The value of the variable called `name` as the paused code would see it, if it's been
+    /// assigned one.
+    pub fn lookup(&self, name: &str) -> Option<Value> {
+        let mut environment = self.interpreter.environment().clone();
+        loop {
+            if environment.borrow().declared_names().iter().any(|declared| &declared[..] == name) {
+                return environment.borrow().value(name);
+            }
+            let enclosing = environment.borrow().enclosing()?;
+            environment = enclosing;
+        }
+    }
+
+    pub fn stringify(&self, value: Value) -> String {
+        self.interpreter.stringify(value)
+    }
+
+    pub fn set_breakpoint(&mut self, line: i32) {
+        self.interpreter.set_breakpoint(line);
+    }
+
+    pub fn clear_breakpoint(&mut self, line: i32) {
+        self.interpreter.clear_breakpoint(line);
+    }
+
+    pub fn watch(&mut self, name: &str) {
+        self.interpreter.watch(name);
+    }
+
+    pub fn unwatch(&mut self, name: &str) {
+        self.interpreter.unwatch(name);
+    }
+}
+
+const HELP: &'static str = "\
+step (s)          run the next statement, stepping into calls
+next (n)          run the next statement, stepping over calls
+out (o)           run until the current function returns
+continue (c)      run until the next breakpoint
+break (b) LINE    pause whenever LINE is reached
+delete (d) LINE   remove the breakpoint on LINE
+watch (w) NAME    pause whenever NAME is declared or assigned
+unwatch (u) NAME  stop watching NAME
+locals (l)        show the local variables
+print (p) NAME    show the value of a variable
+stack (bt)        show the calls in progress
+quit (q)          stop the script";
+
+/// Lets whoever's at the terminal drive a paused script, by typing commands at a `(debug)`
+/// prompt. `help` lists them.
+pub struct ConsoleDebugger {
+    source: Option<String>,
+    input: Box<dyn BufRead>,
+    output: Box<dyn Write>
+}
+
+impl ConsoleDebugger {
+    /// Creates a debugger that reads commands from stdin and answers on stdout.
+    pub fn new() -> Self {
+        ConsoleDebugger::with_io(None, Box::new(BufReader::new(stdin())), Box::new(stdout()))
+    }
+
+    /// Creates a debugger that shows the line it's paused on from `source`, the script being
+    /// debugged.
+    pub fn with_source(source: String) -> Self {
+        ConsoleDebugger::with_io(Some(source), Box::new(BufReader::new(stdin())), Box::new(stdout()))
+    }
+
+    /// Creates a debugger that reads commands from `input` and answers on `output`, showing
+    /// the line it's paused on from `source` if there is one.
+    pub fn with_io(source: Option<String>, input: Box<dyn BufRead>, output: Box<dyn Write>) -> Self {
+        ConsoleDebugger {
+            source: source,
+            input: input,
+            output: output
+        }
+    }
+
+    // Like the diagnostics, what's shown is only an aid, so an output that can't be written to
+    // doesn't stop the script.
+    fn show_position(&mut self, pause: &Pause) {
+        let function = pause.call_stack().last().map_or("script".to_string(), |frame| format!("{}()", frame.function));
+        let _ = writeln!(self.output, "Paused at [line {}] in {}", pause.span().line, function);
+        if let Some(ref source) = self.source {
+            for line in diagnostics::snippet(source, pause.span()) {
+                let _ = writeln!(self.output, "{}", line);
+            }
+        }
+    }
+
+    fn prompt(&mut self, pause: &mut Pause) -> Resume {
+        let mut input = String::new();
+        loop {
+            let _ = write!(self.output, "(debug) ");
+            let _ = self.output.flush();
+            input.clear();
+            // With nothing more to read there's no one to take commands from.
+            match self.input.read_line(&mut input) {
+                Ok(0) | Err(_) => return Resume::Continue,
+                Ok(_) => ()
+            }
+
+            let words: Vec<&str> = input.split_whitespace().collect();
+            let output = &mut self.output;
+            match (words.get(0).cloned().unwrap_or(""), words.get(1)) {
+                ("s", _) | ("step", _) => return Resume::Step,
+                ("n", _) | ("next", _) => return Resume::StepOver,
+                ("o", _) | ("out", _) => return Resume::StepOut,
+                ("c", _) | ("continue", _) => return Resume::Continue,
+                ("q", _) | ("quit", _) => return Resume::Stop,
+                ("b", Some(line)) | ("break", Some(line)) => match line.parse() {
+                    Ok(line) => pause.set_breakpoint(line),
+                    Err(_) => { let _ = writeln!(output, "Expected a line number."); }
+                },
+                ("d", Some(line)) | ("delete", Some(line)) => match line.parse() {
+                    Ok(line) => pause.clear_breakpoint(line),
+                    Err(_) => { let _ = writeln!(output, "Expected a line number."); }
+                },
+                ("w", Some(name)) | ("watch", Some(name)) => pause.watch(name),
+                ("u", Some(name)) | ("unwatch", Some(name)) => pause.unwatch(name),
+                ("l", _) | ("locals", _) => {
+                    for (name, value) in pause.locals() {
+                        let _ = writeln!(output, "{} = {}", name, pause.stringify(value));
+                    }
+                },
+                ("p", Some(name)) | ("print", Some(name)) => match pause.lookup(name) {
+                    Some(value) => { let _ = writeln!(output, "{}", pause.stringify(value)); },
+                    None => { let _ = writeln!(output, "No variable '{}' has a value here.", name); }
+                },
+                ("bt", _) | ("stack", _) => {
+                    let mut line = pause.span().line;
+                    for frame in pause.call_stack().iter().rev() {
+                        let _ = writeln!(output, "[line {}] in {}()", line, frame.function);
+                        line = frame.line;
+                    }
+                    let _ = writeln!(output, "[line {}] in script", line);
+                },
+                ("", _) => (),
+                _ => { let _ = writeln!(output, "{}", HELP); }
+            }
+        }
+    }
+}
+
+impl Debugger for ConsoleDebugger {
+    fn paused(&mut self, pause: &mut Pause) -> Resume {
+        self.show_position(pause);
+        self.prompt(pause)
+    }
+
+    fn changed(&mut self, pause: &mut Pause, change: &Change) -> Resume {
+        let _ = writeln!(self.output, "Watched variable {}", change.describe(|value| pause.stringify(value)));
+        self.show_position(pause);
+        self.prompt(pause)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::io::{self, Cursor};
+    use std::rc::Rc;
+    use {CollectingReporter, Lox};
+
+    const SOURCE: &'static str = "fun inner() {
+  var a = 1;
+  return a;
+}
+fun outer() {
+  var b = inner();
+  return b;
+}
+var x = outer();
+var y = 2;";
+
+    // Answers each pause with the next of `script`, then carries on, noting the line and how
+    // many calls deep each pause was.
+    struct Scripted {
+        script: Vec<Resume>,
+        pauses: Rc<RefCell<Vec<(i32, usize)>>>
+    }
+
+    impl Debugger for Scripted {
+        fn paused(&mut self, pause: &mut Pause) -> Resume {
+            self.pauses.borrow_mut().push((pause.span().line, pause.call_stack().len()));
+            if self.script.is_empty() { Resume::Continue } else { self.script.remove(0) }
+        }
+    }
+
+    // Notes each change it's told of, as `describe` puts it with the line it was on, and answers
+    // with `resume`.
+    struct Watcher {
+        resume: Resume,
+        changes: Rc<RefCell<Vec<(i32, String)>>>
+    }
+
+    impl Debugger for Watcher {
+        fn paused(&mut self, _pause: &mut Pause) -> Resume {
+            self.changes.borrow_mut().push((0, "paused".to_string()));
+            Resume::Continue
+        }
+
+        fn changed(&mut self, pause: &mut Pause, change: &Change) -> Resume {
+            assert_eq!(pause.span().line, change.line);
+            self.changes.borrow_mut().push((change.line, change.describe(|value| pause.stringify(value))));
+            self.resume
+        }
+    }
+
+    struct Buffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn run(interpreter: &mut Interpreter, source: &str) {
+        let mut lox = Lox::new();
+        lox.set_reporter(Box::new(CollectingReporter::new()));
+        lox.run(&source.to_string(), interpreter).unwrap();
+    }
+
+    fn pauses(script: Vec<Resume>) -> Vec<(i32, usize)> {
+        let pauses = Rc::new(RefCell::new(Vec::new()));
+        let mut interpreter = Interpreter::with_stdlib();
+        interpreter.set_debugger(Some(Box::new(Scripted { script: script, pauses: pauses.clone() })));
+        interpreter.break_at_next_statement();
+        run(&mut interpreter, SOURCE);
+        let pauses = pauses.borrow().clone();
+        pauses
+    }
+
+    #[test]
+    fn step_pauses_inside_calls() {
+        let pauses = pauses(vec![Resume::Step; 8]);
+        assert_eq!(pauses, vec![(1, 0), (5, 0), (9, 0), (6, 1), (2, 2), (3, 2), (7, 1), (10, 0)]);
+    }
+
+    #[test]
+    fn step_over_skips_the_calls_made() {
+        let pauses = pauses(vec![Resume::Step, Resume::Step, Resume::StepOver, Resume::StepOver]);
+        assert_eq!(pauses, vec![(1, 0), (5, 0), (9, 0), (10, 0)]);
+    }
+
+    #[test]
+    fn step_out_pauses_once_the_function_returns() {
+        let pauses = pauses(vec![Resume::Step, Resume::Step, Resume::Step, Resume::Step, Resume::StepOut]);
+        assert_eq!(pauses, vec![(1, 0), (5, 0), (9, 0), (6, 1), (2, 2), (7, 1)]);
+    }
+
+    #[test]
+    fn continue_runs_to_the_next_breakpoint() {
+        let pauses = Rc::new(RefCell::new(Vec::new()));
+        let mut interpreter = Interpreter::with_stdlib();
+        interpreter.set_debugger(Some(Box::new(Scripted { script: Vec::new(), pauses: pauses.clone() })));
+        interpreter.set_breakpoint(3);
+        run(&mut interpreter, SOURCE);
+        assert_eq!(*pauses.borrow(), vec![(3, 2)]);
+    }
+
+    #[test]
+    fn console_debugger_reads_commands_and_writes_answers() {
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let input = Cursor::new("p a\nbt\nbogus 1\nc\n");
+        let debugger = ConsoleDebugger::with_io(Some(SOURCE.to_string()), Box::new(input), Box::new(Buffer(output.clone())));
+        let mut interpreter = Interpreter::with_stdlib();
+        interpreter.set_debugger(Some(Box::new(debugger)));
+        interpreter.set_breakpoint(3);
+        run(&mut interpreter, SOURCE);
+
+        let output = String::from_utf8(output.borrow().clone()).unwrap();
+        assert!(output.starts_with("Paused at [line 3] in inner()\n"));
+        assert!(output.contains("(debug) 1\n"));
+        assert!(output.contains("(debug) [line 3] in inner()\n[line 6] in outer()\n[line 9] in script\n"));
+        assert!(output.contains(HELP));
+        assert!(output.ends_with("(debug) "));
+    }
+
+    #[test]
+    fn console_debugger_carries_on_once_input_runs_out() {
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let debugger = ConsoleDebugger::with_io(None, Box::new(Cursor::new("")), Box::new(Buffer(output.clone())));
+        let mut interpreter = Interpreter::with_stdlib();
+        interpreter.set_debugger(Some(Box::new(debugger)));
+        interpreter.break_at_next_statement();
+        run(&mut interpreter, SOURCE);
+        assert_eq!(String::from_utf8(output.borrow().clone()).unwrap(), "Paused at [line 1] in script\n(debug) ");
+        assert_eq!(interpreter.stringify(interpreter.get_global("y").unwrap()), "2");
+    }
+
+    fn changes(resume: Resume, source: &str) -> Vec<(i32, String)> {
+        let changes = Rc::new(RefCell::new(Vec::new()));
+        let mut interpreter = Interpreter::with_stdlib();
+        interpreter.set_debugger(Some(Box::new(Watcher { resume: resume, changes: changes.clone() })));
+        interpreter.watch("x");
+        run(&mut interpreter, source);
+        let changes = changes.borrow().clone();
+        changes
+    }
+
+    #[test]
+    fn watches_report_declarations_and_assignments() {
+        let changes = changes(Resume::Continue, "var x;\nx = 1;\nvar y = 5;\nx = 2;\n{\n  var x = 3;\n}");
+        assert_eq!(changes, vec![
+            (1, "x declared".to_string()),
+            (2, "x set to 1".to_string()),
+            (4, "x changed from 1 to 2".to_string()),
+            (6, "x set to 3".to_string())
+        ]);
+    }
+
+    #[test]
+    fn watches_report_assignments_from_inside_functions() {
+        let changes = changes(Resume::Continue, "var x = 1;\nfun bump() {\n  x = x + 1;\n}\nbump();");
+        assert_eq!(changes, vec![(1, "x set to 1".to_string()), (3, "x changed from 1 to 2".to_string())]);
+    }
+
+    #[test]
+    fn stepping_from_a_change_pauses_at_the_next_statement() {
+        let changes = changes(Resume::Step, "var x = 1;\nvar y = 2;");
+        assert_eq!(changes, vec![(1, "x set to 1".to_string()), (0, "paused".to_string())]);
+    }
+
+    #[test]
+    fn console_debugger_says_what_changed() {
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let debugger = ConsoleDebugger::with_io(None, Box::new(Cursor::new("c\n")), Box::new(Buffer(output.clone())));
+        let mut interpreter = Interpreter::with_stdlib();
+        interpreter.set_debugger(Some(Box::new(debugger)));
+        interpreter.watch("x");
+        run(&mut interpreter, "var x = 1;");
+        assert_eq!(String::from_utf8(output.borrow().clone()).unwrap(), "Watched variable x set to 1\nPaused at [line 1] in script\n(debug) ");
+    }
+}
@@ -0,0 +1,36 @@
+// Renders the source an error points at, for messages that show where it happened.
+
+use scanner::Span;
+
+/// The line of `source` that `span` starts on, numbered, with carets under the part the span
+/// covers. A span running onto later lines is only underlined to the end of the first. Gives
+/// nothing if the span doesn't fall inside `source`.
+///
+/// ```text
+///  3 |     return x - "b";
+///    |              ^
+/// ```
+pub fn snippet(source: &str, span: Span) -> Vec<String> {
+    let start = span.start;
+    if !source.is_char_boundary(start) {
+        return Vec::new();
+    }
+
+    let line_start = source[..start].rfind('\n').map_or(0, |index| index + 1);
+    let line_end = source[start..].find('\n').map_or(source.len(), |index| start + index);
+    let line = source[line_start..line_end].trim_end_matches('\r');
+
+    // Tabs are kept in the padding so the carets line up however wide they're shown.
+    let padding: String = source[line_start..start].chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let end = span.end.max(start).min(line_start + line.len());
+    let width = source.get(start..end).map_or(0, |text| text.chars().count()).max(1);
+
+    let number = span.line.to_string();
+    let gutter = " ".repeat(number.len());
+    vec![
+        format!(" {} | {}", number, line),
+        format!(" {} | {}{}", gutter, padding, "^".repeat(width))
+    ]
+}
@@ -0,0 +1,464 @@
+// A C interface to the interpreter, for embedding it in programs that aren't written in Rust.
+// Built into the cdylib when the `ffi` feature is on, and declared for C in include/lox1.h.
+// Every handle given out has to be passed back to the matching `_free` function once it's no
+// longer needed.
+//
+// Null pointers are never dereferenced: a function given one where it needs a handle, value
+// or string does nothing, and says so through its return value or `lox_last_error`. Pointers
+// that aren't null have to be ones these functions handed out, not yet freed.
+
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr;
+use interpreter::{ErrorKind, Interpreter, Value};
+use {CollectingReporter, Lox, LoxError};
+
+/// A Lox interpreter along with its globals and the last error it ran into.
+pub struct LoxHandle {
+    lox: Lox,
+    // Only kept so nothing is printed; what went wrong comes from the `LoxError`.
+    reporter: CollectingReporter,
+    interpreter: Interpreter,
+    last_error: Option<CString>
+}
+
+impl LoxHandle {
+    fn fail(&mut self, message: &str) {
+        self.last_error = Some(to_c_string(message.to_string()));
+    }
+}
+
+/// A Lox value, owned by whoever it was handed to.
+pub struct LoxValue(Value);
+
+pub const LOX_NIL: c_int = 0;
+pub const LOX_BOOL: c_int = 1;
+pub const LOX_NUMBER: c_int = 2;
+pub const LOX_STRING: c_int = 3;
+/// A function, class, instance, list or map, which can only be passed back to Lox.
+pub const LOX_OBJECT: c_int = 4;
+
+/// What `lox_run` returns when it's given a null handle or source, as sysexits.h's EX_USAGE.
+pub const LOX_USAGE_ERROR: c_int = 64;
+
+/// A native function written in C. It's given the arguments and the `user_data` it was
+/// registered with, and returns a new value from one of the `lox_value_` functions for the
+/// interpreter to take ownership of, or null to fail with a runtime error. The arguments stay
+/// the interpreter's, and are only valid until the function returns. It mustn't call back
+/// into the interpreter running it.
+pub type LoxNativeFn = extern "C" fn(args: *const *const LoxValue, arg_count: usize, user_data: *mut c_void) -> *mut LoxValue;
+
+// C strings can't hold a NUL, so anything after one is cut off.
+fn to_c_string(string: String) -> CString {
+    let end = string.find('\0').unwrap_or(string.len());
+    CString::new(&string[..end]).unwrap()
+}
+
+unsafe fn to_str<'a>(string: *const c_char) -> Option<&'a str> {
+    if string.is_null() {
+        None
+    } else {
+        CStr::from_ptr(string).to_str().ok()
+    }
+}
+
+fn new_value(value: Value) -> *mut LoxValue {
+    Box::into_raw(Box::new(LoxValue(value)))
+}
+
+/// Creates an interpreter with the standard library. Errors are kept for `lox_last_error`
+/// instead of being printed.
+#[no_mangle]
+pub extern "C" fn lox_new() -> *mut LoxHandle {
+    let reporter = CollectingReporter::new();
+    let mut lox = Lox::new();
+    lox.set_reporter(Box::new(reporter.clone()));
+    Box::into_raw(Box::new(LoxHandle {
+        lox: lox,
+        reporter: reporter,
+        interpreter: Interpreter::with_stdlib(),
+        last_error: None
+    }))
+}
+
+/// # Safety
+///
+/// `handle` must be null or come from `lox_new`, and mustn't be used again afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn lox_free(handle: *mut LoxHandle) {
+    if !handle.is_null() {
+        drop(Box::from_raw(handle));
+    }
+}
+
+/// Runs `source`, keeping any globals it defines for later runs. Returns 0 if it ran to the
+/// end, or otherwise the status `lox1exec` would exit with: the code a script passed to
+/// `exit()`, 65 if it didn't compile, 70 if it failed as it ran, and `LOX_USAGE_ERROR` if
+/// `handle` or `source` is null.
+///
+/// # Safety
+///
+/// `handle` must be null or a live handle from `lox_new`, and `source` null or a
+/// NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn lox_run(handle: *mut LoxHandle, source: *const c_char) -> c_int {
+    let handle = match handle.as_mut() {
+        Some(handle) => handle,
+        None => return LOX_USAGE_ERROR
+    };
+    handle.last_error = None;
+    if source.is_null() {
+        handle.fail("No source was given.");
+        return LOX_USAGE_ERROR;
+    }
+    let source = match to_str(source) {
+        Some(source) => source.to_string(),
+        None => {
+            handle.fail("The source isn't valid UTF-8.");
+            return 65;
+        }
+    };
+
+    handle.reporter.take();
+    match handle.lox.run(&source, &mut handle.interpreter) {
+        Ok(()) => 0,
+        Err(error) => {
+            let code = error.exit_code();
+            if let LoxError::Exit(_) = error {
+                return code;
+            }
+            handle.fail(&error.to_string());
+            code
+        }
+    }
+}
+
+/// The message for what went wrong in the last call on `handle` that failed, or null if it
+/// succeeded or `handle` is null. It stays valid until the next call on `handle`.
+///
+/// # Safety
+///
+/// `handle` must be null or a live handle from `lox_new`.
+#[no_mangle]
+pub unsafe extern "C" fn lox_last_error(handle: *const LoxHandle) -> *const c_char {
+    match handle.as_ref().and_then(|handle| handle.last_error.as_ref()) {
+        Some(message) => message.as_ptr(),
+        None => ptr::null()
+    }
+}
+
+/// The value of the global called `name`, to be freed with `lox_value_free`. Null if there's
+/// no such global or it hasn't been given a value, or if `handle` or `name` is null.
+///
+/// # Safety
+///
+/// `handle` must be null or a live handle from `lox_new`, and `name` null or a NUL-terminated
+/// string.
+#[no_mangle]
+pub unsafe extern "C" fn lox_get_global(handle: *mut LoxHandle, name: *const c_char) -> *mut LoxValue {
+    let handle = match handle.as_mut() {
+        Some(handle) => handle,
+        None => return ptr::null_mut()
+    };
+    handle.last_error = None;
+    match to_str(name).and_then(|name| handle.interpreter.get_global(name)) {
+        Some(value) => new_value(value),
+        None => ptr::null_mut()
+    }
+}
+
+/// Defines the global called `name` to hold a copy of `value`, which stays the caller's.
+/// Returns whether it did: nothing is defined if any of the pointers is null or `name` isn't
+/// valid UTF-8.
+///
+/// # Safety
+///
+/// `handle` must be null or a live handle from `lox_new`, `name` null or a NUL-terminated
+/// string, and `value` null or a live value from one of these functions.
+#[no_mangle]
+pub unsafe extern "C" fn lox_set_global(handle: *mut LoxHandle, name: *const c_char, value: *const LoxValue) -> bool {
+    let handle = match handle.as_mut() {
+        Some(handle) => handle,
+        None => return false
+    };
+    handle.last_error = None;
+    match (to_str(name), value.as_ref()) {
+        (Some(name), Some(value)) => {
+            handle.interpreter.set_global(name, value.0.clone());
+            true
+        },
+        (None, _) => {
+            handle.fail("The name is null or isn't valid UTF-8.");
+            false
+        },
+        (_, None) => {
+            handle.fail("No value was given.");
+            false
+        }
+    }
+}
+
+/// Defines a global function called `name` that calls `function` with `arity` arguments.
+/// `user_data` is passed along untouched, for the function to find its own state with.
+/// Returns whether it did: nothing is defined if `handle`, `name` or `function` is null or
+/// `name` isn't valid UTF-8.
+///
+/// # Safety
+///
+/// `handle` must be null or a live handle from `lox_new`, and `name` null or a NUL-terminated
+/// string. `function` must follow the rules given for `LoxNativeFn`, and `user_data` must stay
+/// valid for as long as `function` can be called.
+#[no_mangle]
+pub unsafe extern "C" fn lox_register_native(handle: *mut LoxHandle, name: *const c_char, arity: usize, function: Option<LoxNativeFn>, user_data: *mut c_void) -> bool {
+    let handle = match handle.as_mut() {
+        Some(handle) => handle,
+        None => return false
+    };
+    handle.last_error = None;
+    let (name, function) = match (to_str(name), function) {
+        (Some(name), Some(function)) => (name.to_string(), function),
+        (None, _) => {
+            handle.fail("The name is null or isn't valid UTF-8.");
+            return false;
+        },
+        (_, None) => {
+            handle.fail("No function was given.");
+            return false;
+        }
+    };
+
+    let message = format!("Native function '{}' failed.", name);
+    handle.interpreter.define_native_fn(&name, arity, move |arguments| {
+        let arguments: Vec<LoxValue> = arguments.into_iter().map(LoxValue).collect();
+        let pointers: Vec<*const LoxValue> = arguments.iter().map(|argument| argument as *const LoxValue).collect();
+        let result = function(pointers.as_ptr(), pointers.len(), user_data);
+        if result.is_null() {
+            Err(ErrorKind::InvalidArgument(message.clone()))
+        } else {
+            // The function promised a value from `lox_value_`, now the interpreter's.
+            Ok(unsafe { Box::from_raw(result) }.0)
+        }
+    });
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn lox_value_nil() -> *mut LoxValue {
+    new_value(Value::Nil)
+}
+
+#[no_mangle]
+pub extern "C" fn lox_value_bool(value: bool) -> *mut LoxValue {
+    new_value(Value::Bool(value))
+}
+
+#[no_mangle]
+pub extern "C" fn lox_value_number(number: f64) -> *mut LoxValue {
+    new_value(Value::Number(number))
+}
+
+/// A string holding a copy of `string`, or null if it's null or isn't valid UTF-8.
+///
+/// # Safety
+///
+/// `string` must be null or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn lox_value_string(string: *const c_char) -> *mut LoxValue {
+    match to_str(string) {
+        Some(string) => new_value(Value::from(string)),
+        None => ptr::null_mut()
+    }
+}
+
+/// # Safety
+///
+/// `value` must be null or a value from one of these functions that the caller owns, and
+/// mustn't be used again afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn lox_value_free(value: *mut LoxValue) {
+    if !value.is_null() {
+        drop(Box::from_raw(value));
+    }
+}
+
+/// One of `LOX_NIL`, `LOX_BOOL`, `LOX_NUMBER`, `LOX_STRING` or `LOX_OBJECT`. A null value is
+/// taken to be nil.
+///
+/// # Safety
+///
+/// `value` must be null or a live value from one of these functions.
+#[no_mangle]
+pub unsafe extern "C" fn lox_value_type(value: *const LoxValue) -> c_int {
+    match value.as_ref().map(|value| &value.0) {
+        None | Some(&Value::Nil) => LOX_NIL,
+        Some(&Value::Bool(_)) => LOX_BOOL,
+        Some(&Value::Number(_)) => LOX_NUMBER,
+        Some(&Value::String(_)) => LOX_STRING,
+        Some(_) => LOX_OBJECT
+    }
+}
+
+/// Whether `value` is truthy, as an `if` would decide. A null value is taken to be nil.
+///
+/// # Safety
+///
+/// `value` must be null or a live value from one of these functions.
+#[no_mangle]
+pub unsafe extern "C" fn lox_value_as_bool(value: *const LoxValue) -> bool {
+    match value.as_ref().map(|value| &value.0) {
+        None | Some(&Value::Nil) | Some(&Value::Bool(false)) => false,
+        Some(_) => true
+    }
+}
+
+/// The number `value` holds, or NaN if it's null or doesn't hold one.
+///
+/// # Safety
+///
+/// `value` must be null or a live value from one of these functions.
+#[no_mangle]
+pub unsafe extern "C" fn lox_value_as_number(value: *const LoxValue) -> f64 {
+    match value.as_ref().map(|value| &value.0) {
+        Some(&Value::Number(number)) => number,
+        _ => f64::NAN
+    }
+}
+
+/// `value` as `print` would show it, as a new string to be freed with `lox_string_free`. Null
+/// if `handle` or `value` is null.
+///
+/// # Safety
+///
+/// `handle` must be null or a live handle from `lox_new`, and `value` null or a live value
+/// from one of these functions.
+#[no_mangle]
+pub unsafe extern "C" fn lox_value_to_string(handle: *const LoxHandle, value: *const LoxValue) -> *mut c_char {
+    match (handle.as_ref(), value.as_ref()) {
+        (Some(handle), Some(value)) => to_c_string(handle.interpreter.stringify(value.0.clone())).into_raw(),
+        _ => ptr::null_mut()
+    }
+}
+
+/// # Safety
+///
+/// `string` must be null or a string from `lox_value_to_string`, and mustn't be used again
+/// afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn lox_string_free(string: *mut c_char) {
+    if !string.is_null() {
+        drop(CString::from_raw(string));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern "C" fn twice(args: *const *const LoxValue, arg_count: usize, user_data: *mut c_void) -> *mut LoxValue {
+        unsafe {
+            assert_eq!(arg_count, 1);
+            let argument = *args;
+            if lox_value_type(argument) != LOX_NUMBER {
+                return ptr::null_mut();
+            }
+            *(user_data as *mut usize) += 1;
+            lox_value_number(lox_value_as_number(argument) * 2.0)
+        }
+    }
+
+    fn c(string: &str) -> CString {
+        CString::new(string).unwrap()
+    }
+
+    unsafe fn last_error(handle: *const LoxHandle) -> Option<String> {
+        let message = lox_last_error(handle);
+        if message.is_null() {
+            None
+        } else {
+            Some(CStr::from_ptr(message).to_str().unwrap().to_string())
+        }
+    }
+
+    #[test]
+    fn drives_an_interpreter() {
+        unsafe {
+            let handle = lox_new();
+            let mut calls = 0usize;
+            assert!(lox_register_native(handle, c("twice").as_ptr(), 1, Some(twice), &mut calls as *mut usize as *mut c_void));
+
+            let greeting = lox_value_string(c("hi").as_ptr());
+            assert!(lox_set_global(handle, c("greeting").as_ptr(), greeting));
+            lox_value_free(greeting);
+
+            assert_eq!(lox_run(handle, c("var x = twice(21); var y = greeting + \"!\";").as_ptr()), 0);
+            assert_eq!(last_error(handle), None);
+            assert_eq!(calls, 1);
+
+            let x = lox_get_global(handle, c("x").as_ptr());
+            assert_eq!(lox_value_type(x), LOX_NUMBER);
+            assert_eq!(lox_value_as_number(x), 42.0);
+            assert!(lox_value_as_bool(x));
+            lox_value_free(x);
+
+            let y = lox_get_global(handle, c("y").as_ptr());
+            let string = lox_value_to_string(handle, y);
+            assert_eq!(CStr::from_ptr(string).to_str(), Ok("hi!"));
+            lox_string_free(string);
+            lox_value_free(y);
+
+            assert!(lox_get_global(handle, c("missing").as_ptr()).is_null());
+            lox_free(handle);
+        }
+    }
+
+    #[test]
+    fn reports_errors_until_the_next_call() {
+        unsafe {
+            let handle = lox_new();
+            let mut calls = 0usize;
+            lox_register_native(handle, c("twice").as_ptr(), 1, Some(twice), &mut calls as *mut usize as *mut c_void);
+
+            assert_eq!(lox_run(handle, c("twice(\"a\");").as_ptr()), 70);
+            assert!(last_error(handle).unwrap().starts_with("Native function 'twice' failed."));
+
+            assert_eq!(lox_run(handle, c("var ;").as_ptr()), 65);
+            assert!(last_error(handle).unwrap().contains("Expect variable name."));
+
+            assert_eq!(lox_run(handle, c("exit(3);").as_ptr()), 3);
+            assert_eq!(last_error(handle), None);
+            assert_eq!(lox_run(handle, c("var z = 1;").as_ptr()), 0);
+            lox_free(handle);
+        }
+    }
+
+    #[test]
+    fn ignores_null_pointers() {
+        unsafe {
+            let handle = lox_new();
+            assert_eq!(lox_run(ptr::null_mut(), c("1;").as_ptr()), LOX_USAGE_ERROR);
+            assert_eq!(lox_run(handle, ptr::null()), LOX_USAGE_ERROR);
+            assert!(last_error(handle).is_some());
+            assert!(last_error(ptr::null()).is_none());
+
+            assert!(lox_get_global(ptr::null_mut(), c("x").as_ptr()).is_null());
+            assert!(lox_get_global(handle, ptr::null()).is_null());
+            assert!(!lox_set_global(handle, c("x").as_ptr(), ptr::null()));
+            let nil = lox_value_nil();
+            assert!(!lox_set_global(handle, ptr::null(), nil));
+            lox_value_free(nil);
+            assert!(!lox_register_native(handle, c("f").as_ptr(), 0, None, ptr::null_mut()));
+            assert!(!lox_register_native(ptr::null_mut(), c("f").as_ptr(), 0, Some(twice), ptr::null_mut()));
+
+            assert_eq!(lox_value_type(ptr::null()), LOX_NIL);
+            assert!(!lox_value_as_bool(ptr::null()));
+            assert!(lox_value_as_number(ptr::null()).is_nan());
+            assert!(lox_value_string(ptr::null()).is_null());
+            assert!(lox_value_to_string(handle, ptr::null()).is_null());
+
+            lox_value_free(ptr::null_mut());
+            lox_string_free(ptr::null_mut());
+            lox_free(ptr::null_mut());
+            lox_free(handle);
+        }
+    }
+}
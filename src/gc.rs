@@ -0,0 +1,493 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::mem::{size_of, size_of_val};
+use std::rc::{Rc, Weak};
+use interpreter::{Callable, Environment, LoxClass, LoxInstance, Value};
+
+// How many objects the heap tracks before it first collects. After that it waits until the
+// number has doubled from what survived the last collection.
+const INITIAL_THRESHOLD: usize = 1024;
+
+/// Anything on the heap that can hold references to other objects.
+pub trait Trace {
+    /// Hands every reference this holds to `tracer`.
+    fn trace(&self, tracer: &mut Tracer);
+
+    /// Roughly how many bytes this takes up, not counting the objects it refers to.
+    fn size(&self) -> usize;
+
+    /// Drops every reference this holds. Only called on objects nothing can reach any more,
+    /// to break the cycles keeping them alive.
+    fn clear(&mut self) {}
+}
+
+/// Collects the references held by the object being traced.
+pub struct Tracer<'t> {
+    graph: &'t mut Graph,
+    references: Vec<usize>
+}
+
+impl<'t> Tracer<'t> {
+    pub fn value(&mut self, value: &Value) {
+        if let Some(object) = Object::from_value(value) {
+            self.reference(object);
+        }
+    }
+
+    pub fn environment(&mut self, environment: &Rc<RefCell<Environment>>) {
+        self.reference(Object::Environment(Rc::downgrade(environment)));
+    }
+
+    pub fn class(&mut self, class: &Rc<LoxClass>) {
+        self.reference(Object::Class(Rc::downgrade(class)));
+    }
+
+    fn reference(&mut self, object: Object) {
+        let index = self.graph.insert(object);
+        self.references.push(index);
+    }
+}
+
+/// Counts of what the collector has done, for tests and for `gcStats()`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Stats {
+    pub collections: usize,
+    pub freed: usize,
+    pub tracked: usize
+}
+
+/// Frees cycles of objects that reference counting alone never would, like an instance
+/// holding one of its own bound methods.
+///
+/// Objects that could end up in a cycle are tracked from when they're created, as is every
+/// scope, so that the strings only a variable holds are counted too. Collecting counts how many references each object gets
+/// from others on the heap; one with more references than that is held from outside, by the
+/// interpreter or a Rust caller, and so is everything it reaches. Whatever is left can only be
+/// reached from other garbage, and has its references dropped so that the cycles fall apart.
+pub struct Heap {
+    objects: Vec<Object>,
+    threshold: usize,
+    stats: Stats,
+    used: usize
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Heap {
+            objects: Vec::new(),
+            threshold: INITIAL_THRESHOLD,
+            stats: Stats::default(),
+            used: 0
+        }
+    }
+
+    /// Roughly how many bytes the program is using: what was still reachable at the last
+    /// collection, plus whatever has been allocated since. Values only the Rust stack holds,
+    /// like the operands of an expression being evaluated, aren't counted by a collection.
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    /// Counts `bytes` newly allocated by the program towards `used`.
+    pub fn allocate(&mut self, bytes: usize) {
+        self.used += bytes;
+    }
+
+    /// Starts tracking the object `value` refers to, if it's one that can be part of a cycle.
+    /// Collects first if enough objects have been tracked since the last collection.
+    pub fn track(&mut self, value: &Value) {
+        if let Some(object) = Object::from_value(value) {
+            self.add(object);
+        }
+    }
+
+    pub fn track_environment(&mut self, environment: &Rc<RefCell<Environment>>) {
+        self.add(Object::Environment(Rc::downgrade(environment)));
+    }
+
+    fn add(&mut self, object: Object) {
+        // Most scopes are gone by the time the threshold's reached, and dropping those is
+        // much cheaper than a collection.
+        if self.objects.len() >= self.threshold {
+            self.objects.retain(|object| object.strong_count() > 0);
+        }
+        if self.objects.len() >= self.threshold {
+            self.collect();
+        }
+        self.objects.push(object);
+    }
+
+    /// Frees every cycle nothing outside the heap can reach, returning how many objects were
+    /// freed.
+    pub fn collect(&mut self) -> usize {
+        self.objects.retain(|object| object.strong_count() > 0);
+        let mut graph = Graph::new();
+        for object in &self.objects {
+            graph.insert(object.clone());
+        }
+
+        // Tracing can find objects that were never tracked themselves, which are added to the
+        // graph and traced in turn. An object that can't be traced, because it's in the middle
+        // of being changed, is in use and so counts as reachable.
+        let mut references: Vec<Vec<usize>> = Vec::new();
+        let mut sizes = Vec::new();
+        while references.len() < graph.objects.len() {
+            let object = graph.objects[references.len()].clone();
+            let mut tracer = Tracer { graph: &mut graph, references: Vec::new() };
+            sizes.push(object.trace(&mut tracer));
+            references.push(tracer.references);
+        }
+
+        let mut internal = vec![0; graph.objects.len()];
+        for &reference in references.iter().flat_map(|references| references.iter()) {
+            internal[reference] += 1;
+        }
+
+        let mut pending: Vec<usize> = (0..graph.objects.len())
+            .filter(|&index| sizes[index].is_none() || graph.objects[index].strong_count() > internal[index])
+            .collect();
+        let mut reachable = vec![false; graph.objects.len()];
+        while let Some(index) = pending.pop() {
+            if !reachable[index] {
+                reachable[index] = true;
+                pending.extend(references[index].iter().cloned());
+            }
+        }
+
+        self.used = sizes.iter().zip(&reachable)
+            .filter(|&(_, &reachable)| reachable)
+            .filter_map(|(size, _)| *size)
+            .sum();
+
+        // Everything is held on to until all of it has been cleared, so nothing is freed while
+        // another piece of garbage still refers to it.
+        let garbage: Vec<Held> = graph.objects.iter().zip(reachable)
+            .filter(|&(_, reachable)| !reachable)
+            .filter_map(|(object, _)| object.upgrade())
+            .collect();
+        for held in &garbage {
+            held.clear();
+        }
+        let freed = garbage.len();
+        drop(garbage);
+
+        self.objects.retain(|object| object.strong_count() > 0);
+        self.threshold = INITIAL_THRESHOLD.max(self.objects.len() * 2);
+        self.stats.collections += 1;
+        self.stats.freed += freed;
+        freed
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats { tracked: self.objects.len(), ..self.stats }
+    }
+}
+
+struct Graph {
+    objects: Vec<Object>,
+    indices: HashMap<*const (), usize>
+}
+
+impl Graph {
+    fn new() -> Self {
+        Graph {
+            objects: Vec::new(),
+            indices: HashMap::new()
+        }
+    }
+
+    fn insert(&mut self, object: Object) -> usize {
+        let objects = &mut self.objects;
+        *self.indices.entry(object.address()).or_insert_with(|| {
+            objects.push(object);
+            objects.len() - 1
+        })
+    }
+}
+
+// Objects are only referred to weakly, so that tracking one doesn't keep it alive or add to
+// the count of references the collector relies on.
+#[derive(Clone)]
+enum Object {
+    Environment(Weak<RefCell<Environment>>),
+    Instance(Weak<RefCell<LoxInstance>>),
+    List(Weak<RefCell<Vec<Value>>>),
+    Map(Weak<RefCell<BTreeMap<String, Value>>>),
+    Callable(Weak<dyn Callable>),
+    Class(Weak<LoxClass>)
+}
+
+impl Object {
+    fn from_value(value: &Value) -> Option<Object> {
+        match *value {
+            Value::Callable(ref callable) => Some(Object::Callable(Rc::downgrade(callable))),
+            Value::Class(ref class) => Some(Object::Class(Rc::downgrade(class))),
+            Value::Instance(ref instance) => Some(Object::Instance(Rc::downgrade(instance))),
+            Value::List(ref list) => Some(Object::List(Rc::downgrade(list))),
+            Value::Map(ref map) => Some(Object::Map(Rc::downgrade(map))),
+            _ => None
+        }
+    }
+
+    fn address(&self) -> *const () {
+        match *self {
+            Object::Environment(ref object) => object.as_ptr() as *const (),
+            Object::Instance(ref object) => object.as_ptr() as *const (),
+            Object::List(ref object) => object.as_ptr() as *const (),
+            Object::Map(ref object) => object.as_ptr() as *const (),
+            Object::Callable(ref object) => object.as_ptr() as *const (),
+            Object::Class(ref object) => object.as_ptr() as *const ()
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match *self {
+            Object::Environment(ref object) => object.strong_count(),
+            Object::Instance(ref object) => object.strong_count(),
+            Object::List(ref object) => object.strong_count(),
+            Object::Map(ref object) => object.strong_count(),
+            Object::Callable(ref object) => object.strong_count(),
+            Object::Class(ref object) => object.strong_count()
+        }
+    }
+
+    // Returns the object's size, or None if it couldn't be traced.
+    fn trace(&self, tracer: &mut Tracer) -> Option<usize> {
+        match *self {
+            Object::Environment(ref object) => trace_cell(object, tracer),
+            Object::Instance(ref object) => trace_cell(object, tracer),
+            Object::List(ref object) => trace_cell(object, tracer),
+            Object::Map(ref object) => trace_cell(object, tracer),
+            Object::Callable(ref object) => object.upgrade().map(|callable| {
+                callable.trace(tracer);
+                size_of_val(&*callable)
+            }),
+            Object::Class(ref object) => object.upgrade().map(|class| {
+                class.trace(tracer);
+                class.size()
+            })
+        }
+    }
+
+    fn upgrade(&self) -> Option<Held> {
+        match *self {
+            Object::Environment(ref object) => object.upgrade().map(Held::Environment),
+            Object::Instance(ref object) => object.upgrade().map(|object| Held::Value(Value::Instance(object))),
+            Object::List(ref object) => object.upgrade().map(|object| Held::Value(Value::List(object))),
+            Object::Map(ref object) => object.upgrade().map(|object| Held::Value(Value::Map(object))),
+            Object::Callable(ref object) => object.upgrade().map(|object| Held::Value(Value::Callable(object))),
+            Object::Class(ref object) => object.upgrade().map(|object| Held::Value(Value::Class(object)))
+        }
+    }
+}
+
+fn trace_cell<T: Trace>(object: &Weak<RefCell<T>>, tracer: &mut Tracer) -> Option<usize> {
+    let object = object.upgrade()?;
+    let size = match object.try_borrow() {
+        Ok(object) => {
+            object.trace(tracer);
+            Some(object.size())
+        },
+        Err(_) => None
+    };
+    size
+}
+
+/// Roughly how many bytes holding `value` takes, counting a string's text but not the objects
+/// other values refer to.
+pub fn size_of_value(value: &Value) -> usize {
+    match *value {
+        Value::String(ref string) => size_of::<Value>() + string.len(),
+        _ => size_of::<Value>()
+    }
+}
+
+enum Held {
+    Value(Value),
+    Environment(Rc<RefCell<Environment>>)
+}
+
+impl Held {
+    // Functions and classes never change once made, so any cycle through one also runs through
+    // something that's cleared here.
+    fn clear(&self) {
+        match *self {
+            Held::Environment(ref object) => clear_cell(object),
+            Held::Value(Value::Instance(ref object)) => clear_cell(object),
+            Held::Value(Value::List(ref object)) => clear_cell(object),
+            Held::Value(Value::Map(ref object)) => clear_cell(object),
+            Held::Value(_) => ()
+        }
+    }
+}
+
+fn clear_cell<T: Trace>(object: &RefCell<T>) {
+    if let Ok(mut object) = object.try_borrow_mut() {
+        object.clear();
+    }
+}
+
+impl Trace for Vec<Value> {
+    fn trace(&self, tracer: &mut Tracer) {
+        for value in self {
+            tracer.value(value);
+        }
+    }
+
+    fn size(&self) -> usize {
+        size_of::<Self>() + (self.capacity() - self.len()) * size_of::<Value>() + self.iter().map(size_of_value).sum::<usize>()
+    }
+
+    fn clear(&mut self) {
+        Vec::clear(self);
+    }
+}
+
+impl Trace for BTreeMap<String, Value> {
+    fn trace(&self, tracer: &mut Tracer) {
+        for value in self.values() {
+            tracer.value(value);
+        }
+    }
+
+    fn size(&self) -> usize {
+        size_of::<Self>() + self.iter().map(|(key, value)| size_of::<String>() + key.len() + size_of_value(value)).sum::<usize>()
+    }
+
+    fn clear(&mut self) {
+        BTreeMap::clear(self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use interpreter::{ErrorKind, Interpreter, Value};
+    use {CollectingReporter, Lox, LoxError};
+    use super::Object;
+
+    fn run(source: &str, interpreter: &mut Interpreter) -> Result<(), LoxError> {
+        let mut lox = Lox::new();
+        lox.set_reporter(Box::new(CollectingReporter::new()));
+        lox.run(&source.to_string(), interpreter)
+    }
+
+    // Lets go of the global `name`, giving back a weak reference to the object it held.
+    fn forget(interpreter: &mut Interpreter, name: &str) -> Object {
+        let object = Object::from_value(&interpreter.get_global(name).unwrap()).unwrap();
+        interpreter.set_global(name, Value::Nil);
+        object
+    }
+
+    fn number(map: &Value, key: &str) -> f64 {
+        match *map {
+            Value::Map(ref map) => match map.borrow().get(key) {
+                Some(&Value::Number(number)) => number,
+                _ => panic!("no number at {:?}", key)
+            },
+            _ => panic!("not a map")
+        }
+    }
+
+    #[test]
+    fn frees_a_list_containing_itself() {
+        let mut interpreter = Interpreter::with_stdlib();
+        run("var l = list(); listPush(l, 1); listPush(l, l);", &mut interpreter).unwrap();
+        let list = forget(&mut interpreter, "l");
+        assert_eq!(list.strong_count(), 1);
+
+        assert_eq!(interpreter.collect_garbage(), 1);
+        assert_eq!(list.strong_count(), 0);
+    }
+
+    #[test]
+    fn frees_an_instance_holding_a_closure_over_it() {
+        let mut interpreter = Interpreter::with_stdlib();
+        let source = "class Box {}\n\
+                      fun capture(box) { fun get() { return box; } return get; }\n\
+                      var box = Box();\n\
+                      box.get = capture(box);";
+        run(source, &mut interpreter).unwrap();
+        let instance = forget(&mut interpreter, "box");
+        assert!(instance.strong_count() > 0);
+
+        // The instance, the closure and the scope it captured.
+        assert_eq!(interpreter.collect_garbage(), 3);
+        assert_eq!(instance.strong_count(), 0);
+    }
+
+    #[test]
+    fn keeps_a_cycle_that_can_still_be_reached() {
+        let mut interpreter = Interpreter::with_stdlib();
+        run("var a = list(); var b = list(); listPush(a, b); listPush(b, a); listPush(b, \"kept\");", &mut interpreter).unwrap();
+
+        assert_eq!(interpreter.collect_garbage(), 0);
+        match interpreter.get_global("a") {
+            Some(Value::List(ref a)) => match a.borrow()[0] {
+                Value::List(ref b) => {
+                    assert_eq!(b.borrow().len(), 2);
+                    assert_eq!(interpreter.stringify(b.borrow()[1].clone()), "kept");
+                },
+                _ => panic!("lost b")
+            },
+            _ => panic!("lost a")
+        }
+    }
+
+    #[test]
+    fn gc_stats_count_what_gc_did() {
+        let mut interpreter = Interpreter::with_stdlib();
+        let source = "var before = gcStats();\n\
+                      { var l = list(); listPush(l, l); }\n\
+                      var freed = gc();\n\
+                      var after = gcStats();";
+        run(source, &mut interpreter).unwrap();
+        let before = interpreter.get_global("before").unwrap();
+        let after = interpreter.get_global("after").unwrap();
+
+        match interpreter.get_global("freed") {
+            Some(Value::Number(freed)) => {
+                assert_eq!(freed, 1.0);
+                assert_eq!(number(&after, "freed"), number(&before, "freed") + freed);
+            },
+            _ => panic!("gc() didn't give a number")
+        }
+        assert_eq!(number(&after, "collections"), number(&before, "collections") + 1.0);
+        // Only the map `before` holds is left of what was tracked since.
+        assert_eq!(number(&after, "tracked"), number(&before, "tracked") + 1.0);
+    }
+
+    fn assert_over_limit(result: Result<(), LoxError>) {
+        match result {
+            Err(LoxError::Runtime { error, .. }) => match error.1 {
+                ErrorKind::MemoryLimitExceeded => (),
+                kind => panic!("unexpected {}", kind)
+            },
+            result => panic!("unexpected {:?}", result)
+        }
+    }
+
+    // Declares a string of 512KB, then eight more of them.
+    fn strings(indent: &str) -> String {
+        let mut source = format!("{}var s = \"a\";\n{}for (var i = 0; i < 19; i = i + 1) s = s + s;\n", indent, indent);
+        for name in &["a", "b", "c", "d", "e", "f", "g", "h"] {
+            source += &format!("{}var {} = s + \"{}\";\n", indent, name, name);
+        }
+        source
+    }
+
+    #[test]
+    fn counts_strings_held_by_globals() {
+        let mut interpreter = Interpreter::with_stdlib();
+        interpreter.set_memory_limit(Some(1 << 20));
+        assert_over_limit(run(&strings(""), &mut interpreter));
+        assert!(interpreter.memory_usage() >= 512 * 1024);
+    }
+
+    #[test]
+    fn counts_strings_held_by_locals() {
+        let mut interpreter = Interpreter::with_stdlib();
+        interpreter.set_memory_limit(Some(1 << 20));
+        let source = format!("fun f() {{\n{}}}\n{{ f(); }}", strings("  "));
+        assert_over_limit(run(&source, &mut interpreter));
+    }
+}
@@ -0,0 +1,422 @@
+use std::sync::Arc;
+use ast::*;
+use parser::{ParseError, Parser};
+use scanner::{ScanError, Scanner, Span, Token};
+use ErrorReporter;
+
+/// A top-level declaration and the source it was parsed from.
+#[derive(Debug, Clone)]
+pub struct Declaration {
+    pub statement: Stmt,
+    pub span: Span,
+    pub errors: Vec<ParseError>
+}
+
+/// Keeps a parsed file around so that an edit only re-parses the declarations it touches.
+/// Scanning is cheap next to parsing, so everything after an edit is re-scanned, but parsing
+/// stops as soon as it lines up with a declaration from before the edit again. Declarations
+/// after that are kept, with their positions moved to match the new source.
+pub struct IncrementalParser {
+    source: String,
+    declarations: Vec<Declaration>,
+    scan_errors: Vec<ScanError>
+}
+
+impl IncrementalParser {
+    pub fn new(source: String, reporter: &mut dyn ErrorReporter) -> Self {
+        let mut parser = IncrementalParser {
+            source: source,
+            declarations: Vec::new(),
+            scan_errors: Vec::new()
+        };
+
+        parser.parse_from(0, Vec::new(), 0, reporter);
+        parser
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn declarations(&self) -> &[Declaration] {
+        &self.declarations
+    }
+
+    pub fn scan_errors(&self) -> &[ScanError] {
+        &self.scan_errors
+    }
+
+    /// The statements of every declaration that parsed without errors, ready to interpret.
+    pub fn statements(&self) -> Vec<Stmt> {
+        self.declarations.iter()
+            .filter(|declaration| declaration.errors.is_empty())
+            .map(|declaration| declaration.statement.clone())
+            .collect()
+    }
+
+    /// Replaces the bytes from `start` to `end` of the source with `text` and brings the parse
+    /// up to date. Errors in the re-parsed part are sent to `reporter`. Fails, changing
+    /// nothing, if the range doesn't lie within the source or splits a character.
+    pub fn edit(&mut self, start: usize, end: usize, text: &str, reporter: &mut dyn ErrorReporter) -> Result<(), String> {
+        if start > end || end > self.source.len() {
+            return Err(format!("Can't edit bytes {} to {} of a source {} bytes long.", start, end, self.source.len()));
+        }
+        if !self.source.is_char_boundary(start) || !self.source.is_char_boundary(end) {
+            return Err(format!("Can't edit bytes {} to {}, which split a character.", start, end));
+        }
+
+        let end_line = position_of(&self.source, end).0;
+        self.source.replace_range(start..end, text);
+        let delta = text.len() as isize - (end - start) as isize;
+
+        // The declaration before the edit is re-parsed too, since where a declaration ends can
+        // depend on the token after it: an `else`, or where error recovery stops. Parsing restarts
+        // from the end of the one before that, which is a token boundary.
+        let first = self.declarations.iter().position(|declaration| declaration.span.end >= start).unwrap_or(self.declarations.len());
+        let first = first.saturating_sub(1);
+        let after = self.declarations.split_off(first);
+        let offset = self.declarations.last().map_or(0, |declaration| declaration.span.end);
+
+        // Only declarations starting on a line after the edit can be kept, so that their
+        // columns don't change.
+        let reusable = after.into_iter()
+            .filter(|declaration| declaration.span.start >= end && declaration.span.line > end_line)
+            .collect();
+
+        self.parse_from(offset, reusable, delta, reporter);
+        Ok(())
+    }
+
+    fn parse_from(&mut self, offset: usize, reusable: Vec<Declaration>, delta: isize, reporter: &mut dyn ErrorReporter) {
+        let (line, column) = position_of(&self.source, offset);
+        let (tokens, scan_errors) = {
+            let mut scanner = Scanner::new(&self.source[offset..]);
+            scanner.set_position(offset, line, column);
+            scanner.scan_tokens()
+        };
+
+        self.scan_errors.retain(|error| (error.line, error.column) < (line, column));
+        for error in scan_errors {
+            reporter.report(error.line, error.column, "".to_string(), error.message.clone());
+            self.scan_errors.push(error);
+        }
+
+        let mut parser = Parser::new(&tokens);
+        let mut reusable = reusable.into_iter().peekable();
+
+        while let Some((statement, span, errors)) = parser.next_declaration(reporter) {
+            self.declarations.push(Declaration {
+                statement: statement,
+                span: span,
+                errors: errors
+            });
+
+            let next = &tokens[parser.position()];
+            while reusable.peek().map_or(false, |declaration| (declaration.span.start as isize + delta) < next.span.start as isize) {
+                reusable.next();
+            }
+
+            let lines_up = reusable.peek().map_or(false, |declaration| (declaration.span.start as isize + delta) as usize == next.span.start);
+            if lines_up {
+                let shift = Shift {
+                    bytes: delta,
+                    lines: next.span.line - reusable.peek().unwrap().span.line
+                };
+
+                for mut declaration in reusable {
+                    shift.declaration(&mut declaration);
+                    self.declarations.push(declaration);
+                }
+                return;
+            }
+        }
+    }
+}
+
+// The line and column `offset` falls on, counting both from 1 as the scanner does.
+fn position_of(source: &str, offset: usize) -> (i32, i32) {
+    let mut line = 1;
+    let mut column = 1;
+
+    for c in source[..offset].chars() {
+        if c == '\n' {
+            line += 1;
+            column = 1;
+        } else {
+            column += 1;
+        }
+    }
+
+    (line, column)
+}
+
+// Moves every position in a kept declaration by the size of an edit before it.
+struct Shift {
+    bytes: isize,
+    lines: i32
+}
+
+impl Shift {
+    fn declaration(&self, declaration: &mut Declaration) {
+        self.span(&mut declaration.span);
+
+        self.stmt(&mut declaration.statement);
+
+        for error in declaration.errors.iter_mut() {
+            self.token(&mut error.token);
+        }
+    }
+
+    fn span(&self, span: &mut Span) {
+        span.start = (span.start as isize + self.bytes) as usize;
+        span.end = (span.end as isize + self.bytes) as usize;
+        span.line += self.lines;
+    }
+
+    fn token(&self, token: &mut Token) {
+        self.span(&mut token.span);
+        token.line += self.lines;
+
+        for trivia in token.leading_trivia.iter_mut() {
+            self.span(&mut trivia.span);
+        }
+    }
+
+    fn error(&self, error: &mut ErrorNode) {
+        self.token(&mut error.token);
+        self.span(&mut error.span);
+    }
+
+    fn stmt(&self, stmt: &mut Stmt) {
+        match *stmt {
+            Stmt::Defer(ref mut expr, ref mut span) => {
+                self.expr(Arc::make_mut(expr));
+                self.span(span);
+            },
+            Stmt::Expression(ref mut expr, ref mut span) | Stmt::Print(ref mut expr, ref mut span) => {
+                self.expr(expr);
+                self.span(span);
+            },
+            Stmt::Function(ref mut v) => self.function(Arc::make_mut(v)),
+            Stmt::Class(ref mut v) => {
+                self.token(&mut v.name);
+                if let Some(ref mut superclass) = v.superclass {
+                    self.token(&mut superclass.name);
+                    self.span(&mut superclass.span);
+                }
+                for mixin in v.mixins.iter_mut() {
+                    self.token(&mut mixin.name);
+                    self.span(&mut mixin.span);
+                }
+                for method in v.methods.iter_mut() {
+                    self.function(Arc::make_mut(method));
+                }
+                self.span(&mut v.span);
+            },
+            Stmt::Return(ref mut v) => {
+                self.token(&mut v.keyword);
+                if let Some(ref mut value) = v.value {
+                    self.expr(value);
+                }
+                self.span(&mut v.span);
+            },
+            Stmt::If(ref mut v) => {
+                self.expr(&mut v.condition);
+                self.stmt(&mut v.then_branch);
+                if let Some(ref mut else_branch) = v.else_branch {
+                    self.stmt(else_branch);
+                }
+                self.span(&mut v.span);
+            },
+            Stmt::Var(ref mut v) => {
+                self.token(&mut v.name);
+                if let Some(ref mut type_annotation) = v.type_annotation {
+                    self.token(type_annotation);
+                }
+                if let Some(ref mut initializer) = v.initializer {
+                    self.expr(initializer);
+                }
+                self.span(&mut v.span);
+            },
+            Stmt::While(ref mut v) => {
+                self.expr(&mut v.condition);
+                self.stmt(&mut v.body);
+                self.span(&mut v.span);
+            },
+            Stmt::Block(ref mut v) => {
+                for statement in v.statements.iter_mut() {
+                    self.stmt(statement);
+                }
+                self.span(&mut v.span);
+            },
+            Stmt::Error(ref mut v) => self.error(v)
+        }
+    }
+
+    fn function(&self, function: &mut Function) {
+        self.token(&mut function.name);
+        for param in function.params.iter_mut() {
+            self.token(param);
+        }
+        for param_type in function.param_types.iter_mut().chain(Some(&mut function.return_type)).filter_map(Option::as_mut) {
+            self.token(param_type);
+        }
+        for statement in function.body.iter_mut() {
+            self.stmt(statement);
+        }
+        self.span(&mut function.span);
+    }
+
+    fn expr(&self, expr: &mut Expr) {
+        match *expr {
+            Expr::Binary(ref mut v) => {
+                self.expr(&mut v.left);
+                self.token(&mut v.operator);
+                self.expr(&mut v.right);
+                self.span(&mut v.span);
+            },
+            Expr::Call(ref mut v) => {
+                self.expr(&mut v.callee);
+                self.token(&mut v.paren);
+                for argument in v.arguments.iter_mut() {
+                    self.expr(argument);
+                }
+                self.span(&mut v.span);
+            },
+            Expr::Grouping(ref mut v) => {
+                self.expr(&mut v.expression);
+                self.span(&mut v.span);
+            },
+            Expr::Literal(_, ref mut span) => self.span(span),
+            Expr::Logical(ref mut v) => {
+                self.expr(&mut v.left);
+                self.token(&mut v.operator);
+                self.expr(&mut v.right);
+                self.span(&mut v.span);
+            },
+            Expr::Unary(ref mut v) => {
+                self.token(&mut v.operator);
+                self.expr(&mut v.right);
+                self.span(&mut v.span);
+            },
+            Expr::Variable(ref mut v) => {
+                self.token(&mut v.name);
+                self.span(&mut v.span);
+            },
+            Expr::Assign(ref mut v) => {
+                self.token(&mut v.name);
+                self.expr(&mut v.value);
+                self.span(&mut v.span);
+            },
+            Expr::Get(ref mut v) => {
+                self.expr(&mut v.object);
+                self.token(&mut v.name);
+                self.span(&mut v.span);
+            },
+            Expr::Set(ref mut v) => {
+                self.expr(&mut v.object);
+                self.token(&mut v.name);
+                self.expr(&mut v.value);
+                self.span(&mut v.span);
+            },
+            Expr::This(ref mut v) => {
+                self.token(&mut v.keyword);
+                self.span(&mut v.span);
+            },
+            Expr::Super(ref mut v) => {
+                self.token(&mut v.keyword);
+                self.token(&mut v.method);
+                self.span(&mut v.span);
+            },
+            Expr::Error(ref mut v) => self.error(v)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Pieces of Lox to insert, some of them incomplete so that edits pass through invalid
+    // source on the way.
+    const FRAGMENTS: &'static [&'static str] = &[
+        "var x = 1;\n", "print x;\n", "fun f(a) { return a + 1; }\n", "{", "}", "(", ")", ";",
+        "if (x) print 1; else print 2;\n", "else", "class A < B { m() { this.x = super.y; } }\n",
+        "\"str", "\"", "// comment\n", "/* block */", "\n", " ", "while (x < 3) x = x + 1;\n",
+        "defer print 1;\n", "class C with A, B {}\n", "é", "var", "x", "1.5", "@"
+    ];
+
+    // A fixed sequence of pseudo-random numbers, so failures can be reproduced.
+    struct Random(u64);
+
+    impl Random {
+        fn below(&mut self, limit: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % limit as u64) as usize
+        }
+
+        fn boundary(&mut self, source: &str) -> usize {
+            let boundaries: Vec<usize> = source.char_indices().map(|(index, _)| index).chain(Some(source.len())).collect();
+            boundaries[self.below(boundaries.len())]
+        }
+    }
+
+    fn parse(source: &str) -> String {
+        format!("{:?}", IncrementalParser::new(source.to_string(), &mut Vec::new()).declarations())
+    }
+
+    #[test]
+    fn edits_parse_the_same_as_a_fresh_parse() {
+        let mut random = Random(0x2361);
+        for _ in 0..300 {
+            let mut source = String::new();
+            for _ in 0..random.below(8) {
+                source.push_str(FRAGMENTS[random.below(FRAGMENTS.len())]);
+            }
+            let mut parser = IncrementalParser::new(source.clone(), &mut Vec::new());
+
+            for _ in 0..10 {
+                let start = random.boundary(&source);
+                let end = start + random.boundary(&source[start..]);
+                let text = if random.below(4) == 0 { "" } else { FRAGMENTS[random.below(FRAGMENTS.len())] };
+                source.replace_range(start..end, text);
+
+                parser.edit(start, end, text, &mut Vec::new()).unwrap();
+                assert_eq!(parser.source(), source);
+                assert_eq!(format!("{:?}", parser.declarations()), parse(&source), "after replacing {}..{} with {:?} in {:?}", start, end, text, source);
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_declarations_after_the_edit() {
+        let mut errors = Vec::new();
+        let mut parser = IncrementalParser::new("var a = 1;\nvar b = 2;\nvar = 3;\n".to_string(), &mut errors);
+        assert_eq!(errors.len(), 1);
+
+        // The broken declaration is kept rather than parsed again, so its error isn't reported
+        // a second time.
+        let mut errors = Vec::new();
+        parser.edit(8, 9, "100", &mut errors).unwrap();
+        assert!(errors.is_empty());
+        assert_eq!(parser.declarations().len(), 3);
+        assert_eq!(parser.declarations()[2].errors.len(), 1);
+        assert_eq!(parser.declarations()[2].span.start, 24);
+
+        let mut errors = Vec::new();
+        parser.edit(0, 0, "var = 0;", &mut errors).unwrap();
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn rejects_edits_outside_the_source() {
+        let mut parser = IncrementalParser::new("print \"é\";".to_string(), &mut Vec::new());
+        assert!(parser.edit(3, 2, "", &mut Vec::new()).is_err());
+        assert!(parser.edit(0, 100, "", &mut Vec::new()).is_err());
+        assert!(parser.edit(8, 8, "x", &mut Vec::new()).is_err());
+        assert_eq!(parser.source(), "print \"é\";");
+        assert!(parser.edit(11, 11, " ", &mut Vec::new()).is_ok());
+    }
+}
@@ -0,0 +1,36 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// Shares one allocation between every copy of the same text, so that identifiers and string
+/// literals can be cloned without copying and usually compared by pointer. Clones of an
+/// interner share its table, which lets a scanner hand out the same strings as the interpreter
+/// it's scanning for.
+#[derive(Debug, Clone, Default)]
+pub struct Interner {
+    strings: Rc<RefCell<HashSet<Arc<str>>>>
+}
+
+impl Interner {
+    pub fn new() -> Self {
+        Interner::default()
+    }
+
+    pub fn intern(&self, text: &str) -> Arc<str> {
+        let mut strings = self.strings.borrow_mut();
+        if let Some(string) = strings.get(text) {
+            return string.clone();
+        }
+
+        let string: Arc<str> = Arc::from(text);
+        strings.insert(string.clone());
+        string
+    }
+}
+
+/// Compares strings by pointer before falling back to their text, which only needs looking at
+/// when they weren't interned by the same interner.
+pub fn same(string1: &Arc<str>, string2: &Arc<str>) -> bool {
+    Arc::ptr_eq(string1, string2) || string1 == string2
+}
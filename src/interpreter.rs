@@ -1,313 +1,2499 @@
 use ast::*;
-use scanner::{TokenType, Token};
+use scanner::{Span, TokenType, Token};
 use std::error::Error;
 use std::fmt::{Display, Result as FmtResult, Formatter};
-use std::collections::HashMap;
-use super::Lox;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryFrom;
+use intern::{self, Interner};
+use gc::{self, Heap, Trace, Tracer};
+use profile::Profile;
+use coverage::Coverage;
+use debugger::{Change, Debugger, Pause, Resume};
+use stdlib;
+use suggest;
+use std::mem::{self, size_of};
 use std::rc::Rc;
+use std::cell::RefCell;
+use std::io::{BufRead, Result as IoResult, Write, stderr, stdin, stdout};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// How many function calls can be in progress at once before a script fails with a stack
+/// overflow, unless changed with `set_max_call_depth`.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 1000;
+
+/// How deeply expressions can nest while being evaluated, counting those in every call in
+/// progress, unless changed with `set_max_expression_depth`.
+pub const DEFAULT_MAX_EXPRESSION_DEPTH: usize = 5000;
+
+// How much of the thread's stack has to be left for a call or an expression to go ahead,
+// enough for whatever runs before the next check, natives included.
+#[cfg(not(target_arch = "wasm32"))]
+const STACK_RED_ZONE: usize = 256 * 1024;
 
 pub struct Interpreter {
-    environment: Environment
+    globals: Rc<RefCell<Environment>>,
+    environment: Rc<RefCell<Environment>>,
+    interner: Interner,
+    heap: Heap,
+    call_stack: Vec<Frame>,
+    max_call_depth: usize,
+    expression_depth: usize,
+    max_expression_depth: usize,
+    fuel: Option<u64>,
+    cancelled: Arc<AtomicBool>,
+    memory_limit: Option<usize>,
+    deferred: Vec<Vec<Arc<Expr>>>,
+    // Where the scopes of the call in progress start in `deferred`.
+    call_deferred: usize,
+    random_state: u64,
+    input: Option<Box<dyn BufRead>>,
+    output: Option<Box<dyn Write>>,
+    error_output: Option<Box<dyn Write>>,
+    trace: Option<Box<dyn Write>>,
+    profile: Option<Profile>,
+    coverage: Option<Coverage>,
+    debugger: Option<Box<dyn Debugger>>,
+    breakpoints: HashSet<i32>,
+    watches: HashSet<Arc<str>>,
+    // While stepping, the deepest call a statement can be in and still be paused at.
+    step: Option<usize>,
+    // The line and call depth of the last statement run while debugging, so that a breakpoint
+    // pauses on arriving at its line rather than at every statement on it.
+    debug_position: Option<(i32, usize)>,
+    config: InterpreterConfig,
+    args: Vec<String>,
+    exit_code: Option<i32>
 }
 
 impl Interpreter {
     pub fn new() -> Self {
+        let globals = Rc::new(RefCell::new(Environment::new()));
+        let mut heap = Heap::new();
+        heap.track_environment(&globals);
         let mut interpreter = Interpreter {
-            environment: Environment::new()
+            globals: globals.clone(),
+            environment: globals,
+            interner: Interner::new(),
+            heap: heap,
+            call_stack: Vec::new(),
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            expression_depth: 0,
+            max_expression_depth: DEFAULT_MAX_EXPRESSION_DEPTH,
+            fuel: None,
+            cancelled: Arc::new(AtomicBool::new(false)),
+            memory_limit: None,
+            deferred: Vec::new(),
+            call_deferred: 0,
+            random_state: 0,
+            input: None,
+            output: None,
+            error_output: None,
+            trace: None,
+            profile: None,
+            coverage: None,
+            debugger: None,
+            breakpoints: HashSet::new(),
+            watches: HashSet::new(),
+            step: None,
+            debug_position: None,
+            config: InterpreterConfig::default(),
+            args: Vec::new(),
+            exit_code: None
         };
 
-        interpreter.environment.define("clock".to_string(), Literal::Callable(Rc::new(Clock)));
+        let now = stdlib::unix_time().unwrap_or_default();
+        interpreter.seed_random(now.as_secs() ^ now.subsec_nanos() as u64);
+
+        interpreter.define_native("clock", 0, clock);
         interpreter
     }
 
-    pub fn interpret<'a>(&mut self, lox: &mut Lox, statements: &'a Vec<Stmt>) {
-        for statement in statements {
-            match self.execute(statement) {
-                Ok(_) => (),
-                Err(error) => {
-                    lox.runtime_error(error);
-                    return;
-                }
-            }
+    /// Creates an interpreter with the standard library natives already defined.
+    pub fn with_stdlib() -> Self {
+        let mut interpreter = Self::new();
+        stdlib::define_all(&mut interpreter);
+        interpreter
+    }
+
+    /// Creates an interpreter with `natives` defined, which can be shared by any number of
+    /// interpreters on any number of threads.
+    pub fn with_natives(natives: &Natives) -> Self {
+        let mut interpreter = Self::new();
+        for &(ref name, arity, function) in &natives.natives {
+            interpreter.define_native(name, arity, function);
         }
+        interpreter
     }
 
-    fn stringify(&self, value: Literal) -> String {
-        match value {
-            Literal::Nil => "nil".to_string(),
-            Literal::Number(number) => {
-                let value = number.to_string();
-                value
+    /// Limits how deeply scripts can recurse. Scripts also fail with a stack overflow when the
+    /// thread they run on is close to running out of stack, whatever its size, so these only
+    /// matter on a thread with more stack than they use. The exception is wasm, which can't
+    /// tell how much stack is left; there the limits have to be low enough on their own.
+    pub fn set_max_call_depth(&mut self, max_call_depth: usize) {
+        self.max_call_depth = max_call_depth;
+    }
+
+    pub fn set_max_expression_depth(&mut self, max_expression_depth: usize) {
+        self.max_expression_depth = max_expression_depth;
+    }
+
+    /// Gives scripts a budget of statements and expressions they can run, after which they fail
+    /// with an error, or with None lets them run for as long as they like. The budget is shared
+    /// by everything the interpreter runs until it's set again.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    /// What's left of the budget set with `set_fuel`.
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    // Spends one unit of fuel on running the code at `span`, if there's a budget at all.
+    fn consume_fuel(&mut self, span: Span) -> Result<(), RuntimeError> {
+        match self.fuel {
+            Some(0) => Err(RuntimeError::new(Token::at(span), ErrorKind::BudgetExceeded)),
+            Some(ref mut fuel) => {
+                *fuel -= 1;
+                Ok(())
             },
-            Literal::String(value) => value,
-            Literal::Bool(value) => value.to_string(),
-            Literal::Callable(_) => panic!()
+            None => Ok(())
         }
     }
 
-    fn execute<'a>(&mut self, stmt: &'a Stmt) -> Result<(), RuntimeError> {
-        stmt.accept(self)
+    /// Spends `amount` of the budget set with `set_fuel` on work a native is doing for the
+    /// script, failing at `token` once it runs out. Natives that can take a while should call
+    /// this every so often, along with `check_cancelled`.
+    pub fn spend_fuel(&mut self, amount: u64, token: &Token) -> Result<(), RuntimeError> {
+        match self.fuel {
+            Some(ref mut fuel) if *fuel < amount => {
+                *fuel = 0;
+                Err(RuntimeError::new(token.clone(), ErrorKind::BudgetExceeded))
+            },
+            Some(ref mut fuel) => {
+                *fuel -= amount;
+                Ok(())
+            },
+            None => Ok(())
+        }
     }
 
-    fn execute_block<'a>(&mut self, statements: &'a Vec<Stmt>) -> Result<(), RuntimeError> {
-        self.environment.push();
-        let mut result = Ok(());
-        for statement in statements {
-            result = self.execute(statement);
+    /// Caps roughly how many bytes of strings, lists, maps and instances scripts can hold on
+    /// to, or with None lets them use as much as they like. An allocation that would go over
+    /// the limit fails with an error, after first collecting garbage to make room.
+    pub fn set_memory_limit(&mut self, memory_limit: Option<usize>) {
+        self.memory_limit = memory_limit;
+    }
 
-            if result.is_err() {
-                break;
+    /// Roughly how many bytes scripts are using; see `gc::Heap::used`.
+    pub fn memory_usage(&self) -> usize {
+        self.heap.used()
+    }
+
+    /// Counts `bytes` a native is about to allocate for the script towards the memory limit,
+    /// failing at `token` if there's no room for them.
+    pub fn allocate(&mut self, bytes: usize, token: &Token) -> Result<(), RuntimeError> {
+        if let Some(limit) = self.memory_limit {
+            if self.heap.used() + bytes > limit {
+                self.heap.collect();
+            }
+            if self.heap.used() + bytes > limit {
+                return Err(RuntimeError::new(token.clone(), ErrorKind::MemoryLimitExceeded));
             }
         }
 
-        self.environment.pop();
+        self.heap.allocate(bytes);
+        Ok(())
+    }
 
-        result
+    /// A handle another thread can use to stop whatever script this interpreter is running.
+    pub fn cancel_handle(&self) -> CancelHandle {
+        CancelHandle { cancelled: self.cancelled.clone() }
     }
 
-    fn evaluate<'a>(&mut self, expr: &'a Expr) -> Result<Literal, RuntimeError> {
-        expr.accept(self)
+    /// Fails once the running script has been cancelled, pointing at `token`. Natives that can
+    /// take a while should check this every so often.
+    pub fn check_cancelled(&self, token: &Token) -> Result<(), RuntimeError> {
+        if self.cancelled.load(Ordering::Relaxed) {
+            Err(RuntimeError::new(token.clone(), ErrorKind::Cancelled))
+        } else {
+            Ok(())
+        }
     }
 
-    fn is_truthy<'a>(&self, literal: &'a Literal) -> bool {
-        match literal {
-            &Literal::Bool(bool) => bool,
-            &Literal::Nil => false,
-            _ => true
+    fn check_cancelled_at(&self, span: Span) -> Result<(), RuntimeError> {
+        if self.cancelled.load(Ordering::Relaxed) {
+            self.check_cancelled(&Token::at(span))
+        } else {
+            Ok(())
         }
     }
 
-    fn cast_to_float<'a>(&self, literal: Literal, operator: &'a Token) -> Result<f64, RuntimeError> {
-        match literal {
-            Literal::Number(number) => Ok(number),
-            _ => Err(RuntimeError(operator.clone(), "Operand must be a numbers".to_string()))
+    /// Sets what natives may reach outside the interpreter for scripts.
+    pub fn set_config(&mut self, config: InterpreterConfig) {
+        self.config = config;
+    }
+
+    pub fn config(&self) -> InterpreterConfig {
+        self.config
+    }
+
+    /// Allows or forbids scripts to use `readFile()` and `writeFile()`; forbidden by default.
+    pub fn set_file_access(&mut self, file_access: bool) {
+        self.config.filesystem = file_access;
+    }
+
+    pub fn has_file_access(&self) -> bool {
+        self.config.filesystem
+    }
+
+    /// Fails at `paren` unless `allowed`, one of the config's flags, is set. Natives that reach
+    /// outside the interpreter check this before doing anything, `what` being what they need.
+    pub fn require(&self, allowed: bool, what: &str, paren: &Token) -> Result<(), RuntimeError> {
+        if allowed {
+            Ok(())
+        } else {
+            Err(RuntimeError::new(paren.clone(), ErrorKind::Disabled(what.to_string())))
         }
     }
 
-    fn is_equal(&self, left: Literal, right: Literal) -> bool {
-        left == right
+    /// Replaces stdin as the source `readLine()` reads from.
+    pub fn set_input(&mut self, input: Box<dyn BufRead>) {
+        self.input = Some(input);
     }
-}
 
-impl ExprVisitor<Result<Literal, RuntimeError>> for Interpreter {
-    fn visit_binary<'a>(&mut self, binary: &'a Binary) -> Result<Literal, RuntimeError> {
-        let left = self.evaluate(&*binary.left)?;
-        let right = self.evaluate(&*binary.right)?;
+    /// Replaces stdout as where `print` and `write()` send the program's output.
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = Some(output);
+    }
 
-        Ok(match binary.operator.token_type {
-            TokenType::Minus => Literal::Number(self.cast_to_float(left, &binary.operator)? - self.cast_to_float(right, &binary.operator)?),
-            TokenType::Slash => Literal::Number(self.cast_to_float(left, &binary.operator)? / self.cast_to_float(right, &binary.operator)?),
-            TokenType::Star => Literal::Number(self.cast_to_float(left, &binary.operator)? * self.cast_to_float(right, &binary.operator)?),
-            TokenType::Plus => {
-                match (left, right) {
-                    (Literal::String(mut left), Literal::String(right)) => {
-                        left.push_str(&right[..]);
-                        Literal::String(left)
-                    },
-                    (left, right) => Literal::Number(self.cast_to_float(left, &binary.operator)? + self.cast_to_float(right, &binary.operator)?)
-                }
-            },
-            TokenType::Greater => Literal::Bool(self.cast_to_float(left, &binary.operator)? > self.cast_to_float(right, &binary.operator)?),
-            TokenType::GreaterEqual => Literal::Bool(self.cast_to_float(left, &binary.operator)? >= self.cast_to_float(right, &binary.operator)?),
-            TokenType::Less => Literal::Bool(self.cast_to_float(left, &binary.operator)? < self.cast_to_float(right, &binary.operator)?),
-            TokenType::LessEqual => Literal::Bool(self.cast_to_float(left, &binary.operator)? <= self.cast_to_float(right, &binary.operator)?),
-            TokenType::BangEqual => Literal::Bool(!self.is_equal(left, right)),
-            TokenType::EqualEqual => Literal::Bool(self.is_equal(left, right)),
-            _ => unreachable!()
-        })
+    /// Replaces stderr as where `eprint()` writes.
+    pub fn set_error_output(&mut self, error_output: Box<dyn Write>) {
+        self.error_output = Some(error_output);
     }
 
-    fn visit_call<'a>(&mut self, expr: &'a Call) -> Result<Literal, RuntimeError> {
-        let callee = self.evaluate(&*expr.callee)?;
+    /// Logs every statement before it runs and every expression's value once it's evaluated to
+    /// `trace`, or with None stops logging. Each entry has the line it's from, and is indented
+    /// by how many calls deep it is.
+    pub fn set_trace(&mut self, trace: Option<Box<dyn Write>>) {
+        self.trace = trace;
+    }
 
-        let mut arguments = Vec::new();
+    // Kept apart from `evaluate` so that the usual case, with no tracing, stays as quick as it
+    // can be. Literals are left out, their values being right there in the code.
+    #[cold]
+    fn evaluate_traced(&mut self, expr: &Expr) -> Result<Value, RuntimeError> {
+        self.expression_depth += 1;
+        let result = expr.accept(self);
+        self.expression_depth -= 1;
 
-        for argument in &expr.arguments {
-            arguments.push(self.evaluate(argument)?);
+        if let Ok(ref value) = result {
+            if !is_literal(expr) {
+                let entry = format!("{} = {}", AstPrinter.print(expr), self.stringify(value.clone()));
+                self.trace_line(expr.span().line, &entry);
+            }
         }
+        result
+    }
 
-        match callee {
-            Literal::Callable(ref callable) => {
-                if arguments.len() != callable.arity() {
-                    Err(RuntimeError(expr.paren.clone(), format!(
-                        "Expected {} arguments but got {}.",
-                        callable.arity(),
-                        arguments.len()
-                    )))
-                } else {
-                    Ok(callable.call(self, arguments)?)
-                }
-            },
-            _ => Err(RuntimeError(expr.paren.clone(), "Can only call functions and classes.".to_string()))
+    // Tracing is only an aid, so a sink that can't be written to doesn't stop the script.
+    #[cold]
+    fn trace_line(&mut self, line: i32, entry: &str) {
+        let depth = self.call_stack.len();
+        if let Some(ref mut trace) = self.trace {
+            let _ = writeln!(trace, "{}[line {}] {}", "  ".repeat(depth), line, entry);
         }
     }
 
-    fn visit_grouping<'a>(&mut self, grouping: &'a Grouping) -> Result<Literal, RuntimeError> {
-        self.evaluate(&*grouping.expression)
+    /// Starts recording calls to each function and how many statements run on each line, or
+    /// stops and throws away what's been recorded. Natives aren't included.
+    pub fn set_profiling(&mut self, profiling: bool) {
+        self.profile = if profiling { Some(Profile::new()) } else { None };
     }
 
-    fn visit_literal<'a>(&mut self, literal: &'a Literal) -> Result<Literal, RuntimeError> {
-        Ok(literal.clone())
+    /// What's been recorded since profiling was turned on.
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
     }
 
-    fn visit_unary<'a>(&mut self, unary: &'a Unary) -> Result<Literal, RuntimeError> {
-        let right = self.evaluate(&*unary.right)?;
+    /// Starts recording which lines of the scripts run from now on have statements, and how
+    /// often those run, or stops and throws away what's been recorded.
+    pub fn set_coverage(&mut self, coverage: bool) {
+        self.coverage = if coverage { Some(Coverage::new()) } else { None };
+    }
 
-        Ok(match unary.operator.token_type {
-            TokenType::Minus => Literal::Number(-self.cast_to_float(right, &unary.operator)?),
-            TokenType::Bang => Literal::Bool(self.is_truthy(&right)),
-            _ => unreachable!()
-        })
+    /// What's been recorded since coverage was turned on.
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
     }
 
-    fn visit_variable<'a>(&mut self, variable: &'a Variable) -> Result<Literal, RuntimeError> {
-        self.environment.get(&variable.name)
+    /// Hands control to `debugger` whenever the script reaches a breakpoint or is being stepped
+    /// through, or with None stops debugging.
+    pub fn set_debugger(&mut self, debugger: Option<Box<dyn Debugger>>) {
+        self.debugger = debugger;
     }
 
-    fn visit_assign<'a>(&mut self, assign: &'a Assign) -> Result<Literal, RuntimeError> {
-        let value = self.evaluate(&*assign.value)?;
+    /// Pauses before running the first statement on `line`, each time it's reached.
+    pub fn set_breakpoint(&mut self, line: i32) {
+        self.breakpoints.insert(line);
+    }
 
-        self.environment.assign(&assign.name, value.clone())?;
-        Ok(value)
+    pub fn clear_breakpoint(&mut self, line: i32) {
+        self.breakpoints.remove(&line);
     }
 
-    fn visit_logical<'a>(&mut self, logical: &'a Logical) -> Result<Literal, RuntimeError> {
-        let left = self.evaluate(&*logical.left)?;
+    /// Reports every declaration of and assignment to a variable called `name`, in whatever
+    /// scope, to the debugger and in the trace.
+    pub fn watch(&mut self, name: &str) {
+        self.watches.insert(Arc::from(name));
+    }
 
-        Ok(match logical.operator.token_type {
-            TokenType::Or if self.is_truthy(&left) => left,
-            TokenType::And if !self.is_truthy(&left) => left,
-            _ => self.evaluate(&*logical.right)?
-        })
+    pub fn unwatch(&mut self, name: &str) {
+        self.watches.remove(name);
     }
-}
 
-impl StmtVisitor<Result<(), RuntimeError>> for Interpreter {
-    fn visit_expr<'a>(&mut self, expr: &'a Expr) -> Result<(), RuntimeError> {
-        self.evaluate(expr)?;
-        Ok(())
+    fn watching(&self, name: &str) -> bool {
+        !self.watches.is_empty() && self.watches.contains(name)
     }
 
-    fn visit_print<'a>(&mut self, print: &'a Expr) -> Result<(), RuntimeError> {
-        let result = self.evaluate(print)?;
-        println!("{}", self.stringify(result));
-        Ok(())
+    /// Pauses before the next statement the interpreter runs, whatever line it's on.
+    pub fn break_at_next_statement(&mut self) {
+        self.step = Some(usize::max_value());
     }
 
-    fn visit_var<'a>(&mut self, stmt: &'a Var) -> Result<(), RuntimeError> {
-        let value = if stmt.initializer.is_some() {
-            self.evaluate(&stmt.initializer.clone().unwrap())?
-        } else {
-            Literal::Nil
+    // Gives the debugger control if the statement at `span` is one to pause at.
+    #[cold]
+    fn debug(&mut self, span: Span) -> Result<(), RuntimeError> {
+        let depth = self.call_stack.len();
+        let arrived = self.debug_position != Some((span.line, depth));
+        self.debug_position = Some((span.line, depth));
+
+        let stepping = self.step.map_or(false, |deepest| depth <= deepest);
+        if !stepping && !(arrived && self.breakpoints.contains(&span.line)) {
+            return Ok(());
+        }
+
+        let mut debugger = match self.debugger.take() {
+            Some(debugger) => debugger,
+            None => return Ok(())
         };
+        let resume = debugger.paused(&mut Pause::new(self, span));
+        self.debugger = Some(debugger);
+        self.resume(resume, span)
+    }
+
+    // Reports a watched variable being given a value at `name`, then lets the debugger decide
+    // whether to pause.
+    #[cold]
+    fn watched(&mut self, name: &Token, old: Option<Value>, new: Option<Value>) -> Result<(), RuntimeError> {
+        let change = Change {
+            name: name.lexeme.clone(),
+            old: old,
+            new: new,
+            line: name.line
+        };
+
+        if self.trace.is_some() {
+            let entry = format!("watch: {}", change.describe(|value| self.stringify(value)));
+            self.trace_line(name.line, &entry);
+        }
+
+        let mut debugger = match self.debugger.take() {
+            Some(debugger) => debugger,
+            None => return Ok(())
+        };
+        let resume = debugger.changed(&mut Pause::new(self, name.span), &change);
+        self.debugger = Some(debugger);
+        self.resume(resume, name.span)
+    }
 
-        self.environment.define(stmt.name.lexeme.clone(), value);
+    fn resume(&mut self, resume: Resume, span: Span) -> Result<(), RuntimeError> {
+        let depth = self.call_stack.len();
+        self.step = match resume {
+            Resume::Continue => None,
+            Resume::Step => Some(usize::max_value()),
+            Resume::StepOver => Some(depth),
+            Resume::StepOut => depth.checked_sub(1),
+            Resume::Stop => return Err(RuntimeError::new(Token::at(span), ErrorKind::Cancelled))
+        };
         Ok(())
     }
 
-    fn visit_block<'a>(&mut self, block: &'a Block) -> Result<(), RuntimeError> {
-        self.execute_block(&block.statements)
+    /// Reads the next line of input without its line ending, or `None` once the input is exhausted.
+    pub fn read_line(&mut self) -> IoResult<Option<String>> {
+        let mut line = String::new();
+        let read = match self.input {
+            Some(ref mut input) => input.read_line(&mut line)?,
+            None => stdin().read_line(&mut line)?
+        };
+
+        if read == 0 {
+            return Ok(None);
+        }
+
+        if line.ends_with('\n') {
+            line.pop();
+            if line.ends_with('\r') {
+                line.pop();
+            }
+        }
+
+        Ok(Some(line))
     }
 
-    fn visit_if<'a>(&mut self, if_statement: &'a If) -> Result<(), RuntimeError> {
-        let value = self.evaluate(&if_statement.condition)?;
-        if self.is_truthy(&value) {
-            self.execute(&*if_statement.then_branch)
-        } else if let Some(ref else_branch) = if_statement.else_branch {
-            self.execute(else_branch)
-        } else {
-            Ok(())
+    /// Writes `text` to the program's output, flushing it so that text without a line ending
+    /// shows up straight away.
+    pub fn write_output(&mut self, text: &str) -> IoResult<()> {
+        match self.output {
+            Some(ref mut output) => write_flushed(&mut **output, text),
+            None => write_flushed(&mut stdout().lock(), text)
         }
     }
 
-    fn visit_while<'a>(&mut self, while_statement: &'a While) -> Result<(), RuntimeError> {
-        loop {
-            let value = self.evaluate(&while_statement.condition)?;
+    /// Writes `text` to where `eprint()` writes.
+    pub fn write_error(&mut self, text: &str) -> IoResult<()> {
+        match self.error_output {
+            Some(ref mut error_output) => write_flushed(&mut **error_output, text),
+            None => write_flushed(&mut stderr().lock(), text)
+        }
+    }
 
-            if !self.is_truthy(&value) {
-                break;
-            }
+    /// Resets the generator behind `random()` so the numbers it produces are reproducible.
+    pub fn seed_random(&mut self, seed: u64) {
+        self.random_state = seed;
+    }
+
+    /// Returns the next pseudo-random number in `[0, 1)`, using splitmix64.
+    pub fn next_random(&mut self) -> f64 {
+        self.random_state = self.random_state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.random_state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Makes a host function available to scripts as a global named `name`.
+    pub fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        self.globals.borrow_mut().define(Arc::from(name), Value::Callable(Rc::new(NativeFunction {
+            arity: arity,
+            function: function
+        })));
+    }
+
+    /// Makes a Rust closure available to scripts as a global named `name`, for natives that
+    /// only need their arguments. A type error the closure gives back, as converting a value
+    /// with `TryFrom` does, is reported at the call.
+    ///
+    /// ```ignore
+    /// interpreter.define_native_fn("greet", 1, |arguments| {
+    ///     Ok(format!("Hello, {}!", String::try_from(arguments[0].clone())?).into())
+    /// });
+    /// ```
+    pub fn define_native_fn<F>(&mut self, name: &str, arity: usize, function: F)
+        where F: Fn(Vec<Value>) -> Result<Value, ErrorKind> + 'static {
+        self.globals.borrow_mut().define(Arc::from(name), Value::Callable(Rc::new(NativeClosure {
+            arity: arity,
+            function: Box::new(function)
+        })));
+    }
+
+    /// The value of the global `name`, or None if it hasn't been declared or assigned one.
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        self.globals.borrow().value(name)
+    }
 
-            self.execute(&while_statement.body)?;
+    /// Gives the global `name` the value `value`, declaring it first if it hasn't been, as a
+    /// top-level `var` declaration would. Scripts see it like any other global.
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        let name = self.interner.intern(name);
+        self.globals.borrow_mut().define(name, value);
+    }
+
+    /// Runs a program, as `Lox::compile` gives back, stopping at the first runtime error. A
+    /// script calling `exit()` stops it without an error, leaving the code in `exit_code` until
+    /// the next program is run.
+    pub fn interpret<'a>(&mut self, statements: &'a Vec<Stmt>) -> Result<(), RuntimeError> {
+        self.exit_code = None;
+        if let Some(ref mut coverage) = self.coverage {
+            coverage.add_statements(statements);
+        }
+        self.deferred.push(Vec::new());
+        // A return can't get this far, since the resolver only allows them inside functions.
+        let mut result = Ok(ControlFlow::Normal);
+        for statement in statements {
+            result = self.execute(statement);
+
+            match result {
+                Ok(ControlFlow::Normal) => (),
+                _ => break
+            }
         }
 
-        Ok(())
+        let deferred_result = self.run_deferred();
+
+        // A cancellation only stops the script that was running when it came in, and stepping
+        // through one doesn't carry on into the next.
+        self.cancelled.store(false, Ordering::Relaxed);
+        self.step = None;
+        self.debug_position = None;
+
+        match result.and(deferred_result) {
+            // Unwinding from exit() isn't a failure worth reporting.
+            Err(RuntimeError(_, ErrorKind::Exit, _)) => Ok(()),
+            Err(error) => Err(error),
+            Ok(_) => Ok(())
+        }
     }
-}
 
-#[derive(Debug)]
-pub struct RuntimeError(pub Token, pub String);
+    /// Executes statements directly in the current scope, returning the value of the last one
+    /// if it is an expression statement and nil otherwise. Like `interpret`, it forgets any
+    /// `exit()` an earlier program made.
+    pub fn execute_in_scope<'a>(&mut self, statements: &'a Vec<Stmt>) -> Result<Value, RuntimeError> {
+        self.exit_code = None;
+        let mut value = Value::Nil;
+        for statement in statements {
+            value = match *statement {
+                Stmt::Expression(ref expr, _) => self.evaluate(expr)?,
+                ref statement => {
+                    self.execute(statement)?;
+                    Value::Nil
+                }
+            };
+        }
 
-impl Error for RuntimeError {
-    fn description(&self) -> &str {
-        "A runtime error occurred"
+        Ok(value)
     }
-}
 
-impl Display for RuntimeError {
-    fn fmt(&self, f: &mut Formatter) -> FmtResult {
-        write!(f, "A runtime error occurred: {}", self.1)
+    /// The outermost scope, where top-level declarations and natives live.
+    pub fn globals(&self) -> &Rc<RefCell<Environment>> {
+        &self.globals
     }
-}
 
-pub struct Environment {
-    values: Vec<HashMap<String, Literal>>
-}
+    /// The innermost scope of whatever is running.
+    pub fn environment(&self) -> &Rc<RefCell<Environment>> {
+        &self.environment
+    }
 
-impl Environment {
-    fn new() -> Self {
-        let mut environment = Environment {
-            values: Vec::new()
+    /// Copies the globals, so that `restore` can put them back as they are now. Lists, maps and
+    /// instances they hold are copied too, as deeply as they go. Functions and classes are
+    /// shared rather than copied, along with any variables their closures hold on to.
+    pub fn snapshot(&mut self) -> Snapshot {
+        let (names, values) = {
+            let globals = self.globals.borrow();
+            (globals.names.clone(), globals.values.clone())
         };
-        environment.push();
-        environment
+        let mut copies = HashMap::new();
+        Snapshot {
+            names: names,
+            values: values.iter().map(|value| value.as_ref().map(|value| self.copy(value, &mut copies))).collect()
+        }
+    }
+
+    /// Puts the globals back as they were when `snapshot` was taken. The snapshot is left as it
+    /// is, so it can be restored again.
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        let mut copies = HashMap::new();
+        let values = snapshot.values.iter()
+            .map(|value| value.as_ref().map(|value| self.copy(value, &mut copies)))
+            .collect();
+
+        let mut globals = self.globals.borrow_mut();
+        globals.index = Some(snapshot.names.iter().cloned().zip(0..).collect());
+        globals.names = snapshot.names.clone();
+        globals.values = values;
     }
 
-    fn define(&mut self, name: String, value: Literal) {
-        self.values.last_mut().unwrap().insert(name, value);
+    // A deep copy of `value`. `copies` maps what's been copied so far to its copy, so that
+    // anything reachable more than once, cycles included, is copied once.
+    fn copy(&mut self, value: &Value, copies: &mut HashMap<*const (), Value>) -> Value {
+        let address = match *value {
+            Value::List(ref list) => Rc::as_ptr(list) as *const (),
+            Value::Map(ref map) => Rc::as_ptr(map) as *const (),
+            Value::Instance(ref instance) => Rc::as_ptr(instance) as *const (),
+            _ => return value.clone()
+        };
+        if let Some(copy) = copies.get(&address) {
+            return copy.clone();
+        }
+
+        // The copy is recorded before what's inside is, in case that leads back to it.
+        match *value {
+            Value::List(ref list) => {
+                let copy = Rc::new(RefCell::new(Vec::new()));
+                copies.insert(address, Value::List(copy.clone()));
+                let values = list.borrow().clone();
+                let values = values.iter().map(|value| self.copy(value, copies)).collect();
+                *copy.borrow_mut() = values;
+                self.track(&Value::List(copy.clone()));
+                Value::List(copy)
+            },
+            Value::Map(ref map) => {
+                let copy = Rc::new(RefCell::new(BTreeMap::new()));
+                copies.insert(address, Value::Map(copy.clone()));
+                let entries = map.borrow().clone();
+                let entries = entries.into_iter().map(|(key, value)| (key, self.copy(&value, copies))).collect();
+                *copy.borrow_mut() = entries;
+                self.track(&Value::Map(copy.clone()));
+                Value::Map(copy)
+            },
+            Value::Instance(ref instance) => {
+                let class = instance.borrow().class.clone();
+                let copy = Rc::new(RefCell::new(LoxInstance { class: class, fields: HashMap::new() }));
+                copies.insert(address, Value::Instance(copy.clone()));
+                let fields = instance.borrow().fields.clone();
+                let fields = fields.into_iter().map(|(name, value)| (name, self.copy(&value, copies))).collect();
+                copy.borrow_mut().fields = fields;
+                self.track(&Value::Instance(copy.clone()));
+                Value::Instance(copy)
+            },
+            _ => unreachable!()
+        }
     }
 
-    fn get<'a>(&self, name: &'a Token) -> Result<Literal, RuntimeError> {
-        for values in self.values.iter().rev() {
-            match values.get(&name.lexeme).cloned() {
-                Some(v) => return Ok(v),
-                None => ()
+    /// Every variable visible from the innermost scope, including shadowed ones, innermost
+    /// scope first and in the order they were declared within each.
+    pub fn bindings(&self) -> Vec<Binding> {
+        let mut bindings = Vec::new();
+        let mut environment = Some(self.environment.clone());
+        let mut depth = 0;
+        while let Some(scope) = environment {
+            let scope = scope.borrow();
+            for (name, value) in scope.names.iter().zip(&scope.values) {
+                bindings.push(Binding {
+                    name: name.clone(),
+                    value: value.clone().map(|value| self.stringify(value)),
+                    depth: depth,
+                    native: match *value {
+                        Some(Value::Callable(ref callable)) => callable.is_native(),
+                        _ => false
+                    }
+                });
             }
+            environment = scope.enclosing.clone();
+            depth += 1;
+        }
+        bindings
+    }
+
+    /// The calls in progress, outermost first.
+    pub fn call_stack(&self) -> &[Frame] {
+        &self.call_stack
+    }
+
+    /// The interner source run by this interpreter should be scanned with, so that its names and
+    /// strings are shared with those already running.
+    pub fn interner(&self) -> &Interner {
+        &self.interner
+    }
+
+    /// Has the cycle collector keep an eye on `value`. Natives that make lists or maps should
+    /// call this on them, or a cycle running through one will never be freed.
+    pub fn track(&mut self, value: &Value) {
+        self.heap.track(value);
+    }
+
+    /// Frees any cycles of objects the program can no longer reach, returning how many objects
+    /// were freed. This happens by itself every so often.
+    pub fn collect_garbage(&mut self) -> usize {
+        self.heap.collect()
+    }
+
+    pub fn gc_stats(&self) -> gc::Stats {
+        self.heap.stats()
+    }
+
+    /// Sets the command-line arguments scripts see through `args()`.
+    pub fn set_args(&mut self, args: Vec<String>) {
+        self.args = args;
+    }
+
+    pub fn args(&self) -> &Vec<String> {
+        &self.args
+    }
+
+    /// Records that the script called `exit()`. The native then returns an error so execution
+    /// unwinds back to `interpret`, running deferred expressions along the way.
+    pub fn request_exit(&mut self, code: i32) {
+        self.exit_code = Some(code);
+    }
+
+    /// The code the script asked to exit with, if it called `exit()`.
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    pub fn stringify(&self, value: Value) -> String {
+        self.stringify_nested(value, &mut Vec::new())
+    }
+
+    // `enclosing` holds the lists and maps currently being printed so one that contains itself
+    // prints as "[...]" or "{...}" instead of recursing forever.
+    fn stringify_nested(&self, value: Value, enclosing: &mut Vec<*const ()>) -> String {
+        match value {
+            Value::Nil => "nil".to_string(),
+            Value::Number(number) => format_number(number),
+            Value::String(value) => value.to_string(),
+            Value::Bool(value) => value.to_string(),
+            Value::List(list) => {
+                let pointer = Rc::as_ptr(&list) as *const ();
+                if enclosing.contains(&pointer) {
+                    return "[...]".to_string();
+                }
+
+                enclosing.push(pointer);
+                let values: Vec<String> = list.borrow().iter()
+                    .map(|value| self.stringify_nested(value.clone(), enclosing))
+                    .collect();
+                enclosing.pop();
+
+                format!("[{}]", values.join(", "))
+            },
+            Value::Map(map) => {
+                let pointer = Rc::as_ptr(&map) as *const ();
+                if enclosing.contains(&pointer) {
+                    return "{...}".to_string();
+                }
+
+                enclosing.push(pointer);
+                let entries: Vec<String> = map.borrow().iter()
+                    .map(|(key, value)| format!("{}: {}", key, self.stringify_nested(value.clone(), enclosing)))
+                    .collect();
+                enclosing.pop();
+
+                format!("{{{}}}", entries.join(", "))
+            },
+            Value::Class(class) => class.name.to_string(),
+            Value::Instance(instance) => format!("{} instance", instance.borrow().class.name),
+            Value::Callable(callable) => callable.describe()
+        }
+    }
+
+    fn execute<'a>(&mut self, stmt: &'a Stmt) -> Result<ControlFlow, RuntimeError> {
+        self.consume_fuel(stmt.span())?;
+        self.check_cancelled_at(stmt.span())?;
+        if self.trace.is_some() {
+            self.trace_line(stmt.span().line, &describe(stmt));
         }
+        if let Some(ref mut profile) = self.profile {
+            profile.count_line(stmt.span().line);
+        }
+        if let Some(ref mut coverage) = self.coverage {
+            coverage.hit(stmt.span().line);
+        }
+        if self.debugger.is_some() {
+            self.debug(stmt.span())?;
+        }
+        stmt.accept(self)
+    }
+
+    fn execute_block<'a>(&mut self, statements: &'a Vec<Stmt>, environment: Environment) -> Result<ControlFlow, RuntimeError> {
+        // Every scope is tracked, so that the values held in it count towards the memory limit.
+        let environment = Rc::new(RefCell::new(environment));
+        self.heap.track_environment(&environment);
+        let previous = mem::replace(&mut self.environment, environment);
+        let result = self.execute_statements(statements);
+        self.environment = previous;
 
-        Err(RuntimeError(name.clone(), format!("Undefined variable '{}'.", name.lexeme)))
+        result
     }
 
-    fn assign<'a>(&mut self, name: &'a Token, value: Literal) -> Result<(), RuntimeError> {
-        for values in self.values.iter_mut().rev() {
-            if values.contains_key(&name.lexeme) {
-                values.insert(name.lexeme.clone(), value);
-                return Ok(())
+    // Runs statements in the current scope, along with anything they defer. Anything but
+    // running off the end stops the statements early, though what they deferred still runs.
+    fn execute_statements<'a>(&mut self, statements: &'a Vec<Stmt>) -> Result<ControlFlow, RuntimeError> {
+        self.deferred.push(Vec::new());
+        let mut result = Ok(ControlFlow::Normal);
+        for statement in statements {
+            result = self.execute(statement);
+
+            match result {
+                Ok(ControlFlow::Normal) => (),
+                _ => break
             }
         }
 
-        Err(RuntimeError(name.clone(), format!("Undefined variable '{}'.", name.lexeme)))
+        let deferred_result = self.run_deferred();
+        let flow = result?;
+        deferred_result?;
+        Ok(flow)
     }
 
-    fn push(&mut self) {
-        self.values.push(HashMap::new());
+    fn evaluate_call<'a>(&mut self, expr: &'a Call) -> Result<(Value, Vec<Value>), RuntimeError> {
+        let callee = self.evaluate(&*expr.callee)?;
+
+        let mut arguments = Vec::new();
+
+        for argument in &expr.arguments {
+            arguments.push(self.evaluate(argument)?);
+        }
+
+        Ok((callee, arguments))
     }
 
-    fn pop(&mut self) {
-        self.values.pop();
+    fn call_value(&mut self, callee: Value, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+        match callee {
+            Value::Callable(ref callable) => {
+                if arguments.len() != callable.arity() {
+                    Err(RuntimeError::new(paren.clone(), ErrorKind::ArityMismatch {
+                        expected: callable.arity(),
+                        got: arguments.len()
+                    }))
+                } else {
+                    Ok(callable.call(self, paren, arguments)?)
+                }
+            },
+            Value::Class(ref class) => {
+                if arguments.len() != class.arity() {
+                    return Err(RuntimeError::new(paren.clone(), ErrorKind::ArityMismatch {
+                        expected: class.arity(),
+                        got: arguments.len()
+                    }));
+                }
+
+                self.allocate(size_of::<LoxInstance>(), paren)?;
+                let instance = Value::Instance(Rc::new(RefCell::new(LoxInstance {
+                    class: class.clone(),
+                    fields: HashMap::new()
+                })));
+                self.track(&instance);
+                if let Some(initializer) = class.bind("init", &instance) {
+                    self.call_function(&initializer, paren, arguments)?;
+                }
+                Ok(instance)
+            },
+            _ => Err(RuntimeError::new(paren.clone(), ErrorKind::TypeError("Can only call functions and classes.".to_string())))
+        }
     }
-}
 
-#[derive(Debug)]
-pub struct Clock;
+    /// Calls a function declared in a script from `paren`, the closing parenthesis of the call.
+    /// The body runs in a fresh scope inside the one the function was declared in, holding its
+    /// parameters and, for a method, `this` and `super`.
+    pub fn call_function(&mut self, function: &LoxFunction, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+        let mut result = self.run_function(function, paren, arguments);
+        // A call in tail position comes back here to be made once the call it's in is over,
+        // so that recursion standing in for a loop doesn't use up the stack.
+        while let Ok(ControlFlow::TailCall(callable, paren, arguments)) = result {
+            result = match callable.as_function() {
+                Some(function) => self.run_function(function, &paren, arguments),
+                None => callable.call(self, &paren, arguments).map(ControlFlow::Return)
+            };
+        }
 
-impl Callable for Clock {
-    fn call(&self, _: &mut Interpreter, _: Vec<Literal>) -> Result<Literal, RuntimeError> {
-        let durection = ::std::time::SystemTime::now().duration_since(::std::time::UNIX_EPOCH).unwrap();
-        Ok(Literal::Number(durection.as_secs() as f64 * 1000.0 + durection.subsec_nanos() as f64 / 1000.0))
+        match result {
+            Ok(ControlFlow::Return(value)) => Ok(value),
+            Ok(_) => Ok(Value::Nil),
+            Err(error) => Err(error)
+        }
     }
 
-    fn arity(&self) -> usize {
-        0
+    // Runs the function's body, finishing with either its return value or a call to make in
+    // its place.
+    fn run_function(&mut self, function: &LoxFunction, paren: &Token, arguments: Vec<Value>) -> Result<ControlFlow, RuntimeError> {
+        let mut environment = Environment::with_enclosing(function.closure.clone());
+        if let Some(ref this) = function.this {
+            environment.define(Arc::from("this"), this.clone());
+        }
+        if let Some(ref superclass) = function.superclass {
+            environment.define(Arc::from("super"), Value::Class(superclass.clone()));
+        }
+        for (param, argument) in function.declaration.params.iter().zip(arguments) {
+            environment.define(param.lexeme.clone(), argument);
+        }
+
+        if self.call_stack.len() >= self.max_call_depth || !stack_left() {
+            return Err(RuntimeError::new(paren.clone(), ErrorKind::StackOverflow));
+        }
+
+        self.call_stack.push(Frame {
+            function: function.declaration.name.lexeme.clone(),
+            line: paren.line
+        });
+
+        let call = self.profile.as_mut()
+            .map(|profile| profile.enter(function.declaration.name.lexeme.clone(), function.declaration.name.line));
+        let call_deferred = mem::replace(&mut self.call_deferred, self.deferred.len());
+        let result = self.execute_block(&function.declaration.body, environment);
+        self.call_deferred = call_deferred;
+        if let (Some(profile), Some(call)) = (self.profile.as_mut(), call) {
+            profile.exit(call);
+        }
+
+        let result = match result {
+            // An initializer always hands back the instance, even from a bare `return;`.
+            Ok(_) if function.is_initializer => Ok(ControlFlow::Return(function.this.clone().unwrap_or(Value::Nil))),
+            Ok(flow @ ControlFlow::Return(_)) | Ok(flow @ ControlFlow::TailCall(..)) => Ok(flow),
+            Ok(_) => Ok(ControlFlow::Return(Value::Nil)),
+            Err(mut error) => {
+                // The stack is only as deep as it'll get at the innermost call.
+                if error.2.is_empty() {
+                    error.2 = self.call_stack.clone();
+                }
+                Err(error)
+            }
+        };
+        self.call_stack.pop();
+        result
+    }
+
+    // Runs the innermost scope's deferred expressions, last deferred first. Every one of them
+    // runs even if an earlier one fails, and the first error is the one reported.
+    fn run_deferred(&mut self) -> Result<(), RuntimeError> {
+        let deferred = self.deferred.pop().unwrap_or_default();
+        let mut result = Ok(());
+        for expr in deferred.iter().rev() {
+            if let Err(error) = self.evaluate(expr) {
+                if result.is_ok() {
+                    result = Err(error);
+                }
+            }
+        }
+
+        result
+    }
+
+    // Names the resolver couldn't place are globals, unless eval() has since declared them in
+    // an enclosing scope.
+    fn get_unresolved(&self, name: &Token) -> Result<Value, RuntimeError> {
+        if self.globals.borrow().contains(&name.lexeme) {
+            return self.globals.borrow().get(name);
+        }
+
+        self.environment.borrow().get(name)
+    }
+
+    fn assign_unresolved(&mut self, name: &Token, value: Value) -> Result<(), RuntimeError> {
+        if self.globals.borrow().contains(&name.lexeme) {
+            return self.globals.borrow_mut().assign(name, value);
+        }
+
+        self.environment.borrow_mut().assign(name, value)
+    }
+
+    fn evaluate<'a>(&mut self, expr: &'a Expr) -> Result<Value, RuntimeError> {
+        // The error needs a token to point at, so a node without one is let through and the
+        // limit enforced by whichever expression inside it has one.
+        if self.expression_depth >= self.max_expression_depth || !stack_left() {
+            if let Some(token) = operator_or_name(expr) {
+                return Err(RuntimeError::new(token.clone(), ErrorKind::StackOverflow));
+            }
+        }
+
+        self.consume_fuel(expr.span())?;
+        self.check_cancelled_at(expr.span())?;
+        if self.trace.is_some() {
+            return self.evaluate_traced(expr);
+        }
+
+        self.expression_depth += 1;
+        let result = expr.accept(self);
+        self.expression_depth -= 1;
+        result
+    }
+
+    fn is_truthy<'a>(&self, literal: &'a Value) -> bool {
+        match literal {
+            &Value::Bool(bool) => bool,
+            &Value::Nil => false,
+            _ => true
+        }
+    }
+
+    fn cast_to_float<'a>(&self, literal: Value, operator: &'a Token) -> Result<f64, RuntimeError> {
+        match literal {
+            Value::Number(number) => Ok(number),
+            _ => Err(RuntimeError::new(operator.clone(), ErrorKind::TypeError("Operand must be a numbers".to_string())))
+        }
+    }
+
+    // `==` never fails: values of different types are just unequal, and anything with identity
+    // is only equal to itself.
+    fn is_equal(&self, left: Value, right: Value) -> bool {
+        left == right
+    }
+}
+
+impl ExprVisitor<Result<Value, RuntimeError>> for Interpreter {
+    fn visit_binary<'a>(&mut self, binary: &'a Binary) -> Result<Value, RuntimeError> {
+        let left = self.evaluate(&*binary.left)?;
+        let right = self.evaluate(&*binary.right)?;
+
+        Ok(match binary.operator.token_type {
+            TokenType::Minus => Value::Number(self.cast_to_float(left, &binary.operator)? - self.cast_to_float(right, &binary.operator)?),
+            TokenType::Slash => Value::Number(self.cast_to_float(left, &binary.operator)? / self.cast_to_float(right, &binary.operator)?),
+            TokenType::Star => Value::Number(self.cast_to_float(left, &binary.operator)? * self.cast_to_float(right, &binary.operator)?),
+            TokenType::Plus => {
+                match (left, right) {
+                    (Value::String(left), Value::String(right)) => {
+                        self.allocate(left.len() + right.len(), &binary.operator)?;
+                        Value::String(format!("{}{}", left, right).into())
+                    },
+                    (left, right) => Value::Number(self.cast_to_float(left, &binary.operator)? + self.cast_to_float(right, &binary.operator)?)
+                }
+            },
+            TokenType::Greater => Value::Bool(self.cast_to_float(left, &binary.operator)? > self.cast_to_float(right, &binary.operator)?),
+            TokenType::GreaterEqual => Value::Bool(self.cast_to_float(left, &binary.operator)? >= self.cast_to_float(right, &binary.operator)?),
+            TokenType::Less => Value::Bool(self.cast_to_float(left, &binary.operator)? < self.cast_to_float(right, &binary.operator)?),
+            TokenType::LessEqual => Value::Bool(self.cast_to_float(left, &binary.operator)? <= self.cast_to_float(right, &binary.operator)?),
+            TokenType::BangEqual => Value::Bool(!self.is_equal(left, right)),
+            TokenType::EqualEqual => Value::Bool(self.is_equal(left, right)),
+            TokenType::Is => match right {
+                // Only instances belong to a class.
+                Value::Class(ref class) => Value::Bool(match left {
+                    Value::Instance(ref instance) => instance.borrow().class.is_subclass_of(class),
+                    _ => false
+                }),
+                _ => return Err(RuntimeError::new(binary.operator.clone(), ErrorKind::TypeError("Right operand of 'is' must be a class.".to_string())))
+            },
+            _ => unreachable!()
+        })
+    }
+
+    fn visit_call<'a>(&mut self, expr: &'a Call) -> Result<Value, RuntimeError> {
+        let (callee, arguments) = self.evaluate_call(expr)?;
+        self.call_value(callee, &expr.paren, arguments)
+    }
+
+    fn visit_grouping<'a>(&mut self, grouping: &'a Grouping) -> Result<Value, RuntimeError> {
+        self.evaluate(&*grouping.expression)
+    }
+
+    fn visit_literal<'a>(&mut self, literal: &'a Literal) -> Result<Value, RuntimeError> {
+        Ok(Value::from(literal))
+    }
+
+    fn visit_unary<'a>(&mut self, unary: &'a Unary) -> Result<Value, RuntimeError> {
+        let right = self.evaluate(&*unary.right)?;
+
+        Ok(match unary.operator.token_type {
+            TokenType::Minus => Value::Number(-self.cast_to_float(right, &unary.operator)?),
+            TokenType::Bang => Value::Bool(self.is_truthy(&right)),
+            _ => unreachable!()
+        })
+    }
+
+    fn visit_variable<'a>(&mut self, variable: &'a Variable) -> Result<Value, RuntimeError> {
+        match variable.slot {
+            Some(slot) => self.environment.borrow().get_at(slot, &variable.name),
+            None => self.get_unresolved(&variable.name)
+        }
+    }
+
+    fn visit_assign<'a>(&mut self, assign: &'a Assign) -> Result<Value, RuntimeError> {
+        let value = self.evaluate(&*assign.value)?;
+
+        // A variable that's never been assigned has no old value, rather than being an error.
+        let old = if self.watching(&assign.name.lexeme) {
+            let old = match assign.slot {
+                Some(slot) => self.environment.borrow().get_at(slot, &assign.name),
+                None => self.get_unresolved(&assign.name)
+            };
+            Some(old.ok())
+        } else {
+            None
+        };
+
+        match assign.slot {
+            Some(slot) => self.environment.borrow_mut().assign_at(slot, &assign.name, value.clone())?,
+            None => self.assign_unresolved(&assign.name, value.clone())?
+        }
+
+        if let Some(old) = old {
+            self.watched(&assign.name, old, Some(value.clone()))?;
+        }
+        Ok(value)
+    }
+
+    fn visit_logical<'a>(&mut self, logical: &'a Logical) -> Result<Value, RuntimeError> {
+        let left = self.evaluate(&*logical.left)?;
+
+        Ok(match logical.operator.token_type {
+            TokenType::Or if self.is_truthy(&left) => left,
+            TokenType::And if !self.is_truthy(&left) => left,
+            _ => self.evaluate(&*logical.right)?
+        })
+    }
+
+    fn visit_get<'a>(&mut self, get: &'a Get) -> Result<Value, RuntimeError> {
+        let object = self.evaluate(&*get.object)?;
+
+        let instance = match object {
+            Value::Instance(ref instance) => instance,
+            _ => return Err(RuntimeError::new(get.name.clone(), ErrorKind::TypeError("Only instances have properties.".to_string())))
+        };
+
+        // Fields shadow methods of the same name.
+        if let Some(value) = instance.borrow().fields.get(&get.name.lexeme) {
+            return Ok(value.clone());
+        }
+
+        let class = instance.borrow().class.clone();
+        match class.bind(&get.name.lexeme, &object) {
+            Some(method) => {
+                let method = Value::Callable(Rc::new(method));
+                self.track(&method);
+                Ok(method)
+            },
+            None => Err(RuntimeError::new(get.name.clone(), ErrorKind::UndefinedProperty(get.name.lexeme.clone())))
+        }
+    }
+
+    fn visit_set<'a>(&mut self, set: &'a Set) -> Result<Value, RuntimeError> {
+        let object = self.evaluate(&*set.object)?;
+
+        let instance = match object {
+            Value::Instance(instance) => instance,
+            _ => return Err(RuntimeError::new(set.name.clone(), ErrorKind::TypeError("Only instances have fields.".to_string())))
+        };
+
+        let value = self.evaluate(&*set.value)?;
+        if !instance.borrow().fields.contains_key(&set.name.lexeme) {
+            self.allocate(size_of::<Arc<str>>() + size_of::<Value>(), &set.name)?;
+        }
+        instance.borrow_mut().fields.insert(set.name.lexeme.clone(), value.clone());
+        Ok(value)
+    }
+
+    fn visit_this<'a>(&mut self, this: &'a This) -> Result<Value, RuntimeError> {
+        match this.slot {
+            Some(slot) => self.environment.borrow().get_at(slot, &this.keyword),
+            None => self.get_unresolved(&this.keyword)
+        }
+    }
+
+    fn visit_super<'a>(&mut self, expr: &'a Super) -> Result<Value, RuntimeError> {
+        // `this` lives in the same scope as `super`, in the slot before it, so look it up through
+        // a token named for it.
+        let mut this_token = expr.keyword.clone();
+        this_token.lexeme = Arc::from("this");
+
+        let (superclass, this) = match expr.slot {
+            Some(slot) => {
+                let this_slot = Slot { depth: slot.depth, index: slot.index.saturating_sub(1) };
+                (self.environment.borrow().get_at(slot, &expr.keyword)?, self.environment.borrow().get_at(this_slot, &this_token)?)
+            },
+            None => (self.get_unresolved(&expr.keyword)?, self.get_unresolved(&this_token)?)
+        };
+
+        let method = match superclass {
+            Value::Class(ref class) => class.bind(&expr.method.lexeme, &this),
+            _ => None
+        };
+        match method {
+            Some(method) => {
+                let method = Value::Callable(Rc::new(method));
+                self.track(&method);
+                Ok(method)
+            },
+            None => Err(RuntimeError::new(expr.method.clone(), ErrorKind::UndefinedProperty(expr.method.lexeme.clone())))
+        }
+    }
+
+    fn visit_error<'a>(&mut self, error: &'a ErrorNode) -> Result<Value, RuntimeError> {
+        Err(syntax_error(error))
+    }
+}
+
+impl StmtVisitor<Result<ControlFlow, RuntimeError>> for Interpreter {
+    fn visit_defer<'a>(&mut self, defer: &'a Arc<Expr>) -> Result<ControlFlow, RuntimeError> {
+        self.deferred.last_mut().unwrap().push(defer.clone());
+        Ok(ControlFlow::Normal)
+    }
+
+    fn visit_expr<'a>(&mut self, expr: &'a Expr) -> Result<ControlFlow, RuntimeError> {
+        self.evaluate(expr)?;
+        Ok(ControlFlow::Normal)
+    }
+
+    fn visit_function<'a>(&mut self, function: &'a Arc<Function>) -> Result<ControlFlow, RuntimeError> {
+        let callable = Value::Callable(Rc::new(LoxFunction {
+            declaration: function.clone(),
+            closure: self.environment.clone(),
+            this: None,
+            superclass: None,
+            is_initializer: false
+        }));
+        self.track(&callable);
+
+        self.environment.borrow_mut().define(function.name.lexeme.clone(), callable.clone());
+        if self.watching(&function.name.lexeme) {
+            self.watched(&function.name, None, Some(callable))?;
+        }
+        Ok(ControlFlow::Normal)
+    }
+
+    fn visit_class<'a>(&mut self, class: &'a Class) -> Result<ControlFlow, RuntimeError> {
+        let superclass = match class.superclass {
+            Some(ref superclass) => match self.visit_variable(superclass)? {
+                Value::Class(superclass) => Some(superclass),
+                _ => return Err(RuntimeError::new(superclass.name.clone(), ErrorKind::TypeError("Superclass must be a class.".to_string())))
+            },
+            None => None
+        };
+
+        let mut mixins = Vec::new();
+        for mixin in &class.mixins {
+            match self.visit_variable(mixin)? {
+                Value::Class(mixin) => mixins.push(mixin),
+                _ => return Err(RuntimeError::new(mixin.name.clone(), ErrorKind::TypeError("Mixin must be a class.".to_string())))
+            }
+        }
+
+        let methods = class.methods.iter()
+            .map(|method| (method.name.lexeme.clone(), method.clone()))
+            .collect();
+
+        let value = Value::Class(Rc::new(LoxClass {
+            name: class.name.lexeme.clone(),
+            superclass: superclass,
+            mixins: mixins,
+            methods: methods,
+            closure: self.environment.clone()
+        }));
+        self.track(&value);
+
+        self.environment.borrow_mut().define(class.name.lexeme.clone(), value.clone());
+        if self.watching(&class.name.lexeme) {
+            self.watched(&class.name, None, Some(value))?;
+        }
+        Ok(ControlFlow::Normal)
+    }
+
+    fn visit_return<'a>(&mut self, stmt: &'a Return) -> Result<ControlFlow, RuntimeError> {
+        // Calling a Lox function is left to `call_function`, unless something still has to run
+        // after it: an expression deferred in this call, or a line of the trace.
+        if let Some(Expr::Call(ref call)) = stmt.value {
+            if self.trace.is_none() && self.deferred[self.call_deferred..].iter().all(Vec::is_empty) {
+                self.consume_fuel(call.span)?;
+                self.check_cancelled_at(call.span)?;
+                let (callee, arguments) = self.evaluate_call(call)?;
+                if let Value::Callable(ref callable) = callee {
+                    if callable.as_function().is_some() && callable.arity() == arguments.len() {
+                        return Ok(ControlFlow::TailCall(callable.clone(), call.paren.clone(), arguments));
+                    }
+                }
+                return Ok(ControlFlow::Return(self.call_value(callee, &call.paren, arguments)?));
+            }
+        }
+
+        let value = match stmt.value {
+            Some(ref value) => self.evaluate(value)?,
+            None => Value::Nil
+        };
+
+        Ok(ControlFlow::Return(value))
+    }
+
+    fn visit_print<'a>(&mut self, print: &'a Expr) -> Result<ControlFlow, RuntimeError> {
+        let result = self.evaluate(print)?;
+        let text = format!("{}\n", self.stringify(result));
+        self.write_output(&text)
+            .map_err(|error| RuntimeError::new(Token::at(print.span()), ErrorKind::Io(format!("Could not write output: {}.", error))))?;
+        Ok(ControlFlow::Normal)
+    }
+
+    fn visit_var<'a>(&mut self, stmt: &'a Var) -> Result<ControlFlow, RuntimeError> {
+        let value = match stmt.initializer {
+            Some(ref initializer) => Some(self.evaluate(initializer)?),
+            None => None
+        };
+        match value {
+            Some(ref value) => self.environment.borrow_mut().define(stmt.name.lexeme.clone(), value.clone()),
+            None => self.environment.borrow_mut().declare(stmt.name.lexeme.clone())
+        }
+
+        if self.watching(&stmt.name.lexeme) {
+            self.watched(&stmt.name, None, value)?;
+        }
+        Ok(ControlFlow::Normal)
+    }
+
+    fn visit_block<'a>(&mut self, block: &'a Block) -> Result<ControlFlow, RuntimeError> {
+        let environment = Environment::with_enclosing(self.environment.clone());
+        self.execute_block(&block.statements, environment)
+    }
+
+    fn visit_if<'a>(&mut self, if_statement: &'a If) -> Result<ControlFlow, RuntimeError> {
+        let value = self.evaluate(&if_statement.condition)?;
+        if self.is_truthy(&value) {
+            self.execute(&*if_statement.then_branch)
+        } else if let Some(ref else_branch) = if_statement.else_branch {
+            self.execute(else_branch)
+        } else {
+            Ok(ControlFlow::Normal)
+        }
+    }
+
+    fn visit_while<'a>(&mut self, while_statement: &'a While) -> Result<ControlFlow, RuntimeError> {
+        loop {
+            let value = self.evaluate(&while_statement.condition)?;
+
+            if !self.is_truthy(&value) {
+                break;
+            }
+
+            match self.execute(&while_statement.body)? {
+                ControlFlow::Normal | ControlFlow::Continue => (),
+                ControlFlow::Break => break,
+                flow @ ControlFlow::Return(_) | flow @ ControlFlow::TailCall(..) => return Ok(flow)
+            }
+        }
+
+        Ok(ControlFlow::Normal)
+    }
+
+    fn visit_error<'a>(&mut self, error: &'a ErrorNode) -> Result<ControlFlow, RuntimeError> {
+        Err(syntax_error(error))
+    }
+}
+
+// Top-level functions and classes hold on to the globals they're declared in, so the globals
+// have to be let go of and collected for any of them to be freed.
+impl Drop for Interpreter {
+    fn drop(&mut self) {
+        self.globals = Rc::new(RefCell::new(Environment::new()));
+        self.environment = self.globals.clone();
+        self.heap.collect();
+    }
+}
+
+/// Stops a script from another thread, say a host's timeout or a Ctrl-C handler. The script
+/// fails with an error at the next statement or expression it gets to, deferred ones included,
+/// and the interpreter is ready for the next one afterwards.
+#[derive(Debug, Clone)]
+pub struct CancelHandle {
+    cancelled: Arc<AtomicBool>
+}
+
+impl CancelHandle {
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+/// How a statement finished, which tells whatever is running it whether to carry on with the
+/// next one.
+#[derive(Debug)]
+pub enum ControlFlow {
+    Normal,
+    Break,
+    Continue,
+    Return(Value),
+    /// A return of what calling the function with the arguments gives back, with the call
+    /// still to be made.
+    TailCall(Rc<dyn Callable>, Token, Vec<Value>)
+}
+
+/// Anything a Lox expression can evaluate to. Cloning one is cheap: everything with identity
+/// is shared through an Rc, and strings through an Arc, since they're shared with the program
+/// text too.
+#[derive(Debug, Clone)]
+pub enum Value {
+    Callable(Rc<dyn Callable>),
+    Class(Rc<LoxClass>),
+    Instance(Rc<RefCell<LoxInstance>>),
+    List(Rc<RefCell<Vec<Value>>>),
+    Map(Rc<RefCell<BTreeMap<String, Value>>>),
+    String(Arc<str>),
+    Number(f64),
+    Bool(bool),
+    Nil
+}
+
+impl<'a> From<&'a Literal> for Value {
+    fn from(literal: &'a Literal) -> Self {
+        match *literal {
+            Literal::String(ref string) => Value::String(string.clone()),
+            Literal::Number(number) => Value::Number(number),
+            Literal::Bool(value) => Value::Bool(value),
+            Literal::Nil => Value::Nil
+        }
+    }
+}
+
+impl From<f64> for Value {
+    fn from(number: f64) -> Self {
+        Value::Number(number)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+impl<'a> From<&'a str> for Value {
+    fn from(string: &'a str) -> Self {
+        Value::String(Arc::from(string))
+    }
+}
+
+impl From<String> for Value {
+    fn from(string: String) -> Self {
+        Value::String(Arc::from(string))
+    }
+}
+
+impl From<Arc<str>> for Value {
+    fn from(string: Arc<str>) -> Self {
+        Value::String(string)
+    }
+}
+
+/// Makes a new list. It isn't known to the cycle collector, so one that a script might make
+/// part of a cycle should be passed to `Interpreter::track`.
+impl From<Vec<Value>> for Value {
+    fn from(values: Vec<Value>) -> Self {
+        Value::List(Rc::new(RefCell::new(values)))
+    }
+}
+
+/// Makes a new map. Like a list made from a `Vec`, it isn't known to the cycle collector.
+impl From<BTreeMap<String, Value>> for Value {
+    fn from(entries: BTreeMap<String, Value>) -> Self {
+        Value::Map(Rc::new(RefCell::new(entries)))
+    }
+}
+
+/// None becomes nil.
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Value::Nil, Into::into)
+    }
+}
+
+// Taking values apart fails with the type error a native would give back, so that one can
+// turn it into a runtime error at its call.
+impl TryFrom<Value> for f64 {
+    type Error = ErrorKind;
+
+    fn try_from(value: Value) -> Result<Self, ErrorKind> {
+        match value {
+            Value::Number(number) => Ok(number),
+            _ => Err(ErrorKind::TypeError("Expected a number.".to_string()))
+        }
+    }
+}
+
+impl TryFrom<Value> for bool {
+    type Error = ErrorKind;
+
+    fn try_from(value: Value) -> Result<Self, ErrorKind> {
+        match value {
+            Value::Bool(value) => Ok(value),
+            _ => Err(ErrorKind::TypeError("Expected a boolean.".to_string()))
+        }
+    }
+}
+
+impl TryFrom<Value> for String {
+    type Error = ErrorKind;
+
+    fn try_from(value: Value) -> Result<Self, ErrorKind> {
+        match value {
+            Value::String(string) => Ok(string.to_string()),
+            _ => Err(ErrorKind::TypeError("Expected a string.".to_string()))
+        }
+    }
+}
+
+/// Copies the list's elements out of it. The elements themselves are shared, not copied.
+impl TryFrom<Value> for Vec<Value> {
+    type Error = ErrorKind;
+
+    fn try_from(value: Value) -> Result<Self, ErrorKind> {
+        match value {
+            Value::List(list) => Ok(list.borrow().clone()),
+            _ => Err(ErrorKind::TypeError("Expected a list.".to_string()))
+        }
+    }
+}
+
+/// Copies the map's entries out of it, sharing their values as a list does.
+impl TryFrom<Value> for BTreeMap<String, Value> {
+    type Error = ErrorKind;
+
+    fn try_from(value: Value) -> Result<Self, ErrorKind> {
+        match value {
+            Value::Map(map) => Ok(map.borrow().clone()),
+            _ => Err(ErrorKind::TypeError("Expected a map.".to_string()))
+        }
+    }
+}
+
+// Strings, numbers, booleans and nil are equal when they hold the same thing. Everything else
+// is only equal to itself, so two functions are equal only if they're the same closure: each
+// access to a method binds a new one.
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (&Value::String(ref string1), &Value::String(ref string2)) => intern::same(string1, string2),
+            (&Value::Number(ref number1), &Value::Number(ref number2)) => number1 == number2,
+            (&Value::Bool(ref bool1), &Value::Bool(ref bool2)) => bool1 == bool2,
+            (&Value::Nil, &Value::Nil) => true,
+            // Compared by address alone, since the same function can be seen through different
+            // vtables.
+            (&Value::Callable(ref callable1), &Value::Callable(ref callable2)) => {
+                Rc::as_ptr(callable1) as *const () == Rc::as_ptr(callable2) as *const ()
+            },
+            (&Value::List(ref list1), &Value::List(ref list2)) => Rc::ptr_eq(list1, list2),
+            (&Value::Map(ref map1), &Value::Map(ref map2)) => Rc::ptr_eq(map1, map2),
+            (&Value::Class(ref class1), &Value::Class(ref class2)) => Rc::ptr_eq(class1, class2),
+            (&Value::Instance(ref instance1), &Value::Instance(ref instance2)) => Rc::ptr_eq(instance1, instance2),
+            _ => false
+        }
+    }
+}
+
+pub trait Callable: ::std::fmt::Debug {
+    fn call(&self, interpreter: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError>;
+    fn arity(&self) -> usize;
+
+    /// How the callable prints, as in `<fn add>`.
+    fn describe(&self) -> String {
+        "<native fn>".to_string()
+    }
+
+    /// Whether the callable is written in Rust rather than Lox.
+    fn is_native(&self) -> bool {
+        true
+    }
+
+    /// Hands the cycle collector whatever the callable holds on to.
+    fn trace(&self, _: &mut Tracer) {}
+
+    /// The function, if the callable is one written in Lox.
+    fn as_function(&self) -> Option<&LoxFunction> {
+        None
+    }
+}
+
+/// The globals as they were at some point, for `Interpreter::restore`.
+pub struct Snapshot {
+    names: Vec<Arc<str>>,
+    values: Vec<Option<Value>>
+}
+
+/// A variable as `Interpreter::bindings` lists it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Binding {
+    pub name: Arc<str>,
+    /// The value as `print` would show it, or None if it hasn't been assigned one yet.
+    pub value: Option<String>,
+    /// How many scopes out from the innermost it's in.
+    pub depth: usize,
+    /// Whether it holds a native function, like those of the standard library.
+    pub native: bool
+}
+
+/// A function call in progress: the function's name and the line it was called from.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub function: Arc<str>,
+    pub line: i32
+}
+
+/// What natives may reach outside the interpreter on a script's behalf. Untrusted code can be
+/// run with only what it needs turned on; a native needing anything else fails when called.
+/// By default everything but the filesystem is allowed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InterpreterConfig {
+    /// `readFile()` and `writeFile()`.
+    pub filesystem: bool,
+    /// `readLine()`.
+    pub stdin: bool,
+    /// `getenv()` and `args()`.
+    pub environment: bool,
+    /// `clock()`, `clockMillis()` and `sleep()`.
+    pub time: bool,
+    /// `random()`, which is seeded from the time unless `randomSeed()` is called first.
+    pub random: bool
+}
+
+impl InterpreterConfig {
+    /// Allows everything, for scripts trusted as much as the program running them.
+    pub fn trusted() -> Self {
+        InterpreterConfig {
+            filesystem: true,
+            stdin: true,
+            environment: true,
+            time: true,
+            random: true
+        }
+    }
+
+    /// Allows nothing outside the interpreter. Scripts can still print.
+    pub fn sandboxed() -> Self {
+        InterpreterConfig {
+            filesystem: false,
+            stdin: false,
+            environment: false,
+            time: false,
+            random: false
+        }
+    }
+}
+
+impl Default for InterpreterConfig {
+    fn default() -> Self {
+        InterpreterConfig { filesystem: false, ..InterpreterConfig::trusted() }
+    }
+}
+
+/// What went wrong. Displaying one gives the message shown to the user.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorKind {
+    /// A value of the wrong type was operated on, called or passed to a native function.
+    TypeError(String),
+    UndefinedVariable { name: Arc<str>, suggestion: Option<Arc<str>> },
+    /// A variable declared without an initializer was read before being assigned.
+    UnassignedVariable(Arc<str>),
+    UndefinedProperty(Arc<str>),
+    ArityMismatch { expected: usize, got: usize },
+    IndexOutOfBounds(f64),
+    /// An argument of the right type that the native function still can't work with, like an
+    /// empty list to take an element from.
+    InvalidArgument(String),
+    /// Reading or writing a file or the console failed.
+    Io(String),
+    /// A native needs something the interpreter's config doesn't allow, like file access.
+    Disabled(String),
+    /// A native needs something the platform the interpreter was built for doesn't have, like
+    /// threads to sleep on under wasm32.
+    Unsupported(String),
+    /// The source passed to `eval` doesn't compile; holds the first diagnostic.
+    InvalidSource(String),
+    /// The tree being run has a syntax error in it.
+    SyntaxError,
+    StackOverflow,
+    BudgetExceeded,
+    MemoryLimitExceeded,
+    Cancelled,
+    /// The script called `exit()`, with the code now in `Interpreter::exit_code`.
+    Exit
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match *self {
+            ErrorKind::TypeError(ref message) => write!(f, "{}", message),
+            ErrorKind::UndefinedVariable { ref name, suggestion: Some(ref suggestion) } => {
+                write!(f, "Undefined variable '{}'. Did you mean '{}'?", name, suggestion)
+            },
+            ErrorKind::UndefinedVariable { ref name, suggestion: None } => write!(f, "Undefined variable '{}'.", name),
+            ErrorKind::UnassignedVariable(ref name) => {
+                write!(f, "Variable '{}' is read before it has been assigned a value.", name)
+            },
+            ErrorKind::UndefinedProperty(ref name) => write!(f, "Undefined property '{}'.", name),
+            ErrorKind::ArityMismatch { expected, got } => write!(f, "Expected {} arguments but got {}.", expected, got),
+            ErrorKind::IndexOutOfBounds(index) => write!(f, "Index {} is out of bounds.", index),
+            ErrorKind::InvalidArgument(ref message) => write!(f, "{}", message),
+            ErrorKind::Io(ref message) => write!(f, "{}", message),
+            ErrorKind::Disabled(ref what) => write!(f, "{} is disabled.", what),
+            ErrorKind::Unsupported(ref what) => write!(f, "{} isn't supported on this platform.", what),
+            ErrorKind::InvalidSource(ref diagnostic) => write!(f, "Could not evaluate source: {}", diagnostic),
+            ErrorKind::SyntaxError => write!(f, "Can't run code containing a syntax error."),
+            ErrorKind::StackOverflow => write!(f, "Stack overflow."),
+            ErrorKind::BudgetExceeded => write!(f, "Script exceeded execution budget."),
+            ErrorKind::MemoryLimitExceeded => write!(f, "Memory limit exceeded."),
+            ErrorKind::Cancelled => write!(f, "Script was cancelled."),
+            ErrorKind::Exit => write!(f, "Exit requested.")
+        }
+    }
+}
+
+/// The token an error happened at, what went wrong and the calls it happened inside, outermost
+/// first. The calls are filled in as the error unwinds out of the first of them.
+#[derive(Debug)]
+pub struct RuntimeError(pub Token, pub ErrorKind, pub Vec<Frame>);
+
+impl RuntimeError {
+    pub fn new(token: Token, kind: ErrorKind) -> Self {
+        RuntimeError(token, kind, Vec::new())
+    }
+
+    /// Where the error happened, innermost call first, as in `[line 3] in add()`. An error
+    /// outside of any function is just `[line 3]`. Runs of the same call, as deep recursion
+    /// leaves, are shown once with a count of the rest.
+    pub fn stack_trace(&self) -> Vec<String> {
+        let RuntimeError(ref token, _, ref frames) = *self;
+        if frames.is_empty() {
+            return vec![format!("[line {}]", token.line)];
+        }
+
+        let mut trace = Vec::new();
+        let mut repeated = 0;
+        let mut line = token.line;
+        for frame in frames.iter().rev() {
+            let location = format!("[line {}] in {}()", line, frame.function);
+            if trace.last() == Some(&location) {
+                repeated += 1;
+            } else {
+                push_repeated(&mut trace, repeated);
+                repeated = 0;
+                trace.push(location);
+            }
+            line = frame.line;
+        }
+        push_repeated(&mut trace, repeated);
+        trace.push(format!("[line {}] in script", line));
+        trace
+    }
+}
+
+fn push_repeated(trace: &mut Vec<String>, repeated: usize) {
+    if repeated > 0 {
+        trace.push(format!("[{} more of the same]", repeated));
+    }
+}
+
+impl Error for RuntimeError {
+    fn description(&self) -> &str {
+        "A runtime error occurred"
+    }
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "A runtime error occurred: {}", self.1)?;
+        for location in self.stack_trace() {
+            write!(f, "\n{}", location)?;
+        }
+        Ok(())
+    }
+}
+
+// A variable declared without an initializer holds None until it's first assigned, so reading
+// it early is an error rather than a silent nil.
+//
+// Values sit in slots in the order they were declared, which is how the resolver finds locals.
+// eval() can declare more at runtime and throw the order off, so a slot's name is checked
+// before it's trusted.
+pub struct Environment {
+    names: Vec<Arc<str>>,
+    values: Vec<Option<Value>>,
+    // Only the globals are indexed by name; they can be redefined and are only ever looked up
+    // by name.
+    index: Option<HashMap<Arc<str>, usize>>,
+    enclosing: Option<Rc<RefCell<Environment>>>
+}
+
+impl Environment {
+    fn new() -> Self {
+        Environment {
+            names: Vec::new(),
+            values: Vec::new(),
+            index: Some(HashMap::new()),
+            enclosing: None
+        }
+    }
+
+    fn with_enclosing(enclosing: Rc<RefCell<Environment>>) -> Self {
+        Environment {
+            names: Vec::new(),
+            values: Vec::new(),
+            index: None,
+            enclosing: Some(enclosing)
+        }
+    }
+
+    /// The scope this one is nested in, or None for the globals.
+    pub fn enclosing(&self) -> Option<Rc<RefCell<Environment>>> {
+        self.enclosing.clone()
+    }
+
+    /// The names declared directly in this scope, in the order they were declared.
+    pub fn declared_names(&self) -> Vec<Arc<str>> {
+        self.names.clone()
+    }
+
+    /// The value of `name` if it's declared directly in this scope and has been assigned one.
+    pub fn value(&self, name: &str) -> Option<Value> {
+        self.position(name).and_then(|slot| self.values[slot].clone())
+    }
+
+    fn define(&mut self, name: Arc<str>, value: Value) {
+        self.insert(name, Some(value));
+    }
+
+    fn declare(&mut self, name: Arc<str>) {
+        self.insert(name, None);
+    }
+
+    fn insert(&mut self, name: Arc<str>, value: Option<Value>) {
+        if let Some(ref mut index) = self.index {
+            if let Some(&slot) = index.get(&name) {
+                self.values[slot] = value;
+                return;
+            }
+            index.insert(name.clone(), self.values.len());
+        }
+
+        self.names.push(name);
+        self.values.push(value);
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        match self.index {
+            Some(ref index) => index.get(name).cloned(),
+            None => self.names.iter().rposition(|declared| &declared[..] == name)
+        }
+    }
+
+    fn contains(&self, name: &str) -> bool {
+        self.position(name).is_some()
+    }
+
+    // The slot the resolver worked out, provided it still holds the name.
+    fn checked_position(&self, index: usize, name: &Arc<str>) -> Option<usize> {
+        match self.names.get(index) {
+            Some(declared) if intern::same(declared, name) => Some(index),
+            _ => self.position(name)
+        }
+    }
+
+    // Searches outward from this scope, for names the resolver left to be found at runtime.
+    fn get<'a>(&self, name: &'a Token) -> Result<Value, RuntimeError> {
+        match self.position(&name.lexeme) {
+            Some(slot) => self.initialized(name, &self.values[slot]),
+            None => match self.enclosing {
+                Some(ref enclosing) => enclosing.borrow().get(name),
+                None => Err(self.undefined_variable(name))
+            }
+        }
+    }
+
+    // Looks in the slot the resolver worked out, `slot.depth` scopes out from this one.
+    fn get_at(&self, slot: Slot, name: &Token) -> Result<Value, RuntimeError> {
+        if slot.depth > 0 {
+            return match self.enclosing {
+                Some(ref enclosing) => enclosing.borrow().get_at(Slot { depth: slot.depth - 1, index: slot.index }, name),
+                None => Err(self.undefined_variable(name))
+            };
+        }
+
+        match self.checked_position(slot.index, &name.lexeme) {
+            Some(index) => self.initialized(name, &self.values[index]),
+            None => Err(self.undefined_variable(name))
+        }
+    }
+
+    fn initialized(&self, name: &Token, value: &Option<Value>) -> Result<Value, RuntimeError> {
+        match *value {
+            Some(ref value) => Ok(value.clone()),
+            None => Err(RuntimeError::new(name.clone(), ErrorKind::UnassignedVariable(name.lexeme.clone())))
+        }
+    }
+
+    fn assign_at(&mut self, slot: Slot, name: &Token, value: Value) -> Result<(), RuntimeError> {
+        if slot.depth > 0 {
+            return match self.enclosing {
+                Some(ref enclosing) => enclosing.borrow_mut().assign_at(Slot { depth: slot.depth - 1, index: slot.index }, name, value),
+                None => Err(self.undefined_variable(name))
+            };
+        }
+
+        match self.checked_position(slot.index, &name.lexeme) {
+            Some(index) => {
+                self.values[index] = Some(value);
+                Ok(())
+            },
+            None => Err(self.undefined_variable(name))
+        }
+    }
+
+    fn assign<'a>(&mut self, name: &'a Token, value: Value) -> Result<(), RuntimeError> {
+        if let Some(index) = self.position(&name.lexeme) {
+            self.values[index] = Some(value);
+            return Ok(());
+        }
+
+        match self.enclosing {
+            Some(ref enclosing) => enclosing.borrow_mut().assign(name, value),
+            None => Err(self.undefined_variable(name))
+        }
+    }
+
+    fn undefined_variable(&self, name: &Token) -> RuntimeError {
+        let names = self.names();
+
+        let suggestion = suggest::closest(&name.lexeme, names.iter().map(|key| &key[..]));
+        RuntimeError::new(name.clone(), ErrorKind::UndefinedVariable {
+            name: name.lexeme.clone(),
+            suggestion: suggestion.map(Arc::from)
+        })
+    }
+
+    // Every name visible from this scope, innermost first.
+    fn names(&self) -> Vec<Arc<str>> {
+        let mut names = self.names.clone();
+        if let Some(ref enclosing) = self.enclosing {
+            names.extend(enclosing.borrow().names());
+        }
+        names
+    }
+}
+
+// Values can hold closures over the very scope being printed, so only the names are shown.
+impl ::std::fmt::Debug for Environment {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("Environment")
+            .field("names", &self.names)
+            .field("enclosing", &self.enclosing)
+            .finish()
+    }
+}
+
+impl Trace for Environment {
+    fn trace(&self, tracer: &mut Tracer) {
+        for value in self.values.iter().filter_map(Option::as_ref) {
+            tracer.value(value);
+        }
+        if let Some(ref enclosing) = self.enclosing {
+            tracer.environment(enclosing);
+        }
+    }
+
+    fn size(&self) -> usize {
+        size_of::<Environment>() + self.names.len() * size_of::<Arc<str>>()
+            + self.values.iter().map(|value| value.as_ref().map_or(size_of::<Option<Value>>(), gc::size_of_value)).sum::<usize>()
+    }
+
+    fn clear(&mut self) {
+        self.names.clear();
+        self.values.clear();
+        self.index = None;
+        self.enclosing = None;
+    }
+}
+
+pub type NativeFn = fn(&mut Interpreter, &Token, Vec<Value>) -> Result<Value, RuntimeError>;
+
+/// Somewhere natives can be defined: an interpreter, or a set of them to give to many.
+pub trait NativeScope {
+    fn define_native(&mut self, name: &str, arity: usize, function: NativeFn);
+}
+
+impl NativeScope for Interpreter {
+    fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        Interpreter::define_native(self, name, arity, function);
+    }
+}
+
+/// Natives defined once for `Interpreter::with_natives` to give each new interpreter. Nothing
+/// in here belongs to any one interpreter, so a set can be shared between threads.
+#[derive(Debug, Clone, Default)]
+pub struct Natives {
+    natives: Vec<(Arc<str>, usize, NativeFn)>
+}
+
+impl Natives {
+    pub fn new() -> Self {
+        Natives::default()
+    }
+
+    /// The standard library, as `Interpreter::with_stdlib` defines it.
+    pub fn stdlib() -> Self {
+        let mut natives = Natives::new();
+        stdlib::define_all(&mut natives);
+        natives
+    }
+}
+
+impl NativeScope for Natives {
+    fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        self.natives.push((Arc::from(name), arity, function));
+    }
+}
+
+#[derive(Debug)]
+pub struct NativeFunction {
+    arity: usize,
+    function: NativeFn
+}
+
+impl Callable for NativeFunction {
+    fn call(&self, interpreter: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+        (self.function)(interpreter, paren, arguments)
+    }
+
+    fn arity(&self) -> usize {
+        self.arity
+    }
+}
+
+/// A native defined from a closure with `Interpreter::define_native_fn`.
+pub struct NativeClosure {
+    arity: usize,
+    function: Box<dyn Fn(Vec<Value>) -> Result<Value, ErrorKind>>
+}
+
+impl ::std::fmt::Debug for NativeClosure {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("NativeClosure").field("arity", &self.arity).finish()
+    }
+}
+
+impl Callable for NativeClosure {
+    fn call(&self, _: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+        (self.function)(arguments).map_err(|kind| RuntimeError::new(paren.clone(), kind))
+    }
+
+    fn arity(&self) -> usize {
+        self.arity
+    }
+}
+
+#[derive(Debug)]
+pub struct LoxFunction {
+    declaration: Arc<Function>,
+    // The scope the function was declared in, which its body can still see when called.
+    closure: Rc<RefCell<Environment>>,
+    this: Option<Value>,
+    superclass: Option<Rc<LoxClass>>,
+    is_initializer: bool
+}
+
+impl Callable for LoxFunction {
+    fn call(&self, interpreter: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+        interpreter.call_function(self, paren, arguments)
+    }
+
+    fn arity(&self) -> usize {
+        self.declaration.params.len()
+    }
+
+    fn describe(&self) -> String {
+        format!("<fn {}>", self.declaration.name.lexeme)
+    }
+
+    fn is_native(&self) -> bool {
+        false
+    }
+
+    fn as_function(&self) -> Option<&LoxFunction> {
+        Some(self)
+    }
+
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.environment(&self.closure);
+        if let Some(ref this) = self.this {
+            tracer.value(this);
+        }
+        if let Some(ref superclass) = self.superclass {
+            tracer.class(superclass);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct LoxClass {
+    name: Arc<str>,
+    superclass: Option<Rc<LoxClass>>,
+    // The classes named after `with`, whose methods this one has too.
+    mixins: Vec<Rc<LoxClass>>,
+    methods: HashMap<Arc<str>, Arc<Function>>,
+    closure: Rc<RefCell<Environment>>
+}
+
+impl LoxClass {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether this is `class` or inherits from it, however indirectly.
+    pub fn is_subclass_of(&self, class: &Rc<LoxClass>) -> bool {
+        let mut current = Some(self);
+        while let Some(candidate) = current {
+            if candidate as *const LoxClass == Rc::as_ptr(class) {
+                return true;
+            }
+            current = candidate.superclass.as_ref().map(|superclass| &**superclass);
+        }
+        false
+    }
+
+    // Finds the method along with the class that declared it. A class's own methods come
+    // first, then its mixins' with later ones overriding earlier ones, then its superclass's.
+    fn find_method(&self, name: &str) -> Option<(&LoxClass, &Arc<Function>)> {
+        if let Some(method) = self.methods.get(name) {
+            return Some((self, method));
+        }
+        self.mixins.iter().rev()
+            .filter_map(|mixin| mixin.find_method(name))
+            .next()
+            .or_else(|| self.superclass.as_ref().and_then(|superclass| superclass.find_method(name)))
+    }
+
+    // Looks the method up and binds it to `instance`. The method sees `super` as the
+    // superclass of the class that declared it, not of the instance's class.
+    fn bind(&self, name: &str, instance: &Value) -> Option<LoxFunction> {
+        self.find_method(name).map(|(class, method)| LoxFunction {
+            declaration: method.clone(),
+            closure: class.closure.clone(),
+            this: Some(instance.clone()),
+            superclass: class.superclass.clone(),
+            is_initializer: name == "init"
+        })
+    }
+
+    fn arity(&self) -> usize {
+        self.find_method("init").map_or(0, |(_, initializer)| initializer.params.len())
+    }
+}
+
+impl Trace for LoxClass {
+    fn trace(&self, tracer: &mut Tracer) {
+        if let Some(ref superclass) = self.superclass {
+            tracer.class(superclass);
+        }
+        for mixin in &self.mixins {
+            tracer.class(mixin);
+        }
+        tracer.environment(&self.closure);
+    }
+
+    fn size(&self) -> usize {
+        size_of::<LoxClass>() + self.mixins.len() * size_of::<Rc<LoxClass>>() + self.methods.len() * size_of::<(Arc<str>, Arc<Function>)>()
+    }
+}
+
+#[derive(Debug)]
+pub struct LoxInstance {
+    class: Rc<LoxClass>,
+    fields: HashMap<Arc<str>, Value>
+}
+
+impl LoxInstance {
+    pub fn class(&self) -> &Rc<LoxClass> {
+        &self.class
+    }
+}
+
+impl Trace for LoxInstance {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.class(&self.class);
+        for value in self.fields.values() {
+            tracer.value(value);
+        }
+    }
+
+    fn size(&self) -> usize {
+        size_of::<LoxInstance>() + self.fields.values().map(|value| size_of::<Arc<str>>() + gc::size_of_value(value)).sum::<usize>()
+    }
+
+    fn clear(&mut self) {
+        self.fields.clear();
+    }
+}
+
+fn write_flushed(output: &mut dyn Write, text: &str) -> IoResult<()> {
+    output.write_all(text.as_bytes())?;
+    output.flush()
+}
+
+// Formats a number the way jlox does: as Java's Double.toString would, minus any trailing
+// ".0". That's the shortest digits that read back as the same number, in scientific notation
+// outside of [0.001, 10^7).
+fn format_number(number: f64) -> String {
+    if number.is_nan() {
+        return "NaN".to_string();
+    }
+    if number.is_infinite() {
+        return if number > 0.0 { "Infinity" } else { "-Infinity" }.to_string();
+    }
+
+    let magnitude = number.abs();
+    if number == 0.0 || (magnitude >= 1e-3 && magnitude < 1e7) {
+        // Rust already leaves the ".0" off whole numbers.
+        return number.to_string();
+    }
+
+    let scientific = format!("{:e}", number);
+    let (mantissa, exponent) = scientific.split_at(scientific.find('e').unwrap());
+    if mantissa.contains('.') {
+        format!("{}E{}", mantissa, &exponent[1..])
+    } else {
+        format!("{}.0E{}", mantissa, &exponent[1..])
+    }
+}
+
+// Seconds since the Unix epoch, as in jlox.
+fn clock(interpreter: &mut Interpreter, paren: &Token, _: Vec<Value>) -> Result<Value, RuntimeError> {
+    interpreter.require(interpreter.config().time, "Reading the clock", paren)?;
+    let duration = stdlib::unix_time().ok_or_else(|| RuntimeError::new(paren.clone(), ErrorKind::Unsupported("Reading the clock".to_string())))?;
+    Ok(Value::Number(duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1_000_000_000.0))
+}
+
+fn is_literal(expr: &Expr) -> bool {
+    match *expr {
+        Expr::Literal(..) => true,
+        _ => false
+    }
+}
+
+// What a statement is, for the trace: its keyword, and the name it declares if it has one.
+fn describe(stmt: &Stmt) -> String {
+    match *stmt {
+        Stmt::Defer(..) => "defer".to_string(),
+        Stmt::Expression(..) => "expression".to_string(),
+        Stmt::Function(ref function) => format!("fun {}", function.name.lexeme),
+        Stmt::If(_) => "if".to_string(),
+        Stmt::Print(..) => "print".to_string(),
+        Stmt::Return(_) => "return".to_string(),
+        Stmt::Var(ref var) => format!("var {}", var.name.lexeme),
+        Stmt::While(_) => "while".to_string(),
+        Stmt::Block(_) => "block".to_string(),
+        Stmt::Class(ref class) => format!("class {}", class.name.lexeme),
+        Stmt::Error(_) => "error".to_string()
+    }
+}
+
+// Trees with syntax errors aren't normally run, but embedders can still hand one over.
+fn syntax_error(error: &ErrorNode) -> RuntimeError {
+    RuntimeError::new(error.token.clone(), ErrorKind::SyntaxError)
+}
+
+/// Whether the current thread has enough stack left to go a level deeper. The parser checks it
+/// too, as its frames are large enough for a small thread stack to run out before its limit.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn stack_left() -> bool {
+    stacker::remaining_stack().map_or(true, |remaining| remaining > STACK_RED_ZONE)
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn stack_left() -> bool {
+    true
+}
+
+fn operator_or_name(expr: &Expr) -> Option<&Token> {
+    match *expr {
+        Expr::Binary(ref v) => Some(&v.operator),
+        Expr::Logical(ref v) => Some(&v.operator),
+        Expr::Unary(ref v) => Some(&v.operator),
+        Expr::Call(ref v) => Some(&v.paren),
+        Expr::Variable(ref v) => Some(&v.name),
+        Expr::Assign(ref v) => Some(&v.name),
+        Expr::Get(ref v) => Some(&v.name),
+        Expr::Set(ref v) => Some(&v.name),
+        Expr::This(ref v) => Some(&v.keyword),
+        Expr::Super(ref v) => Some(&v.keyword),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {CollectingReporter, Lox, LoxError};
+
+    fn run(source: &str) -> (Interpreter, Result<(), LoxError>) {
+        let mut interpreter = Interpreter::with_stdlib();
+        let result = run_in(&mut interpreter, source);
+        (interpreter, result)
+    }
+
+    fn run_in(interpreter: &mut Interpreter, source: &str) -> Result<(), LoxError> {
+        let mut lox = Lox::new();
+        lox.set_reporter(Box::new(CollectingReporter::new()));
+        lox.run(&source.to_string(), interpreter)
+    }
+
+    fn global(interpreter: &Interpreter, name: &str) -> String {
+        interpreter.stringify(interpreter.get_global(name).unwrap())
+    }
+
+    fn runtime_error(result: Result<(), LoxError>) -> ErrorKind {
+        match result {
+            Err(LoxError::Runtime { error: RuntimeError(_, kind, _), .. }) => kind,
+            Err(error) => panic!("expected a runtime error, got {}", error),
+            Ok(()) => panic!("expected a runtime error")
+        }
+    }
+
+    #[test]
+    fn calls_pass_arguments_and_return_a_value() {
+        let (interpreter, result) = run("fun add(a, b) { return a + b; } var x = add(1, 2);");
+        assert!(result.is_ok());
+        assert_eq!(global(&interpreter, "x"), "3");
+    }
+
+    #[test]
+    fn functions_return_nil_without_a_return_value() {
+        let (interpreter, result) = run("fun f() {} fun g() { return; } var x = f(); var y = g();");
+        assert!(result.is_ok());
+        assert_eq!(global(&interpreter, "x"), "nil");
+        assert_eq!(global(&interpreter, "y"), "nil");
+    }
+
+    #[test]
+    fn closures_capture_and_mutate_a_local() {
+        let source = "fun counter() { var n = 0; fun next() { n = n + 1; return n; } return next; }\n\
+                      var c = counter(); c(); var x = c(); var d = counter(); var y = d();";
+        let (interpreter, result) = run(source);
+        assert!(result.is_ok());
+        assert_eq!(global(&interpreter, "x"), "2");
+        assert_eq!(global(&interpreter, "y"), "1");
+    }
+
+    // Calls through a variable can't be checked before running.
+    #[test]
+    fn calls_with_the_wrong_number_of_arguments_fail_at_runtime() {
+        let (_, result) = run("fun f(a, b) {} var g = f; g(1);");
+        match runtime_error(result) {
+            ErrorKind::ArityMismatch { expected: 2, got: 1 } => (),
+            kind => panic!("unexpected {}", kind)
+        }
+    }
+
+    #[test]
+    fn init_gives_back_the_instance_even_on_a_bare_return() {
+        let source = "class A { init(x) { this.x = x; if (x > 0) return; this.x = -1; } }\n\
+                      var a = A(1); var x = a.x; var again = a.init(0); var y = again.x;";
+        let (interpreter, result) = run(source);
+        assert!(result.is_ok());
+        assert_eq!(global(&interpreter, "x"), "1");
+        assert_eq!(global(&interpreter, "again"), "A instance");
+        assert_eq!(global(&interpreter, "y"), "-1");
+    }
+
+    #[test]
+    fn super_calls_the_superclass_method_on_the_same_instance() {
+        let source = "class A { name() { return \"A\"; } describe() { return this.name(); } }\n\
+                      class B < A { name() { return \"B\" + super.name(); } }\n\
+                      class C < B { name() { return \"C\" + super.name(); } }\n\
+                      var x = C().describe();";
+        let (interpreter, result) = run(source);
+        assert!(result.is_ok());
+        assert_eq!(global(&interpreter, "x"), "CBA");
+    }
+
+    #[test]
+    fn closures_in_a_loop_capture_each_iteration() {
+        let source = "var fs = list(); var gs = list();\n\
+                      for (var i = 0; i < 3; i = i + 1) {\n\
+                        var j = i;\n\
+                        fun f() { return j; } fun g() { return i; }\n\
+                        listPush(fs, f); listPush(gs, g);\n\
+                      }\n\
+                      var x = 0; var y = 0;\n\
+                      for (var k = 0; k < 3; k = k + 1) { x = x * 10 + listGet(fs, k)(); y = y * 10 + listGet(gs, k)(); }";
+        let (interpreter, result) = run(source);
+        assert!(result.is_ok(), "{:?}", result.err().map(|error| error.to_string()));
+        // Each iteration's block is a new scope, but the loop variable lives in one outside it.
+        assert_eq!(global(&interpreter, "x"), "12");
+        assert_eq!(global(&interpreter, "y"), "333");
+    }
+
+    #[test]
+    fn nested_blocks_shadow_and_restore_names() {
+        let source = "var a = \"global\"; var x0; var x1; var x2;\n\
+                      { var a = \"outer\"; { var a = \"inner\"; x2 = a; a = \"changed\"; } x1 = a; }\n\
+                      x0 = a;";
+        let (interpreter, result) = run(source);
+        assert!(result.is_ok());
+        assert_eq!(global(&interpreter, "x0"), "global");
+        assert_eq!(global(&interpreter, "x1"), "outer");
+        assert_eq!(global(&interpreter, "x2"), "inner");
+    }
+
+    #[test]
+    fn eval_resolves_the_locals_of_the_code_calling_it() {
+        let source = "var x; var y;\n\
+                      fun f(p) { var a = 1; { var b = 2; x = eval(\"a + b + p\"); eval(\"a = 5\"); } y = a; }\n\
+                      f(10);";
+        let (interpreter, result) = run(source);
+        assert!(result.is_ok(), "{:?}", result.err().map(|error| error.to_string()));
+        assert_eq!(global(&interpreter, "x"), "13");
+        assert_eq!(global(&interpreter, "y"), "5");
+    }
+
+    // Code compiled before eval() ran couldn't see what it declares, so a global of the same
+    // name still wins.
+    #[test]
+    fn unresolved_names_are_globals_before_eval_declared_locals() {
+        let source = "var g = 1; var x; var y;\n\
+                      { eval(\"var g = 2; var h = 3;\"); x = g; y = h; }";
+        let (interpreter, result) = run(source);
+        assert!(result.is_ok(), "{:?}", result.err().map(|error| error.to_string()));
+        assert_eq!(global(&interpreter, "x"), "1");
+        assert_eq!(global(&interpreter, "y"), "3");
+    }
+
+    #[test]
+    fn numbers_print_as_in_jlox() {
+        for &(number, expected) in [
+            (1.0, "1"),
+            (-0.0, "-0"),
+            (1.5, "1.5"),
+            (0.1 + 0.2, "0.30000000000000004"),
+            (1234567.0, "1234567"),
+            (1e7, "1.0E7"),
+            (1e21, "1.0E21"),
+            (-2.5e-5, "-2.5E-5"),
+            (0.001, "0.001"),
+            (::std::f64::NAN, "NaN"),
+            (::std::f64::INFINITY, "Infinity"),
+            (::std::f64::NEG_INFINITY, "-Infinity")
+        ].iter() {
+            assert_eq!(format_number(number), expected);
+        }
+    }
+
+    #[test]
+    fn restore_puts_the_globals_back_as_they_were() {
+        let mut interpreter = Interpreter::with_stdlib();
+        assert!(run_in(&mut interpreter, "var a = 1; var l = list(); listPush(l, 1);").is_ok());
+        let snapshot = interpreter.snapshot();
+
+        assert!(run_in(&mut interpreter, "a = 2; listPush(l, 2); var later = 3;").is_ok());
+        assert_eq!(global(&interpreter, "l"), "[1, 2]");
+        interpreter.restore(&snapshot);
+        assert_eq!(global(&interpreter, "a"), "1");
+        assert_eq!(global(&interpreter, "l"), "[1]");
+        assert!(interpreter.get_global("later").is_none());
+
+        // Natives are globals like any other, and were there when the snapshot was taken.
+        assert!(run_in(&mut interpreter, "var n = len(l); var t = clock();").is_ok());
+        assert_eq!(global(&interpreter, "n"), "1");
+
+        // The snapshot can be restored again after more changes.
+        assert!(run_in(&mut interpreter, "listPush(l, 3);").is_ok());
+        interpreter.restore(&snapshot);
+        assert_eq!(global(&interpreter, "l"), "[1]");
+    }
+
+    #[test]
+    fn values_convert_to_rust_types_and_back() {
+        assert_eq!(f64::try_from(Value::from(1.5)).unwrap(), 1.5);
+        assert_eq!(bool::try_from(Value::from(true)).unwrap(), true);
+        assert_eq!(String::try_from(Value::from("héllo")).unwrap(), "héllo");
+        assert_eq!(String::try_from(Value::from("owned".to_string())).unwrap(), "owned");
+        assert!(Value::from(None::<f64>) == Value::Nil);
+        assert!(Value::from(Some(2.0)) == Value::Number(2.0));
+
+        let list = Vec::<Value>::try_from(Value::from(vec![Value::from(1.0), Value::from("a")])).unwrap();
+        assert!(list == vec![Value::Number(1.0), Value::from("a")]);
+
+        let mut entries = BTreeMap::new();
+        entries.insert("k".to_string(), Value::from(false));
+        let map = BTreeMap::<String, Value>::try_from(Value::from(entries)).unwrap();
+        assert!(map["k"] == Value::Bool(false));
+    }
+
+    #[test]
+    fn converting_the_wrong_type_is_a_type_error() {
+        let message = |result: Result<(), ErrorKind>| match result {
+            Err(ErrorKind::TypeError(message)) => message,
+            Err(other) => panic!("unexpected {}", other),
+            Ok(()) => panic!("converted")
+        };
+        assert_eq!(message(f64::try_from(Value::from("1")).map(|_| ())), "Expected a number.");
+        assert_eq!(message(bool::try_from(Value::Nil).map(|_| ())), "Expected a boolean.");
+        assert_eq!(message(String::try_from(Value::from(1.0)).map(|_| ())), "Expected a string.");
+        assert_eq!(message(Vec::<Value>::try_from(Value::from(BTreeMap::new())).map(|_| ())), "Expected a list.");
+        assert_eq!(message(BTreeMap::<String, Value>::try_from(Value::from(Vec::new())).map(|_| ())), "Expected a map.");
     }
 }
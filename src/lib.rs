@@ -1,92 +1,886 @@
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(feature = "wasm")]
+extern crate wasm_bindgen;
+#[cfg(feature = "wasm")]
+extern crate js_sys;
+#[cfg(not(target_arch = "wasm32"))]
+extern crate stacker;
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
 use std::fs::File;
+use std::io;
 use std::io::prelude::*;
-use std::io::{Result as IoResult, stdout, stdin};
-use std::process::exit;
+use std::io::{stderr, stdout, stdin};
+use std::mem;
+use std::path::Path;
+use std::rc::Rc;
 
+pub mod intern;
 pub mod scanner;
 pub mod ast;
 pub mod parser;
+pub mod incremental;
+pub mod resolver;
+pub mod lint;
+pub mod optimizer;
+pub mod typecheck;
 pub mod interpreter;
+pub mod gc;
+pub mod profile;
+pub mod coverage;
+pub mod debugger;
+pub mod persist;
+pub mod shared;
+#[cfg(feature = "serde")]
+mod serialize;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+pub mod stdlib;
+pub mod regex;
+pub mod suggest;
+pub mod diagnostics;
+
+// How many inputs back the REPL's `:undo` can go.
+const UNDO_LIMIT: usize = 20;
+
+// What the REPL keeps between inputs: the file `:reload` runs again, and the globals as they
+// were before each of the latest inputs, for `:undo`.
+struct Session {
+    loaded: Option<String>,
+    history: Vec<interpreter::Snapshot>
+}
+
+impl Session {
+    fn checkpoint(&mut self, interpreter: &mut interpreter::Interpreter) {
+        if self.history.len() == UNDO_LIMIT {
+            self.history.remove(0);
+        }
+        self.history.push(interpreter.snapshot());
+    }
+}
+
+/// An error found before a program could run, by scanning, parsing, resolving or linting it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub line: i32,
+    pub column: i32,
+    /// Which token it's at, as in ` at 'x'` or ` at end`, or empty if it isn't at one.
+    pub location: String,
+    pub message: String
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", format_diagnostic(self.line, self.column, &self.location, &self.message))
+    }
+}
+
+/// Why a program didn't run to the end.
+#[derive(Debug)]
+pub enum LoxError {
+    /// It didn't run at all, because of these errors.
+    Compile(Vec<Diagnostic>),
+    /// It failed while running. `snippet` is the code the error happened in, as
+    /// `diagnostics::snippet` shows it, or empty if that couldn't be found.
+    Runtime {
+        error: interpreter::RuntimeError,
+        snippet: Vec<String>
+    },
+    /// It called `exit()` with this code.
+    Exit(i32),
+    /// A file couldn't be read or written.
+    Io(io::Error)
+}
+
+impl fmt::Display for LoxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoxError::Compile(ref diagnostics) => {
+                let messages: Vec<String> = diagnostics.iter().map(|diagnostic| diagnostic.to_string()).collect();
+                write!(f, "{}", messages.join("\n"))
+            },
+            LoxError::Runtime { ref error, ref snippet } => write!(f, "{}", format_runtime_error(error, snippet)),
+            LoxError::Exit(code) => write!(f, "Exited with code {}.", code),
+            LoxError::Io(ref error) => write!(f, "{}", error)
+        }
+    }
+}
+
+impl LoxError {
+    /// The status a command-line tool should exit with, following sysexits.h as jlox does:
+    /// 65 for code that doesn't compile, 70 for an error at runtime and 74 for a file that
+    /// couldn't be read or written. A script that called `exit()` gets the code it asked for.
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            LoxError::Compile(_) => 65,
+            LoxError::Runtime { .. } => 70,
+            LoxError::Exit(code) => code,
+            LoxError::Io(_) => 74
+        }
+    }
+}
+
+impl Error for LoxError {}
+
+impl From<io::Error> for LoxError {
+    fn from(error: io::Error) -> Self {
+        LoxError::Io(error)
+    }
+}
 
 pub struct Lox {
-    pub had_error: bool,
-    pub had_runtime_error: bool
+    pub args: Vec<String>,
+    pub linter: lint::Linter,
+    /// What scripts run by `run_file` and `run_prompt` may reach outside the interpreter.
+    /// Everything, unless changed.
+    pub config: interpreter::InterpreterConfig,
+    /// Whether scripts log what they run to stderr, as `Interpreter::set_trace` does.
+    pub trace: bool,
+    /// Whether scripts are profiled, with a report printed to stderr after each has run.
+    pub profile: bool,
+    /// Whether `run_file` starts the script paused in a console debugger.
+    pub debug: bool,
+    /// Variables whose every change is reported, in the trace or to the debugger.
+    pub watches: Vec<String>,
+    /// Where `run_file` writes a report of which lines the script ran.
+    pub coverage: Option<coverage::Report>,
+    /// A file the globals are loaded from before anything runs, if it exists, and saved to
+    /// afterwards: once `run_file`'s script has finished without errors, or after each REPL
+    /// input.
+    pub state: Option<String>,
+    // Everything run so far, each file or REPL line whole, so runtime errors can show the code
+    // they happened in.
+    sources: Vec<String>,
+    // The errors reported while compiling the current source.
+    errors: Vec<Diagnostic>,
+    // Where errors and warnings go as they're found.
+    reporter: Box<dyn ErrorReporter>,
+    // The top-level `var` declarations of every file loaded with `load_file`, as source text.
+    declarations: HashMap<String, HashSet<String>>
 }
 
 impl Lox {
     pub fn new() -> Self {
-        Lox { had_error: false, had_runtime_error: false }
+        Lox {
+            args: Vec::new(),
+            linter: lint::Linter::with_default_lints(),
+            config: interpreter::InterpreterConfig::trusted(),
+            trace: false,
+            profile: false,
+            debug: false,
+            watches: Vec::new(),
+            coverage: None,
+            state: None,
+            sources: Vec::new(),
+            errors: Vec::new(),
+            reporter: Box::new(PrintReporter::new(Box::new(stdout()))),
+            declarations: HashMap::new()
+        }
     }
 
-    pub fn run_file(&mut self, path: &String) -> IoResult<()> {
+    /// Has every error and warning handed to `reporter` as it's found, in place of printing
+    /// them to stdout. Errors still end up in the `LoxError` as well. A `CollectingReporter`
+    /// keeps them to be read afterwards.
+    pub fn set_reporter(&mut self, reporter: Box<dyn ErrorReporter>) {
+        self.reporter = reporter;
+    }
+
+    /// Runs the script at `path` in a new interpreter with the standard library, and with
+    /// whatever tracing, profiling, debugging and coverage this Lox has been set up for.
+    pub fn run_file(&mut self, path: &String) -> Result<(), LoxError> {
         let mut file = File::open(path)?;
         let mut contents = String::new();
         file.read_to_string(&mut contents)?;
-        let mut interpreter = interpreter::Interpreter::new();
-        self.run(&contents, &mut interpreter);
-
-        if self.had_error {
-            exit(65);
+        let mut interpreter = interpreter::Interpreter::with_stdlib();
+        interpreter.set_config(self.config);
+        interpreter.set_args(self.args.clone());
+        if self.trace {
+            interpreter.set_trace(Some(Box::new(stderr())));
+        }
+        interpreter.set_profiling(self.profile);
+        for name in &self.watches {
+            interpreter.watch(name);
+        }
+        if self.debug {
+            interpreter.set_debugger(Some(Box::new(debugger::ConsoleDebugger::with_source(contents.clone()))));
+            interpreter.break_at_next_statement();
+        }
+        interpreter.set_coverage(self.coverage.is_some());
+        self.load_state(&mut interpreter)?;
+        let result = self.run(&contents, &mut interpreter);
+        match result {
+            Ok(()) | Err(LoxError::Exit(_)) => self.save_state(&interpreter)?,
+            _ => ()
         }
 
-        if self.had_runtime_error {
-            exit(70);
+        if let (Some(report), Some(coverage)) = (self.coverage.as_ref(), interpreter.coverage()) {
+            match *report {
+                coverage::Report::Lcov(ref output) => File::create(output)?.write_all(coverage.lcov(path).as_bytes())?,
+                coverage::Report::Annotated(ref output) => File::create(output)?.write_all(coverage.annotate(&contents).as_bytes())?
+            }
         }
 
-        Ok(())
+        result
     }
 
-    pub fn run_prompt(&mut self) {
+    /// Reads and runs lines from stdin until it runs out, or a script calls `exit()`.
+    /// Errors in what's entered are reported without stopping it.
+    pub fn run_prompt(&mut self) -> Result<(), LoxError> {
         let mut input = String::new();
         let stdin = stdin();
-        let mut interpreter = interpreter::Interpreter::new();
+        let mut interpreter = interpreter::Interpreter::with_stdlib();
+        interpreter.set_config(self.config);
+        interpreter.set_args(self.args.clone());
+        if self.trace {
+            interpreter.set_trace(Some(Box::new(stderr())));
+        }
+        interpreter.set_profiling(self.profile);
+        for name in &self.watches {
+            interpreter.watch(name);
+        }
+        self.load_state(&mut interpreter)?;
+        let mut session = Session { loaded: None, history: Vec::new() };
 
         loop {
             print!("> ");
             stdout().flush().unwrap();
             input.clear();
             match stdin.read_line(&mut input) {
+                Ok(0) => return Ok(()),
+                Ok(_) if input.starts_with(':') => self.run_command(&input, &mut interpreter, &mut session)?,
                 Ok(_) => {
-                    self.run(&input, &mut interpreter);
+                    session.checkpoint(&mut interpreter);
+                    let result = self.run_source(&input, &mut interpreter, true, None);
+                    if let Err(error) = self.save_state(&interpreter) {
+                        eprintln!("Could not save globals: {}.", error);
+                    }
+
+                    // Errors have been reported already.
+                    if let Err(LoxError::Exit(code)) = result {
+                        return Err(LoxError::Exit(code));
+                    }
                 }
                 Err(error) => println!("error: {}", error),
             }
-            self.had_error = false;
         }
     }
 
-    pub fn run(&mut self, source: &String, interpreter: &mut interpreter::Interpreter) {
-        let mut scanner = scanner::Scanner::new(source.clone());
-        let tokens = scanner.scan_tokens(self);
+    // The REPL's commands. `:break LINE` and `:clear LINE` set and clear breakpoints, `:watch
+    // NAME` and `:unwatch NAME` do the same for watchpoints, and `:step` pauses at the start of
+    // whatever's entered next, in a console debugger. `:env` lists the variables defined so
+    // far, and `:env all` the natives too. `:load PATH` runs a file, and `:reload` runs the
+    // last one loaded again after it's been changed. `:undo` puts the globals back as they were
+    // before the last input that ran code. `:save PATH` writes the globals holding plain data to
+    // a file, and `:restore PATH` defines them again from one.
+    fn run_command(&mut self, command: &str, interpreter: &mut interpreter::Interpreter, session: &mut Session) -> Result<(), LoxError> {
+        let words: Vec<&str> = command[1..].split_whitespace().collect();
+        let argument = words.get(1).cloned();
+        let line = argument.and_then(|line| line.parse().ok());
+        match (words.get(0).cloned().unwrap_or(""), argument, line) {
+            ("break", _, Some(line)) => interpreter.set_breakpoint(line),
+            ("clear", _, Some(line)) => interpreter.clear_breakpoint(line),
+            ("watch", Some(name), _) => interpreter.watch(name),
+            ("unwatch", Some(name), _) => interpreter.unwatch(name),
+            ("step", _, _) => interpreter.break_at_next_statement(),
+            ("load", Some(path), _) => {
+                session.checkpoint(interpreter);
+                session.loaded = Some(path.to_string());
+                self.reload(path, interpreter)?;
+                return Ok(());
+            },
+            ("reload", _, _) => {
+                match session.loaded.clone() {
+                    Some(path) => {
+                        session.checkpoint(interpreter);
+                        self.reload(&path, interpreter)?;
+                    },
+                    None => println!("Nothing has been loaded yet.")
+                }
+                return Ok(());
+            },
+            ("undo", _, _) => {
+                match session.history.pop() {
+                    Some(snapshot) => interpreter.restore(&snapshot),
+                    None => println!("Nothing to undo.")
+                }
+                return Ok(());
+            },
+            ("save", Some(path), _) => {
+                let saved = File::create(path).and_then(|mut file| file.write_all(persist::save(interpreter).as_bytes()));
+                if let Err(error) = saved {
+                    println!("Could not save to '{}': {}.", path, error);
+                }
+                return Ok(());
+            },
+            ("restore", Some(path), _) => {
+                session.checkpoint(interpreter);
+                if let Err(error) = Lox::restore_globals(path, interpreter) {
+                    println!("{}", error);
+                }
+                return Ok(());
+            },
+            ("env", argument, _) => {
+                let all = argument == Some("all");
+                for binding in interpreter.bindings().into_iter().filter(|binding| all || !binding.native) {
+                    let indent = "  ".repeat(binding.depth);
+                    match binding.value {
+                        Some(value) => println!("{}{} = {}", indent, binding.name, value),
+                        None => println!("{}{} (unassigned)", indent, binding.name)
+                    }
+                }
+                return Ok(());
+            },
+            _ => {
+                println!("Commands are :break LINE, :clear LINE, :watch NAME, :unwatch NAME, :step, :env, :load PATH, :reload, :undo, :save PATH and :restore PATH.");
+                return Ok(());
+            }
+        }
+
+        interpreter.set_debugger(Some(Box::new(debugger::ConsoleDebugger::new())));
+        Ok(())
+    }
+
+    // Loads `state` if it's set and the file exists. A file that can't be read is an error
+    // rather than ignored, since saving over it later would lose what's in it.
+    fn load_state(&mut self, interpreter: &mut interpreter::Interpreter) -> io::Result<()> {
+        match self.state {
+            Some(ref path) if Path::new(path).exists() => Lox::restore_globals(path, interpreter),
+            _ => Ok(())
+        }
+    }
+
+    fn save_state(&self, interpreter: &interpreter::Interpreter) -> io::Result<()> {
+        match self.state {
+            Some(ref path) => File::create(path)?.write_all(persist::save(interpreter).as_bytes()),
+            None => Ok(())
+        }
+    }
+
+    fn restore_globals(path: &str, interpreter: &mut interpreter::Interpreter) -> io::Result<()> {
+        let mut contents = String::new();
+        File::open(path)?.read_to_string(&mut contents)?;
+        persist::load(interpreter, &contents).map(|_| ()).map_err(|error| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("Could not load globals from '{}': {}", path, error))
+        })
+    }
+
+    // Only a script calling `exit()` stops the REPL. Other errors have been reported already.
+    fn reload(&mut self, path: &str, interpreter: &mut interpreter::Interpreter) -> Result<(), LoxError> {
+        match self.load_file(path, interpreter) {
+            Err(LoxError::Io(error)) => println!("Could not load '{}': {}.", path, error),
+            Err(LoxError::Exit(code)) => return Err(LoxError::Exit(code)),
+            _ => ()
+        }
+        Ok(())
+    }
+
+    /// Runs the file at `path` in `interpreter`. Loading a file again after changing it runs it
+    /// all again except for top-level `var` declarations that are just as they were, so the
+    /// globals they declared keep the values the program has given them since. Functions and
+    /// classes are always declared again, picking up any changes to them.
+    pub fn load_file(&mut self, path: &str, interpreter: &mut interpreter::Interpreter) -> Result<(), LoxError> {
+        let mut contents = String::new();
+        File::open(path)?.read_to_string(&mut contents)?;
+
+        let mut declarations = self.declarations.remove(path).unwrap_or_default();
+        let result = self.run_source(&contents, interpreter, false, Some(&mut declarations));
+        self.declarations.insert(path.to_string(), declarations);
+        result
+    }
+
+    pub fn run(&mut self, source: &String, interpreter: &mut interpreter::Interpreter) -> Result<(), LoxError> {
+        self.run_source(source, interpreter, false, None)
+    }
+
+    // When reloading a file, `declarations` holds its top-level `var` declarations from the last
+    // time it ran, which are skipped if they haven't changed, and is updated with the new ones.
+    fn run_source(&mut self, source: &String, interpreter: &mut interpreter::Interpreter, repl: bool,
+                  declarations: Option<&mut HashSet<String>>) -> Result<(), LoxError> {
+        self.sources.push(source.clone());
+
+        let mut statements = self.compile_source(source, Some(interpreter.interner().clone()), repl)?;
+
+        if let Some(declarations) = declarations {
+            let previous = mem::replace(declarations, HashSet::new());
+            statements.retain(|statement| match *statement {
+                ast::Stmt::Var(ref var) => {
+                    let text = var.span.text(source);
+                    declarations.insert(text.to_string());
+                    !previous.contains(text) || interpreter.get_global(&var.name.lexeme).is_none()
+                },
+                _ => true
+            });
+        }
+
+        let result = interpreter.interpret(&statements);
+
+        if let Some(profile) = interpreter.profile() {
+            eprintln!("{}", profile.report().join("\n"));
+        }
+
+        self.finish(result, interpreter)
+    }
+
+    /// Evaluates `source`, which must be a single expression, and gives back its value. It's
+    /// evaluated in `interpreter`'s globals, so it can use any variable or function they hold.
+    pub fn evaluate(&mut self, source: &str, interpreter: &mut interpreter::Interpreter) -> Result<interpreter::Value, LoxError> {
+        self.sources.push(source.to_string());
+        self.errors.clear();
+
+        let mut scanner = scanner::Scanner::new(source);
+        scanner.set_interner(interpreter.interner().clone());
+        let (tokens, errors) = scanner.scan_tokens();
+        for error in errors {
+            self.report(error.line, error.column, "".to_string(), error.message);
+        }
+        self.check_errors()?;
+
+        let expr = match parser::Parser::new(&tokens).parse_expression(self) {
+            Ok(expr) => expr,
+            // The error has been reported, so check_errors gives it back along with any others.
+            // It's returned by itself if not, rather than passing for an expression that
+            // evaluated to nil.
+            Err(error) => {
+                self.check_errors()?;
+                return Err(LoxError::Compile(vec![Diagnostic {
+                    line: error.token.line,
+                    column: error.token.column,
+                    location: error.location(),
+                    message: error.message
+                }]));
+            }
+        };
+        self.check_errors()?;
+        let span = expr.span();
+        let mut statements = vec![ast::Stmt::Expression(expr, span)];
+
+        resolver::Resolver::new(self).resolve(&mut statements);
+        self.check_errors()?;
+
+        let result = interpreter.execute_in_scope(&statements);
+        self.finish(result, interpreter)
+    }
+
+    // Turns the outcome of running code into what `run` and `evaluate` give back, reporting a
+    // runtime error if there was one.
+    fn finish<T>(&mut self, result: Result<T, interpreter::RuntimeError>, interpreter: &interpreter::Interpreter) -> Result<T, LoxError> {
+        if let Some(code) = interpreter.exit_code() {
+            return Err(LoxError::Exit(code));
+        }
+        result.map_err(|error| {
+            let snippet = self.source_of(&error.0).map_or(Vec::new(), |source| diagnostics::snippet(source, error.0.span));
+            self.reporter.runtime_error(&error, &snippet);
+            LoxError::Runtime { error: error, snippet: snippet }
+        })
+    }
+
+    /// Scans, parses, resolves and lints `source`, giving back the program ready to run if
+    /// there weren't any errors. The program can be shared between threads, for any number of
+    /// interpreters to run with `Interpreter::interpret`.
+    pub fn compile(&mut self, source: &str) -> Result<Vec<ast::Stmt>, LoxError> {
+        self.compile_source(source, None, false)
+    }
 
-        let mut parser = parser::Parser::new(tokens.clone());
-        let mut statements = parser.parse(self);
+    // Names are interned with `interner` if there is one, so that the interpreter that's going
+    // to run the program can compare them by pointer.
+    fn compile_source(&mut self, source: &str, interner: Option<intern::Interner>, repl: bool) -> Result<Vec<ast::Stmt>, LoxError> {
+        self.errors.clear();
+        let mut scanner = scanner::Scanner::new(source);
+        if let Some(interner) = interner {
+            scanner.set_interner(interner);
+        }
+        let (tokens, errors) = scanner.scan_tokens();
 
-        if self.had_error {
-            return;
+        for error in errors {
+            self.report(error.line, error.column, "".to_string(), error.message);
         }
 
+        let mut parser = parser::Parser::new(&tokens);
+        parser.set_implicit_semicolon(repl);
+        let (mut statements, _) = parser.parse(self);
+        self.check_errors()?;
+
         if statements.len() == 1 {
             statements = vec![match statements.pop() {
-                Some(ast::Stmt::Expression(ref expr)) => {
-                    ast::Stmt::Print(expr.clone())
-                },
+                Some(ast::Stmt::Expression(expr, span)) => ast::Stmt::Print(expr, span),
                 Some(other) => other,
                 _ => unreachable!()
             }];
         }
 
-        interpreter.interpret(self, &statements);
+        resolver::Resolver::new(self).resolve(&mut statements);
+        self.check_errors()?;
+
+        // Lints the linter denies are reported as errors.
+        let mut linter = mem::replace(&mut self.linter, lint::Linter::new());
+        linter.check(&statements, self);
+        self.linter = linter;
+        self.check_errors()?;
+
+        optimizer::eliminate_dead_code(&mut statements);
+        Ok(statements)
+    }
+
+    // Fails with the errors reported so far, if there have been any.
+    fn check_errors(&mut self) -> Result<(), LoxError> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(LoxError::Compile(mem::replace(&mut self.errors, Vec::new())))
+        }
+    }
+
+    /// Reports an error in the source being compiled, which will stop it from running.
+    pub fn report(&mut self, line: i32, column: i32, location: String, message: String) {
+        self.reporter.report(line, column, location.clone(), message.clone());
+        self.errors.push(Diagnostic {
+            line: line,
+            column: column,
+            location: location,
+            message: message
+        });
+    }
+
+    // Spans don't say which source they're from, but a function called from a later REPL line
+    // can fail in code from an earlier one. The latest source holding the token's text at its
+    // span is the one it came from, as far as can be told.
+    fn source_of(&self, token: &scanner::Token) -> Option<&str> {
+        let mut sources = self.sources.iter().rev();
+        if token.lexeme.is_empty() {
+            return sources.next().map(|source| &source[..]);
+        }
+        sources
+            .find(|source| source.get(token.span.start..token.span.end) == Some(&token.lexeme[..]))
+            .map(|source| &source[..])
+    }
+}
+
+/// Somewhere for the parser to send syntax errors as it finds them, and for `Lox` to send
+/// those and everything else that goes wrong.
+pub trait ErrorReporter {
+    fn report(&mut self, line: i32, column: i32, location: String, message: String);
+
+    /// Flags code that's valid but probably not what was meant. Unlike `report` this mustn't
+    /// stop the program from running.
+    fn warning(&mut self, line: i32, column: i32, message: String);
+
+    /// A program failing as it runs, with the code it failed in as `diagnostics::snippet`
+    /// shows it, or nothing if that couldn't be found. Only `Lox` reports these.
+    fn runtime_error(&mut self, _error: &interpreter::RuntimeError, _snippet: &[String]) {}
+}
+
+impl ErrorReporter for Lox {
+    fn report(&mut self, line: i32, column: i32, location: String, message: String) {
+        Lox::report(self, line, column, location, message);
+    }
+
+    fn warning(&mut self, line: i32, column: i32, message: String) {
+        self.reporter.warning(line, column, message);
+    }
+}
+
+/// Collects the formatted messages, for callers that want to deal with them later.
+impl ErrorReporter for Vec<String> {
+    fn report(&mut self, line: i32, column: i32, location: String, message: String) {
+        self.push(format_diagnostic(line, column, &location, &message));
+    }
+
+    fn warning(&mut self, line: i32, column: i32, message: String) {
+        self.push(format_warning(line, column, &message));
+    }
+
+    fn runtime_error(&mut self, error: &interpreter::RuntimeError, snippet: &[String]) {
+        self.push(format_runtime_error(error, snippet));
+    }
+}
+
+/// Keeps every error and warning reported to it, worded as `lox1exec` shows them. Clones share
+/// what's been kept, so one can be handed to `Lox::set_reporter` and another held on to.
+#[derive(Debug, Clone, Default)]
+pub struct CollectingReporter {
+    messages: Rc<RefCell<Vec<String>>>
+}
+
+impl CollectingReporter {
+    pub fn new() -> Self {
+        CollectingReporter::default()
+    }
+
+    /// Everything reported so far, oldest first.
+    pub fn messages(&self) -> Vec<String> {
+        self.messages.borrow().clone()
+    }
+
+    /// Everything reported so far, forgetting it.
+    pub fn take(&self) -> Vec<String> {
+        mem::replace(&mut *self.messages.borrow_mut(), Vec::new())
+    }
+}
+
+impl ErrorReporter for CollectingReporter {
+    fn report(&mut self, line: i32, column: i32, location: String, message: String) {
+        self.messages.borrow_mut().report(line, column, location, message);
+    }
+
+    fn warning(&mut self, line: i32, column: i32, message: String) {
+        self.messages.borrow_mut().warning(line, column, message);
+    }
+
+    fn runtime_error(&mut self, error: &interpreter::RuntimeError, snippet: &[String]) {
+        self.messages.borrow_mut().runtime_error(error, snippet);
+    }
+}
+
+/// Writes every error and warning out as it's reported, as `lox1exec` shows them.
+pub struct PrintReporter {
+    output: Box<dyn Write>
+}
+
+impl PrintReporter {
+    pub fn new(output: Box<dyn Write>) -> Self {
+        PrintReporter { output: output }
+    }
+}
+
+// Reporting is only an aid, so an output that can't be written to doesn't stop anything.
+impl ErrorReporter for PrintReporter {
+    fn report(&mut self, line: i32, column: i32, location: String, message: String) {
+        let _ = writeln!(self.output, "{}", format_diagnostic(line, column, &location, &message));
+    }
+
+    fn warning(&mut self, line: i32, column: i32, message: String) {
+        let _ = writeln!(self.output, "{}", format_warning(line, column, &message));
+    }
+
+    fn runtime_error(&mut self, error: &interpreter::RuntimeError, snippet: &[String]) {
+        let _ = writeln!(self.output, "{}", format_runtime_error(error, snippet));
+    }
+}
+
+fn format_diagnostic(line: i32, column: i32, location: &str, message: &str) -> String {
+    format!("[line {}, column {} ] Error {} : {}", line, column, location, message)
+}
+
+fn format_warning(line: i32, column: i32, message: &str) -> String {
+    format!("[line {}, column {} ] Warning : {}", line, column, message)
+}
+
+// The error's message, then the code it happened in, then where the calls in progress were.
+fn format_runtime_error(error: &interpreter::RuntimeError, snippet: &[String]) -> String {
+    let mut lines = vec![error.1.to_string()];
+    lines.extend(snippet.iter().cloned());
+    lines.extend(error.stack_trace());
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+    use interpreter::{Interpreter, Value};
+
+    fn collecting() -> (Lox, CollectingReporter) {
+        let reporter = CollectingReporter::new();
+        let mut lox = Lox::new();
+        lox.set_reporter(Box::new(reporter.clone()));
+        (lox, reporter)
+    }
+
+    #[test]
+    fn evaluate_gives_back_the_value() {
+        let (mut lox, _) = collecting();
+        let mut interpreter = Interpreter::with_stdlib();
+        interpreter.set_global("x", Value::Number(2.0));
+        match lox.evaluate("1 + x * 3", &mut interpreter) {
+            Ok(Value::Number(number)) => assert_eq!(number, 7.0),
+            result => panic!("unexpected {:?}", result.map(|value| interpreter.stringify(value)))
+        }
+    }
+
+    #[test]
+    fn evaluate_fails_on_source_that_does_not_parse() {
+        let (mut lox, reporter) = collecting();
+        let mut interpreter = Interpreter::with_stdlib();
+        for source in &["1 +", "1 2", "", "var x = 1;"] {
+            match lox.evaluate(source, &mut interpreter) {
+                Err(LoxError::Compile(ref diagnostics)) if !diagnostics.is_empty() => (),
+                result => panic!("{:?} gave {:?}", source, result.map(|value| interpreter.stringify(value)))
+            }
+            assert!(!reporter.take().is_empty());
+        }
+    }
+
+    #[test]
+    fn errors_and_warnings_all_go_to_the_reporter() {
+        let (mut lox, reporter) = collecting();
+        let mut interpreter = Interpreter::with_stdlib();
+        assert!(lox.run(&"fun f() { var unused = 1; }".to_string(), &mut interpreter).is_ok());
+        assert!(reporter.take()[0].contains("Warning"));
+
+        match lox.run(&"print nope;".to_string(), &mut interpreter) {
+            Err(LoxError::Runtime { ref error, .. }) => assert_eq!(reporter.take()[0].lines().next(), Some(&error.1.to_string()[..])),
+            result => panic!("unexpected {:?}", result)
+        }
+
+        assert!(lox.run(&"var ;".to_string(), &mut interpreter).is_err());
+        assert!(reporter.take()[0].contains("Expect variable name."));
+    }
+
+    #[test]
+    fn exit_only_stops_the_run_that_called_it() {
+        let (mut lox, reporter) = collecting();
+        let mut interpreter = Interpreter::with_stdlib();
+        match lox.run(&"exit(3);".to_string(), &mut interpreter) {
+            Err(LoxError::Exit(3)) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+
+        assert!(lox.run(&"var x = 1;".to_string(), &mut interpreter).is_ok());
+        match lox.evaluate("x + 1", &mut interpreter) {
+            Ok(Value::Number(number)) => assert_eq!(number, 2.0),
+            result => panic!("unexpected {:?}", result.map(|value| interpreter.stringify(value)))
+        }
+        match lox.run(&"print nope;".to_string(), &mut interpreter) {
+            Err(LoxError::Runtime { .. }) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+        assert_eq!(reporter.take().len(), 1);
+    }
+
+    // Default-sized threads, spawned and main, with the default limits on how deep calls go.
+    #[test]
+    fn deep_recursion_fails_instead_of_overflowing_the_stack() {
+        for &stack_size in &[None, Some(8 << 20)] {
+            let mut builder = ::std::thread::Builder::new();
+            if let Some(stack_size) = stack_size {
+                builder = builder.stack_size(stack_size);
+            }
+            let result = builder.spawn(|| {
+                let (mut lox, _) = collecting();
+                let mut interpreter = Interpreter::with_stdlib();
+                match lox.run(&"fun f(n) { return 1 + f(n + 1); } f(0);".to_string(), &mut interpreter) {
+                    Err(LoxError::Runtime { ref error, .. }) => error.1.to_string(),
+                    result => format!("{:?}", result)
+                }
+            }).unwrap().join();
+            assert_eq!(result.unwrap(), "Stack overflow.");
+        }
     }
 
-    pub fn report(&mut self, line: i32, location: String, message: String) {
-        println!("[line {} ] Error {} : {}", line, location, message);
-        self.had_error = true;
+    fn global(interpreter: &Interpreter, name: &str) -> String {
+        interpreter.stringify(interpreter.get_global(name).unwrap())
     }
 
-    pub fn runtime_error(&mut self, error: interpreter::RuntimeError) {
-        let interpreter::RuntimeError(token, message) = error;
-        println!("{}\n[line {}]", message, token.line);
-        self.had_runtime_error = true;
+    #[test]
+    fn tail_calls_run_in_constant_stack() {
+        let (mut lox, _) = collecting();
+        let mut interpreter = Interpreter::with_stdlib();
+        let source = "fun count(n, total) { if (n == 0) return total; return count(n - 1, total + 1); }\n\
+                      fun even(n) { if (n == 0) return true; return odd(n - 1); }\n\
+                      fun odd(n) { if (n == 0) return false; return even(n - 1); }\n\
+                      var total = count(100000, 0);\n\
+                      var isEven = even(100001);";
+        lox.run(&source.to_string(), &mut interpreter).unwrap();
+        assert_eq!(global(&interpreter, "total"), "100000");
+        assert_eq!(global(&interpreter, "isEven"), "false");
+    }
+
+    #[test]
+    fn deferred_expressions_run_after_a_call_in_tail_position() {
+        let (mut lox, _) = collecting();
+        let mut interpreter = Interpreter::with_stdlib();
+        let source = "var log = \"\";\n\
+                      fun g(n) { log = log + \"g\"; if (n > 0) return g(n - 1); return 1; }\n\
+                      fun f() { defer log = log + \"d\"; return g(2); }\n\
+                      f();";
+        lox.run(&source.to_string(), &mut interpreter).unwrap();
+        assert_eq!(global(&interpreter, "log"), "gggd");
+    }
+
+    #[test]
+    fn is_checks_the_class_and_its_superclasses() {
+        let (mut lox, _) = collecting();
+        let mut interpreter = Interpreter::with_stdlib();
+        let source = "class A {} class B < A {} class C < B {} class D {}\n\
+                      var c = C();\n\
+                      var own = c is C; var parent = c is B; var root = c is A; var other = c is D;\n\
+                      var child = A() is B; var number = 1 is A; var grouped = c is A == true;";
+        lox.run(&source.to_string(), &mut interpreter).unwrap();
+        let results: Vec<String> = ["own", "parent", "root", "other", "child", "number", "grouped"].iter()
+            .map(|name| global(&interpreter, name))
+            .collect();
+        assert_eq!(results, ["true", "true", "true", "false", "false", "false", "true"]);
+
+        match lox.run(&"var c = 1; print c is c;".to_string(), &mut interpreter) {
+            Err(LoxError::Runtime { ref error, .. }) => assert_eq!(error.1.to_string(), "Right operand of 'is' must be a class."),
+            result => panic!("unexpected {:?}", result)
+        }
+    }
+
+    #[test]
+    fn mixins_give_their_methods_in_a_set_order() {
+        let (mut lox, _) = collecting();
+        let mut interpreter = Interpreter::with_stdlib();
+        let source = "var prefix = \"outer\";\n\
+                      class Base { who() { return \"base\"; } base() { return \"base\"; } }\n\
+                      class Loud { who() { return \"loud\"; } loud() { return \"loud\"; } }\n\
+                      fun makeQuiet() {\n\
+                        var prefix = \"inner\";\n\
+                        class Quiet < Base { who() { return \"quiet\"; } quiet() { return prefix + super.who(); } }\n\
+                        return Quiet;\n\
+                      }\n\
+                      var Quiet = makeQuiet();\n\
+                      class A < Base with Loud, Quiet { init(n) { this.n = n; } own() { return this.n; } }\n\
+                      class B with Quiet, Loud {}\n\
+                      var a = A(\"3\");\n\
+                      var results = a.own() + \" \" + a.who() + \" \" + a.loud() + \" \" + a.quiet() + \" \" + a.base() + \" \" + B().who();\n\
+                      var isMixin = a is Loud;";
+        lox.run(&source.to_string(), &mut interpreter).unwrap_or_else(|error| panic!("{:?}", error));
+        assert_eq!(global(&interpreter, "results"), "3 quiet loud innerbase base loud");
+        assert_eq!(global(&interpreter, "isMixin"), "false");
+
+        match lox.run(&"var M = 1; class C with M {}".to_string(), &mut interpreter) {
+            Err(LoxError::Runtime { ref error, .. }) => assert_eq!(error.1.to_string(), "Mixin must be a class."),
+            result => panic!("unexpected {:?}", result)
+        }
+        assert!(lox.run(&"class C with C {}".to_string(), &mut interpreter).is_err());
+    }
+
+
+    #[test]
+    fn loading_a_file_again_keeps_unchanged_globals() {
+        let path = env::temp_dir().join(format!("lox-reload-{}.lox", process::id()));
+        let path = path.to_str().unwrap();
+        let (mut lox, _) = collecting();
+        let mut interpreter = Interpreter::with_stdlib();
+
+        fs::write(path, "var count = 0; var label = \"one\"; fun bump() { count = count + 1; }").unwrap();
+        assert!(lox.load_file(path, &mut interpreter).is_ok());
+        assert!(lox.run(&"bump(); bump();".to_string(), &mut interpreter).is_ok());
+
+        fs::write(path, "var count = 0; var label = \"two\"; fun bump() { count = count + 10; }").unwrap();
+        assert!(lox.load_file(path, &mut interpreter).is_ok());
+        assert!(lox.run(&"bump();".to_string(), &mut interpreter).is_ok());
+        assert_eq!(global(&interpreter, "count"), "12");
+        assert_eq!(global(&interpreter, "label"), "two");
+
+        // Nothing runs if the new version doesn't compile.
+        fs::write(path, "var count = 0; var label = ; fun bump() {}").unwrap();
+        match lox.load_file(path, &mut interpreter) {
+            Err(LoxError::Compile(_)) => (),
+            result => panic!("unexpected {:?}", result.err().map(|error| error.to_string()))
+        }
+        assert!(lox.run(&"bump();".to_string(), &mut interpreter).is_ok());
+        assert_eq!(global(&interpreter, "count"), "22");
+        assert_eq!(global(&interpreter, "label"), "two");
+
+        fs::remove_file(path).unwrap();
     }
 }
@@ -0,0 +1,578 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use ast::*;
+use scanner::{Span, Token};
+use ErrorReporter;
+
+/// What happens to the findings of a lint.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Allow,
+    Warn,
+    Deny
+}
+
+/// What kind of declaration introduced a name, as passed to `Lint::declare`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Binding {
+    Variable,
+    Parameter,
+    Function,
+    Class
+}
+
+/// A check run over a resolved program. The linter walks the tree once per lint, calling the
+/// hooks below along the way; a lint only needs to override the ones it cares about. Scopes
+/// are entered for every block and function body, and `declare` is called for each name as
+/// it comes into scope, so lints that care about bindings can follow along without knowing
+/// the language's scoping rules.
+pub trait Lint {
+    /// The name used to configure the lint, such as "unused-variable".
+    fn name(&self) -> &'static str;
+
+    fn default_severity(&self) -> Severity {
+        Severity::Warn
+    }
+
+    /// Called with every list of statements: the program, each block and each function body.
+    fn check_statements(&mut self, _: &[Stmt], _: &mut Context) {}
+
+    fn check_stmt(&mut self, _: &Stmt, _: &mut Context) {}
+
+    fn check_expr(&mut self, _: &Expr, _: &mut Context) {}
+
+    fn declare(&mut self, _: &Token, _: Binding, _: &mut Context) {}
+
+    fn enter_scope(&mut self, _: &mut Context) {}
+
+    fn leave_scope(&mut self, _: &mut Context) {}
+
+    /// Called in place of `enter_scope` for the scope holding a function's parameters and body,
+    /// methods included.
+    fn enter_function(&mut self, _: &Function, context: &mut Context) {
+        self.enter_scope(context);
+    }
+
+    fn leave_function(&mut self, _: &Function, context: &mut Context) {
+        self.leave_scope(context);
+    }
+}
+
+/// Where a lint sends what it finds.
+pub struct Context {
+    findings: Vec<Finding>
+}
+
+struct Finding {
+    line: i32,
+    column: i32,
+    location: String,
+    message: String
+}
+
+impl Context {
+    pub fn report(&mut self, token: &Token, message: String) {
+        self.findings.push(Finding {
+            line: token.line,
+            column: token.column,
+            location: format!(" at '{}'", token.lexeme),
+            message: message
+        });
+    }
+
+    pub fn report_span(&mut self, span: Span, message: String) {
+        self.findings.push(Finding {
+            line: span.line,
+            column: span.column,
+            location: String::new(),
+            message: message
+        });
+    }
+}
+
+/// The lints to run and how seriously to take each one. Findings of a denied lint are
+/// reported as errors, which stops the program from running.
+pub struct Linter {
+    lints: Vec<(Box<dyn Lint>, Severity)>
+}
+
+impl Linter {
+    /// A linter with no lints registered.
+    pub fn new() -> Self {
+        Linter { lints: Vec::new() }
+    }
+
+    /// A linter with the built-in lints at their default severities.
+    pub fn with_default_lints() -> Self {
+        let mut linter = Linter::new();
+        linter.register(Box::new(UnusedVariable::new()));
+        linter.register(Box::new(UnreachableCode));
+        linter.register(Box::new(AssignmentInCondition));
+        linter.register(Box::new(Shadowing::new()));
+        linter.register(Box::new(::typecheck::TypeMismatch::new()));
+        linter
+    }
+
+    pub fn register(&mut self, lint: Box<dyn Lint>) {
+        let severity = lint.default_severity();
+        self.lints.push((lint, severity));
+    }
+
+    /// Returns false if no lint goes by that name.
+    pub fn set_severity(&mut self, name: &str, severity: Severity) -> bool {
+        match self.lints.iter_mut().find(|&&mut (ref lint, _)| lint.name() == name) {
+            Some(&mut (_, ref mut current)) => {
+                *current = severity;
+                true
+            },
+            None => false
+        }
+    }
+
+    pub fn severity(&self, name: &str) -> Option<Severity> {
+        self.lints.iter().find(|&&(ref lint, _)| lint.name() == name).map(|&(_, severity)| severity)
+    }
+
+    /// Runs every lint that isn't allowed over the program and returns whether any denied one
+    /// found something.
+    pub fn check(&mut self, statements: &[Stmt], reporter: &mut dyn ErrorReporter) -> bool {
+        let mut denied = false;
+        for &mut (ref mut lint, severity) in self.lints.iter_mut() {
+            if severity == Severity::Allow {
+                continue;
+            }
+
+            let mut findings = {
+                let mut walker = Walker { lint: &mut **lint, context: Context { findings: Vec::new() } };
+                walker.statements(statements);
+                walker.context.findings
+            };
+            findings.sort_by_key(|finding| (finding.line, finding.column));
+
+            for finding in findings {
+                if severity == Severity::Deny {
+                    denied = true;
+                    reporter.report(finding.line, finding.column, finding.location, finding.message);
+                } else {
+                    reporter.warning(finding.line, finding.column, finding.message);
+                }
+            }
+        }
+
+        denied
+    }
+}
+
+struct Walker<'l> {
+    lint: &'l mut dyn Lint,
+    context: Context
+}
+
+impl<'l> Walker<'l> {
+    fn statements(&mut self, statements: &[Stmt]) {
+        self.lint.check_statements(statements, &mut self.context);
+        for statement in statements {
+            self.stmt(statement);
+        }
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        self.lint.check_stmt(stmt, &mut self.context);
+        stmt.accept(self);
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        self.lint.check_expr(expr, &mut self.context);
+        expr.accept(self);
+    }
+
+    fn function(&mut self, function: &Function) {
+        self.lint.enter_function(function, &mut self.context);
+        for param in function.params.iter() {
+            self.lint.declare(param, Binding::Parameter, &mut self.context);
+        }
+        self.statements(&function.body);
+        self.lint.leave_function(function, &mut self.context);
+    }
+}
+
+impl<'l> StmtVisitor<()> for Walker<'l> {
+    fn visit_defer<'a>(&mut self, expr: &'a Arc<Expr>) {
+        self.expr(expr);
+    }
+
+    fn visit_expr<'a>(&mut self, expr: &'a Expr) {
+        self.expr(expr);
+    }
+
+    fn visit_function<'a>(&mut self, function: &'a Arc<Function>) {
+        self.lint.declare(&function.name, Binding::Function, &mut self.context);
+        self.function(function);
+    }
+
+    fn visit_print<'a>(&mut self, expr: &'a Expr) {
+        self.expr(expr);
+    }
+
+    fn visit_return<'a>(&mut self, stmt: &'a Return) {
+        if let Some(ref value) = stmt.value {
+            self.expr(value);
+        }
+    }
+
+    fn visit_var<'a>(&mut self, stmt: &'a Var) {
+        if let Some(ref initializer) = stmt.initializer {
+            self.expr(initializer);
+        }
+        self.lint.declare(&stmt.name, Binding::Variable, &mut self.context);
+    }
+
+    fn visit_block<'a>(&mut self, block: &'a Block) {
+        self.lint.enter_scope(&mut self.context);
+        self.statements(&block.statements);
+        self.lint.leave_scope(&mut self.context);
+    }
+
+    fn visit_if<'a>(&mut self, stmt: &'a If) {
+        self.expr(&stmt.condition);
+        self.stmt(&stmt.then_branch);
+        if let Some(ref else_branch) = stmt.else_branch {
+            self.stmt(else_branch);
+        }
+    }
+
+    fn visit_while<'a>(&mut self, stmt: &'a While) {
+        self.expr(&stmt.condition);
+        self.stmt(&stmt.body);
+    }
+
+    fn visit_class<'a>(&mut self, class: &'a Class) {
+        self.lint.declare(&class.name, Binding::Class, &mut self.context);
+        if let Some(ref superclass) = class.superclass {
+            self.expr(&Expr::Variable(superclass.clone()));
+        }
+        for mixin in class.mixins.iter() {
+            self.expr(&Expr::Variable(mixin.clone()));
+        }
+        for method in class.methods.iter() {
+            self.function(method);
+        }
+    }
+
+    fn visit_error<'a>(&mut self, _: &'a ErrorNode) {}
+}
+
+impl<'l> ExprVisitor<()> for Walker<'l> {
+    fn visit_binary<'a>(&mut self, expr: &'a Binary) {
+        self.expr(&expr.left);
+        self.expr(&expr.right);
+    }
+
+    fn visit_call<'a>(&mut self, expr: &'a Call) {
+        self.expr(&expr.callee);
+        for argument in expr.arguments.iter() {
+            self.expr(argument);
+        }
+    }
+
+    fn visit_grouping<'a>(&mut self, expr: &'a Grouping) {
+        self.expr(&expr.expression);
+    }
+
+    fn visit_literal<'a>(&mut self, _: &'a Literal) {}
+
+    fn visit_unary<'a>(&mut self, expr: &'a Unary) {
+        self.expr(&expr.right);
+    }
+
+    fn visit_variable<'a>(&mut self, _: &'a Variable) {}
+
+    fn visit_assign<'a>(&mut self, expr: &'a Assign) {
+        self.expr(&expr.value);
+    }
+
+    fn visit_logical<'a>(&mut self, expr: &'a Logical) {
+        self.expr(&expr.left);
+        self.expr(&expr.right);
+    }
+
+    fn visit_get<'a>(&mut self, expr: &'a Get) {
+        self.expr(&expr.object);
+    }
+
+    fn visit_set<'a>(&mut self, expr: &'a Set) {
+        self.expr(&expr.object);
+        self.expr(&expr.value);
+    }
+
+    fn visit_this<'a>(&mut self, _: &'a This) {}
+
+    fn visit_super<'a>(&mut self, _: &'a Super) {}
+
+    fn visit_error<'a>(&mut self, _: &'a ErrorNode) {}
+}
+
+/// Local variables that are declared but never read. Assignments don't count as reads, and
+/// parameters, functions and classes are left alone.
+pub struct UnusedVariable {
+    // Each local maps to its declaration and whether it has been read.
+    scopes: Vec<HashMap<Arc<str>, (Token, bool)>>
+}
+
+impl UnusedVariable {
+    pub fn new() -> Self {
+        UnusedVariable { scopes: Vec::new() }
+    }
+}
+
+impl Lint for UnusedVariable {
+    fn name(&self) -> &'static str {
+        "unused-variable"
+    }
+
+    fn check_expr(&mut self, expr: &Expr, _: &mut Context) {
+        if let Expr::Variable(ref variable) = *expr {
+            if let Some(scope) = self.scopes.iter_mut().rev().find(|scope| scope.contains_key(&variable.name.lexeme)) {
+                scope.get_mut(&variable.name.lexeme).unwrap().1 = true;
+            }
+        }
+    }
+
+    fn declare(&mut self, name: &Token, binding: Binding, _: &mut Context) {
+        if let Some(scope) = self.scopes.last_mut() {
+            // Anything but a variable counts as read, though it still shadows outer names.
+            scope.insert(name.lexeme.clone(), (name.clone(), binding != Binding::Variable));
+        }
+    }
+
+    fn enter_scope(&mut self, _: &mut Context) {
+        self.scopes.push(HashMap::new());
+    }
+
+    fn leave_scope(&mut self, context: &mut Context) {
+        for (_, (name, used)) in self.scopes.pop().unwrap_or_default() {
+            if !used {
+                context.report(&name, format!("Local variable '{}' is never read.", name.lexeme));
+            }
+        }
+    }
+}
+
+/// Statements following a return in the same block. Only the first is reported; the rest are
+/// unreachable for the same reason.
+pub struct UnreachableCode;
+
+impl Lint for UnreachableCode {
+    fn name(&self) -> &'static str {
+        "unreachable-code"
+    }
+
+    fn check_statements(&mut self, statements: &[Stmt], context: &mut Context) {
+        let position = statements.iter().position(|statement| match *statement {
+            Stmt::Return(_) => true,
+            _ => false
+        });
+
+        if let Some(statement) = position.and_then(|position| statements.get(position + 1)) {
+            context.report_span(statement.span(), "Unreachable code.".to_string());
+        }
+    }
+}
+
+/// A bare assignment used as an `if`, `while` or `for` condition, which is almost always a
+/// mistyped '=='. Wrapping it in an extra pair of parentheses says it's deliberate.
+pub struct AssignmentInCondition;
+
+impl Lint for AssignmentInCondition {
+    fn name(&self) -> &'static str {
+        "assignment-in-condition"
+    }
+
+    fn check_stmt(&mut self, stmt: &Stmt, context: &mut Context) {
+        let condition = match *stmt {
+            Stmt::If(ref v) => &v.condition,
+            Stmt::While(ref v) => &v.condition,
+            _ => return
+        };
+
+        if let Expr::Assign(ref assign) = *condition {
+            context.report_span(assign.span, format!("Assignment to '{}' used as a condition. Did you mean '=='?", assign.name.lexeme));
+        }
+    }
+}
+
+/// Local declarations that hide a name from an enclosing scope. Allowed unless turned on,
+/// since shadowing is often deliberate.
+pub struct Shadowing {
+    scopes: Vec<Vec<Token>>
+}
+
+impl Shadowing {
+    pub fn new() -> Self {
+        // The outermost scope holds the program's globals.
+        Shadowing { scopes: vec![Vec::new()] }
+    }
+}
+
+impl Lint for Shadowing {
+    fn name(&self) -> &'static str {
+        "shadowing"
+    }
+
+    fn default_severity(&self) -> Severity {
+        Severity::Allow
+    }
+
+    fn declare(&mut self, name: &Token, _: Binding, context: &mut Context) {
+        let (current, enclosing) = self.scopes.split_last_mut().unwrap();
+        let shadowed = enclosing.iter().rev().flat_map(|scope| scope.iter()).find(|declared| declared.lexeme == name.lexeme);
+        if let Some(shadowed) = shadowed {
+            context.report(name, format!("'{}' shadows the declaration on line {}.", name.lexeme, shadowed.line));
+        }
+        current.push(name.clone());
+    }
+
+    fn enter_scope(&mut self, _: &mut Context) {
+        self.scopes.push(Vec::new());
+    }
+
+    fn leave_scope(&mut self, _: &mut Context) {
+        self.scopes.pop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser::Parser;
+    use resolver::Resolver;
+    use scanner::Scanner;
+    use CollectingReporter;
+
+    fn parse(source: &str) -> Vec<Stmt> {
+        let (tokens, errors) = Scanner::new(source).scan_tokens();
+        assert!(errors.is_empty(), "{:?}", errors);
+        let mut messages: Vec<String> = Vec::new();
+        let (mut statements, _) = Parser::new(&tokens).parse(&mut messages);
+        Resolver::new(&mut messages).resolve(&mut statements);
+        assert!(messages.is_empty(), "{:?}", messages);
+        statements
+    }
+
+    // Whether the linter denied the program, and everything it reported.
+    fn check(linter: &mut Linter, source: &str) -> (bool, Vec<String>) {
+        let mut reporter = CollectingReporter::new();
+        let denied = linter.check(&parse(source), &mut reporter);
+        (denied, reporter.take())
+    }
+
+    fn lint(lint: Box<dyn Lint>, severity: Severity, source: &str) -> Vec<String> {
+        let mut linter = Linter::new();
+        let name = lint.name();
+        linter.register(lint);
+        linter.set_severity(name, severity);
+        check(&mut linter, source).1
+    }
+
+    // Reports every print statement.
+    struct NoPrint;
+
+    impl Lint for NoPrint {
+        fn name(&self) -> &'static str {
+            "no-print"
+        }
+
+        fn check_stmt(&mut self, stmt: &Stmt, context: &mut Context) {
+            if let Stmt::Print(_, span) = *stmt {
+                context.report_span(span, "Don't print.".to_string());
+            }
+        }
+    }
+
+    #[test]
+    fn severity_decides_where_findings_go() {
+        let mut linter = Linter::new();
+        linter.register(Box::new(NoPrint));
+        assert_eq!(linter.severity("no-print"), Some(Severity::Warn));
+        assert_eq!(check(&mut linter, "print 1;\n{ print 2; }"), (false, vec![
+            "[line 1, column 1 ] Warning : Don't print.".to_string(),
+            "[line 2, column 3 ] Warning : Don't print.".to_string()
+        ]));
+
+        assert!(linter.set_severity("no-print", Severity::Deny));
+        assert_eq!(check(&mut linter, "print 1;"), (true, vec!["[line 1, column 1 ] Error  : Don't print.".to_string()]));
+
+        assert!(linter.set_severity("no-print", Severity::Allow));
+        assert_eq!(check(&mut linter, "print 1;"), (false, Vec::new()));
+    }
+
+    #[test]
+    fn set_severity_refuses_unknown_names() {
+        let mut linter = Linter::with_default_lints();
+        assert!(!linter.set_severity("no-such-lint", Severity::Deny));
+        assert_eq!(linter.severity("no-such-lint"), None);
+        assert_eq!(linter.severity("unused-variable"), Some(Severity::Warn));
+        assert_eq!(linter.severity("shadowing"), Some(Severity::Allow));
+    }
+
+    #[test]
+    fn unused_variable_reports_locals_that_are_never_read() {
+        let source = "var global = 1;\nfun f(p) { var unused = 1; var read = 2; print read; unused = 3; }";
+        assert_eq!(lint(Box::new(UnusedVariable::new()), Severity::Warn, source), vec![
+            "[line 2, column 16 ] Warning : Local variable 'unused' is never read.".to_string()
+        ]);
+    }
+
+    #[test]
+    fn unused_variable_leaves_parameters_and_read_locals_alone() {
+        let source = "fun f(a, b) { { var x = 1; fun g() { return x; } g(); } }";
+        assert!(lint(Box::new(UnusedVariable::new()), Severity::Warn, source).is_empty());
+    }
+
+    #[test]
+    fn unreachable_code_reports_only_the_first_statement_after_return() {
+        let source = "fun f() {\n  return 1;\n  print 2;\n  print 3;\n}";
+        assert_eq!(lint(Box::new(UnreachableCode), Severity::Warn, source), vec![
+            "[line 3, column 3 ] Warning : Unreachable code.".to_string()
+        ]);
+    }
+
+    #[test]
+    fn unreachable_code_ignores_a_return_in_a_branch() {
+        let source = "fun f(x) { if (x) return 1; print 2; { return 3; } }";
+        assert!(lint(Box::new(UnreachableCode), Severity::Warn, source).is_empty());
+    }
+
+    #[test]
+    fn assignment_in_condition_warns_on_a_bare_assignment() {
+        let source = "var x;\nif (x = 1) print x;\nwhile (x = nil) {}";
+        assert_eq!(lint(Box::new(AssignmentInCondition), Severity::Warn, source), vec![
+            "[line 2, column 5 ] Warning : Assignment to 'x' used as a condition. Did you mean '=='?".to_string(),
+            "[line 3, column 8 ] Warning : Assignment to 'x' used as a condition. Did you mean '=='?".to_string()
+        ]);
+    }
+
+    #[test]
+    fn assignment_in_condition_accepts_extra_parentheses() {
+        let source = "var x; if ((x = 1)) print x; if (x == 1) print x;";
+        assert!(lint(Box::new(AssignmentInCondition), Severity::Warn, source).is_empty());
+    }
+
+    #[test]
+    fn shadowing_is_silent_until_enabled() {
+        let source = "var a = 1;\n{ var a = 2; print a; }";
+        let mut linter = Linter::with_default_lints();
+        assert_eq!(check(&mut linter, source), (false, Vec::new()));
+
+        linter.set_severity("shadowing", Severity::Warn);
+        assert_eq!(check(&mut linter, source), (false, vec![
+            "[line 2, column 7 ] Warning : 'a' shadows the declaration on line 1.".to_string()
+        ]));
+    }
+
+    #[test]
+    fn shadowing_ignores_names_in_sibling_scopes() {
+        let source = "{ var a = 1; print a; } { var a = 2; print a; } fun f(b) {} fun g(b) {}";
+        assert!(lint(Box::new(Shadowing::new()), Severity::Warn, source).is_empty());
+    }
+}
@@ -0,0 +1,188 @@
+use std::mem;
+use std::sync::Arc;
+use ast::*;
+use scanner::Span;
+
+/// Removes code that can never run: branches whose condition is a constant that rules them
+/// out, loops whose condition is constantly false, and statements following a return in the
+/// same block. Only literal conditions are treated as constant, so nothing with a side effect
+/// is ever dropped. Scopes are left as they were, so it's safe to run after resolving.
+pub fn eliminate_dead_code(statements: &mut Vec<Stmt>) {
+    let original = mem::replace(statements, Vec::new());
+    for statement in original {
+        let terminates = match statement {
+            Stmt::Return(_) => true,
+            _ => false
+        };
+
+        if let Some(statement) = stmt(statement) {
+            statements.push(statement);
+        }
+
+        if terminates {
+            break;
+        }
+    }
+}
+
+// Returns None when the whole statement can go.
+fn stmt(statement: Stmt) -> Option<Stmt> {
+    match statement {
+        Stmt::If(v) => match constant_truth(&v.condition) {
+            Some(true) => stmt(*v.then_branch),
+            Some(false) => v.else_branch.and_then(|else_branch| stmt(*else_branch)),
+            None => Some(Stmt::If(If {
+                then_branch: Box::new(branch(*v.then_branch, v.span)),
+                else_branch: v.else_branch.and_then(|else_branch| stmt(*else_branch)).map(Box::new),
+                ..v
+            }))
+        },
+        Stmt::While(v) => match constant_truth(&v.condition) {
+            Some(false) => None,
+            _ => Some(Stmt::While(While {
+                body: Box::new(branch(*v.body, v.span)),
+                ..v
+            }))
+        },
+        Stmt::Block(mut v) => {
+            eliminate_dead_code(&mut v.statements);
+            Some(Stmt::Block(v))
+        },
+        Stmt::Function(mut v) => {
+            eliminate_dead_code(&mut Arc::make_mut(&mut v).body);
+            Some(Stmt::Function(v))
+        },
+        Stmt::Class(mut v) => {
+            for method in v.methods.iter_mut() {
+                eliminate_dead_code(&mut Arc::make_mut(method).body);
+            }
+            Some(Stmt::Class(v))
+        },
+        other => Some(other)
+    }
+}
+
+// A branch has to be some statement, so one removed entirely becomes an empty block.
+fn branch(branch: Stmt, span: Span) -> Stmt {
+    stmt(branch).unwrap_or_else(|| Stmt::Block(Block { statements: Vec::new(), span: span }))
+}
+
+fn constant_truth(expr: &Expr) -> Option<bool> {
+    match *expr {
+        Expr::Literal(Literal::Bool(value), _) => Some(value),
+        Expr::Literal(Literal::Nil, _) => Some(false),
+        Expr::Literal(Literal::Number(_), _) | Expr::Literal(Literal::String(_), _) => Some(true),
+        Expr::Grouping(ref v) => constant_truth(&v.expression),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::io::{self, Write};
+    use std::rc::Rc;
+    use interpreter::Interpreter;
+    use parser::Parser;
+    use resolver::Resolver;
+    use scanner::Scanner;
+
+    #[derive(Clone)]
+    struct Buffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn resolve(source: &str) -> Vec<Stmt> {
+        let (tokens, errors) = Scanner::new(source).scan_tokens();
+        assert!(errors.is_empty(), "{:?}", errors);
+        let mut messages: Vec<String> = Vec::new();
+        let (mut statements, _) = Parser::new(&tokens).parse(&mut messages);
+        Resolver::new(&mut messages).resolve(&mut statements);
+        assert!(messages.is_empty(), "{:?}", messages);
+        statements
+    }
+
+    fn optimize(source: &str) -> Vec<Stmt> {
+        let mut statements = resolve(source);
+        eliminate_dead_code(&mut statements);
+        statements
+    }
+
+    // What the program prints.
+    fn run(statements: &Vec<Stmt>) -> String {
+        let output = Buffer(Rc::new(RefCell::new(Vec::new())));
+        let mut interpreter = Interpreter::with_stdlib();
+        interpreter.set_output(Box::new(output.clone()));
+        interpreter.interpret(statements).unwrap();
+        let bytes = output.0.borrow().clone();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[test]
+    fn drops_branches_and_loops_that_cannot_run() {
+        let statements = optimize("if (false) print 1; while (nil) print 2; if ((0)) print 3; else print 4;");
+        match statements[..] {
+            [Stmt::Print(Expr::Literal(Literal::Number(number), _), _)] => assert_eq!(number, 3.0),
+            _ => panic!("unexpected {:?}", statements)
+        }
+    }
+
+    #[test]
+    fn drops_statements_after_a_return() {
+        let statements = optimize("fun f() { print 1; { return 2; print 3; } print 4; return 5; print 6; }");
+        match statements[..] {
+            [Stmt::Function(ref function)] => match function.body[..] {
+                [Stmt::Print(..), Stmt::Block(ref block), Stmt::Print(..), Stmt::Return(_)] => {
+                    assert_eq!(block.statements.len(), 1);
+                },
+                _ => panic!("unexpected {:?}", function.body)
+            },
+            _ => panic!("unexpected {:?}", statements)
+        }
+    }
+
+    #[test]
+    fn a_removed_branch_becomes_an_empty_block() {
+        let statements = optimize("var x; if (x) if (false) print 1; while (x) if (nil) print 2;");
+        match statements[..] {
+            [Stmt::Var(_), Stmt::If(ref v), Stmt::While(ref w)] => {
+                for branch in &[&v.then_branch, &w.body] {
+                    match ***branch {
+                        Stmt::Block(ref block) => assert!(block.statements.is_empty()),
+                        ref other => panic!("unexpected {:?}", other)
+                    }
+                }
+            },
+            _ => panic!("unexpected {:?}", statements)
+        }
+    }
+
+    #[test]
+    fn a_resolved_program_runs_the_same_afterwards() {
+        let source = "fun make() {\n\
+                        var n = 0;\n\
+                        if (false) { var hidden = 1; print hidden; }\n\
+                        fun add(x) { n = n + x; return n; print \"never\"; }\n\
+                        return add;\n\
+                        print \"never\";\n\
+                      }\n\
+                      var add = make();\n\
+                      { var a = 1; while (nil) { var b = 2; } if (true) { var c = a + 1; print add(c); } else print \"no\"; }\n\
+                      { var d = 10; if (false) {} else { var e = d; print add(e); } }";
+        let statements = resolve(source);
+        let mut optimized = statements.clone();
+        eliminate_dead_code(&mut optimized);
+        assert_eq!(run(&statements), "2\n12\n");
+        assert_eq!(run(&optimized), "2\n12\n");
+    }
+}
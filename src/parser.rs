@@ -1,117 +1,308 @@
-use scanner::{TokenType, Token};
+use scanner::{KEYWORDS, LiteralValue, Span, TokenType, Token};
 use ast::*;
-
-pub struct Parser {
-    tokens: Vec<Token>,
-    current: usize
+use ErrorReporter;
+use interpreter::stack_left;
+use suggest;
+use std::error::Error;
+use std::mem;
+use std::fmt::{Display, Result as FmtResult, Formatter};
+use std::sync::Arc;
+
+pub const MAX_ARGUMENTS: usize = 255;
+/// How deeply expressions may nest before the parser gives up rather than overflow the stack.
+/// It gives up sooner if the thread it runs on is short of stack.
+pub const MAX_NESTING_DEPTH: usize = 200;
+
+pub struct Parser<'a> {
+    tokens: &'a [Token],
+    current: usize,
+    max_arguments: usize,
+    max_depth: usize,
+    depth: usize,
+    implicit_semicolon: bool,
+    errors: Vec<ParseError>,
+    last_error_at: Option<usize>
 }
 
-impl Parser {
-    pub fn new(tokens: Vec<Token>) -> Self {
+impl<'a> Parser<'a> {
+    pub fn new(tokens: &'a [Token]) -> Self {
         Parser {
             tokens: tokens,
-            current: 0
+            current: 0,
+            max_arguments: MAX_ARGUMENTS,
+            max_depth: MAX_NESTING_DEPTH,
+            depth: 0,
+            implicit_semicolon: false,
+            errors: Vec::new(),
+            last_error_at: None
         }
     }
 
-    pub fn parse(&mut self, lox: &mut super::Lox) -> Vec<Stmt> {
+    pub fn set_max_arguments(&mut self, max_arguments: usize) {
+        self.max_arguments = max_arguments;
+    }
+
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    /// Lets the last statement omit its ';' when it runs into the end of input, as in the REPL.
+    pub fn set_implicit_semicolon(&mut self, implicit_semicolon: bool) {
+        self.implicit_semicolon = implicit_semicolon;
+    }
+
+    /// Parses the whole program, recovering after each syntax error. Returns the statements that
+    /// parsed along with every error found; each error is also reported to `reporter` as it is found.
+    pub fn parse(&mut self, reporter: &mut dyn ErrorReporter) -> (Vec<Stmt>, Vec<ParseError>) {
         let mut statements = Vec::new();
 
         while !self.is_at_end() {
-            if let Some(statement) = self.declaration(lox) {
-                statements.push(statement);
-            }
+            statements.push(self.declaration(reporter));
+        }
+
+        (statements, mem::replace(&mut self.errors, Vec::new()))
+    }
+
+    /// Parses the next top-level declaration, or returns None at the end of input. Along with the
+    /// statement comes the span it was parsed from and the errors found in it.
+    pub fn next_declaration(&mut self, reporter: &mut dyn ErrorReporter) -> Option<(Stmt, Span, Vec<ParseError>)> {
+        if self.is_at_end() {
+            return None;
         }
 
-        statements
+        let start = self.peek().span;
+        let statement = self.declaration(reporter);
+        Some((statement, self.span_from(start), mem::replace(&mut self.errors, Vec::new())))
+    }
+
+    /// The index of the next token to be parsed.
+    pub fn position(&self) -> usize {
+        self.current
+    }
+
+    /// Parses a single expression that must make up the whole input.
+    pub fn parse_expression(&mut self, reporter: &mut dyn ErrorReporter) -> Result<Expr, ParseError> {
+        let expr = self.expression(reporter)?;
+
+        if !self.is_at_end() {
+            let token = self.peek();
+            return self.error(reporter, token, "Expect end of expression.".to_string());
+        }
+
+        // The expression may have parsed with error nodes standing in for missing parts.
+        match self.errors.drain(..).next() {
+            Some(error) => Err(error),
+            None => Ok(expr)
+        }
     }
 
-    fn declaration(&mut self, lox: &mut super::Lox) -> Option<Stmt> {
-        match if self.match_token_types(vec![TokenType::Var]) {
-            self.var_declaration(lox)
+    // A declaration that fails to parse is skipped up to where parsing can carry on, and
+    // replaced with an error node covering what was skipped.
+    fn declaration(&mut self, reporter: &mut dyn ErrorReporter) -> Stmt {
+        let start = self.peek().span;
+
+        match if self.match_token_types(vec![TokenType::Class]) {
+            self.class_declaration(reporter)
+        } else if self.match_token_types(vec![TokenType::Fun]) {
+            self.function(reporter, "function")
+        } else if self.match_token_types(vec![TokenType::Var]) {
+            self.var_declaration(reporter)
         } else {
-            self.statement(lox)
+            self.statement(reporter)
         } {
-            Ok(stmt) => Some(stmt),
-            Err(_) => {
+            Ok(stmt) => stmt,
+            Err(error) => {
                 self.synchronize();
-                None
+                Stmt::Error(ErrorNode {
+                    token: error.token,
+                    span: self.span_from(start)
+                })
+            }
+        }
+    }
+
+    fn class_declaration(&mut self, reporter: &mut dyn ErrorReporter) -> Result<Stmt, ParseError> {
+        let start = self.previous().span;
+        let name = self.consume(reporter, TokenType::Identifier, "Expect class name.".to_string())?.clone();
+
+        let superclass = if self.match_token_types(vec![TokenType::Less]) {
+            let superclass = self.consume(reporter, TokenType::Identifier, "Expect superclass name.".to_string())?.clone();
+            Some(Variable { span: superclass.span, name: superclass, slot: None })
+        } else {
+            None
+        };
+
+        let mut mixins = Vec::new();
+        if self.match_token_types(vec![TokenType::With]) {
+            loop {
+                let mixin = self.consume(reporter, TokenType::Identifier, "Expect mixin name.".to_string())?.clone();
+                mixins.push(Variable { span: mixin.span, name: mixin, slot: None });
+                if !self.match_token_types(vec![TokenType::Comma]) {
+                    break;
+                }
             }
         }
+
+        self.consume(reporter, TokenType::LeftBrace, "Expect '{' before class body.".to_string())?;
+
+        let mut methods = Vec::new();
+        while !self.check(TokenType::RightBrace) && !self.is_at_end() {
+            methods.push(Arc::new(self.function_body(reporter, "method")?));
+        }
+
+        self.consume(reporter, TokenType::RightBrace, "Expect '}' after class body.".to_string())?;
+
+        Ok(Stmt::Class(Class {
+            name: name,
+            superclass: superclass,
+            mixins: mixins,
+            methods: methods,
+            span: self.span_from(start)
+        }))
+    }
+
+    fn function(&mut self, reporter: &mut dyn ErrorReporter, kind: &str) -> Result<Stmt, ParseError> {
+        Ok(Stmt::Function(Arc::new(self.function_body(reporter, kind)?)))
     }
 
-    fn var_declaration(&mut self, lox: &mut super::Lox) -> Result<Stmt, ()> {
-        let name = self.consume(lox, TokenType::Identifier, "Expect variable name.".to_string())?;
+    // Parses a function's name, parameters and body. Methods have no `fun` keyword, so the span
+    // starts from the name.
+    fn function_body(&mut self, reporter: &mut dyn ErrorReporter, kind: &str) -> Result<Function, ParseError> {
+        let start = if self.previous().token_type == TokenType::Fun { self.previous().span } else { self.peek().span };
+        let name = self.consume(reporter, TokenType::Identifier, format!("Expect {} name.", kind))?.clone();
+        self.consume(reporter, TokenType::LeftParen, format!("Expect '(' after {} name.", kind))?;
+
+        let mut params = Vec::new();
+        let mut param_types = Vec::new();
+        if !self.check(TokenType::RightParen) {
+            while {
+                if params.len() >= self.max_arguments {
+                    let message = format!("Cannot have more than {} parameters.", self.max_arguments);
+                    self.error::<Stmt>(reporter, self.peek(), message).unwrap_err();
+                }
+                params.push(self.consume(reporter, TokenType::Identifier, "Expect parameter name.".to_string())?.clone());
+                param_types.push(self.type_annotation(reporter, TokenType::Colon)?);
+                self.match_token_types(vec![TokenType::Comma])
+            } {};
+        }
+
+        self.consume(reporter, TokenType::RightParen, "Expect ')' after parameters.".to_string())?;
+        let return_type = self.type_annotation(reporter, TokenType::Arrow)?;
+        self.consume(reporter, TokenType::LeftBrace, format!("Expect '{{' before {} body.", kind))?;
+        let body = self.block(reporter)?;
+
+        Ok(Function {
+            name: name,
+            params: params,
+            param_types: param_types,
+            return_type: return_type,
+            body: body,
+            span: self.span_from(start)
+        })
+    }
+
+    // Types are only checked by the type-mismatch lint, so any name is accepted here.
+    fn type_annotation(&mut self, reporter: &mut dyn ErrorReporter, introducer: TokenType) -> Result<Option<Token>, ParseError> {
+        if !self.match_token_types(vec![introducer]) {
+            return Ok(None);
+        }
+
+        Ok(Some(self.consume(reporter, TokenType::Identifier, "Expect type name.".to_string())?.clone()))
+    }
+
+    fn var_declaration(&mut self, reporter: &mut dyn ErrorReporter) -> Result<Stmt, ParseError> {
+        let start = self.previous().span;
+        let name = self.consume(reporter, TokenType::Identifier, "Expect variable name.".to_string())?.clone();
+        let type_annotation = self.type_annotation(reporter, TokenType::Colon)?;
 
         let mut initializer = None;
         if self.match_token_types(vec![TokenType::Equal]) {
-            initializer = Some(self.expression(lox)?);
+            initializer = Some(self.expression(reporter)?);
         }
 
-        self.consume(lox, TokenType::Semicolon, "Expect ';' after variable declaration.".to_string())?;
+        self.consume_semicolon(reporter, "Expect ';' after variable declaration.".to_string())?;
         Ok(Stmt::Var(Var {
             name: name,
-            initializer: initializer
+            type_annotation: type_annotation,
+            initializer: initializer,
+            span: self.span_from(start)
         }))
     }
 
-    fn statement(&mut self, lox: &mut super::Lox) -> Result<Stmt, ()> {
-        if self.match_token_types(vec![TokenType::For]) {
-            self.for_statement(lox)
+    fn statement(&mut self, reporter: &mut dyn ErrorReporter) -> Result<Stmt, ParseError> {
+        if self.match_token_types(vec![TokenType::Defer]) {
+            self.defer_statement(reporter)
+        } else if self.match_token_types(vec![TokenType::For]) {
+            self.for_statement(reporter)
         } else if self.match_token_types(vec![TokenType::If]) {
-            self.if_statement(lox)
+            self.if_statement(reporter)
         } else if self.match_token_types(vec![TokenType::Print]) {
-            self.print_statement(lox)
+            self.print_statement(reporter)
+        } else if self.match_token_types(vec![TokenType::Return]) {
+            self.return_statement(reporter)
         } else if self.match_token_types(vec![TokenType::While]) {
-            self.while_statement(lox)
+            self.while_statement(reporter)
         } else if self.match_token_types(vec![TokenType::LeftBrace]) {
-            Ok(Stmt::Block(Block { statements: self.block(lox)? }))
+            let start = self.previous().span;
+            let statements = self.block(reporter)?;
+            Ok(Stmt::Block(Block { statements: statements, span: self.span_from(start) }))
         } else {
-            self.expression_statement(lox)
+            self.expression_statement(reporter)
         }
     }
 
-    fn for_statement(&mut self, lox: &mut super::Lox) -> Result<Stmt, ()> {
-        self.consume(lox, TokenType::LeftParen, "Expect '(' after 'for'.".to_string())?;
+    fn defer_statement(&mut self, reporter: &mut dyn ErrorReporter) -> Result<Stmt, ParseError> {
+        let start = self.previous().span;
+        let value = self.expression(reporter)?;
+        self.consume_semicolon(reporter, "Expect ';' after deferred expression.".to_string())?;
+        Ok(Stmt::Defer(Arc::new(value), self.span_from(start)))
+    }
+
+    fn for_statement(&mut self, reporter: &mut dyn ErrorReporter) -> Result<Stmt, ParseError> {
+        let start = self.previous().span;
+        self.consume(reporter, TokenType::LeftParen, "Expect '(' after 'for'.".to_string())?;
 
         let initializer = if self.match_token_types(vec![TokenType::Semicolon]) {
             None
         } else if self.match_token_types(vec![TokenType::Var]) {
-            Some(self.var_declaration(lox)?)
+            Some(self.var_declaration(reporter)?)
         } else {
-            Some(self.expression_statement(lox)?)
+            Some(self.expression_statement(reporter)?)
         };
 
         let condition = if self.check(TokenType::Semicolon) {
-            Expr::Literal(Literal::Bool(true))
+            Expr::Literal(Literal::Bool(true), self.peek().span)
         } else {
-            self.expression(lox)?
+            self.expression(reporter)?
         };
 
-        self.consume(lox, TokenType::Semicolon, "Expect ';' after loop condition.".to_string())?;
+        self.consume(reporter, TokenType::Semicolon, "Expect ';' after loop condition.".to_string())?;
 
         let increment = if self.check(TokenType::RightParen) {
             None
         } else {
-            Some(self.expression(lox)?)
+            Some(self.expression(reporter)?)
         };
 
-        self.consume(lox, TokenType::RightParen, "Expect ')' after for clauses.".to_string())?;
+        self.consume(reporter, TokenType::RightParen, "Expect ')' after for clauses.".to_string())?;
 
-        let mut body = self.statement(lox)?;
+        let mut body = self.statement(reporter)?;
+        let span = self.span_from(start);
 
         if let Some(increment) = increment {
             body = Stmt::Block(Block {
                 statements: vec![
                     body,
-                    Stmt::Expression(increment)
-                ]
+                    Stmt::Expression(increment, span)
+                ],
+                span: span
             });
         }
 
         body = Stmt::While(While {
             condition: condition,
-            body: Box::new(body)
+            body: Box::new(body),
+            span: span
         });
 
         if let Some(initializer) = initializer {
@@ -119,21 +310,23 @@ impl Parser {
                 statements: vec![
                     initializer,
                     body
-                ]
+                ],
+                span: span
             })
         }
 
         Ok(body)
     }
 
-    fn if_statement(&mut self, lox: &mut super::Lox) -> Result<Stmt, ()> {
-        self.consume(lox, TokenType::LeftParen, "Expect '(' after 'if'.".to_string())?;
-        let condition = self.expression(lox)?;
-        self.consume(lox, TokenType::RightParen, "Expect ')' after if condition.".to_string())?;
+    fn if_statement(&mut self, reporter: &mut dyn ErrorReporter) -> Result<Stmt, ParseError> {
+        let start = self.previous().span;
+        self.consume(reporter, TokenType::LeftParen, "Expect '(' after 'if'.".to_string())?;
+        let condition = self.expression(reporter)?;
+        self.consume(reporter, TokenType::RightParen, "Expect ')' after if condition.".to_string())?;
 
-        let then_branch = Box::new(self.statement(lox)?);
+        let then_branch = Box::new(self.statement(reporter)?);
         let else_branch = if self.match_token_types(vec![TokenType::Else]) {
-            Some(Box::new(self.statement(lox)?))
+            Some(Box::new(self.statement(reporter)?))
         } else {
             None
         };
@@ -141,189 +334,177 @@ impl Parser {
         Ok(Stmt::If(If {
             condition: condition,
             then_branch: then_branch,
-            else_branch: else_branch
+            else_branch: else_branch,
+            span: self.span_from(start)
         }))
     }
 
-    fn print_statement(&mut self, lox: &mut super::Lox) -> Result<Stmt, ()> {
-        let value = self.expression(lox)?;
-        self.consume(lox, TokenType::Semicolon, "Expect ';' after value.".to_string())?;
-        Ok(Stmt::Print(value))
+    fn print_statement(&mut self, reporter: &mut dyn ErrorReporter) -> Result<Stmt, ParseError> {
+        let start = self.previous().span;
+        let value = self.expression(reporter)?;
+        self.consume_semicolon(reporter, "Expect ';' after value.".to_string())?;
+        Ok(Stmt::Print(value, self.span_from(start)))
     }
 
-    fn while_statement(&mut self, lox: &mut super::Lox) -> Result<Stmt, ()> {
-        self.consume(lox, TokenType::LeftParen, "Expect '(' after 'while'.".to_string())?;
-        let condition = self.expression(lox)?;
-        self.consume(lox, TokenType::RightParen, "Expect ')' after condition.".to_string())?;
-        let body = self.statement(lox)?;
+    fn return_statement(&mut self, reporter: &mut dyn ErrorReporter) -> Result<Stmt, ParseError> {
+        let keyword = self.previous().clone();
+        let value = if self.check(TokenType::Semicolon) || (self.implicit_semicolon && self.is_at_end()) {
+            None
+        } else {
+            Some(self.expression(reporter)?)
+        };
+
+        self.consume_semicolon(reporter, "Expect ';' after return value.".to_string())?;
+        Ok(Stmt::Return(Return {
+            span: self.span_from(keyword.span),
+            keyword: keyword,
+            value: value
+        }))
+    }
+
+    fn while_statement(&mut self, reporter: &mut dyn ErrorReporter) -> Result<Stmt, ParseError> {
+        let start = self.previous().span;
+        self.consume(reporter, TokenType::LeftParen, "Expect '(' after 'while'.".to_string())?;
+        let condition = self.expression(reporter)?;
+        self.consume(reporter, TokenType::RightParen, "Expect ')' after condition.".to_string())?;
+        let body = self.statement(reporter)?;
 
         Ok(Stmt::While(While {
             condition: condition,
-            body: Box::new(body)
+            body: Box::new(body),
+            span: self.span_from(start)
         }))
     }
 
-    fn block(&mut self, lox: &mut super::Lox) -> Result<Vec<Stmt>, ()> {
+    fn block(&mut self, reporter: &mut dyn ErrorReporter) -> Result<Vec<Stmt>, ParseError> {
         let mut statements = Vec::new();
 
         while !self.check(TokenType::RightBrace) && !self.is_at_end() {
-            if let Some(statement) = self.declaration(lox) {
-                statements.push(statement);
-            }
+            statements.push(self.declaration(reporter));
         }
 
-        self.consume(lox, TokenType::RightBrace, "Expect '}' after block.".to_string())?;
+        self.consume(reporter, TokenType::RightBrace, "Expect '}' after block.".to_string())?;
         Ok(statements)
     }
 
-    fn expression_statement(&mut self, lox: &mut super::Lox) -> Result<Stmt, ()> {
-        let expr = self.expression(lox)?;
+    fn expression_statement(&mut self, reporter: &mut dyn ErrorReporter) -> Result<Stmt, ParseError> {
+        let first = self.peek();
+        let expr = self.expression(reporter)?;
 
-        if !self.is_at_end() {
-            self.consume(lox, TokenType::Semicolon, "Expect ';' after expression.".to_string())?;
+        // A statement that starts with a misspelt keyword usually parses as an expression up to
+        // the point where the keyword's syntax carries on, so that's where to point it out.
+        let mut message = "Expect ';' after expression.".to_string();
+        if first.token_type == TokenType::Identifier {
+            if let Some(keyword) = suggest::closest(&first.lexeme, KEYWORDS.iter().map(|&(keyword, _)| keyword)) {
+                message.push_str(&format!(" Did you mean '{}'?", keyword));
+            }
         }
 
-        Ok(Stmt::Expression(expr))
+        self.consume_semicolon(reporter, message)?;
+        Ok(Stmt::Expression(expr, self.span_from(first.span)))
     }
 
-    fn expression(&mut self, lox: &mut super::Lox) -> Result<Expr, ()> {
-        self.assignment(lox)
+    fn expression(&mut self, reporter: &mut dyn ErrorReporter) -> Result<Expr, ParseError> {
+        self.enter_nesting(reporter)?;
+        let expr = self.assignment(reporter);
+        self.depth -= 1;
+        expr
     }
 
-    fn assignment(&mut self, lox: &mut super::Lox) -> Result<Expr, ()> {
-        let expr = self.or(lox)?;
+    fn assignment(&mut self, reporter: &mut dyn ErrorReporter) -> Result<Expr, ParseError> {
+        let expr = self.binary(reporter, Precedence::Or)?;
 
         if self.match_token_types(vec![TokenType::Equal]) {
             let equals = self.previous();
-            let value = self.assignment(lox)?;
+            self.enter_nesting(reporter)?;
+            let value = self.assignment(reporter);
+            self.depth -= 1;
+            let value = value?;
 
             match expr {
                 Expr::Variable(v) => Ok(Expr::Assign(Assign {
                     name: v.name,
+                    span: v.span.to(value.span()),
+                    value: Box::new(value),
+                    slot: None
+                })),
+                Expr::Get(get) => Ok(Expr::Set(Set {
+                    span: get.span.to(value.span()),
+                    object: get.object,
+                    name: get.name,
                     value: Box::new(value)
                 })),
-                _ => self.error(lox, equals, "Invalid assignment target.".to_string())
+                _ => self.error(reporter, equals, "Invalid assignment target.".to_string())
             }
         } else {
             Ok(expr)
         }
     }
 
-    fn or(&mut self, lox: &mut super::Lox) -> Result<Expr, ()> {
-        let mut expr = self.and(lox)?;
-
-        while self.match_token_types(vec![TokenType::Or]) {
-            let operator = self.previous();
-            let right = self.and(lox)?;
-            expr = Expr::Logical(Logical {
-                left: Box::new(expr),
-                operator: operator,
-                right: Box::new(right)
-            });
-        }
-
-        Ok(expr)
-    }
-
-    fn and(&mut self, lox: &mut super::Lox) -> Result<Expr, ()> {
-        let mut expr = self.equality(lox)?;
-
-        while self.match_token_types(vec![TokenType::And]) {
-            let operator = self.previous();
-            let right = self.equality(lox)?;
-            expr = Expr::Logical(Logical {
-                left: Box::new(expr),
-                operator: operator,
-                right: Box::new(right)
-            });
-        }
-
-        Ok(expr)
-    }
-
-    fn equality(&mut self, lox: &mut super::Lox) -> Result<Expr, ()> {
-        let mut expr = self.comparison(lox)?;
-
-        while self.match_token_types(vec![TokenType::BangEqual, TokenType::EqualEqual]) {
-            let operator = self.previous();
-            let right = self.comparison(lox)?;
-            expr = Expr::Binary(Binary {
-                left: Box::new(expr),
-                operator: operator,
-                right: Box::new(right)
-            });
-        }
-
-        Ok(expr)
-    }
+    // Parses a chain of infix operators binding at least as tightly as `precedence`. Each
+    // operator's right operand only takes operators that bind tighter, so they associate left.
+    fn binary(&mut self, reporter: &mut dyn ErrorReporter, precedence: Precedence) -> Result<Expr, ParseError> {
+        let mut expr = self.unary(reporter)?;
 
-    fn comparison(&mut self, lox: &mut super::Lox) -> Result<Expr, ()> {
-        let mut expr = self.term(lox)?;
+        while let Some(infix) = infix_operator(self.peek().token_type) {
+            if infix.precedence < precedence {
+                break;
+            }
 
-        while self.match_token_types(vec![TokenType::Greater,TokenType::GreaterEqual, TokenType::Less, TokenType::LessEqual]) {
-            let operator = self.previous();
-            let right = self.term(lox)?;
-            expr = Expr::Binary(Binary {
-                left: Box::new(expr),
-                operator: operator,
-                right: Box::new(right)
-            });
+            let operator = self.advance().clone();
+            let right = self.binary(reporter, infix.precedence.next())?;
+            let span = expr.span().to(right.span());
+
+            expr = if infix.logical {
+                Expr::Logical(Logical {
+                    left: Box::new(expr),
+                    operator: operator,
+                    right: Box::new(right),
+                    span: span
+                })
+            } else {
+                Expr::Binary(Binary {
+                    left: Box::new(expr),
+                    operator: operator,
+                    right: Box::new(right),
+                    span: span
+                })
+            };
         }
 
         Ok(expr)
     }
 
-    fn term(&mut self, lox: &mut super::Lox) -> Result<Expr, ()> {
-        let mut expr = self.factor(lox)?;
-
-        while self.match_token_types(vec![TokenType::Minus, TokenType::Plus]) {
-            let operator = self.previous();
-            let right = self.factor(lox)?;
-            expr = Expr::Binary(Binary {
-                left: Box::new(expr),
-                operator: operator,
-                right: Box::new(right)
-            });
-        }
-
-        return Ok(expr);
-    }
-
-    fn factor(&mut self, lox: &mut super::Lox) -> Result<Expr, ()> {
-        let mut expr = self.unary(lox)?;
-
-        while self.match_token_types(vec![TokenType::Slash, TokenType::Star]) {
-            let operator = self.previous();
-            let right = self.unary(lox)?;
-            expr = Expr::Binary(Binary {
-                left: Box::new(expr),
-                operator: operator,
-                right: Box::new(right)
-            });
-        }
-
-        return Ok(expr);
-    }
-
-    fn unary(&mut self, lox: &mut super::Lox) -> Result<Expr, ()> {
+    fn unary(&mut self, reporter: &mut dyn ErrorReporter) -> Result<Expr, ParseError> {
         if self.match_token_types(vec![TokenType::Bang, TokenType::Minus]) {
-            let operator = self.previous();
-            let right = self.unary(lox)?;
+            let operator = self.previous().clone();
+            self.enter_nesting(reporter)?;
+            let right = self.unary(reporter);
+            self.depth -= 1;
+            let right = right?;
             return Ok(Expr::Unary(Unary {
+                span: operator.span.to(right.span()),
                 operator: operator,
                 right: Box::new(right)
             }));
         }
 
-        Ok(self.call(lox)?)
+        Ok(self.call(reporter)?)
     }
 
-    fn call(&mut self, lox: &mut super::Lox) -> Result<Expr, ()> {
-        let mut expr = self.primary(lox)?;
+    fn call(&mut self, reporter: &mut dyn ErrorReporter) -> Result<Expr, ParseError> {
+        let mut expr = self.primary(reporter)?;
 
         loop {
             if self.match_token_types(vec![TokenType::LeftParen]) {
-                expr = self.finish_call(lox, expr)?;
+                expr = self.finish_call(reporter, expr)?;
+            } else if self.match_token_types(vec![TokenType::Dot]) {
+                let name = self.consume(reporter, TokenType::Identifier, "Expect property name after '.'.".to_string())?.clone();
+                expr = Expr::Get(Get {
+                    span: expr.span().to(name.span),
+                    object: Box::new(expr),
+                    name: name
+                });
             } else {
                 break;
             }
@@ -332,59 +513,123 @@ impl Parser {
         Ok(expr)
     }
 
-    fn finish_call(&mut self, lox: &mut super::Lox, callee: Expr) -> Result<Expr, ()> {
+    fn finish_call(&mut self, reporter: &mut dyn ErrorReporter, callee: Expr) -> Result<Expr, ParseError> {
         let mut arguments = Vec::new();
         if !self.check(TokenType::RightParen) {
             while {
-                if arguments.len() >= 8 {
-                    self.error::<Expr>(lox, self.peek(), "Cannot have more than 8 arguments.".to_string()).unwrap_err();
+                if arguments.len() >= self.max_arguments {
+                    let message = format!("Cannot have more than {} arguments.", self.max_arguments);
+                    self.error::<Expr>(reporter, self.peek(), message).unwrap_err();
                 }
-                arguments.push(self.expression(lox)?);
+                arguments.push(self.expression(reporter)?);
                 self.match_token_types(vec![TokenType::Comma])
             } {};
         }
 
-        let paren = self.consume(lox, TokenType::RightParen, "Expect ')' after arguments.".to_string())?;
+        let paren = self.consume(reporter, TokenType::RightParen, "Expect ')' after arguments.".to_string())?.clone();
 
         return Ok(Expr::Call(Call {
+            span: callee.span().to(paren.span),
             callee: Box::new(callee),
             paren: paren,
             arguments: arguments
         }));
     }
 
-    fn primary(&mut self, lox: &mut super::Lox) -> Result<Expr, ()> {
+    fn primary(&mut self, reporter: &mut dyn ErrorReporter) -> Result<Expr, ParseError> {
         if self.match_token_types(vec![TokenType::False]) {
-            return Ok(Expr::Literal(Literal::Bool(false)));
+            return Ok(Expr::Literal(Literal::Bool(false), self.previous().span));
         }
         if self.match_token_types(vec![TokenType::True]) {
-            return Ok(Expr::Literal(Literal::Bool(true)));
+            return Ok(Expr::Literal(Literal::Bool(true), self.previous().span));
         }
         if self.match_token_types(vec![TokenType::Nil]) {
-            return Ok(Expr::Literal(Literal::Nil));
+            return Ok(Expr::Literal(Literal::Nil, self.previous().span));
+        }
+
+        if self.match_token_types(vec![TokenType::Number, TokenType::String]) {
+            let token = self.previous();
+            return Ok(Expr::Literal(match token.literal {
+                Some(LiteralValue::Number(number)) => Literal::Number(number),
+                Some(LiteralValue::String(ref string)) => Literal::String(string.clone()),
+                None => unreachable!()
+            }, token.span));
+        }
+
+        if self.match_token_types(vec![TokenType::This]) {
+            let keyword = self.previous().clone();
+            return Ok(Expr::This(This { span: keyword.span, keyword: keyword, slot: None }));
         }
 
-        if self.match_token_types(vec![TokenType::Number(12.0), TokenType::String("".to_string())]) {
-            return Ok(Expr::Literal(match self.previous().token_type {
-                TokenType::Number(number) => Literal::Number(number),
-                TokenType::String(string) => Literal::String(string),
-                _ => panic!()
+        if self.match_token_types(vec![TokenType::Super]) {
+            let keyword = self.previous().clone();
+            self.consume(reporter, TokenType::Dot, "Expect '.' after 'super'.".to_string())?;
+            let method = self.consume(reporter, TokenType::Identifier, "Expect superclass method name.".to_string())?.clone();
+            return Ok(Expr::Super(Super {
+                span: keyword.span.to(method.span),
+                keyword: keyword,
+                method: method,
+                slot: None
             }));
         }
 
         if self.match_token_types(vec![TokenType::Identifier]) {
-            return Ok(Expr::Variable(Variable { name: self.previous() }));
+            let name = self.previous().clone();
+            return Ok(Expr::Variable(Variable { span: name.span, name: name, slot: None }));
         }
 
         if self.match_token_types(vec![TokenType::LeftParen]) {
-            let expr = self.expression(lox)?;
-            self.consume(lox, TokenType::RightParen, "Expect ')' after expression.".to_string())?;
+            let start = self.previous().span;
+            let expr = self.expression(reporter)?;
+            self.consume(reporter, TokenType::RightParen, "Expect ')' after expression.".to_string())?;
             return Ok(Expr::Grouping(Grouping {
-                expression: Box::new(expr)
+                expression: Box::new(expr),
+                span: self.span_from(start)
             }));
         }
 
-        self.error(lox, self.peek(), "Expect expression".to_string())
+        // Error production: a binary operator with nothing on its left. Parse and discard the
+        // right operand so the error doesn't cascade into the rest of the expression.
+        if let Some(infix) = infix_operator(self.peek().token_type) {
+            self.advance();
+            return self.missing_left_operand(reporter, infix.precedence.next());
+        }
+
+        // When the expression is missing altogether, put an error node in its place and let the
+        // enclosing construct carry on from the token that ended it.
+        let token = self.peek();
+        let error = self.error::<Expr>(reporter, token, "Expect expression".to_string());
+        match token.token_type {
+            TokenType::Semicolon | TokenType::RightParen | TokenType::RightBrace | TokenType::Comma | TokenType::Eof => Ok(Expr::Error(ErrorNode {
+                token: token.clone(),
+                span: Span::new(token.span.start, token.span.start, token.span.line, token.span.column)
+            })),
+            _ => error
+        }
+    }
+
+    fn missing_left_operand(&mut self, reporter: &mut dyn ErrorReporter, precedence: Precedence) -> Result<Expr, ParseError> {
+        let operator = self.previous();
+        let error = self.error::<Expr>(reporter, operator, "Binary operator missing left-hand operand.".to_string()).unwrap_err();
+        self.binary(reporter, precedence)?;
+        Err(error)
+    }
+
+    // Guards the recursive descent into a nested expression; the caller steps back out by
+    // decrementing `depth` once the nested parse returns, whether or not it succeeded.
+    fn enter_nesting(&mut self, reporter: &mut dyn ErrorReporter) -> Result<(), ParseError> {
+        if self.depth >= self.max_depth || !stack_left() {
+            let token = self.peek();
+            return self.error(reporter, token, "Expression too deeply nested.".to_string());
+        }
+
+        self.depth += 1;
+        Ok(())
+    }
+
+    // The span from `start` up to the end of the last token consumed.
+    fn span_from(&self, start: Span) -> Span {
+        start.to(self.previous().span)
     }
 
     fn match_token_types(&mut self, token_types: Vec<TokenType>) -> bool {
@@ -403,14 +648,10 @@ impl Parser {
             return false;
         }
 
-        match (self.peek().token_type, token_type) {
-            (TokenType::Number(_), TokenType::Number(_)) => true,
-            (TokenType::String(_), TokenType::String(_)) => true,
-            (peeked_type, token_type) => peeked_type == token_type
-        }
+        self.peek().token_type == token_type
     }
 
-    fn advance(&mut self) -> Token {
+    fn advance(&mut self) -> &'a Token {
         if !self.is_at_end() {
             self.current+=1;
         }
@@ -425,12 +666,12 @@ impl Parser {
         }
     }
 
-    fn peek(&self) -> Token {
-        self.tokens.get(self.current).unwrap().clone()
+    fn peek(&self) -> &'a Token {
+        &self.tokens[self.current]
     }
 
-    fn previous(&self) -> Token {
-        self.tokens.get(self.current - 1).unwrap().clone()
+    fn previous(&self) -> &'a Token {
+        &self.tokens[self.current - 1]
     }
 
     fn synchronize(&mut self) {
@@ -440,27 +681,226 @@ impl Parser {
             match self.previous().token_type {
                 TokenType::Semicolon => return,
                 _ => match self.peek().token_type {
-                    TokenType::Class | TokenType::Fun | TokenType::Var | TokenType::For | TokenType::If | TokenType::While | TokenType::Print | TokenType::Return => return,
+                    TokenType::Class | TokenType::Defer | TokenType::Fun | TokenType::Var | TokenType::For | TokenType::If | TokenType::While | TokenType::Print | TokenType::Return => return,
                     _ => self.advance()
                 }
             };
         }
     }
 
-    fn consume(&mut self, lox: &mut super::Lox, token_type: TokenType, message: String) -> Result<Token, ()> {
+    fn consume(&mut self, reporter: &mut dyn ErrorReporter, token_type: TokenType, message: String) -> Result<&'a Token, ParseError> {
         if self.check(token_type) {
             return Ok(self.advance());
         }
 
-        self.error(lox, self.peek(), message)
+        self.error_expecting(reporter, self.peek(), message, Some(token_type))
+    }
+
+    fn consume_semicolon(&mut self, reporter: &mut dyn ErrorReporter, message: String) -> Result<(), ParseError> {
+        if self.implicit_semicolon && self.is_at_end() {
+            return Ok(());
+        }
+
+        self.consume(reporter, TokenType::Semicolon, message)?;
+        Ok(())
     }
 
-    fn error<T>(&self, lox: &mut super::Lox, token: Token, message: String) -> Result<T, ()> {
-        if token.token_type == TokenType::Eof {
-            lox.report(token.line, " at end".to_string(), message);
+    fn error<T>(&mut self, reporter: &mut dyn ErrorReporter, token: &Token, message: String) -> Result<T, ParseError> {
+        self.error_expecting(reporter, token, message, None)
+    }
+
+    fn error_expecting<T>(&mut self, reporter: &mut dyn ErrorReporter, token: &Token, message: String, expected: Option<TokenType>) -> Result<T, ParseError> {
+        let error = ParseError {
+            token: token.clone(),
+            message: message,
+            expected: expected
+        };
+
+        // Only the first error at any one token is worth reporting; the rest follow from it.
+        let repeated = self.last_error_at == Some(token.span.start);
+        self.last_error_at = Some(token.span.start);
+
+        if !repeated {
+            reporter.report(error.token.line, error.token.column, error.location(), error.message.clone());
+            self.errors.push(error.clone());
+        }
+        Err(error)
+    }
+}
+
+// How tightly an infix operator binds, loosest first.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum Precedence {
+    Or,
+    And,
+    Equality,
+    Comparison,
+    Term,
+    Factor,
+    Unary
+}
+
+impl Precedence {
+    fn next(self) -> Precedence {
+        match self {
+            Precedence::Or => Precedence::And,
+            Precedence::And => Precedence::Equality,
+            Precedence::Equality => Precedence::Comparison,
+            Precedence::Comparison => Precedence::Term,
+            Precedence::Term => Precedence::Factor,
+            Precedence::Factor | Precedence::Unary => Precedence::Unary
+        }
+    }
+}
+
+struct InfixOperator {
+    token_type: TokenType,
+    precedence: Precedence,
+    // Logical operators short-circuit, so they get their own node.
+    logical: bool
+}
+
+// Every infix operator. A new one needs a row here and a case in the interpreter.
+const INFIX_OPERATORS: [InfixOperator; 13] = [
+    InfixOperator { token_type: TokenType::Or, precedence: Precedence::Or, logical: true },
+    InfixOperator { token_type: TokenType::And, precedence: Precedence::And, logical: true },
+    InfixOperator { token_type: TokenType::BangEqual, precedence: Precedence::Equality, logical: false },
+    InfixOperator { token_type: TokenType::EqualEqual, precedence: Precedence::Equality, logical: false },
+    InfixOperator { token_type: TokenType::Greater, precedence: Precedence::Comparison, logical: false },
+    InfixOperator { token_type: TokenType::GreaterEqual, precedence: Precedence::Comparison, logical: false },
+    InfixOperator { token_type: TokenType::Less, precedence: Precedence::Comparison, logical: false },
+    InfixOperator { token_type: TokenType::LessEqual, precedence: Precedence::Comparison, logical: false },
+    InfixOperator { token_type: TokenType::Is, precedence: Precedence::Comparison, logical: false },
+    InfixOperator { token_type: TokenType::Minus, precedence: Precedence::Term, logical: false },
+    InfixOperator { token_type: TokenType::Plus, precedence: Precedence::Term, logical: false },
+    InfixOperator { token_type: TokenType::Slash, precedence: Precedence::Factor, logical: false },
+    InfixOperator { token_type: TokenType::Star, precedence: Precedence::Factor, logical: false }
+];
+
+fn infix_operator(token_type: TokenType) -> Option<&'static InfixOperator> {
+    INFIX_OPERATORS.iter().find(|infix| infix.token_type == token_type)
+}
+
+#[derive(Debug, Clone)]
+pub struct ParseError {
+    pub token: Token,
+    pub message: String,
+    /// The kind of token that would have been accepted, when a specific one was required.
+    pub expected: Option<TokenType>
+}
+
+impl ParseError {
+    /// Which token the error is at, as in ` at 'x'` or ` at end`.
+    pub fn location(&self) -> String {
+        if self.token.token_type == TokenType::Eof {
+            " at end".to_string()
         } else {
-            lox.report(token.line, format!(" at '{}'", token.lexeme), message);
+            format!(" at '{}'", self.token.lexeme)
         }
-        Err(())
     }
-}
\ No newline at end of file
+}
+
+impl Error for ParseError {
+    fn description(&self) -> &str {
+        "A parse error occurred"
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "[line {}, column {}] Error{}: {}", self.token.line, self.token.column, self.location(), self.message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ast::AstPrinter;
+    use scanner::Scanner;
+
+    fn tokens(source: &str) -> Vec<Token> {
+        let (tokens, errors) = Scanner::new(source).scan_tokens();
+        assert!(errors.is_empty(), "{:?}", errors);
+        tokens
+    }
+
+    // The expression as AstPrinter shows it, or the first error.
+    fn print(source: &str) -> String {
+        let tokens = tokens(source);
+        let mut messages: Vec<String> = Vec::new();
+        match Parser::new(&tokens).parse_expression(&mut messages) {
+            Ok(expr) => AstPrinter.print(&expr),
+            Err(error) => error.to_string()
+        }
+    }
+
+    #[test]
+    fn binary_operators_follow_precedence_and_associativity() {
+        for &(source, expected) in [
+            ("1 - 2 - 3", "(- (- Number(1.0) Number(2.0)) Number(3.0))"),
+            ("1 + 2 * 3", "(+ Number(1.0) (* Number(2.0) Number(3.0)))"),
+            ("a == b < c", "(== a (< b c))"),
+            ("a or b and c", "(or a (and b c))"),
+            ("c is A == true", "(== (is c A) Bool(true))")
+        ].iter() {
+            assert_eq!(print(source), expected, "{}", source);
+        }
+    }
+
+    fn parse(source: &str) -> (Vec<Stmt>, Vec<String>) {
+        let tokens = tokens(source);
+        let mut messages: Vec<String> = Vec::new();
+        let (statements, errors) = Parser::new(&tokens).parse(&mut messages);
+        assert_eq!(errors.len(), messages.len());
+        (statements, messages)
+    }
+
+    #[test]
+    fn deep_nesting_is_one_error_instead_of_a_stack_overflow() {
+        let depth = 100000;
+        for nested in &[
+            format!("{}1{}", "(".repeat(depth), ")".repeat(depth)),
+            format!("{}1", "-".repeat(depth)),
+            format!("a{}1", " = a".repeat(depth))
+        ] {
+            let (statements, messages) = parse(&format!("print {};\nprint 2;", nested));
+            assert_eq!(messages.len(), 1, "{:?}", messages);
+            assert!(messages[0].contains("Expression too deeply nested."), "{}", messages[0]);
+            assert_eq!(statements.len(), 2);
+        }
+
+        let (_, messages) = parse(&format!("print {}1{};", "(".repeat(20), ")".repeat(20)));
+        assert!(messages.is_empty(), "{:?}", messages);
+
+        let tokens = tokens("((((1))))");
+        let mut messages: Vec<String> = Vec::new();
+        let mut parser = Parser::new(&tokens);
+        parser.set_max_depth(3);
+        assert!(parser.parse_expression(&mut messages).is_err());
+        assert_eq!(messages.len(), 1);
+    }
+
+    #[test]
+    fn a_binary_operator_without_a_left_operand_has_its_own_error() {
+        assert_eq!(print("== 4"), "[line 1, column 1] Error at '==': Binary operator missing left-hand operand.");
+        assert_eq!(print("* 3 + 1"), "[line 1, column 1] Error at '*': Binary operator missing left-hand operand.");
+
+        // The right operand is parsed and thrown away, so parsing carries on after it.
+        let (statements, messages) = parse("print == 4; print 5;");
+        assert_eq!(messages.len(), 1, "{:?}", messages);
+        assert_eq!(statements.len(), 2);
+    }
+
+    #[test]
+    fn a_bad_declaration_becomes_an_error_node_between_good_ones() {
+        let source = "var a = 1;\nvar = 2;\nprint a;";
+        let (statements, messages) = parse(source);
+        assert_eq!(messages.len(), 1, "{:?}", messages);
+        match statements[..] {
+            [Stmt::Var(_), Stmt::Error(ref error), Stmt::Print(..)] => {
+                assert_eq!(error.span.text(source), "var = 2;");
+            },
+            _ => panic!("unexpected {:?}", statements)
+        }
+    }
+}
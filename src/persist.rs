@@ -0,0 +1,393 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use interpreter::{Interpreter, Value};
+
+// Saves the globals that hold plain data as text and loads them back into another
+// interpreter, so that simple state can be kept from one run to the next. The text is a JSON
+// object with a member for each global: nil is written as null, lists as arrays and maps as
+// objects.
+
+/// The globals holding nil, a boolean, a number, a string, or a list or map of those, as a
+/// JSON object with one global to a line. Globals holding anything else are left out, as are
+/// those holding a list or map that contains itself, a number JSON has no way to write, like
+/// NaN, or nothing yet. A list or map held in two places is written out twice, so it loads
+/// back as two.
+pub fn save(interpreter: &Interpreter) -> String {
+    let globals = interpreter.globals().borrow();
+    let mut members = Vec::new();
+    for name in globals.declared_names() {
+        if let Some(value) = globals.value(&name).and_then(|value| encode(&value, &mut Vec::new())) {
+            members.push(format!("  {}: {}", encode_string(&name), value));
+        }
+    }
+
+    if members.is_empty() {
+        "{}\n".to_string()
+    } else {
+        format!("{{\n{}\n}}\n", members.join(",\n"))
+    }
+}
+
+/// Defines a global for each member of `text`, a JSON object like `save` writes, returning
+/// their names. Nothing is defined if `text` isn't one.
+pub fn load(interpreter: &mut Interpreter, text: &str) -> Result<Vec<String>, String> {
+    let mut parser = JsonParser {
+        text: text.chars().collect(),
+        current: 0
+    };
+
+    parser.skip_whitespace();
+    if !parser.match_char('{') {
+        return Err(parser.error("Expect '{' at the start of the saved globals."));
+    }
+    let members = parser.members()?;
+    parser.skip_whitespace();
+    if parser.current < parser.text.len() {
+        return Err(parser.error("Expect nothing after the saved globals."));
+    }
+
+    let mut names = Vec::new();
+    for (name, value) in members {
+        let value = decode(interpreter, value);
+        interpreter.set_global(&name, value);
+        names.push(name);
+    }
+    Ok(names)
+}
+
+// `enclosing` holds the lists and maps currently being written, to catch one that contains
+// itself.
+fn encode(value: &Value, enclosing: &mut Vec<*const ()>) -> Option<String> {
+    match *value {
+        Value::Nil => Some("null".to_string()),
+        Value::Bool(value) => Some(value.to_string()),
+        Value::Number(number) if number.is_finite() => Some(number.to_string()),
+        Value::String(ref string) => Some(encode_string(string)),
+        Value::List(ref list) => {
+            let pointer = Rc::as_ptr(list) as *const ();
+            if enclosing.contains(&pointer) {
+                return None;
+            }
+
+            enclosing.push(pointer);
+            let values: Option<Vec<String>> = list.borrow().iter()
+                .map(|value| encode(value, enclosing))
+                .collect();
+            enclosing.pop();
+
+            Some(format!("[{}]", values?.join(", ")))
+        },
+        Value::Map(ref map) => {
+            let pointer = Rc::as_ptr(map) as *const ();
+            if enclosing.contains(&pointer) {
+                return None;
+            }
+
+            enclosing.push(pointer);
+            let entries: Option<Vec<String>> = map.borrow().iter()
+                .map(|(key, value)| encode(value, enclosing).map(|value| format!("{}: {}", encode_string(key), value)))
+                .collect();
+            enclosing.pop();
+
+            Some(format!("{{{}}}", entries?.join(", ")))
+        },
+        _ => None
+    }
+}
+
+fn encode_string(string: &str) -> String {
+    let mut encoded = String::with_capacity(string.len() + 2);
+    encoded.push('"');
+    for c in string.chars() {
+        match c {
+            '"' => encoded.push_str("\\\""),
+            '\\' => encoded.push_str("\\\\"),
+            '\n' => encoded.push_str("\\n"),
+            '\r' => encoded.push_str("\\r"),
+            '\t' => encoded.push_str("\\t"),
+            c if (c as u32) < 0x20 => encoded.push_str(&format!("\\u{:04x}", c as u32)),
+            c => encoded.push(c)
+        }
+    }
+    encoded.push('"');
+    encoded
+}
+
+// What's been read, kept apart from the values made from it so that nothing is allocated in
+// the interpreter until all of the text has turned out to be valid.
+enum Json {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    List(Vec<Json>),
+    Map(Vec<(String, Json)>)
+}
+
+// Lists and maps are tracked like those scripts make, since a script can go on to make them
+// part of a cycle.
+fn decode(interpreter: &mut Interpreter, json: Json) -> Value {
+    let value = match json {
+        Json::Nil => return Value::Nil,
+        Json::Bool(value) => return Value::Bool(value),
+        Json::Number(number) => return Value::Number(number),
+        Json::String(string) => return Value::String(string.into()),
+        Json::List(values) => {
+            let values = values.into_iter().map(|value| decode(interpreter, value)).collect();
+            Value::List(Rc::new(RefCell::new(values)))
+        },
+        Json::Map(entries) => {
+            let entries: BTreeMap<String, Value> = entries.into_iter()
+                .map(|(key, value)| (key, decode(interpreter, value)))
+                .collect();
+            Value::Map(Rc::new(RefCell::new(entries)))
+        }
+    };
+    interpreter.track(&value);
+    value
+}
+
+struct JsonParser {
+    text: Vec<char>,
+    current: usize
+}
+
+impl JsonParser {
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        match self.advance() {
+            Some('n') => self.keyword("ull", Json::Nil),
+            Some('t') => self.keyword("rue", Json::Bool(true)),
+            Some('f') => self.keyword("alse", Json::Bool(false)),
+            Some('"') => Ok(Json::String(self.string()?)),
+            Some('[') => self.list(),
+            Some('{') => Ok(Json::Map(self.members()?)),
+            Some(c) if c == '-' || c.is_ascii_digit() => self.number(),
+            _ => Err(self.error("Expect a value."))
+        }
+    }
+
+    fn keyword(&mut self, rest: &str, value: Json) -> Result<Json, String> {
+        for expected in rest.chars() {
+            if !self.match_char(expected) {
+                return Err(self.error("Expect a value."));
+            }
+        }
+        Ok(value)
+    }
+
+    fn number(&mut self) -> Result<Json, String> {
+        let start = self.current - 1;
+        while self.peek().map_or(false, |c| c.is_ascii_digit() || c == '.' || c == 'e' || c == 'E' || c == '+' || c == '-') {
+            self.current += 1;
+        }
+
+        self.text[start..self.current].iter().collect::<String>().parse()
+            .map(Json::Number)
+            .map_err(|_| self.error("Invalid number."))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let mut string = String::new();
+        loop {
+            match self.advance() {
+                Some('"') => return Ok(string),
+                Some('\\') => string.push(self.escape()?),
+                Some(c) => string.push(c),
+                None => return Err(self.error("Unterminated string."))
+            }
+        }
+    }
+
+    fn escape(&mut self) -> Result<char, String> {
+        match self.advance() {
+            Some('"') => Ok('"'),
+            Some('\\') => Ok('\\'),
+            Some('/') => Ok('/'),
+            Some('b') => Ok('\u{8}'),
+            Some('f') => Ok('\u{c}'),
+            Some('n') => Ok('\n'),
+            Some('r') => Ok('\r'),
+            Some('t') => Ok('\t'),
+            Some('u') => {
+                let mut code = self.code_unit()?;
+                // Characters outside the Basic Multilingual Plane are written as a surrogate
+                // pair.
+                if code >= 0xD800 && code < 0xDC00 && self.match_char('\\') && self.match_char('u') {
+                    let low = self.code_unit()?;
+                    if low < 0xDC00 || low >= 0xE000 {
+                        return Err(self.error("Invalid escape sequence."));
+                    }
+                    code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+                }
+                ::std::char::from_u32(code).ok_or_else(|| self.error("Invalid escape sequence."))
+            },
+            _ => Err(self.error("Invalid escape sequence."))
+        }
+    }
+
+    fn code_unit(&mut self) -> Result<u32, String> {
+        let digits: String = self.text.iter().skip(self.current).take(4).collect();
+        match u32::from_str_radix(&digits, 16) {
+            Ok(code) if digits.len() == 4 => {
+                self.current += 4;
+                Ok(code)
+            },
+            _ => Err(self.error("Invalid escape sequence."))
+        }
+    }
+
+    fn list(&mut self) -> Result<Json, String> {
+        let mut values = Vec::new();
+        self.skip_whitespace();
+        if self.match_char(']') {
+            return Ok(Json::List(values));
+        }
+
+        loop {
+            values.push(self.value()?);
+            self.skip_whitespace();
+            if self.match_char(']') {
+                return Ok(Json::List(values));
+            }
+            if !self.match_char(',') {
+                return Err(self.error("Expect ',' or ']' after a list element."));
+            }
+        }
+    }
+
+    // Reads the members of an object up to its closing brace, the opening one having been read
+    // already.
+    fn members(&mut self) -> Result<Vec<(String, Json)>, String> {
+        let mut members = Vec::new();
+        self.skip_whitespace();
+        if self.match_char('}') {
+            return Ok(members);
+        }
+
+        loop {
+            self.skip_whitespace();
+            if !self.match_char('"') {
+                return Err(self.error("Expect a string key."));
+            }
+            let key = self.string()?;
+            self.skip_whitespace();
+            if !self.match_char(':') {
+                return Err(self.error("Expect ':' after a key."));
+            }
+            members.push((key, self.value()?));
+            self.skip_whitespace();
+            if self.match_char('}') {
+                return Ok(members);
+            }
+            if !self.match_char(',') {
+                return Err(self.error("Expect ',' or '}' after a member."));
+            }
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().map_or(false, |c| c == ' ' || c == '\t' || c == '\n' || c == '\r') {
+            self.current += 1;
+        }
+    }
+
+    fn match_char(&mut self, expected: char) -> bool {
+        if self.peek() == Some(expected) {
+            self.current += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn advance(&mut self) -> Option<char> {
+        let c = self.peek();
+        if c.is_some() {
+            self.current += 1;
+        }
+        c
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.text.get(self.current).cloned()
+    }
+
+    fn error(&self, message: &str) -> String {
+        let line = self.text[..self.current.min(self.text.len())].iter().filter(|&&c| c == '\n').count() + 1;
+        format!("[line {}] {}", line, message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn global(interpreter: &Interpreter, name: &str) -> Option<String> {
+        interpreter.get_global(name).map(|value| interpreter.stringify(value))
+    }
+
+    #[test]
+    fn round_trips_plain_data() {
+        let mut from = Interpreter::new();
+        from.set_global("nothing", Value::Nil);
+        from.set_global("yes", Value::Bool(true));
+        from.set_global("number", Value::Number(-1.5e10));
+        from.set_global("text", Value::from("quote \" backslash \\ newline \n tab \t bell \u{7} emoji \u{1F600}"));
+        let mut entries = BTreeMap::new();
+        entries.insert("key \"1\"".to_string(), Value::from(vec![Value::Number(1.0), Value::Nil]));
+        from.set_global("map", Value::from(entries));
+
+        let text = save(&from);
+        let mut to = Interpreter::new();
+        let mut names = load(&mut to, &text).unwrap();
+        names.sort();
+        assert_eq!(names, vec!["map", "nothing", "number", "text", "yes"]);
+        for name in &names {
+            assert_eq!(global(&to, name), global(&from, name), "{}", name);
+        }
+        assert_eq!(save(&to), text);
+    }
+
+    #[test]
+    fn reads_escapes_and_surrogate_pairs() {
+        let mut interpreter = Interpreter::new();
+        load(&mut interpreter, r#"{"s": "é😀\/\b\f\r\n\t\"\\"}"#).unwrap();
+        assert_eq!(global(&interpreter, "s"), Some("\u{e9}\u{1F600}/\u{8}\u{c}\r\n\t\"\\".to_string()));
+
+        assert!(load(&mut interpreter, r#"{"s": "\ud83dA"}"#).is_err());
+        assert!(load(&mut interpreter, r#"{"s": "\u12"}"#).is_err());
+        assert!(load(&mut interpreter, r#"{"s": "\q"}"#).is_err());
+    }
+
+    #[test]
+    fn skips_what_it_cannot_write() {
+        let mut interpreter = Interpreter::with_stdlib();
+        interpreter.set_global("nan", Value::Number(::std::f64::NAN));
+        interpreter.set_global("infinite", Value::Number(::std::f64::INFINITY));
+        let list = Rc::new(RefCell::new(vec![Value::Number(1.0)]));
+        list.borrow_mut().push(Value::List(list.clone()));
+        interpreter.set_global("cycle", Value::List(list.clone()));
+        interpreter.set_global("kept", Value::Number(1.0));
+
+        // The natives are globals holding functions.
+        assert_eq!(save(&interpreter), "{\n  \"kept\": 1\n}\n");
+        list.borrow_mut().clear();
+    }
+
+    #[test]
+    fn leaves_the_globals_alone_on_bad_input() {
+        for text in &["", "[]", "{", r#"{"a": 1"#, r#"{"a": 1, "b": [1,]}"#, r#"{"a": 1} x"#, r#"{a: 1}"#, r#"{"a": tru}"#, r#"{"a": 1.2.3}"#] {
+            let mut interpreter = Interpreter::new();
+            assert!(load(&mut interpreter, text).is_err(), "{:?}", text);
+            assert_eq!(global(&interpreter, "a"), None, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn errors_give_the_line() {
+        let mut interpreter = Interpreter::new();
+        assert_eq!(load(&mut interpreter, "{\n  \"a\": 1,\n  \"b\": ?\n}"), Err("[line 3] Expect a value.".to_string()));
+    }
+}
@@ -0,0 +1,140 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How often a function was called and how long its calls took altogether, including the
+/// functions they called in turn.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FunctionProfile {
+    pub calls: u64,
+    pub time: Duration,
+    // Calls still in progress. Only the outermost of a recursive function's calls is timed, so
+    // time spent in it isn't counted again for every level of recursion.
+    active: usize
+}
+
+/// Where a script spent its time: calls to each of its functions, and how many statements ran
+/// on each line. Functions are told apart by name and the line they're declared on, so methods
+/// with the same name in different classes are kept separate.
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    functions: HashMap<(Arc<str>, i32), FunctionProfile>,
+    lines: BTreeMap<i32, u64>
+}
+
+/// A call being timed, handed back to `Profile::exit` once it returns.
+pub struct Call {
+    function: (Arc<str>, i32),
+    start: Instant
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Profile::default()
+    }
+
+    pub fn enter(&mut self, name: Arc<str>, line: i32) -> Call {
+        let profile = self.functions.entry((name.clone(), line)).or_insert_with(FunctionProfile::default);
+        profile.calls += 1;
+        profile.active += 1;
+        Call { function: (name, line), start: Instant::now() }
+    }
+
+    pub fn exit(&mut self, call: Call) {
+        if let Some(profile) = self.functions.get_mut(&call.function) {
+            profile.active -= 1;
+            if profile.active == 0 {
+                profile.time += call.start.elapsed();
+            }
+        }
+    }
+
+    pub fn count_line(&mut self, line: i32) {
+        *self.lines.entry(line).or_insert(0) += 1;
+    }
+
+    /// Every function called so far with the line it's declared on, slowest first.
+    pub fn functions(&self) -> Vec<(&str, i32, FunctionProfile)> {
+        let mut functions: Vec<_> = self.functions.iter()
+            .map(|(&(ref name, line), profile)| (&name[..], line, *profile))
+            .collect();
+        functions.sort_by(|a, b| b.2.time.cmp(&a.2.time).then(b.2.calls.cmp(&a.2.calls)));
+        functions
+    }
+
+    /// How many statements have run on each line, by line.
+    pub fn lines(&self) -> &BTreeMap<i32, u64> {
+        &self.lines
+    }
+
+    /// A table of the functions, slowest first, followed by the ten busiest lines.
+    pub fn report(&self) -> Vec<String> {
+        let mut report = vec![format!("{:>12} {:>10}  function", "time (ms)", "calls")];
+        for (name, line, profile) in self.functions() {
+            let milliseconds = profile.time.as_secs() as f64 * 1000.0 + profile.time.subsec_nanos() as f64 / 1e6;
+            report.push(format!("{:>12.3} {:>10}  {}() [line {}]", milliseconds, profile.calls, name, line));
+        }
+
+        let mut lines: Vec<_> = self.lines.iter().collect();
+        lines.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        report.push(format!("{:>12} {:>10}  line", "", "statements"));
+        for (line, count) in lines.into_iter().take(10) {
+            report.push(format!("{:>12} {:>10}  {}", "", count, line));
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use interpreter::Interpreter;
+    use {CollectingReporter, Lox};
+
+    fn profile(source: &str) -> Profile {
+        let mut interpreter = Interpreter::with_stdlib();
+        interpreter.set_profiling(true);
+        let mut lox = Lox::new();
+        lox.set_reporter(Box::new(CollectingReporter::new()));
+        lox.run(&source.to_string(), &mut interpreter).unwrap();
+        interpreter.profile().unwrap().clone()
+    }
+
+    #[test]
+    fn counts_calls_to_each_function() {
+        let source = "fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }\n\
+                      class A { m() {} }\n\
+                      class B { m() {} }\n\
+                      fib(5); A().m(); B().m(); B().m(); clock();";
+        let mut calls: Vec<(String, i32, u64)> = profile(source).functions().into_iter()
+            .map(|(name, line, profile)| (name.to_string(), line, profile.calls))
+            .collect();
+        calls.sort();
+        assert_eq!(calls, vec![("fib".to_string(), 1, 15), ("m".to_string(), 2, 1), ("m".to_string(), 3, 2)]);
+    }
+
+    #[test]
+    fn counts_statements_on_each_line() {
+        let source = "var i = 0;\nwhile (i < 3) {\n  i = i + 1;\n}";
+        let lines: Vec<(i32, u64)> = profile(source).lines().iter().map(|(&line, &count)| (line, count)).collect();
+        assert_eq!(lines, vec![(1, 1), (2, 4), (3, 3)]);
+    }
+
+    // Only the outermost of a recursive function's calls is timed.
+    #[test]
+    fn recursion_is_timed_once() {
+        let mut profile = Profile::new();
+        let outer = profile.enter(Arc::from("f"), 1);
+        let inner = profile.enter(Arc::from("f"), 1);
+        thread::sleep(Duration::from_millis(5));
+        profile.exit(inner);
+        assert_eq!(profile.functions[&(Arc::from("f"), 1)].time, Duration::from_millis(0));
+        assert_eq!(profile.functions[&(Arc::from("f"), 1)].active, 1);
+
+        profile.exit(outer);
+        let (_, _, f) = profile.functions()[0];
+        assert_eq!((f.calls, f.active), (2, 0));
+        assert!(f.time >= Duration::from_millis(5));
+    }
+}
@@ -0,0 +1,621 @@
+use std::mem;
+
+// A small regular expression engine. It supports literals, `.`, character classes (`[a-z]`,
+// `[^0-9]`, `\d`, `\w`, `\s` and their negations), anchors (`^`, `$`), grouping, alternation
+// and the `*`, `+`, `?` and `{n,m}` quantifiers. Groups don't capture.
+//
+// Patterns are compiled to a program for a Pike VM, which follows every way the pattern could
+// match at once, a character at a time. Matching takes time in proportion to the length of the
+// text times the length of the program, however the pattern is written, and never recurses.
+
+// How many instructions a pattern can compile to, which counted repetitions multiply.
+const MAX_PROGRAM_SIZE: usize = 100_000;
+
+#[derive(Debug)]
+pub struct Regex {
+    program: Vec<Instruction>
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Char(char),
+    Any,
+    Class(Vec<ClassItem>, bool),
+    Start,
+    End,
+    Alternation(Vec<Vec<Node>>),
+    Repeat(Box<Node>, usize, Option<usize>)
+}
+
+#[derive(Debug, Clone)]
+enum ClassItem {
+    Char(char),
+    Range(char, char),
+    Digit(bool),
+    Word(bool),
+    Space(bool)
+}
+
+impl ClassItem {
+    fn matches(&self, c: char) -> bool {
+        match *self {
+            ClassItem::Char(expected) => c == expected,
+            ClassItem::Range(from, to) => from <= c && c <= to,
+            ClassItem::Digit(negated) => c.is_ascii_digit() != negated,
+            ClassItem::Word(negated) => (c.is_alphanumeric() || c == '_') != negated,
+            ClassItem::Space(negated) => c.is_whitespace() != negated
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Instruction {
+    // Consume a character, if it's the right one.
+    Char(char),
+    Any,
+    Class(Vec<ClassItem>, bool),
+    // Carry on only at the start or end of the text.
+    Start,
+    End,
+    // Carry on at both, preferring the first.
+    Split(usize, usize),
+    Jump(usize),
+    Match
+}
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Self, String> {
+        let mut parser = PatternParser {
+            pattern: pattern.chars().collect(),
+            current: 0
+        };
+
+        let node = parser.alternation()?;
+
+        if parser.current < parser.pattern.len() {
+            return Err("Unmatched ')'.".to_string());
+        }
+
+        let mut program = Vec::new();
+        compile(&node, &mut program)?;
+        program.push(Instruction::Match);
+        Ok(Regex { program: program })
+    }
+
+    /// Whether the pattern matches anywhere in `text`. Matching calls `step` before each
+    /// character it looks at, with how many ways of matching it's following, so that the caller
+    /// can charge for the work or stop it partway by returning an error.
+    pub fn is_match<E>(&self, text: &str, step: &mut dyn FnMut(usize) -> Result<(), E>) -> Result<bool, E> {
+        Ok(self.find(text, step)?.is_some())
+    }
+
+    /// Returns the character offsets of the leftmost match in `text`, calling `step` as
+    /// `is_match` does.
+    pub fn find<E>(&self, text: &str, step: &mut dyn FnMut(usize) -> Result<(), E>) -> Result<Option<(usize, usize)>, E> {
+        let text: Vec<char> = text.chars().collect();
+        self.find_from(&text, 0, step)
+    }
+
+    pub fn replace_all<E>(&self, text: &str, replacement: &str, step: &mut dyn FnMut(usize) -> Result<(), E>) -> Result<String, E> {
+        let chars: Vec<char> = text.chars().collect();
+        let mut result = String::new();
+        let mut position = 0;
+
+        while position <= chars.len() {
+            match self.find_from(&chars, position, step)? {
+                Some((start, end)) => {
+                    result.extend(&chars[position..start]);
+                    result.push_str(replacement);
+
+                    // An empty match would be found again at the same spot, so step over a character.
+                    if end == start {
+                        if start < chars.len() {
+                            result.push(chars[start]);
+                        }
+                        position = start + 1;
+                    } else {
+                        position = end;
+                    }
+                },
+                None => {
+                    result.extend(&chars[position..]);
+                    break;
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+    // Threads are kept in order of preference: those that started earlier first, then those
+    // whose path through the program took more repetitions and earlier alternatives. The first
+    // thread to reach the end of the program is the leftmost match, and the one a backtracking
+    // matcher would have found; the threads after it are dropped.
+    fn find_from<E>(&self, text: &[char], from: usize, step: &mut dyn FnMut(usize) -> Result<(), E>) -> Result<Option<(usize, usize)>, E> {
+        let mut current = Threads::new(self.program.len());
+        let mut next = Threads::new(self.program.len());
+        let mut found = None;
+        let mut position = from;
+
+        loop {
+            // No match can start later than one already found.
+            if found.is_none() {
+                self.add_thread(&mut current, 0, position, position, text);
+            }
+            if current.threads.is_empty() {
+                break;
+            }
+            step(current.threads.len())?;
+
+            for &(pc, start) in &current.threads {
+                let matched = match self.program[pc] {
+                    Instruction::Match => {
+                        found = Some((start, position));
+                        break;
+                    },
+                    Instruction::Char(expected) => text.get(position) == Some(&expected),
+                    Instruction::Any => text.get(position).map_or(false, |&c| c != '\n'),
+                    Instruction::Class(ref items, negated) => text.get(position)
+                        .map_or(false, |&c| items.iter().any(|item| item.matches(c)) != negated),
+                    _ => false
+                };
+
+                if matched {
+                    self.add_thread(&mut next, pc + 1, start, position + 1, text);
+                }
+            }
+
+            if position >= text.len() {
+                break;
+            }
+            mem::swap(&mut current, &mut next);
+            next.clear();
+            position += 1;
+        }
+
+        Ok(found)
+    }
+
+    // Adds a thread at `pc` for a match from `start` to `threads`, following jumps, splits and
+    // anchors to the instructions that consume a character or match. Each instruction gets at
+    // most one thread at a given position; any later one would only repeat what the first does.
+    fn add_thread(&self, threads: &mut Threads, pc: usize, start: usize, position: usize, text: &[char]) {
+        let mut pending = vec![pc];
+        while let Some(pc) = pending.pop() {
+            if mem::replace(&mut threads.seen[pc], true) {
+                continue;
+            }
+            threads.visited.push(pc);
+
+            match self.program[pc] {
+                Instruction::Jump(to) => pending.push(to),
+                // The second is pushed first, so that everything the first leads to comes
+                // before it.
+                Instruction::Split(first, second) => {
+                    pending.push(second);
+                    pending.push(first);
+                },
+                Instruction::Start => if position == 0 {
+                    pending.push(pc + 1);
+                },
+                Instruction::End => if position == text.len() {
+                    pending.push(pc + 1);
+                },
+                _ => threads.threads.push((pc, start))
+            }
+        }
+    }
+}
+
+// The threads at one position in the text, each an instruction and where its match started.
+struct Threads {
+    threads: Vec<(usize, usize)>,
+    seen: Vec<bool>,
+    visited: Vec<usize>
+}
+
+impl Threads {
+    fn new(size: usize) -> Self {
+        Threads {
+            threads: Vec::new(),
+            seen: vec![false; size],
+            visited: Vec::new()
+        }
+    }
+
+    fn clear(&mut self) {
+        self.threads.clear();
+        for &pc in &self.visited {
+            self.seen[pc] = false;
+        }
+        self.visited.clear();
+    }
+}
+
+fn compile(node: &Node, program: &mut Vec<Instruction>) -> Result<(), String> {
+    if program.len() > MAX_PROGRAM_SIZE {
+        return Err("Pattern is too large.".to_string());
+    }
+
+    match *node {
+        Node::Char(c) => program.push(Instruction::Char(c)),
+        Node::Any => program.push(Instruction::Any),
+        Node::Class(ref items, negated) => program.push(Instruction::Class(items.clone(), negated)),
+        Node::Start => program.push(Instruction::Start),
+        Node::End => program.push(Instruction::End),
+        Node::Alternation(ref branches) => {
+            let mut jumps = Vec::new();
+            for (i, branch) in branches.iter().enumerate() {
+                let split = program.len();
+                if i + 1 < branches.len() {
+                    program.push(Instruction::Split(split + 1, 0));
+                }
+                for node in branch {
+                    compile(node, program)?;
+                }
+                if i + 1 < branches.len() {
+                    jumps.push(program.len());
+                    program.push(Instruction::Jump(0));
+                    program[split] = Instruction::Split(split + 1, program.len());
+                }
+            }
+            let end = program.len();
+            for jump in jumps {
+                program[jump] = Instruction::Jump(end);
+            }
+        },
+        // Repetitions are greedy, preferring one more to moving on.
+        Node::Repeat(ref node, min, max) => {
+            for _ in 0..min {
+                compile(node, program)?;
+            }
+            match max {
+                None => {
+                    let split = program.len();
+                    program.push(Instruction::Split(split + 1, 0));
+                    compile(node, program)?;
+                    program.push(Instruction::Jump(split));
+                    program[split] = Instruction::Split(split + 1, program.len());
+                },
+                Some(max) => {
+                    let mut splits = Vec::new();
+                    for _ in min..max {
+                        splits.push(program.len());
+                        program.push(Instruction::Split(0, 0));
+                        compile(node, program)?;
+                    }
+                    let end = program.len();
+                    for split in splits {
+                        program[split] = Instruction::Split(split + 1, end);
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+struct PatternParser {
+    pattern: Vec<char>,
+    current: usize
+}
+
+impl PatternParser {
+    fn alternation(&mut self) -> Result<Node, String> {
+        let mut branches = vec![self.sequence()?];
+
+        while self.match_char('|') {
+            branches.push(self.sequence()?);
+        }
+
+        Ok(Node::Alternation(branches))
+    }
+
+    fn sequence(&mut self) -> Result<Vec<Node>, String> {
+        let mut nodes = Vec::new();
+
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+
+            let atom = self.atom()?;
+            nodes.push(self.quantifier(atom)?);
+        }
+
+        Ok(nodes)
+    }
+
+    fn atom(&mut self) -> Result<Node, String> {
+        match self.advance().unwrap() {
+            '(' => {
+                let node = self.alternation()?;
+                if !self.match_char(')') {
+                    return Err("Unterminated group.".to_string());
+                }
+                Ok(node)
+            },
+            '[' => self.class(),
+            '.' => Ok(Node::Any),
+            '^' => Ok(Node::Start),
+            '$' => Ok(Node::End),
+            '\\' => match self.escape()? {
+                ClassItem::Char(c) => Ok(Node::Char(c)),
+                item => Ok(Node::Class(vec![item], false))
+            },
+            '*' | '+' | '?' => Err("Nothing to repeat.".to_string()),
+            c => Ok(Node::Char(c))
+        }
+    }
+
+    fn quantifier(&mut self, atom: Node) -> Result<Node, String> {
+        let (min, max) = if self.match_char('*') {
+            (0, None)
+        } else if self.match_char('+') {
+            (1, None)
+        } else if self.match_char('?') {
+            (0, Some(1))
+        } else if self.match_char('{') {
+            let min = self.count()?;
+            let max = if self.match_char(',') {
+                if self.peek() == Some('}') { None } else { Some(self.count()?) }
+            } else {
+                Some(min)
+            };
+
+            if !self.match_char('}') {
+                return Err("Expect '}' after repetition count.".to_string());
+            }
+
+            if max.map_or(false, |max| max < min) {
+                return Err("Repetition maximum is less than its minimum.".to_string());
+            }
+
+            (min, max)
+        } else {
+            return Ok(atom);
+        };
+
+        match atom {
+            Node::Start | Node::End => Err("Nothing to repeat.".to_string()),
+            atom => Ok(Node::Repeat(Box::new(atom), min, max))
+        }
+    }
+
+    fn count(&mut self) -> Result<usize, String> {
+        let start = self.current;
+        while self.peek().map_or(false, |c| c.is_ascii_digit()) {
+            self.current += 1;
+        }
+
+        self.pattern[start..self.current].iter().collect::<String>().parse()
+            .map_err(|_| "Expect a repetition count.".to_string())
+    }
+
+    fn class(&mut self) -> Result<Node, String> {
+        let negated = self.match_char('^');
+        let mut items = Vec::new();
+
+        // A ']' straight after the opening bracket is a literal.
+        if self.match_char(']') {
+            items.push(ClassItem::Char(']'));
+        }
+
+        loop {
+            let item = match self.advance() {
+                None => return Err("Unterminated character class.".to_string()),
+                Some(']') => break,
+                Some('\\') => self.escape()?,
+                Some(c) => ClassItem::Char(c)
+            };
+
+            match item {
+                ClassItem::Char(from) if self.peek() == Some('-') && self.peek_next().map_or(false, |c| c != ']') => {
+                    self.advance();
+                    let to = match self.advance().unwrap() {
+                        '\\' => match self.escape()? {
+                            ClassItem::Char(c) => c,
+                            _ => return Err("Invalid character class range.".to_string())
+                        },
+                        c => c
+                    };
+
+                    if to < from {
+                        return Err("Invalid character class range.".to_string());
+                    }
+
+                    items.push(ClassItem::Range(from, to));
+                },
+                item => items.push(item)
+            }
+        }
+
+        Ok(Node::Class(items, negated))
+    }
+
+    fn escape(&mut self) -> Result<ClassItem, String> {
+        match self.advance() {
+            None => Err("Pattern ends with a trailing '\\'.".to_string()),
+            Some('d') => Ok(ClassItem::Digit(false)),
+            Some('D') => Ok(ClassItem::Digit(true)),
+            Some('w') => Ok(ClassItem::Word(false)),
+            Some('W') => Ok(ClassItem::Word(true)),
+            Some('s') => Ok(ClassItem::Space(false)),
+            Some('S') => Ok(ClassItem::Space(true)),
+            Some('n') => Ok(ClassItem::Char('\n')),
+            Some('t') => Ok(ClassItem::Char('\t')),
+            Some('r') => Ok(ClassItem::Char('\r')),
+            Some(c) => Ok(ClassItem::Char(c))
+        }
+    }
+
+    fn advance(&mut self) -> Option<char> {
+        let c = self.peek();
+        if c.is_some() {
+            self.current += 1;
+        }
+        c
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.pattern.get(self.current).cloned()
+    }
+
+    fn peek_next(&self) -> Option<char> {
+        self.pattern.get(self.current + 1).cloned()
+    }
+
+    fn match_char(&mut self, expected: char) -> bool {
+        if self.peek() == Some(expected) {
+            self.current += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use interpreter::{ErrorKind, Interpreter};
+    use {CollectingReporter, Lox, LoxError};
+
+    fn free(_: usize) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn find(pattern: &str, text: &str) -> Option<String> {
+        let text: Vec<char> = text.chars().collect();
+        Regex::new(pattern).unwrap().find_from(&text, 0, &mut free).unwrap()
+            .map(|(start, end)| text[start..end].iter().collect())
+    }
+
+    fn replace(pattern: &str, text: &str, replacement: &str) -> String {
+        Regex::new(pattern).unwrap().replace_all(text, replacement, &mut free).unwrap()
+    }
+
+    #[test]
+    fn matches_characters() {
+        assert_eq!(find("bc", "abcd"), Some("bc".to_string()));
+        assert_eq!(find("bd", "abcd"), None);
+        assert_eq!(find("a.c", "xa\ncabc"), Some("abc".to_string()));
+        assert_eq!(find("é+", "café"), Some("é".to_string()));
+        assert_eq!(find("", "abc"), Some("".to_string()));
+    }
+
+    #[test]
+    fn matches_classes_and_escapes() {
+        assert_eq!(find("[a-c]+", "xxbcaz"), Some("bca".to_string()));
+        assert_eq!(find("[^a-c]+", "abxyz"), Some("xyz".to_string()));
+        assert_eq!(find("[]a]+", "x]a]"), Some("]a]".to_string()));
+        assert_eq!(find("[a-]+", "x-a-"), Some("-a-".to_string()));
+        assert_eq!(find("\\d+", "abc123"), Some("123".to_string()));
+        assert_eq!(find("\\D+", "123abc"), Some("abc".to_string()));
+        assert_eq!(find("\\w+", "  ab_1 "), Some("ab_1".to_string()));
+        assert_eq!(find("\\s+", "a \t\nb"), Some(" \t\n".to_string()));
+        assert_eq!(find("[\\d.]+", "v1.25"), Some("1.25".to_string()));
+        assert_eq!(find("\\.", "a.b"), Some(".".to_string()));
+        assert_eq!(find("\\t", "a\tb"), Some("\t".to_string()));
+    }
+
+    #[test]
+    fn matches_anchors() {
+        assert_eq!(find("^a", "ba"), None);
+        assert_eq!(find("^b", "ba"), Some("b".to_string()));
+        assert_eq!(find("a$", "ab"), None);
+        assert_eq!(find("b$", "ab"), Some("b".to_string()));
+        assert_eq!(find("^$", ""), Some("".to_string()));
+        assert_eq!(replace("^a", "aaa", "b"), "baa");
+    }
+
+    #[test]
+    fn matches_groups_and_alternatives_in_order() {
+        assert_eq!(find("cat|dog", "hotdog"), Some("dog".to_string()));
+        assert_eq!(find("a|ab", "ab"), Some("a".to_string()));
+        assert_eq!(find("(a|ab)(c|bcd)(d*)", "abcd"), Some("abcd".to_string()));
+        assert_eq!(find("x(ab)+y", "xababy"), Some("xababy".to_string()));
+        assert_eq!(find("(|a)b", "ab"), Some("ab".to_string()));
+    }
+
+    #[test]
+    fn repeats_greedily() {
+        assert_eq!(find("a*", "aaab"), Some("aaa".to_string()));
+        assert_eq!(find("a*", "baa"), Some("".to_string()));
+        assert_eq!(find("a+", "baa"), Some("aa".to_string()));
+        assert_eq!(find("ab?c", "acabc"), Some("ac".to_string()));
+        assert_eq!(find("a{2}", "aaaa"), Some("aa".to_string()));
+        assert_eq!(find("a{2,3}", "aaaa"), Some("aaa".to_string()));
+        assert_eq!(find("a{2,}", "aaaaa"), Some("aaaaa".to_string()));
+        assert_eq!(find("a{2,3}", "a"), None);
+        assert_eq!(find("a.*b", "a1b2b3"), Some("a1b2b".to_string()));
+        assert_eq!(find("(a*)*", "b"), Some("".to_string()));
+        assert_eq!(find("(a?){3}a{3}", "aaa"), Some("aaa".to_string()));
+    }
+
+    #[test]
+    fn replaces_every_match() {
+        assert_eq!(replace("a", "banana", "o"), "bonono");
+        assert_eq!(replace("x*", "abc", "-"), "-a-b-c-");
+        assert_eq!(replace("an+", "banana", ""), "ba");
+    }
+
+    #[test]
+    fn rejects_bad_patterns() {
+        for pattern in &["(", "a)", "*", "a|+", "[a", "[z-a]", "a{", "a{3,1}", "a{x}", "\\", "^*", "((a{100}){100}){100}"] {
+            assert!(Regex::new(pattern).is_err(), "{:?} was accepted", pattern);
+        }
+    }
+
+    // Each of these would overflow the stack or take forever backtracking.
+    #[test]
+    fn matches_pathological_patterns_in_linear_time() {
+        let long = "a".repeat(2_000_000);
+        let regex = Regex::new("a*").unwrap();
+        assert_eq!(regex.find(&long, &mut free), Ok(Some((0, long.len()))));
+
+        let mut work = 0;
+        let text = format!("{}c", "a".repeat(24));
+        let regex = Regex::new("(a*)*b").unwrap();
+        assert_eq!(regex.is_match(&text, &mut |threads| -> Result<(), ()> {
+            work += threads;
+            Ok(())
+        }), Ok(false));
+        assert!(work <= (text.len() + 1) * regex.program.len(), "took {} steps", work);
+
+        let regex = Regex::new("(x+x+)+y").unwrap();
+        assert_eq!(regex.is_match(&"x".repeat(5000), &mut free), Ok(false));
+    }
+
+    #[test]
+    fn stops_when_a_step_fails() {
+        let regex = Regex::new("(a|b)*c").unwrap();
+        let mut steps = 0;
+        let result = regex.is_match(&"ab".repeat(1000), &mut |_| {
+            steps += 1;
+            if steps == 10 { Err("stopped") } else { Ok(()) }
+        });
+        assert_eq!(result, Err("stopped"));
+        assert_eq!(steps, 10);
+    }
+
+    #[test]
+    fn natives_spend_fuel_while_matching() {
+        let mut lox = Lox::new();
+        lox.set_reporter(Box::new(CollectingReporter::new()));
+        let mut interpreter = Interpreter::with_stdlib();
+        let source = "var s = \"a\";\n\
+                      for (var i = 0; i < 16; i = i + 1) s = s + s;\n\
+                      regexMatch(\"(a|b)*c\", s);".to_string();
+        interpreter.set_fuel(Some(10_000));
+        match lox.run(&source, &mut interpreter) {
+            Err(LoxError::Runtime { ref error, .. }) => match error.1 {
+                ErrorKind::BudgetExceeded => assert_eq!(error.0.line, 3),
+                ref kind => panic!("unexpected {}", kind)
+            },
+            result => panic!("unexpected {:?}", result)
+        }
+    }
+
+}
@@ -0,0 +1,575 @@
+use std::collections::HashMap;
+use std::mem;
+use std::sync::Arc;
+use ast::*;
+use scanner::Token;
+use ErrorReporter;
+
+/// Works out, before anything runs, which scope each variable reference points at, and records
+/// it on the Variable and Assign nodes so the interpreter can go straight there. Top-level
+/// names are left unresolved, as are any that aren't declared in an enclosing block, since
+/// those can only be found at runtime. Mistakes it can spot along the way are reported as
+/// errors. Calls straight to a declared function have their argument count checked once the
+/// function's scope is finished, provided nothing assigns over it.
+pub struct Resolver<'a> {
+    reporter: &'a mut dyn ErrorReporter,
+    had_error: bool,
+    current_function: FunctionType,
+    current_class: ClassType,
+    scopes: Vec<HashMap<Arc<str>, Local>>,
+    globals: HashMap<Arc<str>, Local>,
+    // Calls to names not declared yet, which may turn out to be globals declared further down.
+    forward_calls: Vec<(Arc<str>, Token, usize)>
+}
+
+impl<'a> Resolver<'a> {
+    pub fn new(reporter: &'a mut dyn ErrorReporter) -> Self {
+        Resolver {
+            reporter: reporter,
+            had_error: false,
+            current_function: FunctionType::None,
+            current_class: ClassType::None,
+            scopes: Vec::new(),
+            globals: HashMap::new(),
+            forward_calls: Vec::new()
+        }
+    }
+
+    pub fn resolve(&mut self, statements: &mut [Stmt]) {
+        self.statements(statements);
+
+        for (name, paren, count) in mem::replace(&mut self.forward_calls, Vec::new()) {
+            if let Some(global) = self.globals.get_mut(&name) {
+                global.calls.push((paren, count));
+            }
+        }
+        let globals = mem::replace(&mut self.globals, HashMap::new());
+        self.check_calls(globals.into_iter().map(|(_, global)| global));
+    }
+
+    /// Resolves what follows as if inside an existing scope holding `names`, for code run in
+    /// the middle of a running program. Push the outermost scope first.
+    pub fn push_scope(&mut self, names: Vec<Arc<str>>) {
+        self.scopes.push(names.into_iter().enumerate().map(|(index, name)| (name, Local::implicit(index))).collect());
+    }
+
+    pub fn had_error(&self) -> bool {
+        self.had_error
+    }
+
+    fn statements(&mut self, statements: &mut [Stmt]) {
+        for statement in statements.iter_mut() {
+            self.stmt(statement);
+        }
+    }
+
+    fn stmt(&mut self, stmt: &mut Stmt) {
+        match *stmt {
+            Stmt::Defer(ref mut expr, _) => self.expr(Arc::make_mut(expr)),
+            Stmt::Expression(ref mut expr, _) | Stmt::Print(ref mut expr, _) => self.expr(expr),
+            Stmt::Function(ref mut v) => {
+                let v = Arc::make_mut(v);
+                self.declare(&v.name);
+                self.define(&v.name);
+                if let Some(local) = self.lookup(&v.name) {
+                    local.arity = Some(v.params.len());
+                }
+                self.function(v, FunctionType::Function);
+            },
+            Stmt::Class(ref mut v) => {
+                self.declare(&v.name);
+                self.define(&v.name);
+
+                let enclosing_class = mem::replace(&mut self.current_class, ClassType::Class);
+                if let Some(ref mut superclass) = v.superclass {
+                    if superclass.name.lexeme == v.name.lexeme {
+                        self.error(&superclass.name, "A class can't inherit from itself.");
+                    }
+                    superclass.slot = self.slot_of(&superclass.name);
+                    self.current_class = ClassType::Subclass;
+                }
+                for mixin in v.mixins.iter_mut() {
+                    if mixin.name.lexeme == v.name.lexeme {
+                        self.error(&mixin.name, "A class can't mix itself in.");
+                    }
+                    mixin.slot = self.slot_of(&mixin.name);
+                }
+
+                for method in v.methods.iter_mut() {
+                    let method = Arc::make_mut(method);
+                    let function_type = if &method.name.lexeme[..] == "init" { FunctionType::Initializer } else { FunctionType::Method };
+                    self.function(method, function_type);
+                }
+
+                self.current_class = enclosing_class;
+            },
+            Stmt::Return(ref mut v) => {
+                if self.current_function == FunctionType::None {
+                    self.error(&v.keyword, "Can't return from top-level code.");
+                }
+                if let Some(ref mut value) = v.value {
+                    if self.current_function == FunctionType::Initializer {
+                        self.error(&v.keyword, "Can't return a value from an initializer.");
+                    }
+                    self.expr(value);
+                }
+            },
+            Stmt::If(ref mut v) => {
+                self.expr(&mut v.condition);
+                self.stmt(&mut v.then_branch);
+                if let Some(ref mut else_branch) = v.else_branch {
+                    self.stmt(else_branch);
+                }
+            },
+            Stmt::Var(ref mut v) => {
+                self.declare(&v.name);
+                if let Some(ref mut initializer) = v.initializer {
+                    self.expr(initializer);
+                }
+                self.define(&v.name);
+            },
+            Stmt::While(ref mut v) => {
+                self.expr(&mut v.condition);
+                self.stmt(&mut v.body);
+            },
+            Stmt::Block(ref mut v) => {
+                self.scopes.push(HashMap::new());
+                self.statements(&mut v.statements);
+                self.end_scope();
+            },
+            Stmt::Error(_) => ()
+        }
+    }
+
+    fn function(&mut self, function: &mut Function, function_type: FunctionType) {
+        let enclosing_function = mem::replace(&mut self.current_function, function_type);
+
+        let mut scope = HashMap::new();
+        if function_type == FunctionType::Method || function_type == FunctionType::Initializer {
+            scope.insert(Arc::from("this"), Local::implicit(0));
+            if self.current_class == ClassType::Subclass {
+                scope.insert(Arc::from("super"), Local::implicit(1));
+            }
+        }
+
+        self.scopes.push(scope);
+        for param in function.params.iter() {
+            self.declare(param);
+            self.define(param);
+        }
+        self.statements(&mut function.body);
+        self.end_scope();
+
+        self.current_function = enclosing_function;
+    }
+
+    fn expr(&mut self, expr: &mut Expr) {
+        match *expr {
+            Expr::Binary(ref mut v) => {
+                self.expr(&mut v.left);
+                self.expr(&mut v.right);
+            },
+            Expr::Call(ref mut v) => {
+                self.expr(&mut v.callee);
+                for argument in v.arguments.iter_mut() {
+                    self.expr(argument);
+                }
+
+                if let Expr::Variable(ref callee) = *v.callee {
+                    match self.lookup(&callee.name) {
+                        Some(local) => local.calls.push((v.paren.clone(), v.arguments.len())),
+                        None => self.forward_calls.push((callee.name.lexeme.clone(), v.paren.clone(), v.arguments.len()))
+                    }
+                }
+            },
+            Expr::Grouping(ref mut v) => self.expr(&mut v.expression),
+            Expr::Logical(ref mut v) => {
+                self.expr(&mut v.left);
+                self.expr(&mut v.right);
+            },
+            Expr::Unary(ref mut v) => self.expr(&mut v.right),
+            Expr::Variable(ref mut v) => {
+                if self.scopes.last().and_then(|scope| scope.get(&v.name.lexeme)).map_or(false, |local| !local.defined) {
+                    self.error(&v.name, "Can't read local variable in its own initializer.");
+                }
+                v.slot = self.slot_of(&v.name);
+            },
+            Expr::Assign(ref mut v) => {
+                self.expr(&mut v.value);
+                v.slot = self.slot_of(&v.name);
+                if let Some(local) = self.lookup(&v.name) {
+                    local.assigned = true;
+                }
+            },
+            Expr::Get(ref mut v) => self.expr(&mut v.object),
+            Expr::Set(ref mut v) => {
+                self.expr(&mut v.value);
+                self.expr(&mut v.object);
+            },
+            Expr::This(ref mut v) => {
+                if self.current_class == ClassType::None {
+                    self.error(&v.keyword, "Can't use 'this' outside of a class.");
+                }
+                v.slot = self.slot_of(&v.keyword);
+            },
+            Expr::Super(ref mut v) => {
+                match self.current_class {
+                    ClassType::None => self.error(&v.keyword, "Can't use 'super' outside of a class."),
+                    ClassType::Class => self.error(&v.keyword, "Can't use 'super' in a class with no superclass."),
+                    ClassType::Subclass => ()
+                }
+                v.slot = self.slot_of(&v.keyword);
+            },
+            Expr::Literal(..) | Expr::Error(_) => ()
+        }
+    }
+
+    // Redeclaring a name is only an error in a local scope; globals can be redefined, which the
+    // REPL relies on. A redefined global is treated as assigned over.
+    fn declare(&mut self, name: &Token) {
+        let duplicate = match self.scopes.last_mut() {
+            Some(scope) => {
+                let slot = scope.len();
+                scope.insert(name.lexeme.clone(), Local::new(slot)).is_some()
+            },
+            None => {
+                match self.globals.get_mut(&name.lexeme) {
+                    Some(global) => global.assigned = true,
+                    None => {
+                        self.globals.insert(name.lexeme.clone(), Local::new(0));
+                    }
+                }
+                false
+            }
+        };
+
+        if duplicate {
+            self.error(name, "Already a variable with this name in this scope.");
+        }
+    }
+
+    fn define(&mut self, name: &Token) {
+        if let Some(local) = self.scopes.last_mut().and_then(|scope| scope.get_mut(&name.lexeme)) {
+            local.defined = true;
+        }
+    }
+
+    // Finds what a name refers to the same way the interpreter will: the nearest enclosing
+    // local, or else a global.
+    fn lookup(&mut self, name: &Token) -> Option<&mut Local> {
+        match self.scopes.iter().rposition(|scope| scope.contains_key(&name.lexeme)) {
+            Some(index) => self.scopes[index].get_mut(&name.lexeme),
+            None => self.globals.get_mut(&name.lexeme)
+        }
+    }
+
+    fn end_scope(&mut self) {
+        let scope = self.scopes.pop().unwrap_or_default();
+        self.check_calls(scope.into_iter().map(|(_, local)| local));
+    }
+
+    // Only calls to functions that are never assigned over can be checked; anything else might
+    // hold a different function by the time it's called.
+    fn check_calls<I: Iterator<Item = Local>>(&mut self, locals: I) {
+        let mut mismatches: Vec<(Token, usize, usize)> = Vec::new();
+        for local in locals.filter(|local| !local.assigned) {
+            if let Some(arity) = local.arity {
+                for (paren, count) in local.calls.into_iter().filter(|&(_, count)| count != arity) {
+                    mismatches.push((paren, arity, count));
+                }
+            }
+        }
+        mismatches.sort_by_key(|&(ref paren, _, _)| (paren.line, paren.column));
+
+        for (paren, arity, count) in mismatches {
+            self.error(&paren, &format!("Expected {} arguments but got {}.", arity, count));
+        }
+    }
+
+    fn slot_of(&self, name: &Token) -> Option<Slot> {
+        self.scopes.iter().rev().enumerate()
+            .filter_map(|(depth, scope)| scope.get(&name.lexeme).map(|local| Slot { depth: depth, index: local.slot }))
+            .next()
+    }
+
+    fn error(&mut self, token: &Token, message: &str) {
+        self.had_error = true;
+        self.reporter.report(token.line, token.column, format!(" at '{}'", token.lexeme), message.to_string());
+    }
+}
+
+struct Local {
+    // Where the local sits among its scope's declarations, in the order the interpreter will
+    // define them. Globals are looked up by name, so theirs goes unused.
+    slot: usize,
+    // Whether the initializer has finished, so reads from inside it can be caught.
+    defined: bool,
+    assigned: bool,
+    // Set for function declarations, along with each direct call's argument count.
+    arity: Option<usize>,
+    calls: Vec<(Token, usize)>
+}
+
+impl Local {
+    fn new(slot: usize) -> Self {
+        Local {
+            slot: slot,
+            defined: false,
+            assigned: false,
+            arity: None,
+            calls: Vec::new()
+        }
+    }
+
+    // The `this` and `super` that methods get without declaring them, and names from scopes
+    // that already exist.
+    fn implicit(slot: usize) -> Self {
+        Local {
+            slot: slot,
+            defined: true,
+            assigned: false,
+            arity: None,
+            calls: Vec::new()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FunctionType {
+    None,
+    Function,
+    Method,
+    Initializer
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ClassType {
+    None,
+    Class,
+    Subclass
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser::Parser;
+    use scanner::Scanner;
+
+    fn parse(source: &str) -> Vec<Stmt> {
+        let (tokens, errors) = Scanner::new(source).scan_tokens();
+        assert!(errors.is_empty(), "{:?}", errors);
+        let mut messages = Vec::new();
+        let (statements, _) = Parser::new(&tokens).parse(&mut messages);
+        assert!(messages.is_empty(), "{:?}", messages);
+        statements
+    }
+
+    fn resolve(source: &str) -> (Vec<Stmt>, Vec<String>) {
+        let mut statements = parse(source);
+        let mut messages = Vec::new();
+        Resolver::new(&mut messages).resolve(&mut statements);
+        (statements, messages)
+    }
+
+    fn errors(source: &str) -> Vec<String> {
+        resolve(source).1
+    }
+
+    // The slot recorded on every variable, assignment, `this` and `super`, in source order.
+    fn slots(source: &str) -> Vec<(String, Option<Slot>)> {
+        let (statements, messages) = resolve(source);
+        assert!(messages.is_empty(), "{:?}", messages);
+        let mut slots = Vec::new();
+        for statement in &statements {
+            stmt_slots(statement, &mut slots);
+        }
+        slots
+    }
+
+    fn stmt_slots(stmt: &Stmt, slots: &mut Vec<(String, Option<Slot>)>) {
+        match *stmt {
+            Stmt::Defer(ref expr, _) => expr_slots(expr, slots),
+            Stmt::Expression(ref expr, _) | Stmt::Print(ref expr, _) => expr_slots(expr, slots),
+            Stmt::Function(ref function) => for statement in &function.body {
+                stmt_slots(statement, slots);
+            },
+            Stmt::Class(ref class) => for method in &class.methods {
+                for statement in &method.body {
+                    stmt_slots(statement, slots);
+                }
+            },
+            Stmt::Return(ref v) => if let Some(ref value) = v.value {
+                expr_slots(value, slots);
+            },
+            Stmt::If(ref v) => {
+                expr_slots(&v.condition, slots);
+                stmt_slots(&v.then_branch, slots);
+                if let Some(ref else_branch) = v.else_branch {
+                    stmt_slots(else_branch, slots);
+                }
+            },
+            Stmt::Var(ref v) => if let Some(ref initializer) = v.initializer {
+                expr_slots(initializer, slots);
+            },
+            Stmt::While(ref v) => {
+                expr_slots(&v.condition, slots);
+                stmt_slots(&v.body, slots);
+            },
+            Stmt::Block(ref v) => for statement in &v.statements {
+                stmt_slots(statement, slots);
+            },
+            Stmt::Error(_) => ()
+        }
+    }
+
+    fn expr_slots(expr: &Expr, slots: &mut Vec<(String, Option<Slot>)>) {
+        match *expr {
+            Expr::Binary(ref v) => {
+                expr_slots(&v.left, slots);
+                expr_slots(&v.right, slots);
+            },
+            Expr::Logical(ref v) => {
+                expr_slots(&v.left, slots);
+                expr_slots(&v.right, slots);
+            },
+            Expr::Call(ref v) => {
+                expr_slots(&v.callee, slots);
+                for argument in &v.arguments {
+                    expr_slots(argument, slots);
+                }
+            },
+            Expr::Grouping(ref v) => expr_slots(&v.expression, slots),
+            Expr::Unary(ref v) => expr_slots(&v.right, slots),
+            Expr::Variable(ref v) => slots.push((v.name.lexeme.to_string(), v.slot)),
+            Expr::Assign(ref v) => {
+                expr_slots(&v.value, slots);
+                slots.push((format!("{} =", v.name.lexeme), v.slot));
+            },
+            Expr::Get(ref v) => expr_slots(&v.object, slots),
+            Expr::Set(ref v) => {
+                expr_slots(&v.value, slots);
+                expr_slots(&v.object, slots);
+            },
+            Expr::This(ref v) => slots.push(("this".to_string(), v.slot)),
+            Expr::Super(ref v) => slots.push(("super".to_string(), v.slot)),
+            Expr::Literal(..) | Expr::Error(_) => ()
+        }
+    }
+
+    fn slot(name: &str, depth: usize, index: usize) -> (String, Option<Slot>) {
+        (name.to_string(), Some(Slot { depth: depth, index: index }))
+    }
+
+    fn global(name: &str) -> (String, Option<Slot>) {
+        (name.to_string(), None)
+    }
+
+    #[test]
+    fn resolves_locals_by_depth_and_declaration_order() {
+        let source = "var g = 1;\n\
+                      { var a = 1; var b = 2; { var a = 3; print a; print b; a = g; } print a; b = a; }";
+        assert_eq!(slots(source), vec![
+            slot("a", 0, 0), slot("b", 1, 1), global("g"), slot("a =", 0, 0),
+            slot("a", 0, 0), slot("a", 0, 0), slot("b =", 0, 1)
+        ]);
+    }
+
+    #[test]
+    fn resolves_closures_through_the_scopes_they_capture() {
+        let source = "fun outer(p) { var x = 1; fun inner(y) { x = x + y + p; return x; } return inner; }";
+        assert_eq!(slots(source), vec![
+            slot("x", 1, 1), slot("y", 0, 0), slot("p", 1, 0), slot("x =", 1, 1), slot("x", 1, 1),
+            slot("inner", 0, 2)
+        ]);
+    }
+
+    // `this` and `super` come before the parameters, in the order `run_function` defines them.
+    #[test]
+    fn resolves_this_and_super_ahead_of_parameters() {
+        let source = "class A { m(a) { var b = a; return this.f + b; } }\n\
+                      class B < A { m(a) { return super.m(a) + this.f; } }";
+        assert_eq!(slots(source), vec![
+            slot("a", 0, 1), slot("this", 0, 0), slot("b", 0, 2),
+            slot("super", 0, 1), slot("a", 0, 2), slot("this", 0, 0)
+        ]);
+    }
+
+    #[test]
+    fn a_local_cannot_read_itself_in_its_initializer() {
+        let messages = errors("{ var a = a; }");
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].contains("Can't read local variable in its own initializer."), "{}", messages[0]);
+    }
+
+    #[test]
+    fn a_global_can_read_itself_in_its_initializer() {
+        assert_eq!(slots("var a = a;"), vec![global("a")]);
+    }
+
+    #[test]
+    fn a_local_cannot_be_declared_twice_in_one_scope() {
+        let messages = errors("{ var a = 1; var a = 2; }");
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].contains("at 'a' : Already a variable with this name in this scope."), "{}", messages[0]);
+        assert!(errors("{ var a = 1; { var a = 2; } }").is_empty());
+    }
+
+    #[test]
+    fn a_global_can_be_redefined_and_is_then_treated_as_assigned() {
+        let mut statements = parse("var a = 1; var a = 2; fun f() {}");
+        let mut messages: Vec<String> = Vec::new();
+        {
+            let mut resolver = Resolver::new(&mut messages);
+            resolver.statements(&mut statements);
+            assert!(resolver.globals[&Arc::from("a")].assigned);
+            assert!(!resolver.globals[&Arc::from("f")].assigned);
+        }
+        assert!(messages.is_empty(), "{:?}", messages);
+
+        // So calls to it are no longer checked against either definition.
+        assert!(errors("fun f() {} fun f(a) {} f(1);").is_empty());
+    }
+
+    #[test]
+    fn return_is_only_allowed_inside_a_function() {
+        let messages = errors("return 1;");
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].contains("at 'return' : Can't return from top-level code."), "{}", messages[0]);
+        assert!(errors("fun f() { { return 1; } }").is_empty());
+    }
+
+    #[test]
+    fn this_and_super_need_a_class_to_refer_to() {
+        let cases = [
+            ("print this;", "at 'this' : Can't use 'this' outside of a class."),
+            ("fun f() { return this; }", "at 'this' : Can't use 'this' outside of a class."),
+            ("print super.m;", "at 'super' : Can't use 'super' outside of a class."),
+            ("class A { m() { return super.m(); } }", "at 'super' : Can't use 'super' in a class with no superclass."),
+            ("class A < A {}", "at 'A' : A class can't inherit from itself.")
+        ];
+        for &(source, expected) in cases.iter() {
+            let messages = errors(source);
+            assert_eq!(messages.len(), 1, "{:?}", messages);
+            assert!(messages[0].contains(expected), "{}", messages[0]);
+        }
+        assert!(errors("class A { m() { return this; } } class B < A { m() { return super.m(); } }").is_empty());
+    }
+
+    #[test]
+    fn calls_are_checked_against_the_declared_arity() {
+        // A global declared after the call, and a local function, which is checked as soon as
+        // its block ends.
+        let messages = errors("f(1);\nfun f(a, b) {}\n{ fun g(a) {} g(); g(1); }");
+        assert_eq!(messages, vec![
+            "[line 3, column 17 ] Error  at ')' : Expected 1 arguments but got 0.".to_string(),
+            "[line 1, column 4 ] Error  at ')' : Expected 2 arguments but got 1.".to_string()
+        ]);
+        assert!(errors("fun f(a, b) {} f(1, 2); { fun g() {} g(); }").is_empty());
+    }
+
+    #[test]
+    fn calls_to_a_reassigned_function_are_not_checked() {
+        assert!(errors("fun f(a) {} f = clock; f();").is_empty());
+        assert!(errors("{ fun g(a) {} g(); g = clock; }").is_empty());
+    }
+}
@@ -1,5 +1,13 @@
-
-#[derive(Debug, PartialEq, Clone)]
+use std::collections::VecDeque;
+use std::error::Error;
+use std::io::{BufReader, ErrorKind, Read};
+use std::sync::Arc;
+use std::str::{self, CharIndices};
+use std::fmt::{Display, Result as FmtResult, Formatter};
+use intern::Interner;
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum TokenType {
     // Single-character tokens.
     LeftParen,
@@ -7,6 +15,7 @@ pub enum TokenType {
     LeftBrace,
     RightBrace,
     Comma,
+    Colon,
     Dot,
     Minus,
     Plus,
@@ -15,6 +24,7 @@ pub enum TokenType {
     Star,
 
     // One or two character tokens.
+    Arrow,
     Bang,
     BangEqual,
     Equal,
@@ -26,17 +36,19 @@ pub enum TokenType {
 
     // Literals.
     Identifier,
-    String(String),
-    Number(f64),
+    String,
+    Number,
 
     // Keywords.
     And,
     Class,
+    Defer,
     Else,
     False,
     Fun,
     For,
     If,
+    Is,
     Nil,
     Or,
     Print,
@@ -46,62 +58,349 @@ pub enum TokenType {
     True,
     Var,
     While,
+    With,
 
     Eof
 }
 
+pub const KEYWORDS: [(&'static str, TokenType); 19] = [
+    ("and", TokenType::And),
+    ("class", TokenType::Class),
+    ("defer", TokenType::Defer),
+    ("else", TokenType::Else),
+    ("false", TokenType::False),
+    ("for", TokenType::For),
+    ("fun", TokenType::Fun),
+    ("if", TokenType::If),
+    ("is", TokenType::Is),
+    ("nil", TokenType::Nil),
+    ("or", TokenType::Or),
+    ("print", TokenType::Print),
+    ("return", TokenType::Return),
+    ("super", TokenType::Super),
+    ("this", TokenType::This),
+    ("true", TokenType::True),
+    ("var", TokenType::Var),
+    ("while", TokenType::While),
+    ("with", TokenType::With)
+];
+
+/// A range of byte offsets into the source, end exclusive, along with the line and column
+/// it starts at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: i32,
+    pub column: i32
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize, line: i32, column: i32) -> Self {
+        Span {
+            start: start,
+            end: end,
+            line: line,
+            column: column
+        }
+    }
+
+    /// The span running from the start of this one to the end of `other`.
+    pub fn to(&self, other: Span) -> Span {
+        Span::new(self.start, other.end, self.line, self.column)
+    }
+
+    /// The text this span covers in `source`.
+    pub fn text<'a>(&self, source: &'a str) -> &'a str {
+        &source[self.start..self.end]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum TriviaKind {
+    Whitespace,
+    LineComment,
+    BlockComment
+}
+
+/// Source text between tokens that the parser doesn't need, kept for tools that have to
+/// reproduce the source exactly.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Trivia {
+    pub kind: TriviaKind,
+    pub text: String,
+    pub span: Span
+}
+
+/// The value of a string or number literal token.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum LiteralValue {
+    String(Arc<str>),
+    Number(f64)
+}
+
+// Lexemes are reference counted so the parser and interpreter can copy tokens around
+// without reallocating their text.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Token {
     pub token_type: TokenType,
-    pub lexeme: String,
-    pub line: i32
+    pub literal: Option<LiteralValue>,
+    pub lexeme: Arc<str>,
+    pub line: i32,
+    pub column: i32,
+    pub span: Span,
+    /// The whitespace and comments before this token. Only filled in when the scanner
+    /// preserves trivia; the Eof token carries whatever trails the last real token.
+    pub leading_trivia: Vec<Trivia>
 }
 
 impl Token {
-    fn new(token_type: TokenType, lexeme: String, line: i32) -> Self {
+    fn new(token_type: TokenType, literal: Option<LiteralValue>, lexeme: Arc<str>, line: i32, column: i32, span: Span, leading_trivia: Vec<Trivia>) -> Self {
         Token {
             token_type: token_type,
+            literal: literal,
             lexeme: lexeme,
             line: line,
+            column: column,
+            span: span,
+            leading_trivia: leading_trivia
         }
     }
+
+    /// An empty token at the start of `span`, for errors about code that doesn't begin with a
+    /// token of its own to point at.
+    pub fn at(span: Span) -> Self {
+        let start = Span::new(span.start, span.start, span.line, span.column);
+        Token::new(TokenType::Eof, None, Arc::from(""), span.line, span.column, start, Vec::new())
+    }
 }
 
-pub struct Scanner {
-    source: String,
-    tokens: Vec<Token>,
+#[derive(Debug, Clone)]
+pub struct ScanError {
+    pub line: i32,
+    pub column: i32,
+    pub message: String
+}
+
+impl Error for ScanError {
+    fn description(&self) -> &str {
+        "A scan error occurred"
+    }
+}
+
+impl Display for ScanError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "[line {}, column {}] {}", self.line, self.column, self.message)
+    }
+}
+
+/// Where the scanner reads its characters from, one at a time along with their byte offsets.
+pub trait Source {
+    fn next_char(&mut self) -> Option<Result<(usize, char), String>>;
+}
+
+impl<'a> Source for CharIndices<'a> {
+    fn next_char(&mut self) -> Option<Result<(usize, char), String>> {
+        self.next().map(Ok)
+    }
+}
+
+/// Decodes UTF-8 from a reader through a buffer, so the whole input never has to be held in
+/// memory at once.
+pub struct ReaderSource<R: Read> {
+    reader: BufReader<R>,
+    offset: usize,
+    failed: bool
+}
+
+impl<R: Read> ReaderSource<R> {
+    pub fn new(reader: R) -> Self {
+        ReaderSource {
+            reader: BufReader::new(reader),
+            offset: 0,
+            failed: false
+        }
+    }
+
+    fn read_byte(&mut self) -> Result<Option<u8>, String> {
+        let mut byte = [0];
+        loop {
+            match self.reader.read(&mut byte) {
+                Ok(0) => return Ok(None),
+                Ok(_) => return Ok(Some(byte[0])),
+                Err(ref error) if error.kind() == ErrorKind::Interrupted => continue,
+                Err(error) => return Err(error.to_string())
+            }
+        }
+    }
+
+    fn decode_char(&mut self) -> Result<Option<char>, String> {
+        let first = match self.read_byte()? {
+            Some(byte) => byte,
+            None => return Ok(None)
+        };
+
+        let width = match first {
+            0x00..=0x7F => 1,
+            0xC0..=0xDF => 2,
+            0xE0..=0xEF => 3,
+            0xF0..=0xF7 => 4,
+            _ => return Err(format!("Invalid UTF-8 at byte {}.", self.offset))
+        };
+
+        let mut bytes = [first, 0, 0, 0];
+        for byte in bytes.iter_mut().take(width).skip(1) {
+            *byte = self.read_byte()?.ok_or_else(|| format!("Invalid UTF-8 at byte {}.", self.offset))?;
+        }
+
+        match str::from_utf8(&bytes[..width]) {
+            Ok(decoded) => Ok(decoded.chars().next()),
+            Err(_) => Err(format!("Invalid UTF-8 at byte {}.", self.offset))
+        }
+    }
+}
+
+impl<R: Read> Source for ReaderSource<R> {
+    fn next_char(&mut self) -> Option<Result<(usize, char), String>> {
+        if self.failed {
+            return None;
+        }
+
+        match self.decode_char() {
+            Ok(Some(c)) => {
+                let offset = self.offset;
+                self.offset += c.len_utf8();
+                Some(Ok((offset, c)))
+            },
+            Ok(None) => None,
+            Err(message) => {
+                self.failed = true;
+                Some(Err(message))
+            }
+        }
+    }
+}
+
+// `start` and `current` are byte offsets into the source, while `column` counts characters.
+// The text of the lexeme being scanned is collected into `lexeme` as it is consumed, so the
+// scanner never needs the source as a whole.
+pub struct Scanner<S: Source> {
+    source: S,
+    lookahead: VecDeque<(usize, char)>,
+    source_error: Option<String>,
+    lexeme: String,
+    interner: Interner,
+    preserve_trivia: bool,
+    trivia: Vec<Trivia>,
+    reached_eof: bool,
+    base_offset: usize,
     start: usize,
     current: usize,
-    line: i32
+    line: i32,
+    column: i32,
+    start_line: i32,
+    start_column: i32
+}
+
+impl<'a> Scanner<CharIndices<'a>> {
+    pub fn new(source: &'a str) -> Self {
+        Scanner::from_source(source.char_indices())
+    }
 }
 
-impl Scanner {
-    pub fn new(source: String) -> Self {
+impl<R: Read> Scanner<ReaderSource<R>> {
+    /// Scans from a reader instead of a string held in memory.
+    pub fn from_reader(reader: R) -> Self {
+        Scanner::from_source(ReaderSource::new(reader))
+    }
+}
+
+impl<S: Source> Scanner<S> {
+    pub fn from_source(source: S) -> Self {
         Scanner {
             source: source,
-            tokens: Vec::new(),
+            lookahead: VecDeque::new(),
+            source_error: None,
+            lexeme: String::new(),
+            interner: Interner::new(),
+            preserve_trivia: false,
+            trivia: Vec::new(),
+            reached_eof: false,
+            base_offset: 0,
             start: 0,
             current: 0,
-            line: 1
+            line: 1,
+            column: 1,
+            start_line: 1,
+            start_column: 1
         }
     }
 
-    pub fn scan_tokens(&mut self, lox: &mut super::Lox) -> &Vec<Token> {
-        while !self.is_at_end() {
-            // We are at the beginning of the next lexeme.
-            self.start = self.current;
-            self.scan_token(lox);
+    /// Keeps whitespace and comments as trivia on the following token instead of discarding them.
+    pub fn set_preserve_trivia(&mut self, preserve_trivia: bool) {
+        self.preserve_trivia = preserve_trivia;
+    }
+
+    /// Interns lexemes and string literals with `interner` rather than one of the scanner's own.
+    pub fn set_interner(&mut self, interner: Interner) {
+        self.interner = interner;
+    }
+
+    /// Treats the source as starting partway through a larger one, so that token positions are
+    /// relative to the whole. Call this before scanning anything.
+    pub fn set_position(&mut self, offset: usize, line: i32, column: i32) {
+        self.base_offset = offset;
+        self.start = offset;
+        self.current = offset;
+        self.line = line;
+        self.column = column;
+        self.start_line = line;
+        self.start_column = column;
+    }
+
+    /// Scans the rest of the source, collecting the tokens and any errors found along the way.
+    pub fn scan_tokens(&mut self) -> (Vec<Token>, Vec<ScanError>) {
+        let mut tokens = Vec::new();
+        let mut errors = Vec::new();
+
+        while let Some(result) = self.next() {
+            match result {
+                Ok(token) => tokens.push(token),
+                Err(error) => errors.push(error)
+            }
         }
 
-        self.tokens.push(Token::new(TokenType::Eof, "".to_string(), self.line));
-        return &self.tokens;
+        (tokens, errors)
+    }
+
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.column = 1;
     }
 
-    fn is_at_end(&self) -> bool {
-        self.current >= self.source.len()
+    // Makes sure the next `count` characters are buffered, unless the source runs out first.
+    fn fill_lookahead(&mut self, count: usize) {
+        while self.lookahead.len() < count && self.source_error.is_none() {
+            match self.source.next_char() {
+                Some(Ok(next)) => self.lookahead.push_back(next),
+                Some(Err(message)) => self.source_error = Some(message),
+                None => break
+            }
+        }
     }
 
-    fn scan_token(&mut self, lox: &mut super::Lox) {
+    fn is_at_end(&mut self) -> bool {
+        self.fill_lookahead(1);
+        self.lookahead.is_empty()
+    }
+
+    // Scans the lexeme starting at `self.start`, returning None for whitespace and comments.
+    fn scan_token(&mut self) -> Option<Result<Token, ScanError>> {
         match self.advance() {
             '(' => self.add_token(TokenType::LeftParen),
             ')' => self.add_token(TokenType::RightParen),
@@ -109,25 +408,29 @@ impl Scanner {
             '}' => self.add_token(TokenType::RightBrace),
             ',' => self.add_token(TokenType::Comma),
             '.' => self.add_token(TokenType::Dot),
-            '-' => self.add_token(TokenType::Minus),
+            ':' => self.add_token(TokenType::Colon),
+            '-' => {
+                let token_type = if self.match_next('>') { TokenType::Arrow } else { TokenType::Minus };
+                self.add_token(token_type)
+            }
             '+' => self.add_token(TokenType::Plus),
             ';' => self.add_token(TokenType::Semicolon),
             '*' => self.add_token(TokenType::Star),
             '!' => {
                 let token_type = if self.match_next('=') { TokenType::BangEqual } else { TokenType::Bang };
-                self.add_token(token_type);
+                self.add_token(token_type)
             }
             '=' => {
                 let token_type = if self.match_next('=') { TokenType::EqualEqual } else { TokenType::Equal };
-                self.add_token(token_type);
+                self.add_token(token_type)
             }
             '<' => {
                 let token_type = if self.match_next('=') { TokenType::LessEqual } else { TokenType::Less };
-                self.add_token(token_type);
+                self.add_token(token_type)
             }
             '>' => {
                 let token_type = if self.match_next('=') { TokenType::GreaterEqual } else { TokenType::Greater };
-                self.add_token(token_type);
+                self.add_token(token_type)
             }
             '/' => {
                 if self.match_next('/') {
@@ -135,83 +438,143 @@ impl Scanner {
                     while !self.is_at_end() && self.peek() != '\n' {
                         self.advance();
                     }
+                    self.add_trivia(TriviaKind::LineComment)
                 } else if self.match_next('*') {
-                    while !self.is_at_end() && (self.advance() != '*' || self.peek_next() != '/') {
-                        self.advance();
-                    }
-                    self.advance();
-                    self.advance();
+                    self.block_comment()
                 } else {
-                    self.add_token(TokenType::Slash);
+                    self.add_token(TokenType::Slash)
                 }
             }
-            ' ' | '\r' | '\t' => {}
-            '\n' => self.line += 1,
+            ' ' | '\r' | '\t' => self.add_trivia(TriviaKind::Whitespace),
+            '\n' => {
+                self.new_line();
+                self.add_trivia(TriviaKind::Whitespace)
+            }
             '"' => self.string(),
             c if Self::is_digit(c) => self.number(),
             c if Self::is_alpha(c) => self.identifier(),
-            c => lox.report(self.line, "".to_string(), format!("Unexpected character {:?}", c))
-        };
+            c => Some(Err(ScanError {
+                line: self.start_line,
+                column: self.start_column,
+                message: format!("Unexpected character {:?}", c)
+            }))
+        }
     }
 
     fn advance(&mut self) -> char {
-        self.current += 1;
-        self.source.chars().nth(self.current - 1).unwrap()
+        self.fill_lookahead(1);
+        let (index, c) = self.lookahead.pop_front().unwrap();
+        self.current = self.base_offset + index + c.len_utf8();
+        self.column += 1;
+        self.lexeme.push(c);
+        c
     }
 
-    fn peek(&self) -> char {
-        if self.is_at_end() {
-            '\0'
-        } else {
-            self.source.chars().nth(self.current).unwrap()
+    fn peek(&mut self) -> char {
+        self.fill_lookahead(1);
+        match self.lookahead.front() {
+            Some(&(_, c)) => c,
+            None => '\0'
         }
     }
 
-    fn peek_next(&self) -> char {
-        if self.current + 1 >= self.source.len() {
-            '\0'
-        } else {
-            self.source.chars().nth(self.current + 1).unwrap()
+    fn peek_next(&mut self) -> char {
+        self.fill_lookahead(2);
+        match self.lookahead.get(1) {
+            Some(&(_, c)) => c,
+            None => '\0'
         }
     }
 
     fn match_next(&mut self, expected: char) -> bool {
-        if self.is_at_end() || self.source.chars().nth(self.current).unwrap() != expected {
+        if self.is_at_end() || self.peek() != expected {
             false
         } else {
-            self.current += 1;
+            self.advance();
             true
         }
     }
 
-    fn add_token(&mut self, token_type: TokenType) {
-        let text = &self.source[self.start..self.current];
-        self.tokens.push(Token::new(token_type, text.to_string(), self.line));
+    fn add_token(&mut self, token_type: TokenType) -> Option<Result<Token, ScanError>> {
+        self.add_literal_token(token_type, None)
     }
 
-    fn string(&mut self) {
+    fn add_literal_token(&mut self, token_type: TokenType, literal: Option<LiteralValue>) -> Option<Result<Token, ScanError>> {
+        let span = Span::new(self.start, self.current, self.start_line, self.start_column);
+        let trivia = ::std::mem::replace(&mut self.trivia, Vec::new());
+        Some(Ok(Token::new(token_type, literal, self.interner.intern(&self.lexeme), self.start_line, self.start_column, span, trivia)))
+    }
+
+    // Records the lexeme just scanned as trivia if that's been asked for. Runs of whitespace
+    // are merged into a single piece.
+    fn add_trivia(&mut self, kind: TriviaKind) -> Option<Result<Token, ScanError>> {
+        if !self.preserve_trivia {
+            return None;
+        }
+
+        if let Some(last) = self.trivia.last_mut() {
+            if kind == TriviaKind::Whitespace && last.kind == TriviaKind::Whitespace && last.span.end == self.start {
+                last.text.push_str(&self.lexeme);
+                last.span.end = self.current;
+                return None;
+            }
+        }
+
+        self.trivia.push(Trivia {
+            kind: kind,
+            text: self.lexeme.clone(),
+            span: Span::new(self.start, self.current, self.start_line, self.start_column)
+        });
+        None
+    }
+
+    // Skips a /* */ comment, whose opening line and column are kept in start_line and
+    // start_column for reporting one that never ends.
+    fn block_comment(&mut self) -> Option<Result<Token, ScanError>> {
+        loop {
+            if self.is_at_end() {
+                return Some(Err(ScanError {
+                    line: self.start_line,
+                    column: self.start_column,
+                    message: "Unterminated block comment.".to_string()
+                }));
+            }
+
+            match self.advance() {
+                '*' if self.peek() == '/' => {
+                    self.advance();
+                    return self.add_trivia(TriviaKind::BlockComment);
+                },
+                '\n' => self.new_line(),
+                _ => ()
+            }
+        }
+    }
+
+    fn string(&mut self) -> Option<Result<Token, ScanError>> {
         while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
-                self.line += 1;
+            if self.advance() == '\n' {
+                self.new_line();
             }
-            self.advance();
         }
 
-        // Unterminated string.
         if self.is_at_end() {
-            println!("{} {}", self.line, "Unterminated string.");
-            return;
+            return Some(Err(ScanError {
+                line: self.start_line,
+                column: self.start_column,
+                message: "Unterminated string.".to_string()
+            }));
         }
 
         // The closing ".
         self.advance();
 
         // Trim the surrounding quotes.
-        let value = self.source[self.start + 1..self.current - 1].to_string();
-        self.add_token(TokenType::String(value));
+        let value = self.interner.intern(&self.lexeme[1..self.lexeme.len() - 1]);
+        self.add_literal_token(TokenType::String, Some(LiteralValue::String(value)))
     }
 
-    fn number(&mut self) {
+    fn number(&mut self) -> Option<Result<Token, ScanError>> {
         while Self::is_digit(self.peek()) {
             self.advance();
         }
@@ -226,36 +589,20 @@ impl Scanner {
             }
         }
 
-        let number = self.source[self.start..self.current].parse().unwrap();
-        self.add_token(TokenType::Number(number));
+        let number = self.lexeme.parse().unwrap();
+        self.add_literal_token(TokenType::Number, Some(LiteralValue::Number(number)))
     }
 
-    fn identifier(&mut self) {
+    fn identifier(&mut self) -> Option<Result<Token, ScanError>> {
         while Self::is_alpha_numeric(self.peek()) {
             self.advance();
         }
 
-        let token_type = match &self.source[self.start..self.current] {
-            "and" => TokenType::And,
-            "class" => TokenType::Class,
-            "else" => TokenType::Else,
-            "false" => TokenType::False,
-            "for" => TokenType::For,
-            "fun" => TokenType::Fun,
-            "if" => TokenType::If,
-            "nil" => TokenType::Nil,
-            "or" => TokenType::Or,
-            "print" => TokenType::Print,
-            "return" => TokenType::Return,
-            "super" => TokenType::Super,
-            "this" => TokenType::This,
-            "true" => TokenType::True,
-            "var" => TokenType::Var,
-            "while" => TokenType::While,
-            _ => TokenType::Identifier
-        };
+        let token_type = KEYWORDS.iter()
+            .find(|&&(keyword, _)| keyword == &self.lexeme[..])
+            .map_or(TokenType::Identifier, |&(_, token_type)| token_type);
 
-        self.add_token(token_type);
+        self.add_token(token_type)
     }
 
     fn is_digit(value: char) -> bool {
@@ -275,4 +622,239 @@ impl Scanner {
     fn is_alpha_numeric(c: char) -> bool {
         Self::is_alpha(c) || Self::is_digit(c)
     }
-}
\ No newline at end of file
+}
+
+impl<S: Source> Iterator for Scanner<S> {
+    type Item = Result<Token, ScanError>;
+
+    // Yields tokens one at a time, ending with a single Eof token.
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.is_at_end() {
+            // We are at the beginning of the next lexeme.
+            self.start = self.current;
+            self.lexeme.clear();
+            self.start_line = self.line;
+            self.start_column = self.column;
+
+            if let Some(result) = self.scan_token() {
+                return Some(result);
+            }
+        }
+
+        if let Some(message) = self.source_error.take() {
+            return Some(Err(ScanError {
+                line: self.line,
+                column: self.column,
+                message: format!("Could not read source: {}", message)
+            }));
+        }
+
+        if self.reached_eof {
+            return None;
+        }
+
+        self.reached_eof = true;
+        let span = Span::new(self.current, self.current, self.line, self.column);
+        let trivia = ::std::mem::replace(&mut self.trivia, Vec::new());
+        Some(Ok(Token::new(TokenType::Eof, None, Arc::from(""), self.line, self.column, span, trivia)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TokenCategory {
+    Keyword,
+    Literal,
+    Comment,
+    Operator,
+    Punctuation,
+    Identifier
+}
+
+impl TokenCategory {
+    /// The category a token of the given type is highlighted as; Eof has none.
+    pub fn of(token_type: &TokenType) -> Option<Self> {
+        Some(match *token_type {
+            TokenType::LeftParen | TokenType::RightParen | TokenType::LeftBrace | TokenType::RightBrace |
+            TokenType::Comma | TokenType::Colon | TokenType::Dot | TokenType::Semicolon |
+            TokenType::Arrow => TokenCategory::Punctuation,
+            TokenType::Minus | TokenType::Plus | TokenType::Slash | TokenType::Star | TokenType::Bang |
+            TokenType::BangEqual | TokenType::Equal | TokenType::EqualEqual | TokenType::Greater |
+            TokenType::GreaterEqual | TokenType::Less | TokenType::LessEqual => TokenCategory::Operator,
+            TokenType::Identifier => TokenCategory::Identifier,
+            TokenType::String | TokenType::Number | TokenType::True | TokenType::False |
+            TokenType::Nil => TokenCategory::Literal,
+            TokenType::Eof => return None,
+            _ => TokenCategory::Keyword
+        })
+    }
+}
+
+/// Classifies the source for syntax highlighting, in source order. Whitespace and anything the
+/// scanner can't make sense of are left out.
+pub fn highlight(source: &str) -> Vec<(Span, TokenCategory)> {
+    let mut scanner = Scanner::new(source);
+    scanner.set_preserve_trivia(true);
+
+    let mut highlights = Vec::new();
+    for token in scanner.filter_map(Result::ok) {
+        for trivia in &token.leading_trivia {
+            if trivia.kind != TriviaKind::Whitespace {
+                highlights.push((trivia.span, TokenCategory::Comment));
+            }
+        }
+
+        if let Some(category) = TokenCategory::of(&token.token_type) {
+            highlights.push((token.span, category));
+        }
+    }
+
+    highlights
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    // Gives out one byte per read, so every multi-byte character is split across reads.
+    struct OneByteAtATime<'a>(&'a [u8]);
+
+    impl<'a> Read for OneByteAtATime<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.split_first() {
+                Some((&first, rest)) if !buf.is_empty() => {
+                    buf[0] = first;
+                    self.0 = rest;
+                    Ok(1)
+                },
+                _ => Ok(0)
+            }
+        }
+    }
+
+    // Each token's type, lexeme, line, column and byte span.
+    fn positions(tokens: &[Token]) -> Vec<(TokenType, String, i32, i32, usize, usize)> {
+        tokens.iter()
+            .map(|token| (token.token_type, token.lexeme.to_string(), token.line, token.column, token.span.start, token.span.end))
+            .collect()
+    }
+
+    #[test]
+    fn columns_count_characters_and_spans_count_bytes() {
+        let source = "print \"héllo→\"+x;\nvar y=é;";
+        let (tokens, errors) = Scanner::new(source).scan_tokens();
+        assert_eq!(positions(&tokens), vec![
+            (TokenType::Print, "print".to_string(), 1, 1, 0, 5),
+            (TokenType::String, "\"héllo→\"".to_string(), 1, 7, 6, 17),
+            (TokenType::Plus, "+".to_string(), 1, 15, 17, 18),
+            (TokenType::Identifier, "x".to_string(), 1, 16, 18, 19),
+            (TokenType::Semicolon, ";".to_string(), 1, 17, 19, 20),
+            (TokenType::Var, "var".to_string(), 2, 1, 21, 24),
+            (TokenType::Identifier, "y".to_string(), 2, 5, 25, 26),
+            (TokenType::Equal, "=".to_string(), 2, 6, 26, 27),
+            (TokenType::Semicolon, ";".to_string(), 2, 8, 29, 30),
+            (TokenType::Eof, "".to_string(), 2, 9, 30, 30)
+        ]);
+        assert_eq!(tokens[1].literal, Some(LiteralValue::String(Arc::from("héllo→"))));
+        assert_eq!(&source[tokens[1].span.start..tokens[1].span.end], "\"héllo→\"");
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].to_string(), "[line 2, column 7] Unexpected character 'é'");
+    }
+
+    #[test]
+    fn an_unterminated_block_comment_is_one_error() {
+        for source in &["1 /* never ends", "1 /* ends in a star *", "1 /*"] {
+            let (tokens, errors) = Scanner::new(source).scan_tokens();
+            assert_eq!(errors.len(), 1, "{:?}", source);
+            assert_eq!(errors[0].to_string(), "[line 1, column 3] Unterminated block comment.");
+            let types: Vec<TokenType> = tokens.iter().map(|token| token.token_type).collect();
+            assert_eq!(types, vec![TokenType::Number, TokenType::Eof]);
+        }
+    }
+
+    #[test]
+    fn a_block_comment_over_several_lines_moves_the_line_on() {
+        let (tokens, errors) = Scanner::new("a /* one\ntwo\n*/ b").scan_tokens();
+        assert!(errors.is_empty());
+        assert_eq!((tokens[1].lexeme.as_ref(), tokens[1].line, tokens[1].column), ("b", 3, 4));
+    }
+
+    #[test]
+    fn a_reader_gives_the_same_tokens_as_a_string() {
+        let source = "var s = \"→é😀\"; print s;";
+        let (expected, _) = Scanner::new(source).scan_tokens();
+        let (tokens, errors) = Scanner::from_reader(OneByteAtATime(source.as_bytes())).scan_tokens();
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(positions(&tokens), positions(&expected));
+        assert_eq!(tokens[3].literal, Some(LiteralValue::String(Arc::from("→é😀"))));
+    }
+
+    #[test]
+    fn invalid_utf8_from_a_reader_is_an_error() {
+        let source = b"print 1; \xff print 2;";
+        let (tokens, errors) = Scanner::from_reader(OneByteAtATime(source)).scan_tokens();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].to_string(), "[line 1, column 10] Could not read source: Invalid UTF-8 at byte 9.");
+        let types: Vec<TokenType> = tokens.iter().map(|token| token.token_type).collect();
+        assert_eq!(types, vec![TokenType::Print, TokenType::Number, TokenType::Semicolon, TokenType::Eof]);
+
+        // A character cut off by the end of the input.
+        let (_, errors) = Scanner::from_reader(OneByteAtATime(b"1 \xe2\x86")).scan_tokens();
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn trivia_and_lexemes_give_back_the_source() {
+        let source = "  // leading\nvar x = 1; /* a\nblock */\tprint x;\n// trailing\n";
+        let mut scanner = Scanner::new(source);
+        scanner.set_preserve_trivia(true);
+        let (tokens, errors) = scanner.scan_tokens();
+        assert!(errors.is_empty());
+
+        let mut text = String::new();
+        for token in &tokens {
+            for trivia in &token.leading_trivia {
+                assert_eq!(trivia.span.text(source), trivia.text);
+                text.push_str(&trivia.text);
+            }
+            text.push_str(&token.lexeme);
+        }
+        assert_eq!(text, source);
+
+        let kinds: Vec<TriviaKind> = tokens[0].leading_trivia.iter().map(|trivia| trivia.kind).collect();
+        assert_eq!(kinds, vec![TriviaKind::Whitespace, TriviaKind::LineComment, TriviaKind::Whitespace]);
+    }
+
+    #[test]
+    fn trivia_is_dropped_unless_asked_for() {
+        let (tokens, _) = Scanner::new("// comment\n var x;").scan_tokens();
+        assert!(tokens.iter().all(|token| token.leading_trivia.is_empty()));
+    }
+
+    #[test]
+    fn highlight_classifies_each_piece_of_the_source() {
+        let source = "var s = \"hi\"; // note\nif (s != nil) print 1.5; /* end */";
+        let highlights: Vec<(&str, TokenCategory)> = highlight(source).into_iter()
+            .map(|(span, category)| (span.text(source), category))
+            .collect();
+        assert_eq!(highlights, vec![
+            ("var", TokenCategory::Keyword),
+            ("s", TokenCategory::Identifier),
+            ("=", TokenCategory::Operator),
+            ("\"hi\"", TokenCategory::Literal),
+            (";", TokenCategory::Punctuation),
+            ("// note", TokenCategory::Comment),
+            ("if", TokenCategory::Keyword),
+            ("(", TokenCategory::Punctuation),
+            ("s", TokenCategory::Identifier),
+            ("!=", TokenCategory::Operator),
+            ("nil", TokenCategory::Literal),
+            (")", TokenCategory::Punctuation),
+            ("print", TokenCategory::Keyword),
+            ("1.5", TokenCategory::Literal),
+            (";", TokenCategory::Punctuation),
+            ("/* end */", TokenCategory::Comment)
+        ]);
+    }
+}
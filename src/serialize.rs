@@ -0,0 +1,199 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::{Formatter, Result as FmtResult};
+use std::rc::Rc;
+use serde::de::{Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::ser::{Error, Serialize, SerializeMap, SerializeSeq, Serializer};
+use interpreter::Value;
+
+// Lets hosts pass values in and out of a script as structured data. Nil, booleans, numbers,
+// strings, lists and maps are written as whatever the format has for them; functions, classes
+// and instances can't be written at all.
+
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Serializing { value: self, enclosing: &RefCell::new(Vec::new()) }.serialize(serializer)
+    }
+}
+
+// `enclosing` holds the lists and maps currently being written, to catch one that contains
+// itself before it's written out forever.
+struct Serializing<'a> {
+    value: &'a Value,
+    enclosing: &'a RefCell<Vec<*const ()>>
+}
+
+impl<'a> Serializing<'a> {
+    fn enter<E: Error>(&self, pointer: *const ()) -> Result<(), E> {
+        if self.enclosing.borrow().contains(&pointer) {
+            return Err(E::custom("Can't serialize a list or map that contains itself."));
+        }
+        self.enclosing.borrow_mut().push(pointer);
+        Ok(())
+    }
+}
+
+impl<'a> Serialize for Serializing<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match *self.value {
+            Value::Nil => serializer.serialize_unit(),
+            Value::Bool(value) => serializer.serialize_bool(value),
+            Value::Number(number) => serializer.serialize_f64(number),
+            Value::String(ref string) => serializer.serialize_str(string),
+            Value::List(ref list) => {
+                self.enter(Rc::as_ptr(list) as *const ())?;
+                let list = list.borrow();
+                let mut seq = serializer.serialize_seq(Some(list.len()))?;
+                for value in list.iter() {
+                    seq.serialize_element(&Serializing { value: value, enclosing: self.enclosing })?;
+                }
+                self.enclosing.borrow_mut().pop();
+                seq.end()
+            },
+            Value::Map(ref map) => {
+                self.enter(Rc::as_ptr(map) as *const ())?;
+                let map = map.borrow();
+                let mut entries = serializer.serialize_map(Some(map.len()))?;
+                for (key, value) in map.iter() {
+                    entries.serialize_entry(key, &Serializing { value: value, enclosing: self.enclosing })?;
+                }
+                self.enclosing.borrow_mut().pop();
+                entries.end()
+            },
+            Value::Callable(_) => Err(S::Error::custom("Can't serialize a function.")),
+            Value::Class(_) => Err(S::Error::custom("Can't serialize a class.")),
+            Value::Instance(_) => Err(S::Error::custom("Can't serialize an instance."))
+        }
+    }
+}
+
+/// Any number becomes a Lox number, sequences become lists, and maps become maps as long as
+/// their keys are strings. Like a list made from a `Vec`, the lists and maps made aren't known
+/// to the cycle collector.
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "nil, a boolean, a number, a string, a list or a map")
+    }
+
+    fn visit_unit<E>(self) -> Result<Value, E> {
+        Ok(Value::Nil)
+    }
+
+    fn visit_none<E>(self) -> Result<Value, E> {
+        Ok(Value::Nil)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+        Value::deserialize(deserializer)
+    }
+
+    fn visit_bool<E>(self, value: bool) -> Result<Value, E> {
+        Ok(Value::Bool(value))
+    }
+
+    fn visit_i64<E>(self, number: i64) -> Result<Value, E> {
+        Ok(Value::Number(number as f64))
+    }
+
+    fn visit_u64<E>(self, number: u64) -> Result<Value, E> {
+        Ok(Value::Number(number as f64))
+    }
+
+    fn visit_f64<E>(self, number: f64) -> Result<Value, E> {
+        Ok(Value::Number(number))
+    }
+
+    fn visit_str<E>(self, string: &str) -> Result<Value, E> {
+        Ok(Value::from(string))
+    }
+
+    fn visit_string<E>(self, string: String) -> Result<Value, E> {
+        Ok(Value::from(string))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+        let mut values = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(value) = seq.next_element()? {
+            values.push(value);
+        }
+        Ok(Value::List(Rc::new(RefCell::new(values))))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+        let mut entries = BTreeMap::new();
+        while let Some((key, value)) = map.next_entry::<String, Value>()? {
+            entries.insert(key, value);
+        }
+        Ok(Value::Map(Rc::new(RefCell::new(entries))))
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    extern crate serde_json;
+
+    use super::*;
+    use interpreter::Interpreter;
+    use {CollectingReporter, Lox};
+
+    fn global(source: &str, name: &str) -> Value {
+        let mut interpreter = Interpreter::with_stdlib();
+        let mut lox = Lox::new();
+        lox.set_reporter(Box::new(CollectingReporter::new()));
+        lox.run(&source.to_string(), &mut interpreter).unwrap();
+        interpreter.get_global(name).unwrap()
+    }
+
+    #[test]
+    fn nested_lists_and_maps_round_trip() {
+        let value = global("var m = map(); var l = list(); listPush(l, 1.5); listPush(l, nil); listPush(l, \"s\");\n\
+                            var inner = map(); mapSet(inner, \"t\", true); listPush(l, inner); mapSet(m, \"l\", l);", "m");
+        let json = serde_json::to_string(&value).unwrap();
+        assert_eq!(json, r#"{"l":[1.5,null,"s",{"t":true}]}"#);
+
+        let back: Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::to_string(&back).unwrap(), json);
+        match back {
+            Value::Map(ref map) => match map.borrow()["l"] {
+                Value::List(ref list) => assert!(list.borrow()[0] == Value::Number(1.5)),
+                _ => panic!("not a list")
+            },
+            _ => panic!("not a map")
+        }
+    }
+
+    #[test]
+    fn a_list_that_contains_itself_is_an_error() {
+        let list = Value::from(vec![Value::Nil]);
+        if let Value::List(ref inner) = list {
+            inner.borrow_mut().push(list.clone());
+        }
+        let error = serde_json::to_string(&list).unwrap_err();
+        assert_eq!(error.to_string(), "Can't serialize a list or map that contains itself.");
+        if let Value::List(ref inner) = list {
+            inner.borrow_mut().clear();
+        }
+
+        // The same list twice, side by side, is fine.
+        let shared = Value::from(vec![Value::from(1.0)]);
+        assert_eq!(serde_json::to_string(&Value::from(vec![shared.clone(), shared])).unwrap(), "[[1.0],[1.0]]");
+    }
+
+    #[test]
+    fn functions_and_instances_are_errors() {
+        let function = global("fun f() {}", "f");
+        assert_eq!(serde_json::to_string(&function).unwrap_err().to_string(), "Can't serialize a function.");
+        let instance = global("class A {} var a = A();", "a");
+        assert_eq!(serde_json::to_string(&instance).unwrap_err().to_string(), "Can't serialize an instance.");
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::rc::Rc;
+use std::sync::Arc;
+use interpreter::{ErrorKind, Value};
+
+/// A copy of a value that can be sent to another thread, to hand a script's results to the
+/// rest of a server or pass data in to an interpreter running elsewhere. `Value` can't be,
+/// since its lists and maps are reference counted for one thread only. Only plain data can
+/// be copied this way, and the copy's lists and maps can't be changed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SharedValue {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(Arc<str>),
+    List(Arc<Vec<SharedValue>>),
+    Map(Arc<BTreeMap<String, SharedValue>>)
+}
+
+/// Copies the value, along with everything in it. A list or map held in two places is
+/// copied twice. Fails on functions, classes and instances, and on lists and maps that
+/// contain themselves.
+impl TryFrom<Value> for SharedValue {
+    type Error = ErrorKind;
+
+    fn try_from(value: Value) -> Result<Self, ErrorKind> {
+        copy(&value, &mut Vec::new())
+    }
+}
+
+// `enclosing` holds the lists and maps currently being copied, to catch one that contains
+// itself.
+fn copy(value: &Value, enclosing: &mut Vec<*const ()>) -> Result<SharedValue, ErrorKind> {
+    match *value {
+        Value::Nil => Ok(SharedValue::Nil),
+        Value::Bool(value) => Ok(SharedValue::Bool(value)),
+        Value::Number(number) => Ok(SharedValue::Number(number)),
+        Value::String(ref string) => Ok(SharedValue::String(string.clone())),
+        Value::List(ref list) => {
+            enter(Rc::as_ptr(list) as *const (), enclosing)?;
+            let values: Result<Vec<SharedValue>, ErrorKind> = list.borrow().iter()
+                .map(|value| copy(value, enclosing))
+                .collect();
+            enclosing.pop();
+            Ok(SharedValue::List(Arc::new(values?)))
+        },
+        Value::Map(ref map) => {
+            enter(Rc::as_ptr(map) as *const (), enclosing)?;
+            let entries: Result<BTreeMap<String, SharedValue>, ErrorKind> = map.borrow().iter()
+                .map(|(key, value)| copy(value, enclosing).map(|value| (key.clone(), value)))
+                .collect();
+            enclosing.pop();
+            Ok(SharedValue::Map(Arc::new(entries?)))
+        },
+        _ => Err(ErrorKind::TypeError("Only nil, booleans, numbers, strings, lists and maps can be shared between threads.".to_string()))
+    }
+}
+
+fn enter(pointer: *const (), enclosing: &mut Vec<*const ()>) -> Result<(), ErrorKind> {
+    if enclosing.contains(&pointer) {
+        return Err(ErrorKind::InvalidArgument("A list or map that contains itself can't be shared between threads.".to_string()));
+    }
+    enclosing.push(pointer);
+    Ok(())
+}
+
+/// Makes a value from the copy, with new lists and maps that a script can change. Like a list
+/// made from a `Vec`, they aren't known to the cycle collector.
+impl From<SharedValue> for Value {
+    fn from(value: SharedValue) -> Self {
+        match value {
+            SharedValue::Nil => Value::Nil,
+            SharedValue::Bool(value) => Value::Bool(value),
+            SharedValue::Number(number) => Value::Number(number),
+            SharedValue::String(string) => Value::String(string),
+            SharedValue::List(values) => {
+                let values: Vec<Value> = values.iter().cloned().map(Value::from).collect();
+                Value::from(values)
+            },
+            SharedValue::Map(entries) => {
+                let entries: BTreeMap<String, Value> = entries.iter()
+                    .map(|(key, value)| (key.clone(), Value::from(value.clone())))
+                    .collect();
+                Value::from(entries)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use interpreter::Interpreter;
+    use {CollectingReporter, Lox};
+
+    fn run(interpreter: &mut Interpreter, source: &str) {
+        let mut lox = Lox::new();
+        lox.set_reporter(Box::new(CollectingReporter::new()));
+        lox.run(&source.to_string(), interpreter).unwrap();
+    }
+
+    #[test]
+    fn values_go_to_another_thread_and_back() {
+        let mut interpreter = Interpreter::with_stdlib();
+        run(&mut interpreter, "var data = map(); var l = list(); listPush(l, 1); listPush(l, 2); mapSet(data, \"numbers\", l);");
+        let shared = SharedValue::try_from(interpreter.get_global("data").unwrap()).unwrap();
+
+        let result = thread::spawn(move || {
+            let mut interpreter = Interpreter::with_stdlib();
+            interpreter.set_global("data", Value::from(shared));
+            run(&mut interpreter, "var l = mapGet(data, \"numbers\"); listPush(l, 3); var result = list(); listPush(result, len(l)); listPush(result, \"done\");");
+            SharedValue::try_from(interpreter.get_global("result").unwrap()).unwrap()
+        }).join().unwrap();
+
+        assert_eq!(result, SharedValue::List(Arc::new(vec![SharedValue::Number(3.0), SharedValue::String(Arc::from("done"))])));
+        interpreter.set_global("result", Value::from(result));
+        assert_eq!(interpreter.stringify(interpreter.get_global("result").unwrap()), "[3, done]");
+    }
+
+    #[test]
+    fn cycles_and_callables_are_refused() {
+        let mut interpreter = Interpreter::with_stdlib();
+        run(&mut interpreter, "var l = list(); listPush(l, l); var m = map(); mapSet(m, \"f\", clock); class A {} var a = A();");
+        for &(name, expected) in [
+            ("l", "A list or map that contains itself can't be shared between threads."),
+            ("m", "Only nil, booleans, numbers, strings, lists and maps can be shared between threads."),
+            ("A", "Only nil, booleans, numbers, strings, lists and maps can be shared between threads."),
+            ("a", "Only nil, booleans, numbers, strings, lists and maps can be shared between threads.")
+        ].iter() {
+            match SharedValue::try_from(interpreter.get_global(name).unwrap()) {
+                Err(error) => assert_eq!(error.to_string(), expected),
+                Ok(value) => panic!("{} was shared as {:?}", name, value)
+            }
+        }
+    }
+}
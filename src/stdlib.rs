@@ -0,0 +1,966 @@
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::env;
+use std::collections::BTreeMap;
+use std::mem::size_of;
+use std::rc::Rc;
+use gc;
+use interpreter::{ErrorKind, Interpreter, NativeScope, RuntimeError, Value};
+use parser::Parser;
+use regex::Regex;
+use resolver::Resolver;
+use scanner::{Scanner, Token};
+use std::fs::File;
+use std::io::prelude::*;
+use std::thread;
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Defines every native in the standard library.
+pub fn define_all(scope: &mut dyn NativeScope) {
+    define_math(scope);
+    define_strings(scope);
+    define_random(scope);
+    define_io(scope);
+    define_time(scope);
+    define_lists(scope);
+    define_maps(scope);
+    define_regex(scope);
+    define_process(scope);
+    define_eval(scope);
+    define_gc(scope);
+}
+
+pub fn define_math(scope: &mut dyn NativeScope) {
+    scope.define_native("sqrt", 1, sqrt);
+    scope.define_native("abs", 1, abs);
+    scope.define_native("floor", 1, floor);
+    scope.define_native("ceil", 1, ceil);
+    scope.define_native("pow", 2, pow);
+    scope.define_native("min", 2, min);
+    scope.define_native("max", 2, max);
+    scope.define_native("sin", 1, sin);
+    scope.define_native("cos", 1, cos);
+    scope.define_native("pi", 0, pi);
+}
+
+pub fn define_strings(scope: &mut dyn NativeScope) {
+    scope.define_native("len", 1, len);
+    scope.define_native("substring", 3, substring);
+    scope.define_native("indexOf", 2, index_of);
+    scope.define_native("upper", 1, upper);
+    scope.define_native("lower", 1, lower);
+    scope.define_native("trim", 1, trim);
+    scope.define_native("replace", 3, replace);
+    scope.define_native("split", 2, split);
+    scope.define_native("join", 2, join);
+}
+
+pub fn define_random(scope: &mut dyn NativeScope) {
+    scope.define_native("random", 0, random);
+    scope.define_native("randomSeed", 1, random_seed);
+}
+
+pub fn define_io(scope: &mut dyn NativeScope) {
+    scope.define_native("readLine", 0, read_line);
+    scope.define_native("write", 1, write);
+    scope.define_native("eprint", 1, eprint);
+    scope.define_native("readFile", 1, read_file);
+    scope.define_native("writeFile", 2, write_file);
+}
+
+pub fn define_time(scope: &mut dyn NativeScope) {
+    scope.define_native("sleep", 1, sleep);
+    scope.define_native("clockMillis", 0, clock_millis);
+    scope.define_native("formatTime", 2, format_time);
+}
+
+pub fn define_lists(scope: &mut dyn NativeScope) {
+    scope.define_native("list", 0, list);
+    scope.define_native("listGet", 2, list_get);
+    scope.define_native("listSet", 3, list_set);
+    scope.define_native("listPush", 2, list_push);
+    scope.define_native("listPop", 1, list_pop);
+    scope.define_native("listInsert", 3, list_insert);
+    scope.define_native("listRemove", 2, list_remove);
+    scope.define_native("listContains", 2, list_contains);
+    scope.define_native("listIndexOf", 2, list_index_of);
+    scope.define_native("listSort", 1, list_sort);
+}
+
+pub fn define_maps(scope: &mut dyn NativeScope) {
+    scope.define_native("map", 0, map);
+    scope.define_native("mapGet", 2, map_get);
+    scope.define_native("mapSet", 3, map_set);
+    scope.define_native("mapHas", 2, map_has);
+    scope.define_native("mapRemove", 2, map_remove);
+    scope.define_native("mapKeys", 1, map_keys);
+    scope.define_native("mapValues", 1, map_values);
+    scope.define_native("mapSize", 1, map_size);
+}
+
+pub fn define_regex(scope: &mut dyn NativeScope) {
+    scope.define_native("regexMatch", 2, regex_match);
+    scope.define_native("regexFind", 2, regex_find);
+    scope.define_native("regexReplace", 3, regex_replace);
+}
+
+pub fn define_process(scope: &mut dyn NativeScope) {
+    scope.define_native("args", 0, args);
+    scope.define_native("getenv", 1, getenv);
+    scope.define_native("exit", 1, exit);
+}
+
+pub fn define_eval(scope: &mut dyn NativeScope) {
+    scope.define_native("eval", 1, eval);
+}
+
+pub fn define_gc(scope: &mut dyn NativeScope) {
+    scope.define_native("gc", 0, gc);
+    scope.define_native("gcStats", 0, gc_stats);
+}
+
+fn number<'a>(paren: &'a Token, value: &'a Value) -> Result<f64, RuntimeError> {
+    match *value {
+        Value::Number(number) => Ok(number),
+        _ => Err(RuntimeError::new(paren.clone(), ErrorKind::TypeError("Argument must be a number.".to_string())))
+    }
+}
+
+fn string<'a>(paren: &'a Token, value: &'a Value) -> Result<&'a str, RuntimeError> {
+    match *value {
+        Value::String(ref string) => Ok(string),
+        _ => Err(RuntimeError::new(paren.clone(), ErrorKind::TypeError("Argument must be a string.".to_string())))
+    }
+}
+
+fn list_argument<'a>(paren: &'a Token, value: &'a Value) -> Result<Rc<RefCell<Vec<Value>>>, RuntimeError> {
+    match *value {
+        Value::List(ref list) => Ok(list.clone()),
+        _ => Err(RuntimeError::new(paren.clone(), ErrorKind::TypeError("Argument must be a list.".to_string())))
+    }
+}
+
+fn map_argument<'a>(paren: &'a Token, value: &'a Value) -> Result<Rc<RefCell<BTreeMap<String, Value>>>, RuntimeError> {
+    match *value {
+        Value::Map(ref map) => Ok(map.clone()),
+        _ => Err(RuntimeError::new(paren.clone(), ErrorKind::TypeError("Argument must be a map.".to_string())))
+    }
+}
+
+// Lists can end up holding themselves, so the cycle collector needs to know about them.
+fn new_list(interpreter: &mut Interpreter, paren: &Token, values: Vec<Value>) -> Result<Value, RuntimeError> {
+    interpreter.allocate(size_of::<Vec<Value>>() + values.iter().map(gc::size_of_value).sum::<usize>(), paren)?;
+    let list = Value::List(Rc::new(RefCell::new(values)));
+    interpreter.track(&list);
+    Ok(list)
+}
+
+fn new_string(interpreter: &mut Interpreter, paren: &Token, string: String) -> Result<Value, RuntimeError> {
+    interpreter.allocate(string.len(), paren)?;
+    Ok(Value::String(string.into()))
+}
+
+// Strings are indexed by character rather than by byte so that multi-byte text can't be split.
+fn index<'a>(paren: &'a Token, value: &'a Value, max: usize) -> Result<usize, RuntimeError> {
+    let index = number(paren, value)?;
+
+    if index.fract() != 0.0 || index < 0.0 || index > max as f64 {
+        Err(RuntimeError::new(paren.clone(), ErrorKind::IndexOutOfBounds(index)))
+    } else {
+        Ok(index as usize)
+    }
+}
+
+fn sqrt(_: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    Ok(Value::Number(number(paren, &arguments[0])?.sqrt()))
+}
+
+fn abs(_: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    Ok(Value::Number(number(paren, &arguments[0])?.abs()))
+}
+
+fn floor(_: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    Ok(Value::Number(number(paren, &arguments[0])?.floor()))
+}
+
+fn ceil(_: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    Ok(Value::Number(number(paren, &arguments[0])?.ceil()))
+}
+
+fn pow(_: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    Ok(Value::Number(number(paren, &arguments[0])?.powf(number(paren, &arguments[1])?)))
+}
+
+fn min(_: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    Ok(Value::Number(number(paren, &arguments[0])?.min(number(paren, &arguments[1])?)))
+}
+
+fn max(_: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    Ok(Value::Number(number(paren, &arguments[0])?.max(number(paren, &arguments[1])?)))
+}
+
+fn sin(_: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    Ok(Value::Number(number(paren, &arguments[0])?.sin()))
+}
+
+fn cos(_: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    Ok(Value::Number(number(paren, &arguments[0])?.cos()))
+}
+
+fn pi(_: &mut Interpreter, _: &Token, _: Vec<Value>) -> Result<Value, RuntimeError> {
+    Ok(Value::Number(::std::f64::consts::PI))
+}
+
+fn len(_: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    match arguments[0] {
+        Value::String(ref string) => Ok(Value::Number(string.chars().count() as f64)),
+        Value::List(ref list) => Ok(Value::Number(list.borrow().len() as f64)),
+        Value::Map(ref map) => Ok(Value::Number(map.borrow().len() as f64)),
+        _ => Err(RuntimeError::new(paren.clone(), ErrorKind::TypeError("Argument must be a string, list or map.".to_string())))
+    }
+}
+
+fn substring(interpreter: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let text = string(paren, &arguments[0])?;
+    let length = text.chars().count();
+    let start = index(paren, &arguments[1], length)?;
+    let end = index(paren, &arguments[2], length)?;
+
+    if start > end {
+        return Err(RuntimeError::new(paren.clone(), ErrorKind::InvalidArgument("Substring start must not be after its end.".to_string())));
+    }
+
+    new_string(interpreter, paren, text.chars().skip(start).take(end - start).collect::<String>())
+}
+
+fn index_of(_: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let text = string(paren, &arguments[0])?;
+    let needle = string(paren, &arguments[1])?;
+
+    Ok(Value::Number(match text.find(needle) {
+        Some(byte_index) => text[..byte_index].chars().count() as f64,
+        None => -1.0
+    }))
+}
+
+fn upper(interpreter: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    new_string(interpreter, paren, string(paren, &arguments[0])?.to_uppercase())
+}
+
+fn lower(interpreter: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    new_string(interpreter, paren, string(paren, &arguments[0])?.to_lowercase())
+}
+
+fn trim(interpreter: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    new_string(interpreter, paren, string(paren, &arguments[0])?.trim().to_string())
+}
+
+fn replace(interpreter: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let text = string(paren, &arguments[0])?;
+    let from = string(paren, &arguments[1])?;
+    let to = string(paren, &arguments[2])?;
+
+    if from.is_empty() {
+        return Err(RuntimeError::new(paren.clone(), ErrorKind::InvalidArgument("Cannot replace an empty string.".to_string())));
+    }
+
+    new_string(interpreter, paren, text.replace(from, to))
+}
+
+fn random(interpreter: &mut Interpreter, paren: &Token, _: Vec<Value>) -> Result<Value, RuntimeError> {
+    interpreter.require(interpreter.config().random, "Generating random numbers", paren)?;
+    Ok(Value::Number(interpreter.next_random()))
+}
+
+fn random_seed(interpreter: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    interpreter.seed_random(number(paren, &arguments[0])?.to_bits());
+    Ok(Value::Nil)
+}
+
+fn read_line(interpreter: &mut Interpreter, paren: &Token, _: Vec<Value>) -> Result<Value, RuntimeError> {
+    interpreter.require(interpreter.config().stdin, "Reading input", paren)?;
+    match interpreter.read_line() {
+        Ok(Some(line)) => new_string(interpreter, paren, line),
+        Ok(None) => Ok(Value::Nil),
+        Err(error) => Err(RuntimeError::new(paren.clone(), ErrorKind::Io(format!("Could not read input: {}.", error))))
+    }
+}
+
+fn write(interpreter: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let text = interpreter.stringify(arguments[0].clone());
+    interpreter.write_output(&text)
+        .map_err(|error| RuntimeError::new(paren.clone(), ErrorKind::Io(format!("Could not write output: {}.", error))))?;
+
+    Ok(Value::Nil)
+}
+
+fn eprint(interpreter: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let text = format!("{}\n", interpreter.stringify(arguments[0].clone()));
+    interpreter.write_error(&text)
+        .map_err(|error| RuntimeError::new(paren.clone(), ErrorKind::Io(format!("Could not write output: {}.", error))))?;
+    Ok(Value::Nil)
+}
+
+fn check_file_access(interpreter: &Interpreter, paren: &Token) -> Result<(), RuntimeError> {
+    interpreter.require(interpreter.config().filesystem, "File access", paren)
+}
+
+fn read_file(interpreter: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    check_file_access(interpreter, paren)?;
+    let path = string(paren, &arguments[0])?;
+    let mut contents = String::new();
+
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .map_err(|error| RuntimeError::new(paren.clone(), ErrorKind::Io(format!("Could not read '{}': {}.", path, error))))?;
+
+    new_string(interpreter, paren, contents)
+}
+
+fn write_file(interpreter: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    check_file_access(interpreter, paren)?;
+    let path = string(paren, &arguments[0])?;
+    let contents = string(paren, &arguments[1])?;
+
+    File::create(path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .map_err(|error| RuntimeError::new(paren.clone(), ErrorKind::Io(format!("Could not write '{}': {}.", path, error))))?;
+
+    Ok(Value::Nil)
+}
+
+/// How long it's been since the Unix epoch. A wasm32 build has no system clock to read, so it
+/// asks JavaScript's `Date` when built with the `wasm` feature and has no time at all otherwise.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn unix_time() -> Option<Duration> {
+    SystemTime::now().duration_since(UNIX_EPOCH).ok()
+}
+
+#[cfg(all(target_arch = "wasm32", feature = "wasm"))]
+pub fn unix_time() -> Option<Duration> {
+    Some(Duration::from_micros((::js_sys::Date::now() * 1000.0) as u64))
+}
+
+#[cfg(all(target_arch = "wasm32", not(feature = "wasm")))]
+pub fn unix_time() -> Option<Duration> {
+    None
+}
+
+fn clock_millis(interpreter: &mut Interpreter, paren: &Token, _: Vec<Value>) -> Result<Value, RuntimeError> {
+    interpreter.require(interpreter.config().time, "Reading the clock", paren)?;
+    let duration = unix_time().ok_or_else(|| RuntimeError::new(paren.clone(), ErrorKind::Unsupported("Reading the clock".to_string())))?;
+    Ok(Value::Number(duration.as_secs() as f64 * 1000.0 + duration.subsec_nanos() as f64 / 1_000_000.0))
+}
+
+// Formats a timestamp in seconds (as returned by clock()) in UTC. The format supports %Y, %m,
+// %d, %H, %M, %S and %%; anything else is copied through unchanged.
+fn format_time(_: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let timestamp = number(paren, &arguments[0])?;
+    let format = string(paren, &arguments[1])?;
+
+    if !timestamp.is_finite() {
+        return Err(RuntimeError::new(paren.clone(), ErrorKind::InvalidArgument("Timestamp must be a finite number.".to_string())));
+    }
+
+    let seconds = timestamp.floor() as i64;
+    let (year, month, day) = civil_from_days(seconds.div_euclid(86400));
+    let seconds_of_day = seconds.rem_euclid(86400);
+
+    let mut formatted = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            formatted.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('Y') => formatted.push_str(&format!("{:04}", year)),
+            Some('m') => formatted.push_str(&format!("{:02}", month)),
+            Some('d') => formatted.push_str(&format!("{:02}", day)),
+            Some('H') => formatted.push_str(&format!("{:02}", seconds_of_day / 3600)),
+            Some('M') => formatted.push_str(&format!("{:02}", seconds_of_day / 60 % 60)),
+            Some('S') => formatted.push_str(&format!("{:02}", seconds_of_day % 60)),
+            Some('%') => formatted.push('%'),
+            Some(other) => {
+                formatted.push('%');
+                formatted.push(other);
+            },
+            None => formatted.push('%')
+        }
+    }
+
+    Ok(Value::String(formatted.into()))
+}
+
+// Converts days since 1970-01-01 into a (year, month, day) date, following Howard Hinnant's
+// civil_from_days algorithm.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+fn sleep(interpreter: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    interpreter.require(interpreter.config().time, "Sleeping", paren)?;
+    let milliseconds = number(paren, &arguments[0])?;
+    // There are no threads to put to sleep in a browser.
+    if cfg!(target_arch = "wasm32") {
+        return Err(RuntimeError::new(paren.clone(), ErrorKind::Unsupported("Sleeping".to_string())));
+    }
+
+    if milliseconds < 0.0 || !milliseconds.is_finite() {
+        return Err(RuntimeError::new(paren.clone(), ErrorKind::InvalidArgument("Sleep duration must be a non-negative number.".to_string())));
+    }
+
+    // Sleeps a little at a time so that cancelling the script doesn't have to wait it out.
+    let mut remaining = Duration::from_micros((milliseconds * 1000.0) as u64);
+    while remaining > Duration::from_millis(0) {
+        interpreter.check_cancelled(paren)?;
+        let step = remaining.min(Duration::from_millis(10));
+        thread::sleep(step);
+        remaining -= step;
+    }
+    Ok(Value::Nil)
+}
+
+// An empty separator splits the string into its individual characters.
+fn split(interpreter: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let text = string(paren, &arguments[0])?;
+    let separator = string(paren, &arguments[1])?;
+
+    let parts = if separator.is_empty() {
+        text.chars().map(|c| Value::String(c.to_string().into())).collect()
+    } else {
+        text.split(separator).map(|part| Value::String(part.to_string().into())).collect()
+    };
+
+    new_list(interpreter, paren, parts)
+}
+
+fn join(interpreter: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let list = list_argument(paren, &arguments[0])?;
+    let separator = string(paren, &arguments[1])?;
+    let parts: Vec<String> = list.borrow().iter().map(|value| interpreter.stringify(value.clone())).collect();
+
+    new_string(interpreter, paren, parts.join(separator))
+}
+
+fn list(interpreter: &mut Interpreter, paren: &Token, _: Vec<Value>) -> Result<Value, RuntimeError> {
+    new_list(interpreter, paren, Vec::new())
+}
+
+fn list_get(_: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let list = list_argument(paren, &arguments[0])?;
+    let list = list.borrow();
+
+    if list.is_empty() {
+        return Err(RuntimeError::new(paren.clone(), ErrorKind::InvalidArgument("Cannot get an element of an empty list.".to_string())));
+    }
+
+    let index = index(paren, &arguments[1], list.len() - 1)?;
+    Ok(list[index].clone())
+}
+
+fn list_set(_: &mut Interpreter, paren: &Token, mut arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let list = list_argument(paren, &arguments[0])?;
+    let mut list = list.borrow_mut();
+
+    if list.is_empty() {
+        return Err(RuntimeError::new(paren.clone(), ErrorKind::InvalidArgument("Cannot set an element of an empty list.".to_string())));
+    }
+
+    let index = index(paren, &arguments[1], list.len() - 1)?;
+    list[index] = arguments.pop().unwrap();
+    Ok(Value::Nil)
+}
+
+fn list_push(interpreter: &mut Interpreter, paren: &Token, mut arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let list = list_argument(paren, &arguments[0])?;
+    interpreter.allocate(size_of::<Value>(), paren)?;
+    list.borrow_mut().push(arguments.pop().unwrap());
+    Ok(Value::Nil)
+}
+
+fn list_pop(_: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let list = list_argument(paren, &arguments[0])?;
+    let value = list.borrow_mut().pop();
+    value.ok_or_else(|| RuntimeError::new(paren.clone(), ErrorKind::InvalidArgument("Cannot pop from an empty list.".to_string())))
+}
+
+fn list_insert(interpreter: &mut Interpreter, paren: &Token, mut arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let list = list_argument(paren, &arguments[0])?;
+    interpreter.allocate(size_of::<Value>(), paren)?;
+    let mut list = list.borrow_mut();
+    let index = index(paren, &arguments[1], list.len())?;
+    list.insert(index, arguments.pop().unwrap());
+    Ok(Value::Nil)
+}
+
+fn list_remove(_: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let list = list_argument(paren, &arguments[0])?;
+    let mut list = list.borrow_mut();
+
+    if list.is_empty() {
+        return Err(RuntimeError::new(paren.clone(), ErrorKind::InvalidArgument("Cannot remove from an empty list.".to_string())));
+    }
+
+    let index = index(paren, &arguments[1], list.len() - 1)?;
+    Ok(list.remove(index))
+}
+
+fn list_contains(_: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let list = list_argument(paren, &arguments[0])?;
+    let contains = list.borrow().contains(&arguments[1]);
+    Ok(Value::Bool(contains))
+}
+
+fn list_index_of(_: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let list = list_argument(paren, &arguments[0])?;
+    let position = list.borrow().iter().position(|value| *value == arguments[1]);
+
+    Ok(Value::Number(match position {
+        Some(index) => index as f64,
+        None => -1.0
+    }))
+}
+
+// Sorts numbers numerically and strings lexicographically; a list mixing the two (or holding
+// anything else) has no ordering and is an error.
+fn list_sort(_: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let list = list_argument(paren, &arguments[0])?;
+    let mut list = list.borrow_mut();
+
+    let sortable = list.iter().all(|value| match *value { Value::Number(_) => true, _ => false })
+        || list.iter().all(|value| match *value { Value::String(_) => true, _ => false });
+
+    if !sortable {
+        return Err(RuntimeError::new(paren.clone(), ErrorKind::TypeError("Can only sort lists of all numbers or all strings.".to_string())));
+    }
+
+    list.sort_by(|left, right| match (left, right) {
+        (&Value::Number(left), &Value::Number(right)) => left.partial_cmp(&right).unwrap_or(Ordering::Equal),
+        (&Value::String(ref left), &Value::String(ref right)) => left.cmp(right),
+        _ => Ordering::Equal
+    });
+
+    Ok(Value::Nil)
+}
+
+// Maps are keyed by strings and kept sorted by key, so iterating or printing one is
+// deterministic.
+fn map(interpreter: &mut Interpreter, paren: &Token, _: Vec<Value>) -> Result<Value, RuntimeError> {
+    interpreter.allocate(size_of::<BTreeMap<String, Value>>(), paren)?;
+    let map = Value::Map(Rc::new(RefCell::new(BTreeMap::new())));
+    interpreter.track(&map);
+    Ok(map)
+}
+
+fn map_get(_: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let map = map_argument(paren, &arguments[0])?;
+    let key = string(paren, &arguments[1])?;
+    let value = map.borrow().get(key).cloned();
+    Ok(value.unwrap_or(Value::Nil))
+}
+
+fn map_set(interpreter: &mut Interpreter, paren: &Token, mut arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let map = map_argument(paren, &arguments[0])?;
+    let key = string(paren, &arguments[1])?.to_string();
+    if !map.borrow().contains_key(&key) {
+        interpreter.allocate(size_of::<String>() + key.len() + size_of::<Value>(), paren)?;
+    }
+    map.borrow_mut().insert(key, arguments.pop().unwrap());
+    Ok(Value::Nil)
+}
+
+fn map_has(_: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let map = map_argument(paren, &arguments[0])?;
+    let has = map.borrow().contains_key(string(paren, &arguments[1])?);
+    Ok(Value::Bool(has))
+}
+
+fn map_remove(_: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let map = map_argument(paren, &arguments[0])?;
+    let value = map.borrow_mut().remove(string(paren, &arguments[1])?);
+    Ok(value.unwrap_or(Value::Nil))
+}
+
+fn map_keys(interpreter: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let map = map_argument(paren, &arguments[0])?;
+    let keys = map.borrow().keys().map(|key| Value::String(key.clone().into())).collect();
+    new_list(interpreter, paren, keys)
+}
+
+fn map_values(interpreter: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let map = map_argument(paren, &arguments[0])?;
+    let values = map.borrow().values().cloned().collect();
+    new_list(interpreter, paren, values)
+}
+
+fn map_size(_: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let map = map_argument(paren, &arguments[0])?;
+    let size = map.borrow().len();
+    Ok(Value::Number(size as f64))
+}
+
+fn regex_argument<'a>(paren: &'a Token, value: &'a Value) -> Result<Regex, RuntimeError> {
+    Regex::new(string(paren, value)?)
+        .map_err(|message| RuntimeError::new(paren.clone(), ErrorKind::InvalidArgument(format!("Invalid regular expression: {}", message))))
+}
+
+// Matching costs a unit of fuel for each way of matching followed past a character, and stops
+// if the script is cancelled.
+fn regex_step<'a>(interpreter: &'a mut Interpreter, paren: &'a Token) -> impl FnMut(usize) -> Result<(), RuntimeError> + 'a {
+    move |work| {
+        interpreter.spend_fuel(work as u64, paren)?;
+        interpreter.check_cancelled(paren)
+    }
+}
+
+// Patterns aren't anchored, so regexMatch() is true if the pattern matches anywhere in the text.
+fn regex_match(interpreter: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let regex = regex_argument(paren, &arguments[0])?;
+    Ok(Value::Bool(regex.is_match(string(paren, &arguments[1])?, &mut regex_step(interpreter, paren))?))
+}
+
+fn regex_find(interpreter: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let regex = regex_argument(paren, &arguments[0])?;
+    let text = string(paren, &arguments[1])?;
+
+    Ok(match regex.find(text, &mut regex_step(interpreter, paren))? {
+        Some((start, end)) => Value::String(text.chars().skip(start).take(end - start).collect::<String>().into()),
+        None => Value::Nil
+    })
+}
+
+fn regex_replace(interpreter: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let regex = regex_argument(paren, &arguments[0])?;
+    let text = string(paren, &arguments[1])?;
+    let replaced = regex.replace_all(text, string(paren, &arguments[2])?, &mut regex_step(interpreter, paren))?;
+    new_string(interpreter, paren, replaced)
+}
+
+fn args(interpreter: &mut Interpreter, paren: &Token, _: Vec<Value>) -> Result<Value, RuntimeError> {
+    interpreter.require(interpreter.config().environment, "Reading the environment", paren)?;
+    let args = interpreter.args().iter().map(|arg| Value::String(arg.clone().into())).collect();
+    new_list(interpreter, paren, args)
+}
+
+fn getenv(interpreter: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    interpreter.require(interpreter.config().environment, "Reading the environment", paren)?;
+    Ok(match env::var(string(paren, &arguments[0])?) {
+        Ok(value) => Value::String(value.into()),
+        Err(_) => Value::Nil
+    })
+}
+
+fn exit(interpreter: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let code = number(paren, &arguments[0])?;
+
+    if code.fract() != 0.0 || code < i32::min_value() as f64 || code > i32::max_value() as f64 {
+        return Err(RuntimeError::new(paren.clone(), ErrorKind::InvalidArgument("Exit code must be an integer.".to_string())));
+    }
+
+    interpreter.request_exit(code as i32);
+    Err(RuntimeError::new(paren.clone(), ErrorKind::Exit))
+}
+
+// Syntax errors in the evaluated source become a runtime error at the eval() call rather
+// than being printed, and the code runs in the caller's scope.
+fn eval(interpreter: &mut Interpreter, paren: &Token, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let source = string(paren, &arguments[0])?;
+    let mut diagnostics: Vec<String> = Vec::new();
+
+    let mut scanner = Scanner::new(source);
+    scanner.set_interner(interpreter.interner().clone());
+    let (tokens, errors) = scanner.scan_tokens();
+
+    if let Some(error) = errors.into_iter().next() {
+        return Err(RuntimeError::new(paren.clone(), ErrorKind::InvalidSource(error.to_string())));
+    }
+
+    let mut parser = Parser::new(&tokens);
+    parser.set_implicit_semicolon(true);
+    let (mut statements, errors) = parser.parse(&mut diagnostics);
+
+    if let Some(error) = errors.into_iter().next() {
+        return Err(RuntimeError::new(paren.clone(), ErrorKind::InvalidSource(error.to_string())));
+    }
+
+    // The resolver only reports errors, so the first thing it reports is the one to pass on.
+    diagnostics.clear();
+    // Every scope but the globals is already running, so the evaluated code resolves against
+    // the names in them.
+    let mut scopes = Vec::new();
+    let mut environment = interpreter.environment().clone();
+    loop {
+        let enclosing = environment.borrow().enclosing();
+        match enclosing {
+            Some(enclosing) => {
+                scopes.push(environment.borrow().declared_names());
+                environment = enclosing;
+            },
+            None => break
+        }
+    }
+
+    let had_error = {
+        let mut resolver = Resolver::new(&mut diagnostics);
+        for names in scopes.into_iter().rev() {
+            resolver.push_scope(names);
+        }
+        resolver.resolve(&mut statements);
+        resolver.had_error()
+    };
+
+    if had_error {
+        return Err(RuntimeError::new(paren.clone(), ErrorKind::InvalidSource(diagnostics.remove(0))));
+    }
+
+    interpreter.execute_in_scope(&statements)
+}
+
+// Collects cycles right away, returning how many objects were freed.
+fn gc(interpreter: &mut Interpreter, _: &Token, _: Vec<Value>) -> Result<Value, RuntimeError> {
+    Ok(Value::Number(interpreter.collect_garbage() as f64))
+}
+
+// A map of how many collections there have been, how many objects they freed in all and how
+// many objects are being tracked now.
+fn gc_stats(interpreter: &mut Interpreter, _: &Token, _: Vec<Value>) -> Result<Value, RuntimeError> {
+    let stats = interpreter.gc_stats();
+    let mut map = BTreeMap::new();
+    map.insert("collections".to_string(), Value::Number(stats.collections as f64));
+    map.insert("freed".to_string(), Value::Number(stats.freed as f64));
+    map.insert("tracked".to_string(), Value::Number(stats.tracked as f64));
+
+    let map = Value::Map(Rc::new(RefCell::new(map)));
+    interpreter.track(&map);
+    Ok(map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {CollectingReporter, Lox, LoxError};
+
+    fn value(expr: &str) -> String {
+        value_after("", expr)
+    }
+
+    // What `expr` evaluates to once `setup` has run, as a script would print it.
+    fn value_after(setup: &str, expr: &str) -> String {
+        let mut interpreter = Interpreter::with_stdlib();
+        let mut lox = Lox::new();
+        let reporter = CollectingReporter::new();
+        lox.set_reporter(Box::new(reporter.clone()));
+        if let Err(error) = lox.run(&format!("{}\nvar result = {};", setup, expr), &mut interpreter) {
+            panic!("{} failed: {} {:?}", expr, error, reporter.take());
+        }
+        interpreter.stringify(interpreter.get_global("result").unwrap())
+    }
+
+    fn error(expr: &str) -> String {
+        error_after("", expr)
+    }
+
+    // The message of the runtime error `expr` fails with once `setup` has run.
+    fn error_after(setup: &str, expr: &str) -> String {
+        let mut lox = Lox::new();
+        let reporter = CollectingReporter::new();
+        lox.set_reporter(Box::new(reporter.clone()));
+        match lox.run(&format!("{}\n{};", setup, expr), &mut Interpreter::with_stdlib()) {
+            Err(LoxError::Runtime { error: RuntimeError(_, kind, _), .. }) => {
+                assert_eq!(reporter.take().len(), 1);
+                kind.to_string()
+            },
+            result => panic!("{} gave {:?}", expr, result.map_err(|error| error.to_string()))
+        }
+    }
+
+    #[test]
+    fn math_natives() {
+        for &(expr, expected) in [
+            ("sqrt(16)", "4"),
+            ("abs(-2.5)", "2.5"),
+            ("floor(-1.5)", "-2"),
+            ("ceil(1.2)", "2"),
+            ("pow(2, 10)", "1024"),
+            ("min(3, -3)", "-3"),
+            ("max(3, -3)", "3"),
+            ("sin(0) + cos(0)", "1"),
+            ("floor(pi() * 100)", "314")
+        ].iter() {
+            assert_eq!(value(expr), expected, "{}", expr);
+        }
+    }
+
+    #[test]
+    fn math_natives_need_numbers() {
+        for expr in &["sqrt(\"4\")", "pow(2, nil)", "min(list(), 1)", "floor(true)"] {
+            assert_eq!(error(expr), "Argument must be a number.", "{}", expr);
+        }
+    }
+
+    #[test]
+    fn string_natives_count_characters() {
+        for &(expr, expected) in [
+            ("len(\"héllo\")", "5"),
+            ("substring(\"héllo\", 1, 3)", "él"),
+            ("substring(\"abc\", 3, 3)", ""),
+            ("indexOf(\"héllo\", \"l\")", "2"),
+            ("indexOf(\"abc\", \"z\")", "-1"),
+            ("upper(\"abc\") + lower(\"DEF\")", "ABCdef"),
+            ("trim(\"  a b  \")", "a b"),
+            ("replace(\"a-b-c\", \"-\", \"+\")", "a+b+c")
+        ].iter() {
+            assert_eq!(value(expr), expected, "{}", expr);
+        }
+    }
+
+    #[test]
+    fn string_natives_reject_bad_arguments() {
+        for &(expr, expected) in [
+            ("substring(\"abc\", 0, 4)", "Index 4 is out of bounds."),
+            ("substring(\"abc\", -1, 2)", "Index -1 is out of bounds."),
+            ("substring(\"abc\", 0.5, 2)", "Index 0.5 is out of bounds."),
+            ("substring(\"abc\", 2, 1)", "Substring start must not be after its end."),
+            ("replace(\"abc\", \"\", \"x\")", "Cannot replace an empty string."),
+            ("upper(1)", "Argument must be a string."),
+            ("indexOf(\"abc\", nil)", "Argument must be a string."),
+            ("len(1)", "Argument must be a string, list or map.")
+        ].iter() {
+            assert_eq!(error(expr), expected, "{}", expr);
+        }
+    }
+
+    #[test]
+    fn list_natives() {
+        let setup = "var l = list(); listPush(l, 3); listPush(l, 1); listPush(l, 2);";
+        for &(expr, expected) in [
+            ("l", "[3, 1, 2]"),
+            ("listGet(l, 2)", "2"),
+            ("listContains(l, 1)", "true"),
+            ("listContains(l, 4)", "false"),
+            ("listIndexOf(l, 2)", "2"),
+            ("listIndexOf(l, 4)", "-1"),
+            ("listPop(l) + len(l)", "4"),
+            ("listRemove(l, 0)", "3")
+        ].iter() {
+            assert_eq!(value_after(setup, expr), expected, "{}", expr);
+        }
+
+        assert_eq!(value_after(&format!("{} listInsert(l, 3, 4); listInsert(l, 0, 0); listSet(l, 1, 5);", setup), "l"), "[0, 5, 1, 2, 4]");
+        assert_eq!(value_after(&format!("{} listSort(l);", setup), "l"), "[1, 2, 3]");
+        assert_eq!(value_after("var l = split(\"b a c\", \" \"); listSort(l);", "l"), "[a, b, c]");
+    }
+
+    #[test]
+    fn list_natives_reject_bad_indexes_and_types() {
+        let setup = "var l = list(); listPush(l, 1); var empty = list();";
+        for &(expr, expected) in [
+            ("listGet(l, 1)", "Index 1 is out of bounds."),
+            ("listGet(l, -1)", "Index -1 is out of bounds."),
+            ("listSet(l, 1, 0)", "Index 1 is out of bounds."),
+            ("listInsert(l, 2, 0)", "Index 2 is out of bounds."),
+            ("listRemove(l, 1)", "Index 1 is out of bounds."),
+            ("listGet(empty, 0)", "Cannot get an element of an empty list."),
+            ("listPop(empty)", "Cannot pop from an empty list."),
+            ("listRemove(empty, 0)", "Cannot remove from an empty list."),
+            ("listGet(l, \"0\")", "Argument must be a number."),
+            ("listPush(map(), 1)", "Argument must be a list."),
+            ("listPush(l, \"a\"); listSort(l)", "Can only sort lists of all numbers or all strings.")
+        ].iter() {
+            assert_eq!(error_after(setup, expr), expected, "{}", expr);
+        }
+    }
+
+    #[test]
+    fn map_natives_keep_keys_in_order() {
+        let setup = "var m = map(); mapSet(m, \"b\", 2); mapSet(m, \"a\", 1); mapSet(m, \"c\", 3); mapSet(m, \"b\", 4);";
+        for &(expr, expected) in [
+            ("mapKeys(m)", "[a, b, c]"),
+            ("mapValues(m)", "[1, 4, 3]"),
+            ("mapSize(m)", "3"),
+            ("mapGet(m, \"b\")", "4"),
+            ("mapGet(m, \"z\")", "nil"),
+            ("mapHas(m, \"a\")", "true"),
+            ("mapHas(m, \"z\")", "false"),
+            ("mapRemove(m, \"a\") + mapSize(m)", "3"),
+            ("mapRemove(m, \"z\")", "nil")
+        ].iter() {
+            assert_eq!(value_after(setup, expr), expected, "{}", expr);
+        }
+    }
+
+    #[test]
+    fn map_natives_need_a_map_and_string_keys() {
+        for &(expr, expected) in [
+            ("mapGet(list(), \"a\")", "Argument must be a map."),
+            ("mapSet(map(), 1, 2)", "Argument must be a string."),
+            ("mapKeys(nil)", "Argument must be a map.")
+        ].iter() {
+            assert_eq!(error(expr), expected, "{}", expr);
+        }
+    }
+
+    #[test]
+    fn split_and_join() {
+        for &(expr, expected) in [
+            ("split(\"a,b,,c\", \",\")", "[a, b, , c]"),
+            ("len(split(\"\", \",\"))", "1"),
+            ("split(\"hé→\", \"\")", "[h, é, →]"),
+            ("len(split(\"\", \"\"))", "0"),
+            ("split(\"a--b\", \"--\")", "[a, b]"),
+            ("join(split(\"a b c\", \" \"), \"-\")", "a-b-c"),
+            ("join(list(), \",\")", "")
+        ].iter() {
+            assert_eq!(value(expr), expected, "{}", expr);
+        }
+        assert_eq!(value_after("var l = list(); listPush(l, 1); listPush(l, nil); listPush(l, true);", "join(l, \" \")"), "1 nil true");
+
+        assert_eq!(error("split(1, \",\")"), "Argument must be a string.");
+        assert_eq!(error("join(\"abc\", \",\")"), "Argument must be a list.");
+    }
+
+    #[test]
+    fn civil_from_days_around_the_epoch_and_leap_days() {
+        for &(days, date) in [
+            (0, (1970, 1, 1)),
+            (-1, (1969, 12, 31)),
+            (11016, (2000, 2, 29)),
+            (19782, (2024, 2, 29)),
+            (47540, (2100, 2, 28)),
+            (47541, (2100, 3, 1)),
+            (-25508, (1900, 3, 1)),
+            (-719468, (0, 3, 1))
+        ].iter() {
+            assert_eq!(civil_from_days(days), date, "{}", days);
+        }
+    }
+
+    #[test]
+    fn format_time_writes_utc_fields() {
+        for &(expr, expected) in [
+            ("formatTime(0, \"%Y-%m-%d %H:%M:%S\")", "1970-01-01 00:00:00"),
+            ("formatTime(-1, \"%Y-%m-%d %H:%M:%S\")", "1969-12-31 23:59:59"),
+            ("formatTime(1709214330.9, \"%d/%m/%Y %H:%M:%S\")", "29/02/2024 13:45:30"),
+            ("formatTime(0, \"100%% %q %\")", "100% %q %")
+        ].iter() {
+            assert_eq!(value(expr), expected, "{}", expr);
+        }
+
+        assert_eq!(error("formatTime(0 / 0, \"%Y\")"), "Timestamp must be a finite number.");
+        assert_eq!(error("formatTime(\"0\", \"%Y\")"), "Argument must be a number.");
+    }
+}
@@ -0,0 +1,46 @@
+// Finds likely intended spellings for mistyped keywords and names, for "did you mean" hints.
+
+/// The closest of `candidates` to `word`, if any is near enough to be a plausible typo.
+pub fn closest<'a, I: IntoIterator<Item = &'a str>>(word: &str, candidates: I) -> Option<&'a str> {
+    let threshold = ::std::cmp::max(1, word.chars().count() / 3);
+
+    candidates.into_iter()
+        .filter(|candidate| *candidate != word)
+        .map(|candidate| (edit_distance(word, candidate), candidate))
+        .filter(|&(distance, _)| distance <= threshold)
+        .min_by_key(|&(distance, _)| distance)
+        .map(|(_, candidate)| candidate)
+}
+
+/// The number of single-character insertions, deletions, substitutions and adjacent
+/// transpositions it takes to turn `a` into `b`.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut distances = vec![vec![0; b.len() + 1]; a.len() + 1];
+
+    for i in 0..a.len() + 1 {
+        distances[i][0] = i;
+    }
+    for j in 0..b.len() + 1 {
+        distances[0][j] = j;
+    }
+
+    for i in 1..a.len() + 1 {
+        for j in 1..b.len() + 1 {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            let mut distance = ::std::cmp::min(
+                ::std::cmp::min(distances[i - 1][j] + 1, distances[i][j - 1] + 1),
+                distances[i - 1][j - 1] + cost
+            );
+
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = ::std::cmp::min(distance, distances[i - 2][j - 2] + 1);
+            }
+
+            distances[i][j] = distance;
+        }
+    }
+
+    distances[a.len()][b.len()]
+}
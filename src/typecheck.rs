@@ -0,0 +1,311 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Result as FmtResult, Formatter};
+use std::sync::Arc;
+use ast::*;
+use lint::{Context, Lint};
+use scanner::{Token, TokenType};
+
+/// The types an annotation can name. Any other name is taken to be a class.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    // Whatever isn't annotated or can't be worked out, which every type accepts.
+    Any,
+    Number,
+    String,
+    Bool,
+    Nil,
+    Function,
+    Class,
+    List,
+    Map,
+    Instance(Arc<str>)
+}
+
+impl Type {
+    pub fn from_annotation(name: &Token) -> Type {
+        match &name.lexeme[..] {
+            "Any" => Type::Any,
+            "Number" => Type::Number,
+            "String" => Type::String,
+            "Bool" => Type::Bool,
+            "Nil" => Type::Nil,
+            "Function" => Type::Function,
+            "Class" => Type::Class,
+            "List" => Type::List,
+            "Map" => Type::Map,
+            _ => Type::Instance(name.lexeme.clone())
+        }
+    }
+}
+
+impl Display for Type {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match *self {
+            Type::Instance(ref class) => write!(f, "{}", class),
+            ref other => write!(f, "{:?}", other)
+        }
+    }
+}
+
+enum Declaration {
+    Value(Type),
+    Function(Vec<Type>, Type),
+    Class(Option<Arc<str>>)
+}
+
+/// Values that don't match their annotations: initializers and assignments to annotated
+/// variables, arguments to annotated parameters and returns from functions with a return
+/// type. Checking is best-effort; only literals, operators and names with annotations have a
+/// known type, and anything else is assumed to fit.
+pub struct TypeMismatch {
+    // The outermost scope holds the program's globals.
+    scopes: Vec<HashMap<Arc<str>, Declaration>>,
+    return_types: Vec<Type>
+}
+
+impl TypeMismatch {
+    pub fn new() -> Self {
+        TypeMismatch {
+            scopes: vec![HashMap::new()],
+            return_types: Vec::new()
+        }
+    }
+
+    fn define(&mut self, name: &Token, declaration: Declaration) {
+        self.scopes.last_mut().unwrap().insert(name.lexeme.clone(), declaration);
+    }
+
+    fn lookup(&self, name: &Token) -> Option<&Declaration> {
+        self.scopes.iter().rev().filter_map(|scope| scope.get(&name.lexeme)).next()
+    }
+
+    fn accepts(&self, expected: &Type, actual: &Type) -> bool {
+        match (expected, actual) {
+            (&Type::Any, _) | (_, &Type::Any) => true,
+            (&Type::Instance(ref expected), &Type::Instance(ref actual)) => self.inherits(actual, expected),
+            (expected, actual) => expected == actual
+        }
+    }
+
+    fn inherits(&self, class: &Arc<str>, ancestor: &Arc<str>) -> bool {
+        // Redeclaring a class can make the chain loop back on itself.
+        let mut seen = Vec::new();
+        let mut class = class.clone();
+        while !seen.contains(&class) {
+            if class == *ancestor {
+                return true;
+            }
+
+            let superclass = self.scopes.iter().rev().filter_map(|scope| scope.get(&class)).next();
+            seen.push(class);
+            match superclass {
+                Some(&Declaration::Class(Some(ref superclass))) => class = superclass.clone(),
+                _ => return false
+            }
+        }
+
+        false
+    }
+
+    fn check(&self, expected: &Type, actual: &Type) -> Option<String> {
+        if self.accepts(expected, actual) {
+            None
+        } else {
+            Some(format!("Expected {} but got {}.", expected, actual))
+        }
+    }
+
+    fn infer(&self, expr: &Expr) -> Type {
+        match *expr {
+            Expr::Literal(Literal::Number(_), _) => Type::Number,
+            Expr::Literal(Literal::String(_), _) => Type::String,
+            Expr::Literal(Literal::Bool(_), _) => Type::Bool,
+            Expr::Literal(Literal::Nil, _) => Type::Nil,
+            Expr::Grouping(ref v) => self.infer(&v.expression),
+            Expr::Unary(ref v) => match v.operator.token_type {
+                TokenType::Minus => Type::Number,
+                _ => Type::Bool
+            },
+            Expr::Binary(ref v) => match v.operator.token_type {
+                TokenType::Minus | TokenType::Slash | TokenType::Star => Type::Number,
+                TokenType::Plus => match (self.infer(&v.left), self.infer(&v.right)) {
+                    (Type::String, Type::String) => Type::String,
+                    (Type::Number, Type::Number) => Type::Number,
+                    _ => Type::Any
+                },
+                _ => Type::Bool
+            },
+            Expr::Logical(ref v) => {
+                let left = self.infer(&v.left);
+                if left == self.infer(&v.right) { left } else { Type::Any }
+            },
+            Expr::Variable(ref v) => match self.lookup(&v.name) {
+                Some(&Declaration::Value(ref value_type)) => value_type.clone(),
+                Some(&Declaration::Function(..)) => Type::Function,
+                Some(&Declaration::Class(_)) => Type::Class,
+                None => Type::Any
+            },
+            Expr::Assign(ref v) => self.infer(&v.value),
+            Expr::Call(ref v) => match *v.callee {
+                Expr::Variable(ref callee) => match self.lookup(&callee.name) {
+                    Some(&Declaration::Function(_, ref return_type)) => return_type.clone(),
+                    Some(&Declaration::Class(_)) => Type::Instance(callee.name.lexeme.clone()),
+                    _ => Type::Any
+                },
+                _ => Type::Any
+            },
+            _ => Type::Any
+        }
+    }
+}
+
+fn annotated(annotation: &Option<Token>) -> Type {
+    annotation.as_ref().map_or(Type::Any, Type::from_annotation)
+}
+
+impl Lint for TypeMismatch {
+    fn name(&self) -> &'static str {
+        "type-mismatch"
+    }
+
+    fn check_stmt(&mut self, stmt: &Stmt, context: &mut Context) {
+        match *stmt {
+            Stmt::Var(ref v) => {
+                let declared = annotated(&v.type_annotation);
+                if let Some(ref initializer) = v.initializer {
+                    if let Some(message) = self.check(&declared, &self.infer(initializer)) {
+                        context.report(&v.name, message);
+                    }
+                }
+                self.define(&v.name, Declaration::Value(declared));
+            },
+            Stmt::Function(ref v) => {
+                let params = v.param_types.iter().map(annotated).collect();
+                self.define(&v.name, Declaration::Function(params, annotated(&v.return_type)));
+            },
+            Stmt::Class(ref v) => {
+                let superclass = v.superclass.as_ref().map(|superclass| superclass.name.lexeme.clone());
+                self.define(&v.name, Declaration::Class(superclass));
+            },
+            Stmt::Return(ref v) => {
+                let expected = match self.return_types.last() {
+                    Some(expected) => expected.clone(),
+                    None => return
+                };
+                let actual = v.value.as_ref().map_or(Type::Nil, |value| self.infer(value));
+                if let Some(message) = self.check(&expected, &actual) {
+                    context.report(&v.keyword, message);
+                }
+            },
+            _ => ()
+        }
+    }
+
+    fn check_expr(&mut self, expr: &Expr, context: &mut Context) {
+        match *expr {
+            Expr::Assign(ref v) => {
+                if let Some(&Declaration::Value(ref declared)) = self.lookup(&v.name) {
+                    if let Some(message) = self.check(declared, &self.infer(&v.value)) {
+                        context.report(&v.name, message);
+                    }
+                }
+            },
+            Expr::Call(ref v) => {
+                let params = match *v.callee {
+                    Expr::Variable(ref callee) => match self.lookup(&callee.name) {
+                        Some(&Declaration::Function(ref params, _)) => params,
+                        _ => return
+                    },
+                    _ => return
+                };
+
+                for (param, argument) in params.iter().zip(v.arguments.iter()) {
+                    if let Some(message) = self.check(param, &self.infer(argument)) {
+                        context.report_span(argument.span(), message);
+                    }
+                }
+            },
+            _ => ()
+        }
+    }
+
+    fn enter_scope(&mut self, _: &mut Context) {
+        self.scopes.push(HashMap::new());
+    }
+
+    fn leave_scope(&mut self, _: &mut Context) {
+        self.scopes.pop();
+    }
+
+    fn enter_function(&mut self, function: &Function, _: &mut Context) {
+        let params = function.params.iter().zip(function.param_types.iter())
+            .map(|(param, param_type)| (param.lexeme.clone(), Declaration::Value(annotated(param_type))))
+            .collect();
+        self.scopes.push(params);
+        self.return_types.push(annotated(&function.return_type));
+    }
+
+    fn leave_function(&mut self, _: &Function, _: &mut Context) {
+        self.scopes.pop();
+        self.return_types.pop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lint::Linter;
+    use parser::Parser;
+    use resolver::Resolver;
+    use scanner::Scanner;
+
+    fn mismatches(source: &str) -> Vec<String> {
+        let (tokens, errors) = Scanner::new(source).scan_tokens();
+        assert!(errors.is_empty(), "{:?}", errors);
+        let mut messages: Vec<String> = Vec::new();
+        let (mut statements, _) = Parser::new(&tokens).parse(&mut messages);
+        Resolver::new(&mut messages).resolve(&mut statements);
+        assert!(messages.is_empty(), "{:?}", messages);
+
+        let mut linter = Linter::new();
+        linter.register(Box::new(TypeMismatch::new()));
+        linter.check(&statements, &mut messages);
+        messages
+    }
+
+    #[test]
+    fn an_initializer_has_to_match_the_annotation() {
+        assert_eq!(mismatches("var x: Number = \"s\";"), vec![
+            "[line 1, column 5 ] Warning : Expected Number but got String.".to_string()
+        ]);
+        assert!(mismatches("var x: Number = 1 + 2; var y: String = \"a\" + \"b\"; var z = \"s\";").is_empty());
+    }
+
+    #[test]
+    fn a_subclass_instance_fits_a_superclass_parameter() {
+        let source = "class A {} class B < A {} class C {}\n\
+                      fun f(a: A) {}\n\
+                      f(B()); f(A()); f(C());";
+        assert_eq!(mismatches(source), vec![
+            "[line 3, column 19 ] Warning : Expected A but got C.".to_string()
+        ]);
+    }
+
+    #[test]
+    fn a_class_redeclared_into_a_loop_does_not_hang() {
+        let source = "class A {} class B < A {} class A < B {} class C {}\n\
+                      fun f(c: C) {}\n\
+                      f(A());";
+        assert_eq!(mismatches(source), vec![
+            "[line 3, column 3 ] Warning : Expected C but got A.".to_string()
+        ]);
+    }
+
+    #[test]
+    fn a_bare_return_is_nil_to_a_return_type() {
+        assert_eq!(mismatches("fun f() -> Number {\n  return;\n}"), vec![
+            "[line 2, column 3 ] Warning : Expected Number but got Nil.".to_string()
+        ]);
+        assert!(mismatches("fun f() -> Number { return 1; } fun g() { return; } fun h() -> Nil { return; }").is_empty());
+    }
+}
@@ -0,0 +1,75 @@
+// Runs scripts in a browser, for a playground page. Built with the `wasm` feature for the
+// wasm32-unknown-unknown target, then bound to JavaScript with wasm-bindgen.
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::mem;
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use interpreter::{Interpreter, InterpreterConfig};
+use {Lox, PrintReporter};
+
+// Collects what's written for `Playground::run` to hand back. Scripts, errors and the
+// reporter all share one, so everything comes out in the order it happened.
+#[derive(Clone)]
+struct Buffer(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Buffer {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// An interpreter with the standard library, keeping its globals from one run to the next.
+/// A page has no files, environment or console input to give scripts, so natives needing those
+/// are disabled.
+#[wasm_bindgen]
+pub struct Playground {
+    lox: Lox,
+    interpreter: Interpreter,
+    output: Buffer
+}
+
+#[wasm_bindgen]
+impl Playground {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Playground {
+        let output = Buffer(Rc::new(RefCell::new(Vec::new())));
+        let mut lox = Lox::new();
+        lox.set_reporter(Box::new(PrintReporter::new(Box::new(output.clone()))));
+
+        let mut playground = Playground {
+            lox: lox,
+            interpreter: Interpreter::with_stdlib(),
+            output: output
+        };
+        playground.reset();
+        playground
+    }
+
+    /// Runs `source`, returning everything it printed along with any errors.
+    pub fn run(&mut self, source: &str) -> String {
+        // Anything that went wrong has already been written out by the reporter.
+        let _ = self.lox.run(&source.to_string(), &mut self.interpreter);
+        let output = mem::replace(&mut *self.output.0.borrow_mut(), Vec::new());
+        String::from_utf8_lossy(&output).into_owned()
+    }
+
+    /// Forgets every global the scripts run so far have defined.
+    pub fn reset(&mut self) {
+        self.interpreter = Interpreter::with_stdlib();
+        self.interpreter.set_config(InterpreterConfig {
+            filesystem: false,
+            stdin: false,
+            environment: false,
+            ..InterpreterConfig::trusted()
+        });
+        self.interpreter.set_output(Box::new(self.output.clone()));
+        self.interpreter.set_error_output(Box::new(self.output.clone()));
+    }
+}